@@ -0,0 +1,233 @@
+//! De-identification of forwarded copies: names, identifiers, free text, the birth date and
+//! private tags are removed or emptied and instance UIDs replaced, at every sequence depth.
+//! Other dates and patient sex, age, size and weight are kept. This covers the attributes
+//! below, not the whole PS3.15 Basic Application Level Confidentiality Profile.
+
+use dicom_core::value::Value;
+use dicom_core::{DataElement, PrimitiveValue, Tag, VR, header::Header};
+use dicom_dictionary_std::tags;
+use dicom_object::InMemDicomObject;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Attributes removed outright
+const REMOVED: &[Tag] = &[
+    tags::PATIENT_BIRTH_TIME, tags::PATIENT_BIRTH_NAME, tags::OTHER_PATIENT_NAMES,
+    tags::OTHER_PATIENT_I_DS_SEQUENCE, tags::ISSUER_OF_PATIENT_ID, tags::PATIENT_ADDRESS,
+    tags::PATIENT_TELEPHONE_NUMBERS, tags::PATIENT_MOTHER_BIRTH_NAME, tags::MILITARY_RANK, tags::ETHNIC_GROUP,
+    tags::OCCUPATION, tags::ADDITIONAL_PATIENT_HISTORY, tags::PATIENT_COMMENTS,
+    tags::CURRENT_PATIENT_LOCATION, tags::ADMISSION_ID, tags::PATIENT_INSURANCE_PLAN_CODE_SEQUENCE,
+    tags::INSTITUTION_NAME, tags::INSTITUTION_ADDRESS, tags::INSTITUTIONAL_DEPARTMENT_NAME, tags::STATION_NAME,
+    tags::DEVICE_SERIAL_NUMBER, tags::REFERRING_PHYSICIAN_ADDRESS, tags::PERFORMING_PHYSICIAN_NAME,
+    tags::NAME_OF_PHYSICIANS_READING_STUDY, tags::OPERATORS_NAME, tags::PHYSICIANS_OF_RECORD,
+    tags::REQUESTING_PHYSICIAN, tags::PERFORMED_PROCEDURE_STEP_ID, tags::REQUESTED_PROCEDURE_ID,
+    tags::REFERENCED_PATIENT_SEQUENCE, tags::REFERENCED_STUDY_SEQUENCE,
+    tags::REFERENCED_PERFORMED_PROCEDURE_STEP_SEQUENCE, tags::REQUEST_ATTRIBUTES_SEQUENCE,
+    // Free text that may name the patient or staff
+    tags::STUDY_DESCRIPTION, tags::SERIES_DESCRIPTION, tags::PROTOCOL_NAME, tags::REQUESTED_PROCEDURE_DESCRIPTION,
+    tags::PERFORMED_PROCEDURE_STEP_DESCRIPTION, tags::ADMITTING_DIAGNOSES_DESCRIPTION, tags::DERIVATION_DESCRIPTION,
+    tags::IMAGE_COMMENTS, tags::VISIT_COMMENTS, tags::REASON_FOR_THE_REQUESTED_PROCEDURE,
+    tags::REQUESTED_PROCEDURE_COMMENTS, tags::IMAGING_SERVICE_REQUEST_COMMENTS,
+    // Retired, but still found in older data: Other Patient IDs, Medical Record Locator,
+    // Acquisition Comments, Study Comments
+    Tag(0x0010, 0x1000), Tag(0x0010, 0x1090), Tag(0x0018, 0x4000), Tag(0x0032, 0x4000),
+];
+
+/// Attributes kept but emptied; person names are emptied wherever they are
+const EMPTIED: &[Tag] = &[
+    tags::PATIENT_BIRTH_DATE, tags::ACCESSION_NUMBER, tags::STUDY_ID,
+];
+
+/// UIDs that name classes and encodings rather than instances, kept as they are
+const KEPT_UIDS: &[Tag] = &[
+    tags::SOP_CLASS_UID, tags::REFERENCED_SOP_CLASS_UID, tags::TRANSFER_SYNTAX_UID, tags::CODING_SCHEME_UID,
+    tags::MEDIA_STORAGE_SOP_CLASS_UID,
+];
+
+/// Root of the UIDs defined by the standard, which identify nothing
+const STANDARD_UID_ROOT: &str = "1.2.840.10008.";
+
+pub struct Anonymizer {
+    key: Vec<u8>,
+}
+
+impl Anonymizer {
+    /// Pseudonyms and UIDs are keyed hashes of the originals: stable across runs with the
+    /// same key, and random per run without one
+    pub fn new(key: Option<&str>) -> Self {
+        let key = match key {
+            Some(key) => key.as_bytes().to_vec(),
+            None => Uuid::new_v4().as_bytes().to_vec(),
+        };
+        Self { key }
+    }
+
+    pub fn apply(&self, dataset: &mut InMemDicomObject) {
+        let patient_id = string(dataset, tags::PATIENT_ID).unwrap_or_default();
+        self.clean(dataset, false);
+
+        let digest = self.digest(patient_id.as_bytes());
+        let pseudonym = format!("ANON-{:012X}", u64::from_be_bytes(digest[..8].try_into().unwrap()) >> 16);
+        dataset.put(DataElement::new(tags::PATIENT_ID, VR::LO, PrimitiveValue::from(pseudonym.as_str())));
+        dataset.put(DataElement::new(tags::PATIENT_NAME, VR::PN, PrimitiveValue::from(pseudonym.as_str())));
+
+        // Patient Identity Removed is left alone: YES would claim the full profile
+        let method: PrimitiveValue = PrimitiveValue::Strs([
+            "Names, IDs, free text, birth date and private tags removed",
+            "Instance UIDs replaced, other dates kept",
+        ].into_iter().map(String::from).collect());
+        dataset.put(DataElement::new(tags::DEIDENTIFICATION_METHOD, VR::LO, method));
+    }
+
+    /// Remove, empty and replace attributes of one data set and of the items of its sequences;
+    /// nested items lose their Patient ID, which only the top level keeps as a pseudonym
+    fn clean(&self, dataset: &mut InMemDicomObject, nested: bool) {
+        let elements: Vec<(Tag, VR)> = dataset.iter().map(|element| (element.tag(), element.vr())).collect();
+        for (tag, vr) in elements {
+            if tag.group() % 2 == 1 || REMOVED.contains(&tag) || (nested && tag == tags::PATIENT_ID) {
+                dataset.remove_element(tag);
+            } else if vr == VR::SQ {
+                dataset.update_value(tag, |value| {
+                    if let Value::Sequence(sequence) = value {
+                        for item in sequence.items_mut() {
+                            self.clean(item, true);
+                        }
+                    }
+                });
+            } else if vr == VR::PN || EMPTIED.contains(&tag) {
+                dataset.put(DataElement::new(tag, vr, PrimitiveValue::Empty));
+            } else if vr == VR::UI && !KEPT_UIDS.contains(&tag) {
+                let Some(uids) = dataset.element(tag).ok().and_then(|element| element.to_multi_str().ok()) else {
+                    continue;
+                };
+                let replaced: Vec<String> = uids.iter()
+                    .map(|uid| uid.trim_end_matches(['\0', ' ']))
+                    .map(|uid| if uid.is_empty() || uid.starts_with(STANDARD_UID_ROOT) { uid.to_string() } else { self.uid(uid) })
+                    .collect();
+                dataset.put(DataElement::new(tag, VR::UI, PrimitiveValue::Strs(replaced.into_iter().collect())));
+            }
+        }
+    }
+
+    /// UID under the 2.25 (UUID-derived) root
+    fn uid(&self, original: &str) -> String {
+        let digest = self.digest(original.as_bytes());
+        let value = u128::from_be_bytes(digest[..16].try_into().unwrap());
+        format!("2.25.{}", value)
+    }
+
+    fn digest(&self, value: &[u8]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.key);
+        hasher.update([0]);
+        hasher.update(value);
+        hasher.finalize().into()
+    }
+}
+
+fn string(dataset: &InMemDicomObject, tag: Tag) -> Option<String> {
+    let element = dataset.element(tag).ok()?;
+    let value = element.to_str().ok()?;
+    Some(value.trim_end_matches(['\0', ' ']).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom_core::value::DataSetSequence;
+    use super::*;
+
+    fn put(dataset: &mut InMemDicomObject, tag: Tag, vr: VR, value: &str) {
+        dataset.put(DataElement::new(tag, vr, PrimitiveValue::from(value)));
+    }
+
+    fn sequence(dataset: &mut InMemDicomObject, tag: Tag, items: Vec<InMemDicomObject>) {
+        dataset.put(DataElement::new(tag, VR::SQ, DataSetSequence::from(items)));
+    }
+
+    fn items(dataset: &InMemDicomObject, tag: Tag) -> &[InMemDicomObject] {
+        dataset.element(tag).unwrap().items().unwrap()
+    }
+
+    fn instance() -> InMemDicomObject {
+        let mut dataset = InMemDicomObject::new_empty();
+        put(&mut dataset, tags::SOP_CLASS_UID, VR::UI, "1.2.840.10008.5.1.4.1.1.2");
+        put(&mut dataset, tags::SOP_INSTANCE_UID, VR::UI, "1.2.3.4.5");
+        put(&mut dataset, tags::SERIES_INSTANCE_UID, VR::UI, "1.2.3.4");
+        put(&mut dataset, tags::PATIENT_NAME, VR::PN, "DOE^JANE");
+        put(&mut dataset, tags::PATIENT_ID, VR::LO, "12345");
+        put(&mut dataset, tags::PATIENT_BIRTH_DATE, VR::DA, "19700101");
+        put(&mut dataset, tags::PATIENT_SEX, VR::CS, "F");
+        put(&mut dataset, tags::STUDY_DATE, VR::DA, "20240102");
+        put(&mut dataset, tags::STUDY_DESCRIPTION, VR::LO, "CT for Jane Doe");
+        put(&mut dataset, tags::SERIES_DESCRIPTION, VR::LO, "Dr Smith's protocol");
+        put(&mut dataset, tags::PATIENT_IDENTITY_REMOVED, VR::CS, "NO");
+        put(&mut dataset, Tag(0x0009, 0x1001), VR::LO, "private");
+
+        // Source Image Sequence > Referenced SOP Instance, and a request with nested PHI
+        let mut source = InMemDicomObject::new_empty();
+        put(&mut source, tags::REFERENCED_SOP_CLASS_UID, VR::UI, "1.2.840.10008.5.1.4.1.1.2");
+        put(&mut source, tags::REFERENCED_SOP_INSTANCE_UID, VR::UI, "1.2.3.4.5");
+        sequence(&mut dataset, tags::SOURCE_IMAGE_SEQUENCE, vec![source]);
+
+        let mut observer = InMemDicomObject::new_empty();
+        put(&mut observer, tags::VERIFYING_OBSERVER_NAME, VR::PN, "SMITH^JOHN");
+        put(&mut observer, tags::INSTITUTION_NAME, VR::LO, "General Hospital");
+        put(&mut observer, tags::PATIENT_ID, VR::LO, "12345");
+        put(&mut observer, Tag(0x0011, 0x1010), VR::LO, "private");
+        let mut code = InMemDicomObject::new_empty();
+        put(&mut code, tags::CODE_MEANING, VR::LO, "Chest");
+        put(&mut code, tags::REFERENCED_SOP_INSTANCE_UID, VR::UI, "1.2.3.4.6");
+        put(&mut code, tags::PROTOCOL_NAME, VR::LO, "Jane's follow-up");
+        sequence(&mut observer, tags::CONCEPT_NAME_CODE_SEQUENCE, vec![code]);
+        sequence(&mut dataset, tags::VERIFYING_OBSERVER_SEQUENCE, vec![observer]);
+        dataset
+    }
+
+    #[test]
+    fn identifying_attributes_are_removed_at_every_depth() {
+        let mut dataset = instance();
+        Anonymizer::new(Some("secret")).apply(&mut dataset);
+
+        for tag in [tags::STUDY_DESCRIPTION, tags::SERIES_DESCRIPTION, Tag(0x0009, 0x1001)] {
+            assert!(dataset.element(tag).is_err(), "{} kept", tag);
+        }
+        assert_eq!(string(&dataset, tags::PATIENT_BIRTH_DATE).as_deref(), Some(""));
+        assert_eq!(string(&dataset, tags::STUDY_DATE).as_deref(), Some("20240102"));
+        assert_eq!(string(&dataset, tags::PATIENT_SEX).as_deref(), Some("F"));
+        assert!(string(&dataset, tags::PATIENT_NAME).unwrap().starts_with("ANON-"));
+        assert_eq!(string(&dataset, tags::PATIENT_IDENTITY_REMOVED).as_deref(), Some("NO"));
+
+        let observer = &items(&dataset, tags::VERIFYING_OBSERVER_SEQUENCE)[0];
+        assert_eq!(string(observer, tags::VERIFYING_OBSERVER_NAME).as_deref(), Some(""));
+        for tag in [tags::INSTITUTION_NAME, tags::PATIENT_ID, Tag(0x0011, 0x1010)] {
+            assert!(observer.element(tag).is_err(), "nested {} kept", tag);
+        }
+        let code = &items(observer, tags::CONCEPT_NAME_CODE_SEQUENCE)[0];
+        assert_eq!(string(code, tags::CODE_MEANING).as_deref(), Some("Chest"));
+        assert!(code.element(tags::PROTOCOL_NAME).is_err());
+        assert!(string(code, tags::REFERENCED_SOP_INSTANCE_UID).unwrap().starts_with("2.25."));
+    }
+
+    #[test]
+    fn instance_uids_are_replaced_consistently_and_class_uids_kept() {
+        let anonymizer = Anonymizer::new(Some("secret"));
+        let mut dataset = instance();
+        anonymizer.apply(&mut dataset);
+
+        let sop_instance = string(&dataset, tags::SOP_INSTANCE_UID).unwrap();
+        assert!(sop_instance.starts_with("2.25."));
+        assert_ne!(string(&dataset, tags::SERIES_INSTANCE_UID).unwrap(), "1.2.3.4");
+        assert_eq!(string(&dataset, tags::SOP_CLASS_UID).as_deref(), Some("1.2.840.10008.5.1.4.1.1.2"));
+
+        // References inside sequences follow the instances they point to
+        let source = &items(&dataset, tags::SOURCE_IMAGE_SEQUENCE)[0];
+        assert_eq!(string(source, tags::REFERENCED_SOP_INSTANCE_UID), Some(sop_instance.clone()));
+        assert_eq!(string(source, tags::REFERENCED_SOP_CLASS_UID).as_deref(), Some("1.2.840.10008.5.1.4.1.1.2"));
+
+        // The same key gives the same UIDs and pseudonyms on another run
+        let mut again = instance();
+        Anonymizer::new(Some("secret")).apply(&mut again);
+        assert_eq!(string(&again, tags::SOP_INSTANCE_UID), Some(sop_instance));
+        assert_eq!(string(&again, tags::PATIENT_ID), string(&dataset, tags::PATIENT_ID));
+    }
+}
@@ -0,0 +1,302 @@
+//! BIDS-style JSON sidecars for MR and PET series, modelled on the fields dcm2niix writes.
+
+use std::path::PathBuf;
+use dicom_core::Tag;
+use dicom_dictionary_std::tags;
+use crate::{DicomSeries, DicomStudy, tag_key};
+use crate::geometry::parse_vector;
+
+/// Siemens MosaicRefAcqTimes, slice acquisition times in ms (private, needs --include-private)
+const SIEMENS_MOSAIC_REF_ACQ_TIMES: Tag = Tag(0x0019, 0x1029);
+
+/// Sidecar fields copied verbatim as strings
+const TEXT_FIELDS: &[(&str, Tag)] = &[
+    ("Modality", tags::MODALITY),
+    ("Manufacturer", tags::MANUFACTURER),
+    ("ManufacturersModelName", tags::MANUFACTURER_MODEL_NAME),
+    ("DeviceSerialNumber", tags::DEVICE_SERIAL_NUMBER),
+    ("StationName", tags::STATION_NAME),
+    ("SoftwareVersions", tags::SOFTWARE_VERSIONS),
+    ("InstitutionName", tags::INSTITUTION_NAME),
+    ("InstitutionalDepartmentName", tags::INSTITUTIONAL_DEPARTMENT_NAME),
+    ("ReceiveCoilName", tags::RECEIVE_COIL_NAME),
+    ("BodyPartExamined", tags::BODY_PART_EXAMINED),
+    ("PatientPosition", tags::PATIENT_POSITION),
+    ("SeriesDescription", tags::SERIES_DESCRIPTION),
+    ("ProtocolName", tags::PROTOCOL_NAME),
+    ("ScanningSequence", tags::SCANNING_SEQUENCE),
+    ("SequenceVariant", tags::SEQUENCE_VARIANT),
+    ("ScanOptions", tags::SCAN_OPTIONS),
+    ("SequenceName", tags::SEQUENCE_NAME),
+    ("MRAcquisitionType", tags::MR_ACQUISITION_TYPE),
+    ("AcquisitionTime", tags::ACQUISITION_TIME),
+    ("Units", tags::UNITS),
+    ("AttenuationCorrection", tags::ATTENUATION_CORRECTION_METHOD),
+    ("ReconMethodName", tags::RECONSTRUCTION_METHOD),
+];
+
+/// Sidecar fields converted to numbers, with a scale factor (ms to s for timings)
+const NUMBER_FIELDS: &[(&str, Tag, f64)] = &[
+    ("MagneticFieldStrength", tags::MAGNETIC_FIELD_STRENGTH, 1.0),
+    ("ImagingFrequency", tags::IMAGING_FREQUENCY, 1.0),
+    ("SliceThickness", tags::SLICE_THICKNESS, 1.0),
+    ("SpacingBetweenSlices", tags::SPACING_BETWEEN_SLICES, 1.0),
+    ("EchoTime", tags::ECHO_TIME, 0.001),
+    ("RepetitionTime", tags::REPETITION_TIME, 0.001),
+    ("InversionTime", tags::INVERSION_TIME, 0.001),
+    ("FlipAngle", tags::FLIP_ANGLE, 1.0),
+    ("PixelBandwidth", tags::PIXEL_BANDWIDTH, 1.0),
+    ("PercentPhaseFOV", tags::PERCENT_PHASE_FIELD_OF_VIEW, 1.0),
+    ("PercentSampling", tags::PERCENT_SAMPLING, 1.0),
+    ("SeriesNumber", tags::SERIES_NUMBER, 1.0),
+    ("AcquisitionNumber", tags::ACQUISITION_NUMBER, 1.0),
+    ("EchoNumber", tags::ECHO_NUMBERS, 1.0),
+    ("EchoTrainLength", tags::ECHO_TRAIN_LENGTH, 1.0),
+];
+
+/// BIDS datatype directory and filename entities guessed for a series
+struct BidsName {
+    datatype: &'static str,
+    task: Option<String>,
+    suffix: String,
+}
+
+/// Whether a series gets a sidecar (MR and PET only)
+pub fn is_supported(series: &DicomSeries) -> bool {
+    matches!(series.modality.as_deref().map(str::trim), Some("MR") | Some("PT"))
+}
+
+/// Suggested sidecar path, e.g. `sub-01/ses-20230101/anat/sub-01_ses-20230101_run-3_T1w.json`
+pub fn suggested_path(study: &DicomStudy, series: &DicomSeries) -> PathBuf {
+    let subject = bids_label(study.patient_info.patient_id.as_deref().unwrap_or_default())
+        .unwrap_or_else(|| "unknown".to_string());
+    let session = study.study_date.as_deref().and_then(bids_label);
+    let name = classify(series);
+
+    let mut path = PathBuf::from(format!("sub-{}", subject));
+    let mut file_name = format!("sub-{}", subject);
+    if let Some(session) = &session {
+        path.push(format!("ses-{}", session));
+        file_name.push_str(&format!("_ses-{}", session));
+    }
+    if let Some(task) = &name.task {
+        file_name.push_str(&format!("_task-{}", task));
+    }
+    if let Some(run) = series.series_number.as_deref().and_then(|n| n.trim().parse::<i64>().ok()) {
+        file_name.push_str(&format!("_run-{}", run));
+    }
+    file_name.push_str(&format!("_{}.json", name.suffix));
+
+    path.push(name.datatype);
+    path.push(file_name);
+    path
+}
+
+pub fn create_sidecar(series: &DicomSeries) -> serde_json::Value {
+    let mut sidecar = serde_json::Map::new();
+
+    for (field, tag) in TEXT_FIELDS {
+        if let Some(value) = series.tag_value(*tag) {
+            sidecar.insert(field.to_string(), serde_json::Value::String(value));
+        }
+    }
+
+    for (field, tag, scale) in NUMBER_FIELDS {
+        if let Some(value) = series.tag_value(*tag).and_then(|v| v.trim().parse::<f64>().ok()) {
+            sidecar.insert(field.to_string(), number(value * scale));
+        }
+    }
+
+    if let Some(image_type) = series.tag_value(tags::IMAGE_TYPE) {
+        sidecar.insert(
+            "ImageType".to_string(),
+            image_type.split('\\').map(|v| serde_json::Value::String(v.trim().to_string())).collect(),
+        );
+    }
+
+    // BIDS PhaseEncodingDirection is the axis plus its polarity ("j" or "j-"). In Plane Phase
+    // Encoding Direction only gives the axis; the polarity is held only in vendor binary headers
+    // (Siemens CSA PhaseEncodingDirectionPositive), which are not decoded here. A guessed sign
+    // would silently flip distortion correction, so only PhaseEncodingAxis is written.
+    match series.tag_value(tags::IN_PLANE_PHASE_ENCODING_DIRECTION).as_deref().map(str::trim) {
+        Some("ROW") => { sidecar.insert("PhaseEncodingAxis".to_string(), "i".into()); }
+        Some("COL") => { sidecar.insert("PhaseEncodingAxis".to_string(), "j".into()); }
+        _ => {}
+    }
+
+    if let Some(slice_timing) = slice_timing(series) {
+        sidecar.insert("SliceTiming".to_string(), slice_timing.into_iter().map(number).collect());
+    }
+
+    sidecar.insert("ConversionSoftware".to_string(), "dicom-json".into());
+    sidecar.insert("ConversionSoftwareVersion".to_string(), env!("CARGO_PKG_VERSION").into());
+
+    serde_json::Value::Object(sidecar)
+}
+
+fn classify(series: &DicomSeries) -> BidsName {
+    let description = format!(
+        "{} {}",
+        series.series_description.as_deref().unwrap_or_default(),
+        series.tag_value(tags::PROTOCOL_NAME).unwrap_or_default()
+    ).to_lowercase();
+    let has = |words: &[&str]| words.iter().any(|w| description.contains(w));
+    let name = |datatype, suffix: &str| BidsName { datatype, task: None, suffix: suffix.to_string() };
+
+    if series.modality.as_deref().map(str::trim) == Some("PT") {
+        return name("pet", "pet");
+    }
+    if has(&["localizer", "localiser", "scout", "survey"]) {
+        return name("misc", "localizer");
+    }
+    if has(&["bold", "fmri", "func", "rest"]) {
+        let task = description.split("task-").nth(1)
+            .and_then(|rest| bids_label(rest.split(|c: char| !c.is_ascii_alphanumeric()).next()?))
+            .unwrap_or_else(|| if has(&["rest"]) { "rest".to_string() } else { "unknown".to_string() });
+        return BidsName { datatype: "func", task: Some(task), suffix: "bold".to_string() };
+    }
+    if has(&["dwi", "dti", "diff"]) {
+        return name("dwi", "dwi");
+    }
+    if has(&["fieldmap", "field_map", "field map", "fmap", "b0map"]) {
+        let is_phase = series.tag_value(tags::IMAGE_TYPE)
+            .is_some_and(|t| t.split('\\').any(|v| matches!(v.trim(), "P" | "PHASE")));
+        return name("fmap", if is_phase { "phasediff" } else { "magnitude" });
+    }
+    if has(&["flair"]) {
+        return name("anat", "FLAIR");
+    }
+    if has(&["t2star", "t2*", "swi"]) {
+        return name("anat", "T2starw");
+    }
+    if has(&["t1", "mprage", "spgr", "mp2rage"]) {
+        return name("anat", "T1w");
+    }
+    if has(&["t2", "tse"]) {
+        return name("anat", "T2w");
+    }
+    if has(&["pd"]) {
+        return name("anat", "PDw");
+    }
+
+    let fallback = series.series_description.as_deref().and_then(bids_label);
+    name("misc", fallback.as_deref().unwrap_or("unknown"))
+}
+
+/// Slice acquisition times in seconds, from the Siemens mosaic field when available,
+/// otherwise from per-slice Acquisition Time of the first volume of a functional run
+fn slice_timing(series: &DicomSeries) -> Option<Vec<f64>> {
+    if let Some(times) = series.tag_value(SIEMENS_MOSAIC_REF_ACQ_TIMES) {
+        let times: Option<Vec<f64>> = times.split('\\').map(|t| t.trim().parse::<f64>().ok().map(|ms| ms / 1000.0)).collect();
+        if let Some(times) = times.filter(|t| !t.is_empty()) {
+            return Some(times);
+        }
+    }
+
+    if classify(series).datatype != "func" {
+        return None;
+    }
+
+    // Instances are in spatial order; keep the earliest acquisition per slice position
+    let mut slices: Vec<(String, f64)> = Vec::new();
+    for instance in &series.instances {
+        let position = parse_vector::<3>(&instance.metadata.tags, tags::IMAGE_POSITION_PATIENT)?;
+        let key = format!("{:.2},{:.2},{:.2}", position[0], position[1], position[2]);
+        let time = instance_value(series, instance, tags::ACQUISITION_TIME).and_then(|t| parse_time(&t))?;
+        match slices.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, earliest)) => *earliest = earliest.min(time),
+            None => slices.push((key, time)),
+        }
+    }
+
+    let start = slices.iter().map(|(_, t)| *t).reduce(f64::min)?;
+    let timing: Vec<f64> = slices.iter().map(|(_, t)| t - start).collect();
+    (slices.len() > 1 && timing.iter().any(|t| *t > 0.0)).then_some(timing)
+}
+
+fn instance_value(series: &DicomSeries, instance: &crate::DicomInstance, tag: Tag) -> Option<String> {
+    let key = tag_key(tag);
+    instance.metadata.tags.get(&key)
+        .or_else(|| series.common_tags.get(&key))
+        .and_then(|t| t.raw_value.clone())
+}
+
+/// DICOM TM (HHMMSS.FFFFFF) as seconds since midnight
+fn parse_time(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let (hours, rest) = value.split_at_checked(2)?;
+    let (minutes, seconds) = rest.split_at_checked(2)?;
+    Some(hours.parse::<f64>().ok()? * 3600.0 + minutes.parse::<f64>().ok()? * 60.0 + seconds.parse::<f64>().ok()?)
+}
+
+/// BIDS labels are alphanumeric only
+fn bids_label(value: &str) -> Option<String> {
+    let label: String = value.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+    (!label.is_empty()).then_some(label)
+}
+
+fn number(value: f64) -> serde_json::Value {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        serde_json::Value::from(value as i64)
+    } else {
+        serde_json::json!((value * 1e6).round() / 1e6)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::testutil;
+
+    fn series(description: &str, entries: &[(Tag, &str, &str)]) -> DicomSeries {
+        DicomSeries {
+            series_instance_uid: "1.2.3".to_string(),
+            series_number: Some("3".to_string()),
+            series_description: Some(description.to_string()),
+            modality: Some("MR".to_string()),
+            common_tags: HashMap::new(),
+            geometry: None,
+            thumbnails: None,
+            volume: None,
+            instances: vec![testutil::instance("1.2.3.1", entries)],
+        }
+    }
+
+    #[test]
+    fn sidecar_converts_timings_to_seconds_and_reports_the_phase_axis_only() {
+        let series = series("t1_mprage", &[
+            (tags::ECHO_TIME, "DS", "2.98"),
+            (tags::REPETITION_TIME, "DS", "2300"),
+            (tags::IMAGE_TYPE, "CS", "ORIGINAL\\PRIMARY\\M"),
+            (tags::IN_PLANE_PHASE_ENCODING_DIRECTION, "CS", "COL"),
+        ]);
+        let sidecar = create_sidecar(&series);
+
+        assert_eq!(sidecar["EchoTime"], serde_json::json!(0.00298));
+        assert_eq!(sidecar["RepetitionTime"], serde_json::json!(2.3));
+        assert_eq!(sidecar["ImageType"], serde_json::json!(["ORIGINAL", "PRIMARY", "M"]));
+        assert_eq!(sidecar["PhaseEncodingAxis"], "j");
+        assert!(sidecar.get("PhaseEncodingDirection").is_none());
+    }
+
+    #[test]
+    fn functional_runs_get_a_task_and_a_run() {
+        let instance = testutil::instance("1.2.3.1", &[
+            (tags::STUDY_INSTANCE_UID, "UI", "1.2"),
+            (tags::SERIES_INSTANCE_UID, "UI", "1.2.3"),
+            (tags::PATIENT_ID, "LO", "sub 01"),
+            (tags::STUDY_DATE, "DA", "20230101"),
+            (tags::SERIES_NUMBER, "IS", "3"),
+            (tags::SERIES_DESCRIPTION, "LO", "fMRI task-nback bold"),
+            (tags::MODALITY, "CS", "MR"),
+        ]);
+        let studies = crate::build_studies(&[instance]);
+        let study = &studies["1.2"];
+
+        assert_eq!(
+            suggested_path(study, &study.series["1.2.3"]),
+            PathBuf::from("sub-sub01/ses-20230101/func/sub-sub01_ses-20230101_task-nback_run-3_bold.json")
+        );
+    }
+}
@@ -0,0 +1,287 @@
+//! Parquet export with a typed column per tag, written one row group at a time.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use anyhow::{Context, Result};
+use arrow_array::builder::{ListBuilder, StringBuilder};
+use arrow_array::types::{Date32Type, Float64Type, Int64Type};
+use arrow_array::{ArrayRef, BooleanArray, Date32Array, Float64Array, Int64Array, ListArray, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use chrono::NaiveDate;
+use dicom_core::dictionary::DataDictionary;
+use dicom_dictionary_std::StandardDataDictionary;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use crate::{DicomInstance, TagInfo, table};
+
+/// Files processed and written per row group
+pub const ROW_GROUP_SIZE: usize = 8192;
+
+#[derive(Clone, Copy, PartialEq)]
+enum ColumnKind {
+    Text,
+    Float,
+    Integer,
+    Date,
+}
+
+struct TagColumn {
+    key: String,
+    kind: ColumnKind,
+    is_list: bool,
+}
+
+/// Parquet file whose schema is fixed by the first row group
+pub struct ParquetTableWriter {
+    writer: ArrowWriter<File>,
+    schema: SchemaRef,
+    columns: Vec<TagColumn>,
+    /// Keys of the tag columns when they are the union of all tags, to spot tags without one
+    union_keys: Option<HashSet<String>>,
+    /// Tags first seen after the schema was fixed, which have no column
+    pub dropped_columns: BTreeSet<String>,
+    /// Rows whose value had to be cut to its first item because the column was inferred as scalar
+    pub truncated_values: usize,
+    pub rows_written: usize,
+}
+
+impl ParquetTableWriter {
+    /// Create the file with columns for the selected tags, or for every tag in `first_rows`.
+    /// Whether a column is a list is inferred from `first_rows`; tags first seen in later
+    /// row groups get no column and are listed in `dropped_columns`.
+    pub fn create(path: &Path, first_rows: &[DicomInstance], selected: &[dicom_core::Tag]) -> Result<Self> {
+        let no_common = HashMap::new();
+        let rows: Vec<(&DicomInstance, &HashMap<String, TagInfo>)> = first_rows.iter().map(|i| (i, &no_common)).collect();
+        let keys = if selected.is_empty() {
+            table::union_columns(&rows)
+        } else {
+            selected.iter().map(|tag| crate::tag_key(*tag)).collect()
+        };
+
+        let columns: Vec<TagColumn> = keys.into_iter().map(|key| {
+            let observed: Vec<&TagInfo> = first_rows.iter().filter_map(|i| i.metadata.tags.get(&key)).collect();
+            // Tags missing from the first row group are typed by the dictionary
+            let kind = match observed.first() {
+                Some(tag_info) => column_kind(&tag_info.vr),
+                None => StandardDataDictionary.parse_tag(&key)
+                    .and_then(|tag| StandardDataDictionary.by_tag(tag))
+                    .map(|entry| column_kind(entry.vr.to_string()))
+                    .unwrap_or(ColumnKind::Text),
+            };
+            let is_list = observed.iter().any(|t| table::tag_values(t).len() > 1);
+            TagColumn { key, kind, is_list }
+        }).collect();
+
+        let mut fields = vec![
+            Field::new("FilePath", DataType::Utf8, false),
+            Field::new("SOPInstanceUID", DataType::Utf8, false),
+            Field::new("TransferSyntaxUID", DataType::Utf8, true),
+            Field::new("HasPixelData", DataType::Boolean, false),
+        ];
+        for column in &columns {
+            let name = table::column_name(&column.key);
+            // The identifying fields above already hold the SOP Instance UID
+            let name = if name == "SOPInstanceUID" { column.key.clone() } else { name };
+            let data_type = match column.kind {
+                ColumnKind::Text => DataType::Utf8,
+                ColumnKind::Float => DataType::Float64,
+                ColumnKind::Integer => DataType::Int64,
+                ColumnKind::Date => DataType::Date32,
+            };
+            fields.push(if column.is_list {
+                Field::new(name, DataType::List(Arc::new(Field::new("item", data_type, true))), true)
+            } else {
+                Field::new(name, data_type, true)
+            });
+        }
+        let schema = Arc::new(Schema::new(fields));
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = File::create(path).with_context(|| format!("Failed to create output file: {:?}", path))?;
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(ROW_GROUP_SIZE)
+            .build();
+        let writer = ArrowWriter::try_new(file, schema.clone(), Some(properties))?;
+
+        let union_keys = selected.is_empty().then(|| columns.iter().map(|column| column.key.clone()).collect());
+        Ok(Self {
+            writer,
+            schema,
+            columns,
+            union_keys,
+            dropped_columns: BTreeSet::new(),
+            truncated_values: 0,
+            rows_written: 0,
+        })
+    }
+
+    /// Write the instances as one row group
+    pub fn write_row_group(&mut self, instances: &[DicomInstance]) -> Result<()> {
+        let mut arrays: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from_iter_values(instances.iter().map(|i| i.file_path.as_str()))),
+            Arc::new(StringArray::from_iter_values(instances.iter().map(|i| i.sop_instance_uid.as_str()))),
+            Arc::new(StringArray::from_iter(instances.iter().map(|i| i.metadata.transfer_syntax.as_deref()))),
+            Arc::new(BooleanArray::from_iter(instances.iter().map(|i| Some(i.has_pixel_data)))),
+        ];
+
+        if let Some(union_keys) = &self.union_keys {
+            let no_common = HashMap::new();
+            let rows: Vec<(&DicomInstance, &HashMap<String, TagInfo>)> = instances.iter().map(|i| (i, &no_common)).collect();
+            self.dropped_columns.extend(table::union_columns(&rows).into_iter().filter(|key| !union_keys.contains(key)));
+        }
+
+        for column in &self.columns {
+            let values: Vec<Option<Vec<String>>> = instances.iter()
+                .map(|i| i.metadata.tags.get(&column.key).map(table::tag_values))
+                .collect();
+            if !column.is_list {
+                self.truncated_values += values.iter().flatten().filter(|v| v.len() > 1).count();
+            }
+            arrays.push(build_array(column, &values));
+        }
+
+        let batch = RecordBatch::try_new(self.schema.clone(), arrays)?;
+        self.writer.write(&batch)?;
+        self.writer.flush()?;
+        self.rows_written += instances.len();
+        Ok(())
+    }
+
+    pub fn close(self) -> Result<()> {
+        self.writer.close()?;
+        Ok(())
+    }
+}
+
+fn column_kind(vr: &str) -> ColumnKind {
+    match vr {
+        "DS" | "FL" | "FD" => ColumnKind::Float,
+        "IS" | "SL" | "SS" | "UL" | "US" | "SV" | "UV" => ColumnKind::Integer,
+        "DA" => ColumnKind::Date,
+        _ => ColumnKind::Text,
+    }
+}
+
+/// Empty strings and values that do not parse as the column type become nulls
+fn build_array(column: &TagColumn, values: &[Option<Vec<String>>]) -> ArrayRef {
+    let items = |v: &Vec<String>| v.iter().map(|s| s.trim().to_string()).collect::<Vec<_>>();
+    let first = |v: &Option<Vec<String>>| v.as_ref().and_then(|v| v.first()).map(|s| s.trim().to_string()).filter(|s| !s.is_empty());
+
+    match (column.kind, column.is_list) {
+        (ColumnKind::Text, false) => Arc::new(StringArray::from_iter(values.iter().map(first))),
+        (ColumnKind::Float, false) => Arc::new(Float64Array::from_iter(values.iter().map(|v| first(v).and_then(|s| s.parse().ok())))),
+        (ColumnKind::Integer, false) => Arc::new(Int64Array::from_iter(values.iter().map(|v| first(v).and_then(|s| s.parse().ok())))),
+        (ColumnKind::Date, false) => Arc::new(Date32Array::from_iter(values.iter().map(|v| first(v).and_then(|s| date32(&s))))),
+        (ColumnKind::Text, true) => {
+            let mut builder = ListBuilder::new(StringBuilder::new());
+            for value in values {
+                match value {
+                    Some(value) => {
+                        for item in items(value) {
+                            builder.values().append_option(Some(item).filter(|s| !s.is_empty()));
+                        }
+                        builder.append(true);
+                    }
+                    None => builder.append(false),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        (ColumnKind::Float, true) => Arc::new(ListArray::from_iter_primitive::<Float64Type, _, _>(
+            values.iter().map(|v| v.as_ref().map(|v| items(v).into_iter().map(|s| s.parse().ok()).collect::<Vec<_>>())),
+        )),
+        (ColumnKind::Integer, true) => Arc::new(ListArray::from_iter_primitive::<Int64Type, _, _>(
+            values.iter().map(|v| v.as_ref().map(|v| items(v).into_iter().map(|s| s.parse().ok()).collect::<Vec<_>>())),
+        )),
+        (ColumnKind::Date, true) => Arc::new(ListArray::from_iter_primitive::<Date32Type, _, _>(
+            values.iter().map(|v| v.as_ref().map(|v| items(v).into_iter().map(|s| date32(&s)).collect::<Vec<_>>())),
+        )),
+    }
+}
+
+/// DICOM DA (YYYYMMDD) as days since the Unix epoch
+fn date32(value: &str) -> Option<i32> {
+    let date = NaiveDate::parse_from_str(value.trim(), "%Y%m%d").ok()?;
+    Some((date - NaiveDate::from_ymd_opt(1970, 1, 1)?).num_days() as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::Array;
+    use dicom_dictionary_std::tags;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use crate::testutil;
+
+    fn read(path: &Path) -> RecordBatch {
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap()).unwrap().build().unwrap();
+        let mut batches: Vec<RecordBatch> = reader.map(|batch| batch.unwrap()).collect();
+        assert_eq!(batches.len(), 1);
+        batches.remove(0)
+    }
+
+    #[test]
+    fn columns_are_typed_by_vr_and_lists_are_inferred() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.parquet");
+        let instances = [
+            testutil::instance("1", &[
+                (tags::STUDY_DATE, "DA", "19700102"),
+                (tags::SLICE_THICKNESS, "DS", "2.5"),
+                (tags::ROWS, "US", "512"),
+                (tags::IMAGE_TYPE, "CS", "ORIGINAL\\PRIMARY"),
+            ]),
+            testutil::instance("2", &[(tags::SLICE_THICKNESS, "DS", "bad"), (tags::IMAGE_TYPE, "CS", "DERIVED")]),
+        ];
+
+        let mut writer = ParquetTableWriter::create(&path, &instances, &[]).unwrap();
+        writer.write_row_group(&instances).unwrap();
+        assert!(writer.dropped_columns.is_empty());
+        writer.close().unwrap();
+
+        let batch = read(&path);
+        let schema = batch.schema();
+        assert_eq!(schema.field_with_name("StudyDate").unwrap().data_type(), &DataType::Date32);
+        assert_eq!(schema.field_with_name("Rows").unwrap().data_type(), &DataType::Int64);
+        assert!(matches!(schema.field_with_name("ImageType").unwrap().data_type(), DataType::List(_)));
+
+        let thickness = batch.column_by_name("SliceThickness").unwrap().as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!(thickness.value(0), 2.5);
+        assert!(thickness.is_null(1));
+        let date = batch.column_by_name("StudyDate").unwrap().as_any().downcast_ref::<Date32Array>().unwrap();
+        assert_eq!(date.value(0), 1);
+    }
+
+    #[test]
+    fn tags_first_seen_in_later_row_groups_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.parquet");
+        let first = [testutil::instance("1", &[(tags::MODALITY, "CS", "CT")])];
+        let later = [testutil::instance("2", &[(tags::MODALITY, "CS", "MR"), (tags::BODY_PART_EXAMINED, "CS", "HEAD")])];
+
+        let mut writer = ParquetTableWriter::create(&path, &first, &[]).unwrap();
+        writer.write_row_group(&first).unwrap();
+        writer.write_row_group(&later).unwrap();
+        assert_eq!(writer.dropped_columns.iter().collect::<Vec<_>>(), [&crate::tag_key(tags::BODY_PART_EXAMINED)]);
+        writer.close().unwrap();
+
+        // A value list in a column inferred as single-valued keeps its first item
+        let mut writer = ParquetTableWriter::create(&path, &first, &[]).unwrap();
+        writer.write_row_group(&[testutil::instance("3", &[(tags::MODALITY, "CS", "CT\\PT")])]).unwrap();
+        assert_eq!(writer.truncated_values, 1);
+        writer.close().unwrap();
+        let modality = read(&path).column_by_name("Modality").unwrap().as_any().downcast_ref::<StringArray>().unwrap().value(0).to_string();
+        assert_eq!(modality, "CT");
+
+        // Selected columns are never reported, even when absent from the first row group
+        let mut writer = ParquetTableWriter::create(&path, &first, &[tags::MODALITY]).unwrap();
+        writer.write_row_group(&later).unwrap();
+        assert!(writer.dropped_columns.is_empty());
+    }
+}
@@ -0,0 +1,55 @@
+//! DICOM JSON model (PS3.18 Annex F) for converted instances.
+
+use std::collections::HashMap;
+use serde_json::{Map, Value, json};
+use crate::{TagInfo, table};
+
+pub const MEDIA_TYPE: &str = "application/dicom+json";
+
+/// DICOM JSON attribute name of a tag key, e.g. `(0010,0010)` becomes `00100010`
+pub fn attribute_name(key: &str) -> String {
+    key.chars().filter(char::is_ascii_hexdigit).collect()
+}
+
+/// One attribute. Binary values and sequences, whose items are not extracted,
+/// carry only their VR.
+pub fn attribute(tag_info: &TagInfo) -> Value {
+    let vr = tag_info.vr.as_str();
+    if table::BINARY_VRS.contains(&vr) {
+        return json!({ "vr": vr });
+    }
+    let values: Vec<String> = table::tag_values(tag_info).iter().map(|v| v.trim().to_string()).collect();
+    if values.iter().all(|v| v.is_empty()) {
+        return json!({ "vr": vr });
+    }
+
+    let values: Vec<Value> = values.into_iter().map(|value| match vr {
+        _ if value.is_empty() => Value::Null,
+        "PN" => json!({ "Alphabetic": value }),
+        "DS" | "FL" | "FD" => value.parse::<f64>().ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(Value::Number)
+            .unwrap_or(Value::String(value)),
+        "IS" | "SL" | "SS" | "UL" | "US" | "SV" | "UV" => value.parse::<i64>()
+            .map(Value::from)
+            .unwrap_or(Value::String(value)),
+        _ => Value::String(value),
+    }).collect();
+    json!({ "vr": vr, "Value": values })
+}
+
+/// Dataset of every tag, in tag order
+pub fn dataset(tags: &HashMap<String, TagInfo>) -> Map<String, Value> {
+    dataset_of(tags, tags.keys())
+}
+
+/// Dataset of the given tag keys; keys the instance does not have are left out
+pub fn dataset_of<'a>(tags: &HashMap<String, TagInfo>, keys: impl IntoIterator<Item = &'a String>) -> Map<String, Value> {
+    let mut keys: Vec<&String> = keys.into_iter().filter(|key| tags.contains_key(*key)).collect();
+    // Tag keys are zero-padded hex, so sorting them sorts by tag
+    keys.sort();
+    keys.dedup();
+    keys.into_iter()
+        .map(|key| (attribute_name(key), attribute(&tags[key])))
+        .collect()
+}
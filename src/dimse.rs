@@ -0,0 +1,750 @@
+//! Minimal DICOM Upper Layer protocol (PS3.8) and DIMSE messaging (PS3.7) over TCP,
+//! shared by the storage SCP and the C-FIND and C-STORE SCUs.
+
+use std::collections::BTreeMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use anyhow::{Context, Result, anyhow, bail};
+use dicom::encoding::TransferSyntaxIndex;
+use dicom::transfer_syntax::TransferSyntaxRegistry;
+use dicom_object::InMemDicomObject;
+
+pub const VERIFICATION: &str = "1.2.840.10008.1.1";
+pub const IMPLICIT_VR_LE: &str = "1.2.840.10008.1.2";
+pub const EXPLICIT_VR_LE: &str = "1.2.840.10008.1.2.1";
+const APPLICATION_CONTEXT: &str = "1.2.840.10008.3.1.1.1";
+/// UUID-derived (2.25) root identifying this implementation to peers
+pub const IMPLEMENTATION_CLASS_UID: &str = "2.25.231870958210468713285947183512617355161";
+pub const IMPLEMENTATION_VERSION_NAME: &str = "DICOM_JSON_100";
+/// Largest PDU we accept, announced during association negotiation
+pub const MAX_PDU_LENGTH: u32 = 65536;
+/// Refuse PDUs beyond this size whatever the peer claims
+const PDU_LENGTH_LIMIT: u32 = 64 * 1024 * 1024;
+
+/// Command Field (0000,0100) values
+pub const C_STORE_RQ: u16 = 0x0001;
+pub const C_STORE_RSP: u16 = 0x8001;
+pub const C_FIND_RQ: u16 = 0x0020;
+pub const C_FIND_RSP: u16 = 0x8020;
+pub const C_ECHO_RQ: u16 = 0x0030;
+pub const C_ECHO_RSP: u16 = 0x8030;
+
+/// Command Data Set Type (0000,0800) when no data set follows the command
+pub const NO_DATA_SET: u16 = 0x0101;
+
+/// Status (0000,0900) values
+pub const STATUS_SUCCESS: u16 = 0x0000;
+pub const STATUS_PENDING: u16 = 0xFF00;
+/// Pending with unsupported optional keys, for C-FIND
+pub const STATUS_PENDING_WARNING: u16 = 0xFF01;
+pub const STATUS_OUT_OF_RESOURCES: u16 = 0xA700;
+pub const STATUS_SOP_CLASS_MISMATCH: u16 = 0xA900;
+pub const STATUS_CANNOT_UNDERSTAND: u16 = 0xC000;
+
+/// Command set elements (group 0000)
+pub const AFFECTED_SOP_CLASS_UID: u16 = 0x0002;
+pub const COMMAND_FIELD: u16 = 0x0100;
+pub const MESSAGE_ID: u16 = 0x0110;
+pub const MESSAGE_ID_BEING_RESPONDED_TO: u16 = 0x0120;
+pub const PRIORITY: u16 = 0x0700;
+pub const COMMAND_DATA_SET_TYPE: u16 = 0x0800;
+pub const STATUS: u16 = 0x0900;
+pub const ERROR_COMMENT: u16 = 0x0902;
+pub const AFFECTED_SOP_INSTANCE_UID: u16 = 0x1000;
+
+/// A presentation context as proposed by the requestor, or as accepted with one transfer syntax
+#[derive(Clone, Debug)]
+pub struct PresentationContext {
+    pub id: u8,
+    pub abstract_syntax: String,
+    pub transfer_syntaxes: Vec<String>,
+}
+
+/// Association negotiated on a connection, from either side
+pub struct Association {
+    stream: TcpStream,
+    pub calling_ae: String,
+    pub called_ae: String,
+    /// Accepted contexts, each with its single transfer syntax
+    pub contexts: Vec<PresentationContext>,
+    /// AE title of the other side: the calling AE when accepting, the called AE when requesting
+    peer_ae: String,
+    peer_max_pdu: u32,
+}
+
+/// Contents of an A-ASSOCIATE-RQ or -AC PDU
+struct AssociatePdu {
+    called_ae: String,
+    calling_ae: String,
+    /// (id, result, abstract syntax, transfer syntaxes); the result is 0 in requests and
+    /// the abstract syntax empty in acceptances
+    contexts: Vec<(u8, u8, String, Vec<String>)>,
+    max_pdu: u32,
+}
+
+/// Why an association request was turned down
+pub enum Rejection {
+    /// Rejected permanently by the service user
+    CalledAeNotRecognized,
+    /// Rejected transiently by the service provider, so the requestor may try again later
+    LocalLimitExceeded,
+}
+
+impl Rejection {
+    /// A-ASSOCIATE-RJ result, source and reason
+    fn fields(&self) -> [u8; 3] {
+        match self {
+            Self::CalledAeNotRecognized => [1, 1, 7],
+            Self::LocalLimitExceeded => [2, 3, 2],
+        }
+    }
+}
+
+/// One DIMSE message on a presentation context
+pub struct Message {
+    pub context_id: u8,
+    pub command: Command,
+    /// Encoded data set in the context's transfer syntax
+    pub data: Option<Vec<u8>>,
+}
+
+/// DIMSE command set, always encoded in Implicit VR Little Endian
+#[derive(Default, Clone, Debug)]
+pub struct Command {
+    elements: BTreeMap<u16, Vec<u8>>,
+}
+
+impl Command {
+    pub fn new(command_field: u16, message_id_field: u16, message_id: u16) -> Self {
+        let mut command = Self::default();
+        command.set_u16(COMMAND_FIELD, command_field);
+        command.set_u16(message_id_field, message_id);
+        command.set_u16(COMMAND_DATA_SET_TYPE, NO_DATA_SET);
+        command
+    }
+
+    pub fn set_u16(&mut self, element: u16, value: u16) {
+        self.elements.insert(element, value.to_le_bytes().to_vec());
+    }
+
+    /// UIDs are padded with NUL, other strings with a space, to an even length
+    pub fn set_str(&mut self, element: u16, value: &str) {
+        let mut bytes = value.as_bytes().to_vec();
+        if bytes.len() % 2 == 1 {
+            let is_uid = matches!(element, AFFECTED_SOP_CLASS_UID | AFFECTED_SOP_INSTANCE_UID);
+            bytes.push(if is_uid { 0 } else { b' ' });
+        }
+        self.elements.insert(element, bytes);
+    }
+
+    pub fn u16(&self, element: u16) -> Option<u16> {
+        let bytes = self.elements.get(&element)?;
+        Some(u16::from_le_bytes([*bytes.first()?, *bytes.get(1)?]))
+    }
+
+    pub fn str(&self, element: u16) -> Option<String> {
+        let bytes = self.elements.get(&element)?;
+        Some(String::from_utf8_lossy(bytes).trim_end_matches(['\0', ' ']).to_string())
+    }
+
+    /// Response to `request`, echoing its message ID and affected SOP Class/Instance UIDs
+    pub fn response(request: &Command, command_field: u16, status: u16) -> Self {
+        let mut response = Self::new(command_field, MESSAGE_ID_BEING_RESPONDED_TO, request.u16(MESSAGE_ID).unwrap_or(0));
+        for element in [AFFECTED_SOP_CLASS_UID, AFFECTED_SOP_INSTANCE_UID] {
+            if let Some(value) = request.elements.get(&element) {
+                response.elements.insert(element, value.clone());
+            }
+        }
+        response.set_u16(STATUS, status);
+        response
+    }
+
+    pub fn has_data_set(&self) -> bool {
+        self.u16(COMMAND_DATA_SET_TYPE).is_some_and(|t| t != NO_DATA_SET)
+    }
+
+    pub fn set_has_data_set(&mut self, has_data_set: bool) {
+        self.set_u16(COMMAND_DATA_SET_TYPE, if has_data_set { 0x0000 } else { NO_DATA_SET });
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (element, value) in &self.elements {
+            body.extend_from_slice(&0u16.to_le_bytes());
+            body.extend_from_slice(&element.to_le_bytes());
+            body.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            body.extend_from_slice(value);
+        }
+        // Command Group Length (0000,0000) first
+        let mut encoded = vec![0, 0, 0, 0, 4, 0, 0, 0];
+        encoded.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        encoded.extend_from_slice(&body);
+        encoded
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut command = Self::default();
+        let mut offset = 0;
+        while offset + 8 <= bytes.len() {
+            let group = u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+            let element = u16::from_le_bytes([bytes[offset + 2], bytes[offset + 3]]);
+            let length = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into()?) as usize;
+            let value = bytes.get(offset + 8..offset + 8 + length)
+                .context("Truncated DIMSE command")?;
+            if group == 0x0000 && element != 0x0000 {
+                command.elements.insert(element, value.to_vec());
+            }
+            offset += 8 + length;
+        }
+        Ok(command)
+    }
+}
+
+impl Association {
+    /// Act as the acceptor: read the A-ASSOCIATE-RQ and accept, for each proposed context whose
+    /// abstract syntax passes `accepts`, the first of `transfer_syntaxes` the requestor offers
+    pub fn accept(
+        mut stream: TcpStream,
+        ae_title: &str,
+        accepts: impl Fn(&str) -> bool,
+        transfer_syntaxes: &[String],
+    ) -> Result<Self> {
+        let (pdu_type, body) = read_pdu(&mut stream)?;
+        if pdu_type != 0x01 {
+            bail!("Expected A-ASSOCIATE-RQ, got PDU type {:#04x}", pdu_type);
+        }
+        let request = parse_associate(&body)?;
+
+        if !request.called_ae.eq_ignore_ascii_case(ae_title) {
+            reject(&mut stream, Rejection::CalledAeNotRecognized)?;
+            bail!("Rejected association for unknown called AE title {:?}", request.called_ae);
+        }
+
+        let mut contexts = Vec::new();
+        let mut results = Vec::new();
+        for (id, _, abstract_syntax, proposed) in &request.contexts {
+            let chosen = transfer_syntaxes.iter().find(|ts| proposed.contains(ts));
+            let (result, transfer_syntax) = match chosen {
+                _ if !accepts(abstract_syntax) => (3, IMPLICIT_VR_LE.to_string()),
+                None => (4, IMPLICIT_VR_LE.to_string()),
+                Some(ts) => {
+                    contexts.push(PresentationContext {
+                        id: *id,
+                        abstract_syntax: abstract_syntax.clone(),
+                        transfer_syntaxes: vec![ts.clone()],
+                    });
+                    (0, ts.clone())
+                }
+            };
+            results.push((*id, result, String::new(), vec![transfer_syntax]));
+        }
+
+        let response = AssociatePdu {
+            called_ae: request.called_ae.clone(),
+            calling_ae: request.calling_ae.clone(),
+            contexts: results,
+            max_pdu: MAX_PDU_LENGTH,
+        };
+        write_pdu(&mut stream, 0x02, &encode_associate(&response, false))?;
+
+        Ok(Self {
+            stream,
+            peer_ae: request.calling_ae.clone(),
+            calling_ae: request.calling_ae,
+            called_ae: request.called_ae,
+            contexts,
+            peer_max_pdu: request.max_pdu,
+        })
+    }
+
+    /// Read the A-ASSOCIATE-RQ waiting on `stream` and turn it down
+    pub fn refuse(mut stream: TcpStream, rejection: Rejection) -> Result<()> {
+        let (pdu_type, _) = read_pdu(&mut stream)?;
+        if pdu_type != 0x01 {
+            bail!("Expected A-ASSOCIATE-RQ, got PDU type {:#04x}", pdu_type);
+        }
+        reject(&mut stream, rejection)
+    }
+
+    /// Act as the requestor: propose the contexts and wait for the acceptor's answer; `timeout`
+    /// bounds the connection attempt and every read and write on the association
+    pub fn request(
+        address: &str,
+        calling_ae: &str,
+        called_ae: &str,
+        proposed: &[PresentationContext],
+        timeout: Duration,
+    ) -> Result<Self> {
+        let mut stream = connect(address, timeout)
+            .with_context(|| format!("Failed to connect to {}", address))?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let request = AssociatePdu {
+            called_ae: called_ae.to_string(),
+            calling_ae: calling_ae.to_string(),
+            contexts: proposed.iter()
+                .map(|c| (c.id, 0, c.abstract_syntax.clone(), c.transfer_syntaxes.clone()))
+                .collect(),
+            max_pdu: MAX_PDU_LENGTH,
+        };
+        write_pdu(&mut stream, 0x01, &encode_associate(&request, true))?;
+
+        let (pdu_type, body) = read_pdu(&mut stream)?;
+        match pdu_type {
+            0x02 => {}
+            0x03 => bail!(
+                "Association rejected by {} (result {}, source {}, reason {})",
+                called_ae, body.get(1).unwrap_or(&0), body.get(2).unwrap_or(&0), body.get(3).unwrap_or(&0)
+            ),
+            0x07 => bail!("Association aborted by {}", called_ae),
+            other => bail!("Unexpected PDU type {:#04x} in answer to A-ASSOCIATE-RQ", other),
+        }
+        let answer = parse_associate(&body)?;
+
+        let contexts = answer.contexts.into_iter()
+            .filter(|(_, result, _, _)| *result == 0)
+            .filter_map(|(id, _, _, transfer_syntaxes)| {
+                let abstract_syntax = proposed.iter().find(|c| c.id == id)?.abstract_syntax.clone();
+                Some(PresentationContext { id, abstract_syntax, transfer_syntaxes })
+            })
+            .collect();
+
+        Ok(Self {
+            stream,
+            calling_ae: calling_ae.to_string(),
+            called_ae: called_ae.to_string(),
+            peer_ae: called_ae.to_string(),
+            contexts,
+            peer_max_pdu: answer.max_pdu,
+        })
+    }
+
+    /// How long to wait for the peer's next PDU
+    pub fn set_read_timeout(&self, timeout: Duration) -> Result<()> {
+        Ok(self.stream.set_read_timeout(Some(timeout))?)
+    }
+
+    pub fn context(&self, id: u8) -> Option<&PresentationContext> {
+        self.contexts.iter().find(|c| c.id == id)
+    }
+
+    /// Next message, or `None` once the peer has released the association
+    pub fn receive(&mut self) -> Result<Option<Message>> {
+        let mut command_bytes = Vec::new();
+        let mut data = Vec::new();
+        let mut command: Option<Command> = None;
+
+        loop {
+            let (pdu_type, body) = read_pdu(&mut self.stream)?;
+            match pdu_type {
+                0x04 => {}
+                0x05 => {
+                    write_pdu(&mut self.stream, 0x06, &[0; 4])?;
+                    return Ok(None);
+                }
+                0x07 => bail!("Association aborted by {}", self.peer_ae),
+                other => bail!("Unexpected PDU type {:#04x} during association", other),
+            }
+
+            let mut offset = 0;
+            while offset + 6 <= body.len() {
+                let length = u32::from_be_bytes(body[offset..offset + 4].try_into()?) as usize;
+                let item = body.get(offset + 4..offset + 4 + length)
+                    .filter(|item| item.len() >= 2)
+                    .context("Malformed P-DATA-TF PDU")?;
+                offset += 4 + length;
+                let (context_id, header, fragment) = (item[0], item[1], &item[2..]);
+                let is_last = header & 0x02 != 0;
+
+                if header & 0x01 != 0 {
+                    command_bytes.extend_from_slice(fragment);
+                    if is_last {
+                        let decoded = Command::decode(&command_bytes)?;
+                        if !decoded.has_data_set() {
+                            return Ok(Some(Message { context_id, command: decoded, data: None }));
+                        }
+                        command = Some(decoded);
+                    }
+                } else {
+                    data.extend_from_slice(fragment);
+                    if is_last {
+                        let command = command.take().context("Data set received before its command")?;
+                        return Ok(Some(Message { context_id, command, data: Some(data) }));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send a message, fragmented to the peer's maximum PDU length
+    pub fn send(&mut self, message: &Message) -> Result<()> {
+        // PDU header (6) and PDV item header (6) come out of the peer's limit
+        let max_fragment = match self.peer_max_pdu {
+            0 => 1 << 20,
+            max => (max as usize).saturating_sub(12).max(1),
+        };
+        let command = message.command.encode();
+        let mut parts = vec![(true, command.as_slice())];
+        if let Some(data) = &message.data {
+            parts.push((false, data.as_slice()));
+        }
+
+        for (is_command, bytes) in parts {
+            let mut chunks: Vec<&[u8]> = bytes.chunks(max_fragment).collect();
+            if chunks.is_empty() {
+                chunks.push(&[]);
+            }
+            let count = chunks.len();
+            for (index, chunk) in chunks.into_iter().enumerate() {
+                let header = u8::from(is_command) | if index + 1 == count { 0x02 } else { 0 };
+                let mut body = Vec::with_capacity(chunk.len() + 6);
+                body.extend_from_slice(&(chunk.len() as u32 + 2).to_be_bytes());
+                body.push(message.context_id);
+                body.push(header);
+                body.extend_from_slice(chunk);
+                write_pdu(&mut self.stream, 0x04, &body)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Request release and wait for the acceptor's reply
+    pub fn release(mut self) -> Result<()> {
+        write_pdu(&mut self.stream, 0x05, &[0; 4])?;
+        loop {
+            let (pdu_type, _) = read_pdu(&mut self.stream)?;
+            match pdu_type {
+                0x06 => return Ok(()),
+                0x07 => bail!("Association aborted by {} during release", self.peer_ae),
+                // Late P-DATA can still arrive before the release reply
+                _ => {}
+            }
+        }
+    }
+
+    pub fn abort(&mut self) {
+        let _ = write_pdu(&mut self.stream, 0x07, &[0, 0, 0, 0]);
+    }
+}
+
+/// Encode a data set for a presentation context's transfer syntax
+pub fn encode_dataset(dataset: &InMemDicomObject, transfer_syntax: &str) -> Result<Vec<u8>> {
+    let ts = TransferSyntaxRegistry.get(transfer_syntax)
+        .with_context(|| format!("Unsupported transfer syntax {}", transfer_syntax))?;
+    let mut bytes = Vec::new();
+    dataset.write_dataset_with_ts(&mut bytes, ts)?;
+    Ok(bytes)
+}
+
+pub fn decode_dataset(bytes: &[u8], transfer_syntax: &str) -> Result<InMemDicomObject> {
+    let ts = TransferSyntaxRegistry.get(transfer_syntax)
+        .with_context(|| format!("Unsupported transfer syntax {}", transfer_syntax))?;
+    Ok(InMemDicomObject::read_dataset_with_ts(bytes, ts)?)
+}
+
+/// AE titles are 1 to 16 characters of printable ASCII other than backslash, not all spaces
+pub fn check_ae_title(title: &str) -> Result<()> {
+    if title.len() > 16 {
+        bail!("AE title {:?} is longer than 16 characters", title);
+    }
+    if title.trim().is_empty() || !title.bytes().all(|byte| (b' '..=b'~').contains(&byte) && byte != b'\\') {
+        bail!("AE title {:?} must be printable ASCII without backslashes and not blank", title);
+    }
+    Ok(())
+}
+
+/// Connect to the first address `address` resolves to that answers within `timeout`
+fn connect(address: &str, timeout: Duration) -> Result<TcpStream> {
+    let mut last_error = None;
+    for socket_address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&socket_address, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    match last_error {
+        Some(e) => Err(e.into()),
+        None => bail!("{} does not resolve to any address", address),
+    }
+}
+
+fn reject(stream: &mut TcpStream, rejection: Rejection) -> Result<()> {
+    let [result, source, reason] = rejection.fields();
+    write_pdu(stream, 0x03, &[0, result, source, reason])
+}
+
+fn read_pdu(stream: &mut TcpStream) -> Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 6];
+    stream.read_exact(&mut header).map_err(|e| match e.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => anyhow!("Timed out waiting for the peer"),
+        _ => anyhow!(e).context("Connection closed by peer"),
+    })?;
+    let length = u32::from_be_bytes(header[2..6].try_into()?);
+    if length > PDU_LENGTH_LIMIT {
+        bail!("PDU of {} bytes exceeds the limit", length);
+    }
+    let mut body = vec![0u8; length as usize];
+    stream.read_exact(&mut body)?;
+    Ok((header[0], body))
+}
+
+fn write_pdu(stream: &mut TcpStream, pdu_type: u8, body: &[u8]) -> Result<()> {
+    let mut pdu = Vec::with_capacity(body.len() + 6);
+    pdu.extend_from_slice(&[pdu_type, 0]);
+    pdu.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    pdu.extend_from_slice(body);
+    stream.write_all(&pdu)?;
+    Ok(())
+}
+
+fn ae_title(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).trim().to_string()
+}
+
+fn padded_ae_title(title: &str) -> [u8; 16] {
+    let mut padded = [b' '; 16];
+    for (slot, byte) in padded.iter_mut().zip(title.bytes()) {
+        *slot = byte;
+    }
+    padded
+}
+
+fn uid_string(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).trim_end_matches(['\0', ' ']).to_string()
+}
+
+fn item(item_type: u8, content: &[u8]) -> Vec<u8> {
+    let mut item = vec![item_type, 0];
+    item.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    item.extend_from_slice(content);
+    item
+}
+
+/// Items of a variable field as (type, content)
+fn items(bytes: &[u8]) -> Result<Vec<(u8, &[u8])>> {
+    let mut items = Vec::new();
+    let mut offset = 0;
+    while offset + 4 <= bytes.len() {
+        let length = u16::from_be_bytes([bytes[offset + 2], bytes[offset + 3]]) as usize;
+        let content = bytes.get(offset + 4..offset + 4 + length).context("Truncated association item")?;
+        items.push((bytes[offset], content));
+        offset += 4 + length;
+    }
+    Ok(items)
+}
+
+fn encode_associate(pdu: &AssociatePdu, is_request: bool) -> Vec<u8> {
+    let mut body = vec![0, 1, 0, 0];
+    body.extend_from_slice(&padded_ae_title(&pdu.called_ae));
+    body.extend_from_slice(&padded_ae_title(&pdu.calling_ae));
+    body.extend_from_slice(&[0; 32]);
+    body.extend(item(0x10, APPLICATION_CONTEXT.as_bytes()));
+
+    for (id, result, abstract_syntax, transfer_syntaxes) in &pdu.contexts {
+        let mut content = vec![*id, 0, *result, 0];
+        if is_request {
+            content.extend(item(0x30, abstract_syntax.as_bytes()));
+        }
+        for transfer_syntax in transfer_syntaxes {
+            content.extend(item(0x40, transfer_syntax.as_bytes()));
+        }
+        body.extend(item(if is_request { 0x20 } else { 0x21 }, &content));
+    }
+
+    let mut user_information = item(0x51, &pdu.max_pdu.to_be_bytes());
+    user_information.extend(item(0x52, IMPLEMENTATION_CLASS_UID.as_bytes()));
+    user_information.extend(item(0x55, IMPLEMENTATION_VERSION_NAME.as_bytes()));
+    body.extend(item(0x50, &user_information));
+    body
+}
+
+fn parse_associate(body: &[u8]) -> Result<AssociatePdu> {
+    if body.len() < 68 {
+        bail!("Truncated association PDU");
+    }
+    let mut pdu = AssociatePdu {
+        called_ae: ae_title(&body[4..20]),
+        calling_ae: ae_title(&body[20..36]),
+        contexts: Vec::new(),
+        max_pdu: 0,
+    };
+
+    for (item_type, content) in items(&body[68..])? {
+        match item_type {
+            0x20 | 0x21 if content.len() >= 4 => {
+                let mut abstract_syntax = String::new();
+                let mut transfer_syntaxes = Vec::new();
+                for (sub_type, sub_content) in items(&content[4..])? {
+                    match sub_type {
+                        0x30 => abstract_syntax = uid_string(sub_content),
+                        0x40 => transfer_syntaxes.push(uid_string(sub_content)),
+                        _ => {}
+                    }
+                }
+                pdu.contexts.push((content[0], content[2], abstract_syntax, transfer_syntaxes));
+            }
+            0x50 => {
+                for (sub_type, sub_content) in items(content)? {
+                    if sub_type == 0x51 && sub_content.len() == 4 {
+                        pdu.max_pdu = u32::from_be_bytes(sub_content.try_into()?);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(pdu)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+    use super::*;
+
+    const CT_IMAGE_STORAGE: &str = "1.2.840.10008.5.1.4.1.1.2";
+
+    fn context(id: u8, abstract_syntax: &str, transfer_syntaxes: &[&str]) -> PresentationContext {
+        PresentationContext {
+            id,
+            abstract_syntax: abstract_syntax.to_string(),
+            transfer_syntaxes: transfer_syntaxes.iter().map(|ts| ts.to_string()).collect(),
+        }
+    }
+
+    /// Connected (requestor, acceptor) streams
+    fn stream_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let requestor = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (acceptor, _) = listener.accept().unwrap();
+        (requestor, acceptor)
+    }
+
+    fn association(stream: TcpStream, peer_max_pdu: u32) -> Association {
+        Association {
+            stream,
+            calling_ae: "SCU".to_string(),
+            called_ae: "SCP".to_string(),
+            contexts: vec![context(1, CT_IMAGE_STORAGE, &[EXPLICIT_VR_LE])],
+            peer_ae: "PEER".to_string(),
+            peer_max_pdu,
+        }
+    }
+
+    #[test]
+    fn associate_pdus_round_trip() {
+        let request = AssociatePdu {
+            called_ae: "STORESCP".to_string(),
+            calling_ae: "A-SIXTEEN-CHARS!".to_string(),
+            contexts: vec![
+                (1, 0, VERIFICATION.to_string(), vec![IMPLICIT_VR_LE.to_string()]),
+                (3, 0, CT_IMAGE_STORAGE.to_string(), vec![EXPLICIT_VR_LE.to_string(), IMPLICIT_VR_LE.to_string()]),
+            ],
+            max_pdu: 16384,
+        };
+        let parsed = parse_associate(&encode_associate(&request, true)).unwrap();
+        assert_eq!((parsed.called_ae.as_str(), parsed.calling_ae.as_str(), parsed.max_pdu), ("STORESCP", "A-SIXTEEN-CHARS!", 16384));
+        assert_eq!(parsed.contexts, request.contexts);
+
+        let acceptance = AssociatePdu {
+            contexts: vec![(1, 0, String::new(), vec![IMPLICIT_VR_LE.to_string()]), (3, 4, String::new(), vec![IMPLICIT_VR_LE.to_string()])],
+            max_pdu: 0,
+            ..request
+        };
+        let parsed = parse_associate(&encode_associate(&acceptance, false)).unwrap();
+        assert_eq!(parsed.contexts, acceptance.contexts);
+        assert_eq!(parsed.max_pdu, 0);
+
+        assert!(parse_associate(&encode_associate(&acceptance, false)[..60]).is_err());
+    }
+
+    #[test]
+    fn negotiation_accepts_supported_contexts_and_rejects_unknown_called_ae() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let acceptor = thread::spawn(move || {
+            let accepts = |abstract_syntax: &str| abstract_syntax == CT_IMAGE_STORAGE;
+            let transfer_syntaxes = [EXPLICIT_VR_LE.to_string()];
+            let results: Vec<Result<Association>> = listener.incoming().take(2)
+                .map(|stream| Association::accept(stream.unwrap(), "STORESCP", accepts, &transfer_syntaxes))
+                .collect();
+            results.into_iter().map(|result| result.map(|association| association.contexts.len())).collect::<Vec<_>>()
+        });
+
+        let proposed = [
+            context(1, VERIFICATION, &[IMPLICIT_VR_LE]),
+            context(3, CT_IMAGE_STORAGE, &[IMPLICIT_VR_LE, EXPLICIT_VR_LE]),
+            context(5, CT_IMAGE_STORAGE, &[IMPLICIT_VR_LE]),
+        ];
+        let timeout = Duration::from_secs(5);
+        let association = Association::request(&address, "SCU", "storescp", &proposed, timeout).unwrap();
+        assert_eq!(association.peer_ae, "storescp");
+        assert_eq!(association.contexts.len(), 1);
+        assert_eq!((association.contexts[0].id, association.contexts[0].abstract_syntax.as_str()), (3, CT_IMAGE_STORAGE));
+        assert_eq!(association.contexts[0].transfer_syntaxes, [EXPLICIT_VR_LE]);
+        drop(association);
+
+        let error = Association::request(&address, "SCU", "OTHER", &proposed, timeout).err().unwrap();
+        assert_eq!(error.to_string(), "Association rejected by OTHER (result 1, source 1, reason 7)");
+
+        let accepted = acceptor.join().unwrap();
+        assert_eq!(accepted[0].as_ref().unwrap(), &1);
+        assert!(accepted[1].is_err());
+    }
+
+    #[test]
+    fn messages_are_fragmented_and_reassembled() {
+        let (requestor, acceptor) = stream_pair();
+        let receiver = thread::spawn(move || {
+            let mut association = association(acceptor, MAX_PDU_LENGTH);
+            let message = association.receive().unwrap().unwrap();
+            let released = association.receive().unwrap();
+            (message.context_id, message.command, message.data, released.is_none())
+        });
+
+        // A 40-byte limit splits both the command and the data set into several PDUs
+        let mut association = association(requestor, 40);
+        let mut command = Command::new(C_STORE_RQ, MESSAGE_ID, 7);
+        command.set_str(AFFECTED_SOP_CLASS_UID, CT_IMAGE_STORAGE);
+        command.set_str(AFFECTED_SOP_INSTANCE_UID, "1.2.3");
+        command.set_has_data_set(true);
+        let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        association.send(&Message { context_id: 1, command, data: Some(data.clone()) }).unwrap();
+        association.release().unwrap();
+
+        let (context_id, command, received, released) = receiver.join().unwrap();
+        assert_eq!(context_id, 1);
+        assert_eq!(command.u16(COMMAND_FIELD), Some(C_STORE_RQ));
+        assert_eq!(command.u16(MESSAGE_ID), Some(7));
+        assert_eq!(command.str(AFFECTED_SOP_CLASS_UID).as_deref(), Some(CT_IMAGE_STORAGE));
+        assert_eq!(command.str(AFFECTED_SOP_INSTANCE_UID).as_deref(), Some("1.2.3"));
+        assert_eq!(received, Some(data));
+        assert!(released);
+    }
+
+    #[test]
+    fn aborts_and_silent_peers_are_reported() {
+        let (requestor, acceptor) = stream_pair();
+        let mut sender = association(requestor, MAX_PDU_LENGTH);
+        sender.abort();
+        let error = association(acceptor, MAX_PDU_LENGTH).receive().err().unwrap();
+        assert_eq!(error.to_string(), "Association aborted by PEER");
+
+        let (_requestor, acceptor) = stream_pair();
+        acceptor.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let error = association(acceptor, MAX_PDU_LENGTH).receive().err().unwrap();
+        assert_eq!(error.to_string(), "Timed out waiting for the peer");
+    }
+
+    #[test]
+    fn ae_titles_are_checked() {
+        assert!(check_ae_title("DICOMJSON").is_ok());
+        assert!(check_ae_title("SIXTEEN-CHARS-OK").is_ok());
+        assert!(check_ae_title("SEVENTEEN-CHARS-X").is_err());
+        assert!(check_ae_title("   ").is_err());
+        assert!(check_ae_title("A\\B").is_err());
+        assert!(check_ae_title("CAFÉ").is_err());
+    }
+}
@@ -0,0 +1,158 @@
+//! Duplicate reports from Pixel Data hashes: re-sent images with regenerated UIDs, and
+//! SOP Instance UIDs reused for different images.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use crate::DicomInstance;
+
+/// Written to the output directory with --hash-pixels
+pub const REPORT_FILE: &str = "duplicates.json";
+
+#[derive(Serialize, Debug)]
+pub struct DuplicateReport {
+    /// Decoded pixels found under more than one SOP Instance UID
+    pub same_pixels: Vec<SamePixels>,
+    /// SOP Instance UIDs found with more than one decoded image
+    pub same_sop_instance_uid: Vec<SameSopInstanceUid>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SamePixels {
+    pub sha256: String,
+    pub instances: Vec<DuplicateInstance>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SameSopInstanceUid {
+    pub sop_instance_uid: String,
+    pub instances: Vec<DuplicateInstance>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DuplicateInstance {
+    pub sop_instance_uid: String,
+    pub sha256: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stored_sha256: Option<String>,
+    pub file_path: String,
+}
+
+impl DuplicateReport {
+    /// Compare the hashes of every instance whose pixels were hashed
+    pub fn new(instances: &[DicomInstance]) -> Self {
+        let mut by_pixels: BTreeMap<&str, Vec<&DicomInstance>> = BTreeMap::new();
+        let mut by_uid: BTreeMap<&str, Vec<&DicomInstance>> = BTreeMap::new();
+        for instance in instances {
+            if let Some(sha256) = pixel_hash(instance) {
+                by_pixels.entry(sha256).or_default().push(instance);
+                by_uid.entry(&instance.sop_instance_uid).or_default().push(instance);
+            }
+        }
+
+        let same_pixels = by_pixels.into_iter()
+            .filter(|(_, group)| distinct(group, |instance| instance.sop_instance_uid.as_str()) > 1)
+            .map(|(sha256, group)| SamePixels { sha256: sha256.to_string(), instances: describe(&group) })
+            .collect();
+        let same_sop_instance_uid = by_uid.into_iter()
+            .filter(|(_, group)| distinct(group, |instance| pixel_hash(instance).unwrap_or_default()) > 1)
+            .map(|(uid, group)| SameSopInstanceUid { sop_instance_uid: uid.to_string(), instances: describe(&group) })
+            .collect();
+        Self { same_pixels, same_sop_instance_uid }
+    }
+
+    pub fn write(&self, output_dir: &Path) -> Result<PathBuf> {
+        let path = output_dir.join(REPORT_FILE);
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(&path, json).with_context(|| format!("Failed to write {:?}", path))?;
+        Ok(path)
+    }
+}
+
+fn pixel_hash(instance: &DicomInstance) -> Option<&str> {
+    instance.pixels.as_ref()?.sha256.as_deref()
+}
+
+fn distinct<'a>(group: &[&'a DicomInstance], key: impl Fn(&'a DicomInstance) -> &'a str) -> usize {
+    let mut keys: Vec<&str> = group.iter().map(|&instance| key(instance)).collect();
+    keys.sort_unstable();
+    keys.dedup();
+    keys.len()
+}
+
+fn describe(group: &[&DicomInstance]) -> Vec<DuplicateInstance> {
+    let mut group = group.to_vec();
+    group.sort_by(|a, b| a.file_path.cmp(&b.file_path));
+    group.iter()
+        .map(|instance| DuplicateInstance {
+            sop_instance_uid: instance.sop_instance_uid.clone(),
+            sha256: pixel_hash(instance).unwrap_or_default().to_string(),
+            stored_sha256: instance.pixels.as_ref().and_then(|pixels| pixels.stored_sha256.clone()),
+            file_path: instance.file_path.clone(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pixels::PixelSummary;
+    use crate::testutil;
+
+    fn hashed(sop_instance_uid: &str, file: &str, sha256: Option<&str>) -> DicomInstance {
+        let mut instance = testutil::instance(sop_instance_uid, &[]);
+        instance.file_path = format!("/data/{}", file);
+        instance.has_pixel_data = true;
+        instance.pixels = Some(PixelSummary {
+            codec: "native".to_string(),
+            frames: 1,
+            photometric_interpretation: "MONOCHROME2".to_string(),
+            png: Vec::new(),
+            statistics: Vec::new(),
+            sha256: sha256.map(str::to_string),
+            stored_sha256: None,
+        });
+        instance
+    }
+
+    #[test]
+    fn resent_images_and_reused_uids_are_reported() {
+        let report = DuplicateReport::new(&[
+            // Re-sent with a new UID
+            hashed("1.1", "b.dcm", Some("aa")),
+            hashed("1.2", "a.dcm", Some("aa")),
+            // One UID for two images
+            hashed("2.1", "c.dcm", Some("bb")),
+            hashed("2.1", "d.dcm", Some("cc")),
+            // Plain copies and unhashed files are neither
+            hashed("3.1", "e.dcm", Some("dd")),
+            hashed("3.1", "f.dcm", Some("dd")),
+            hashed("4.1", "g.dcm", None),
+            hashed("4.2", "h.dcm", None),
+        ]);
+
+        assert_eq!(report.same_pixels.len(), 1);
+        let group = &report.same_pixels[0];
+        assert_eq!(group.sha256, "aa");
+        let files: Vec<&str> = group.instances.iter().map(|instance| instance.file_path.as_str()).collect();
+        assert_eq!(files, ["/data/a.dcm", "/data/b.dcm"]);
+        assert_eq!(group.instances[0].sop_instance_uid, "1.2");
+
+        assert_eq!(report.same_sop_instance_uid.len(), 1);
+        let group = &report.same_sop_instance_uid[0];
+        assert_eq!(group.sop_instance_uid, "2.1");
+        let hashes: Vec<&str> = group.instances.iter().map(|instance| instance.sha256.as_str()).collect();
+        assert_eq!(hashes, ["bb", "cc"]);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert!(json["same_pixels"][0]["instances"][0].get("stored_sha256").is_none());
+    }
+
+    #[test]
+    fn nothing_is_reported_for_unique_images() {
+        let report = DuplicateReport::new(&[hashed("1.1", "a.dcm", Some("aa")), hashed("1.2", "b.dcm", Some("bb"))]);
+        assert!(report.same_pixels.is_empty() && report.same_sop_instance_uid.is_empty());
+    }
+}
@@ -0,0 +1,370 @@
+//! FHIR R4 transaction Bundles with Patient, ImagingStudy and Endpoint resources.
+
+use std::collections::HashMap;
+use dicom_dictionary_std::tags;
+use serde_json::{json, Value};
+use uuid::Uuid;
+use crate::{DicomInstance, DicomSeries, DicomStudy, PatientInfo};
+
+const DICOM_UID_SYSTEM: &str = "urn:dicom:uid";
+const DICOM_MODALITY_SYSTEM: &str = "http://dicom.nema.org/resources/ontology/DCM";
+const SOP_CLASS_SYSTEM: &str = "urn:ietf:rfc:3986";
+const ACCESSION_TYPE_SYSTEM: &str = "http://terminology.hl7.org/CodeSystem/v2-0203";
+const ENDPOINT_TYPE_SYSTEM: &str = "http://terminology.hl7.org/CodeSystem/endpoint-connection-type";
+/// Used when no --fhir-endpoint is given; replace before loading into a server
+const PLACEHOLDER_ENDPOINT: &str = "https://example.org/dicomweb";
+
+/// One transaction Bundle for the given studies. Patients are shared between
+/// studies with the same Patient ID and created conditionally on their identifier;
+/// see `patient_key` for patients without one.
+pub fn create_bundle<'a>(studies: impl IntoIterator<Item = &'a DicomStudy>, endpoint: Option<&str>) -> Value {
+    let endpoint_url = format!("urn:uuid:{}", Uuid::new_v4());
+    let endpoint = endpoint.unwrap_or(PLACEHOLDER_ENDPOINT);
+    let mut entries = vec![json!({
+        "fullUrl": endpoint_url,
+        "resource": {
+            "resourceType": "Endpoint",
+            "identifier": [{"value": endpoint}],
+            "status": "active",
+            "connectionType": {"system": ENDPOINT_TYPE_SYSTEM, "code": "dicom-wado-rs"},
+            "payloadType": [{"text": "DICOM WADO-RS"}],
+            "payloadMimeType": ["application/dicom"],
+            "address": endpoint,
+        },
+        "request": {"method": "POST", "url": "Endpoint", "ifNoneExist": format!("identifier={}", token(None, endpoint))},
+    })];
+
+    let mut patients: HashMap<String, String> = HashMap::new();
+    for study in studies {
+        let patient_key = patient_key(study);
+        let patient_url = match patients.get(&patient_key) {
+            Some(url) => url.clone(),
+            None => {
+                let url = format!("urn:uuid:{}", Uuid::new_v4());
+                entries.push(create_patient_entry(&study.patient_info, &url));
+                patients.insert(patient_key, url.clone());
+                url
+            }
+        };
+
+        entries.push(json!({
+            "fullUrl": format!("urn:uuid:{}", Uuid::new_v4()),
+            "resource": create_imaging_study(study, &patient_url, &endpoint_url),
+            "request": {
+                "method": "PUT",
+                "url": format!(
+                    "ImagingStudy?identifier={}",
+                    token(Some(DICOM_UID_SYSTEM), &format!("urn:oid:{}", study.study_instance_uid))
+                ),
+            },
+        }));
+    }
+
+    json!({
+        "resourceType": "Bundle",
+        "type": "transaction",
+        "entry": entries,
+    })
+}
+
+/// Patient ID, or else name and birth date together; a patient with neither
+/// only belongs to its own study
+fn patient_key(study: &DicomStudy) -> String {
+    let patient = &study.patient_info;
+    if let Some(id) = non_empty(&patient.patient_id) {
+        return format!("id:{}", id);
+    }
+    match (non_empty(&patient.patient_name), non_empty(&patient.patient_birth_date)) {
+        (Some(name), Some(birth_date)) => format!("name:{}|{}", name, birth_date),
+        _ => format!("study:{}", study.study_instance_uid),
+    }
+}
+
+fn create_patient_entry(patient: &PatientInfo, full_url: &str) -> Value {
+    let mut resource = json!({"resourceType": "Patient"});
+    let mut request = json!({"method": "POST", "url": "Patient"});
+
+    if let Some(id) = non_empty(&patient.patient_id) {
+        resource["identifier"] = json!([{"value": id}]);
+        request["ifNoneExist"] = json!(format!("identifier={}", token(None, &id)));
+    }
+    if let Some(name) = non_empty(&patient.patient_name) {
+        resource["name"] = json!([human_name(&name)]);
+    }
+    resource["gender"] = json!(match non_empty(&patient.patient_sex).as_deref() {
+        Some("M") => "male",
+        Some("F") => "female",
+        Some("O") => "other",
+        _ => "unknown",
+    });
+    if let Some(birth_date) = non_empty(&patient.patient_birth_date).and_then(|d| fhir_date(&d)) {
+        resource["birthDate"] = json!(birth_date);
+    }
+
+    json!({"fullUrl": full_url, "resource": resource, "request": request})
+}
+
+fn create_imaging_study(study: &DicomStudy, patient_url: &str, endpoint_url: &str) -> Value {
+    let instances: Vec<&DicomInstance> = study.series.values().flat_map(|s| &s.instances).collect();
+    let first_tag = |tag| study.series.values().find_map(|s| s.tag_value(tag));
+
+    let mut identifiers = vec![json!({
+        "system": DICOM_UID_SYSTEM,
+        "value": format!("urn:oid:{}", study.study_instance_uid),
+    })];
+    if let Some(accession) = first_tag(tags::ACCESSION_NUMBER) {
+        identifiers.push(json!({
+            "type": {"coding": [{"system": ACCESSION_TYPE_SYSTEM, "code": "ACSN"}]},
+            "value": accession,
+        }));
+    }
+
+    let mut modalities: Vec<String> = study.series.values()
+        .filter_map(|s| non_empty(&s.modality))
+        .collect();
+    modalities.sort();
+    modalities.dedup();
+
+    let mut series: Vec<&DicomSeries> = study.series.values().collect();
+    series.sort_by_key(|s| parse_number(&s.series_number).unwrap_or(u64::MAX));
+
+    let mut resource = json!({
+        "resourceType": "ImagingStudy",
+        "identifier": identifiers,
+        "status": "available",
+        "modality": modalities.iter().map(|m| modality_coding(m)).collect::<Vec<_>>(),
+        "subject": {"reference": patient_url},
+        "endpoint": [{"reference": endpoint_url}],
+        "numberOfSeries": study.series.len(),
+        "numberOfInstances": instances.len(),
+        "series": series.iter().map(|s| create_series(s)).collect::<Vec<_>>(),
+    });
+
+    let offset = first_tag(tags::TIMEZONE_OFFSET_FROM_UTC);
+    if let Some(started) = non_empty(&study.study_date)
+        .and_then(|date| fhir_datetime(&date, non_empty(&study.study_time).as_deref(), offset.as_deref())) {
+        resource["started"] = json!(started);
+    }
+    if let Some(description) = non_empty(&study.study_description) {
+        resource["description"] = json!(description);
+    }
+    if let Some(referrer) = first_tag(tags::REFERRING_PHYSICIAN_NAME) {
+        resource["referrer"] = json!({"display": referrer.replace('^', " ").trim()});
+    }
+
+    resource
+}
+
+fn create_series(series: &DicomSeries) -> Value {
+    let first_tag = |tag| series.tag_value(tag);
+
+    let mut resource = json!({
+        "uid": series.series_instance_uid,
+        "modality": modality_coding(non_empty(&series.modality).as_deref().unwrap_or("OT")),
+        "numberOfInstances": series.instances.len(),
+        "instance": series.instances.iter().map(|instance| {
+            let mut entry = json!({"uid": instance.sop_instance_uid});
+            if let Some(sop_class) = non_empty(&instance.metadata.sop_class_uid) {
+                entry["sopClass"] = json!({"system": SOP_CLASS_SYSTEM, "code": format!("urn:oid:{}", sop_class)});
+            }
+            if let Some(number) = parse_number(&instance.instance_number) {
+                entry["number"] = json!(number);
+            }
+            entry
+        }).collect::<Vec<_>>(),
+    });
+
+    if let Some(number) = parse_number(&series.series_number) {
+        resource["number"] = json!(number);
+    }
+    if let Some(description) = non_empty(&series.series_description) {
+        resource["description"] = json!(description);
+    }
+    if let Some(body_part) = first_tag(tags::BODY_PART_EXAMINED) {
+        resource["bodySite"] = json!({"display": body_part});
+    }
+    if let Some(laterality) = first_tag(tags::LATERALITY) {
+        resource["laterality"] = json!({"code": laterality});
+    }
+    let offset = first_tag(tags::TIMEZONE_OFFSET_FROM_UTC);
+    if let Some(started) = first_tag(tags::SERIES_DATE)
+        .and_then(|date| fhir_datetime(&date, first_tag(tags::SERIES_TIME).as_deref(), offset.as_deref())) {
+        resource["started"] = json!(started);
+    }
+
+    resource
+}
+
+fn modality_coding(modality: &str) -> Value {
+    json!({"system": DICOM_MODALITY_SYSTEM, "code": modality})
+}
+
+/// DICOM PN (Family^Given^Middle^Prefix^Suffix) as a FHIR HumanName
+fn human_name(name: &str) -> Value {
+    let parts: Vec<&str> = name.split('=').next().unwrap_or_default().split('^').map(str::trim).collect();
+    let part = |i: usize| parts.get(i).copied().filter(|p| !p.is_empty());
+
+    let mut human_name = json!({"text": parts.iter().filter(|p| !p.is_empty()).copied().collect::<Vec<_>>().join(" ")});
+    if let Some(family) = part(0) {
+        human_name["family"] = json!(family);
+    }
+    let given: Vec<&str> = [part(1), part(2)].into_iter().flatten().collect();
+    if !given.is_empty() {
+        human_name["given"] = json!(given);
+    }
+    if let Some(prefix) = part(3) {
+        human_name["prefix"] = json!([prefix]);
+    }
+    if let Some(suffix) = part(4) {
+        human_name["suffix"] = json!([suffix]);
+    }
+    human_name
+}
+
+/// DICOM DA (YYYYMMDD) as FHIR date (YYYY-MM-DD)
+fn fhir_date(date: &str) -> Option<String> {
+    let date = date.trim();
+    if date.len() != 8 || !date.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some(format!("{}-{}-{}", &date[0..4], &date[4..6], &date[6..8]))
+}
+
+/// FHIR only allows a time of day together with a timezone, so without
+/// Timezone Offset From UTC the date alone is returned
+fn fhir_datetime(date: &str, time: Option<&str>, offset: Option<&str>) -> Option<String> {
+    let date = fhir_date(date)?;
+    let time = time.map(str::trim).filter(|t| t.len() >= 4 && t[..4].chars().all(|c| c.is_ascii_digit()));
+    let offset = offset.map(str::trim).filter(|o| o.len() == 5 && (o.starts_with('+') || o.starts_with('-')));
+
+    match (time, offset) {
+        (Some(time), Some(offset)) => {
+            let seconds = time.get(4..6).filter(|s| s.chars().all(|c| c.is_ascii_digit())).unwrap_or("00");
+            Some(format!("{}T{}:{}:{}{}:{}", date, &time[0..2], &time[2..4], seconds, &offset[0..3], &offset[3..5]))
+        }
+        _ => Some(date),
+    }
+}
+
+/// A token search parameter value (`[system|]code`) for a conditional request: the FHIR
+/// separators `\ | , $` escaped in each part, then the whole percent-encoded for the query
+fn token(system: Option<&str>, code: &str) -> String {
+    let escape = |part: &str| part.chars().fold(String::new(), |mut escaped, c| {
+        if matches!(c, '\\' | '|' | ',' | '$') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+        escaped
+    });
+    let value = match system {
+        Some(system) => format!("{}|{}", escape(system), escape(code)),
+        None => escape(code),
+    };
+    value.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn parse_number(value: &Option<String>) -> Option<u64> {
+    value.as_deref()?.trim().parse().ok()
+}
+
+fn non_empty(value: &Option<String>) -> Option<String> {
+    value.as_deref().map(str::trim).filter(|v| !v.is_empty()).map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn study(uid: &str, patient: &[(dicom_core::Tag, &str, &str)]) -> DicomStudy {
+        let mut entries = vec![
+            (tags::STUDY_INSTANCE_UID, "UI", uid),
+            (tags::SERIES_INSTANCE_UID, "UI", "1.9"),
+            (tags::MODALITY, "CS", "CT"),
+        ];
+        entries.extend_from_slice(patient);
+        crate::build_studies(&[testutil::instance(&format!("{}.1", uid), &entries)]).remove(uid).unwrap()
+    }
+
+    fn subjects(bundle: &Value) -> Vec<String> {
+        bundle["entry"].as_array().unwrap().iter()
+            .filter(|entry| entry["resource"]["resourceType"] == "ImagingStudy")
+            .map(|entry| entry["resource"]["subject"]["reference"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn patients_without_an_id_or_birth_date_are_not_merged() {
+        let studies = [
+            study("1.1", &[(tags::PATIENT_NAME, "PN", "DOE^JOHN")]),
+            study("1.2", &[(tags::PATIENT_NAME, "PN", "ROE^JANE")]),
+            study("1.3", &[]),
+            study("1.4", &[]),
+        ];
+        let subjects = subjects(&create_bundle(&studies, None));
+        let mut distinct = subjects.clone();
+        distinct.sort();
+        distinct.dedup();
+        assert_eq!(distinct.len(), 4);
+    }
+
+    #[test]
+    fn patients_are_merged_by_id_or_by_name_and_birth_date() {
+        let studies = [
+            study("1.1", &[(tags::PATIENT_ID, "LO", "P1"), (tags::PATIENT_NAME, "PN", "DOE^JOHN")]),
+            study("1.2", &[(tags::PATIENT_ID, "LO", "P1")]),
+            study("1.3", &[(tags::PATIENT_NAME, "PN", "ROE^JANE"), (tags::PATIENT_BIRTH_DATE, "DA", "19800101")]),
+            study("1.4", &[(tags::PATIENT_NAME, "PN", "ROE^JANE"), (tags::PATIENT_BIRTH_DATE, "DA", "19800101")]),
+        ];
+        let bundle = create_bundle(&studies, Some("https://pacs/dicomweb"));
+        let subjects = subjects(&bundle);
+        assert_eq!(subjects[0], subjects[1]);
+        assert_eq!(subjects[2], subjects[3]);
+        assert_ne!(subjects[0], subjects[2]);
+
+        let patient = bundle["entry"].as_array().unwrap().iter()
+            .find(|entry| entry["resource"]["resourceType"] == "Patient")
+            .unwrap();
+        assert_eq!(patient["request"]["ifNoneExist"], "identifier=P1");
+        assert_eq!(patient["resource"]["name"][0]["family"], "DOE");
+    }
+
+    #[test]
+    fn conditional_request_values_are_escaped_and_encoded() {
+        let studies = [study("1.1", &[(tags::PATIENT_ID, "LO", "A&B 1|2,3")])];
+        let bundle = create_bundle(&studies, Some("https://pacs/dicom web?x=1#y"));
+        let request = |resource_type: &str| bundle["entry"].as_array().unwrap().iter()
+            .find(|entry| entry["resource"]["resourceType"] == resource_type)
+            .map(|entry| entry["request"].clone())
+            .unwrap();
+        assert_eq!(request("Endpoint")["ifNoneExist"], "identifier=https%3A%2F%2Fpacs%2Fdicom%20web%3Fx%3D1%23y");
+        assert_eq!(request("Patient")["ifNoneExist"], "identifier=A%26B%201%5C%7C2%5C%2C3");
+        assert_eq!(request("ImagingStudy")["url"], "ImagingStudy?identifier=urn%3Adicom%3Auid%7Curn%3Aoid%3A1.1");
+        assert_eq!(token(None, "x+y$"), "x%2By%5C%24");
+    }
+
+    #[test]
+    fn instances_without_a_sop_class_have_no_sop_class_coding() {
+        let instance = |sop_class: Option<&str>| {
+            let mut instance = testutil::instance("1.1.1", &[(tags::STUDY_INSTANCE_UID, "UI", "1.1"), (tags::SERIES_INSTANCE_UID, "UI", "1.9")]);
+            instance.metadata.sop_class_uid = sop_class.map(str::to_string);
+            instance
+        };
+        let series = crate::build_studies(&[instance(None)]).remove("1.1").unwrap().series.remove("1.9").unwrap();
+        assert!(create_series(&series)["instance"][0].get("sopClass").is_none());
+        let series = crate::build_studies(&[instance(Some("1.2.840.10008.5.1.4.1.1.2"))]).remove("1.1").unwrap().series.remove("1.9").unwrap();
+        assert_eq!(create_series(&series)["instance"][0]["sopClass"]["code"], "urn:oid:1.2.840.10008.5.1.4.1.1.2");
+    }
+
+    #[test]
+    fn datetimes_need_an_offset_for_the_time_of_day() {
+        assert_eq!(fhir_datetime("20240102", Some("093015.5"), Some("-0500")).unwrap(), "2024-01-02T09:30:15-05:00");
+        assert_eq!(fhir_datetime("20240102", Some("0930"), None).unwrap(), "2024-01-02");
+        assert!(fhir_date("2024-01-02").is_none());
+    }
+}
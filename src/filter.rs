@@ -0,0 +1,256 @@
+//! `--where` tag predicates evaluated on each file before its tags are converted.
+
+use std::cmp::Ordering;
+use anyhow::{Context, Result, bail};
+use dicom_core::{Tag, VR, dictionary::DataDictionary};
+use dicom_dictionary_std::StandardDataDictionary;
+use dicom_object::InMemDicomObject;
+use regex::Regex;
+use crate::query::{range_separator, wildcard_match};
+
+pub struct TagPredicate {
+    tag: Tag,
+    condition: Condition,
+    /// Person names compare case-insensitively
+    ignore_case: bool,
+}
+
+enum Condition {
+    Exists,
+    Missing,
+    Equals(String),
+    NotEquals(String),
+    Compare(Ordering, bool, String),
+    /// Inclusive range, either bound may be open (dates and times)
+    Range(Option<String>, Option<String>),
+    In(Vec<String>),
+    NotIn(Vec<String>),
+    Matches(Regex),
+    NotMatches(Regex),
+}
+
+/// Value representations whose values compare as numbers; everything else, UIDs and IDs
+/// included, compares as text so `1.2` does not match `1.20`
+const NUMERIC_VRS: &[VR] = &[
+    VR::DS, VR::IS, VR::US, VR::SS, VR::UL, VR::SL, VR::UV, VR::SV, VR::FL, VR::FD,
+    VR::OF, VR::OD, VR::OL, VR::OV,
+];
+
+/// Two-character operators come first so `<=` is not read as `<`
+const OPERATORS: &[&str] = &["!=", "<=", ">=", "!~", "=", "<", ">", "~"];
+
+impl TagPredicate {
+    /// Parse e.g. `Modality in (CT,MR)`, `SliceThickness<=1.0`, `PatientName=DOE*`,
+    /// `StudyDate=20230101-20231231`, `SeriesDescription ~ (?i)t1`, `ContrastBolusAgent exists`
+    pub fn parse(expression: &str) -> Result<Self> {
+        let expression = expression.trim();
+        let key_end = expression
+            .find(|c: char| c.is_whitespace() || "=!<>~".contains(c))
+            .unwrap_or(expression.len());
+        let (keyword, rest) = expression.split_at(key_end);
+        let rest = rest.trim();
+
+        let tag = StandardDataDictionary.parse_tag(keyword)
+            .with_context(|| format!("Unknown tag keyword in --where: {}", keyword))?;
+        let vr = StandardDataDictionary.by_tag(tag).map(|entry| entry.vr);
+
+        let condition = if rest.eq_ignore_ascii_case("exists") {
+            Condition::Exists
+        } else if rest.eq_ignore_ascii_case("missing") {
+            Condition::Missing
+        } else if let Some(list) = strip_keyword(rest, "not in") {
+            Condition::NotIn(parse_list(list, expression)?)
+        } else if let Some(list) = strip_keyword(rest, "in").filter(|l| l.starts_with([' ', '('])) {
+            Condition::In(parse_list(list, expression)?)
+        } else {
+            let operator = OPERATORS.iter().find(|op| rest.starts_with(**op))
+                .with_context(|| format!("Expected =, !=, <, <=, >, >=, ~, !~, in, exists or missing in --where: {}", expression))?;
+            let value = unquote(rest[operator.len()..].trim()).to_string();
+            match *operator {
+                "=" if let Some(separator) = range_separator(vr, &value) => {
+                    let bound = |b: &str| Some(b.trim().to_string()).filter(|b| !b.is_empty());
+                    Condition::Range(bound(&value[..separator]), bound(&value[separator + 1..]))
+                }
+                "=" => Condition::Equals(value),
+                "!=" => Condition::NotEquals(value),
+                "<" => Condition::Compare(Ordering::Less, false, value),
+                "<=" => Condition::Compare(Ordering::Less, true, value),
+                ">" => Condition::Compare(Ordering::Greater, false, value),
+                ">=" => Condition::Compare(Ordering::Greater, true, value),
+                "~" => Condition::Matches(compile(&value)?),
+                "!~" => Condition::NotMatches(compile(&value)?),
+                _ => unreachable!(),
+            }
+        };
+
+        Ok(Self { tag, condition, ignore_case: vr == Some(VR::PN) })
+    }
+
+    /// Multi-valued attributes match when any value does; negated conditions
+    /// (`!=`, `!~`, `not in`) match when no value does, including when the tag is absent
+    pub fn matches(&self, dataset: &InMemDicomObject) -> bool {
+        let Some(element) = dataset.element_opt(self.tag).ok().flatten() else {
+            return matches!(self.condition, Condition::Missing | Condition::NotEquals(_) | Condition::NotIn(_) | Condition::NotMatches(_));
+        };
+        let values: Vec<String> = element.value().to_multi_str()
+            .map(|values| values.iter().map(|v| v.trim_matches([' ', '\0']).to_string()).collect())
+            .unwrap_or_default();
+        let numeric = NUMERIC_VRS.contains(&element.vr());
+        let any = |test: &dyn Fn(&str) -> bool| values.iter().any(|v| test(v));
+
+        match &self.condition {
+            Condition::Exists => true,
+            Condition::Missing => false,
+            Condition::Equals(expected) => any(&|v| self.equals(v, expected, numeric)),
+            Condition::NotEquals(expected) => !any(&|v| self.equals(v, expected, numeric)),
+            Condition::In(list) => any(&|v| list.iter().any(|expected| self.equals(v, expected, numeric))),
+            Condition::NotIn(list) => !any(&|v| list.iter().any(|expected| self.equals(v, expected, numeric))),
+            Condition::Matches(regex) => any(&|v| regex.is_match(v)),
+            Condition::NotMatches(regex) => !any(&|v| regex.is_match(v)),
+            Condition::Range(from, to) => any(&|v| {
+                !v.is_empty()
+                    && from.as_ref().is_none_or(|from| v >= from.as_str())
+                    // The upper bound is compared at its own precision, so 1300 includes 130059
+                    && to.as_ref().is_none_or(|to| v.get(..to.len()).unwrap_or(v) <= to.as_str())
+            }),
+            Condition::Compare(ordering, or_equal, expected) => any(&|v| {
+                !v.is_empty() && compare(v, expected, numeric).is_some_and(|o| o == *ordering || (*or_equal && o == Ordering::Equal))
+            }),
+        }
+    }
+
+    /// Numeric values compare as numbers, so `SliceThickness=1` matches `1.000`; strings may use `*` and `?`
+    fn equals(&self, value: &str, expected: &str, numeric: bool) -> bool {
+        if numeric && let (Ok(a), Ok(b)) = (value.parse::<f64>(), expected.parse::<f64>()) {
+            return a == b;
+        }
+        let (value, expected) = if self.ignore_case {
+            (value.to_uppercase(), expected.to_uppercase())
+        } else {
+            (value.to_string(), expected.to_string())
+        };
+        if expected.contains(['*', '?']) {
+            wildcard_match(&expected.chars().collect::<Vec<_>>(), &value.chars().collect::<Vec<_>>())
+        } else {
+            value == expected
+        }
+    }
+}
+
+/// Numeric comparison for numeric values, otherwise lexicographic (dates, times, text)
+fn compare(value: &str, expected: &str, numeric: bool) -> Option<Ordering> {
+    match (value.parse::<f64>(), expected.parse::<f64>()) {
+        (Ok(a), Ok(b)) if numeric => a.partial_cmp(&b),
+        _ => Some(value.cmp(expected)),
+    }
+}
+
+/// `text` after a leading ASCII `keyword` in any case
+fn strip_keyword<'a>(text: &'a str, keyword: &str) -> Option<&'a str> {
+    text.get(..keyword.len())
+        .filter(|prefix| prefix.eq_ignore_ascii_case(keyword))
+        .map(|_| &text[keyword.len()..])
+}
+
+fn parse_list(list: &str, expression: &str) -> Result<Vec<String>> {
+    let list = list.trim();
+    let Some(inner) = list.strip_prefix('(').and_then(|l| l.strip_suffix(')')) else {
+        bail!("Expected a parenthesized list such as (CT,MR) in --where: {}", expression);
+    };
+    Ok(inner.split(',').map(|v| unquote(v.trim()).to_string()).collect())
+}
+
+fn unquote(value: &str) -> &str {
+    ['"', '\''].iter()
+        .find_map(|q| value.strip_prefix(*q).and_then(|v| v.strip_suffix(*q)))
+        .unwrap_or(value)
+}
+
+fn compile(pattern: &str) -> Result<Regex> {
+    Regex::new(pattern).with_context(|| format!("Invalid regular expression in --where: {}", pattern))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicom_core::{DataElement, PrimitiveValue};
+    use dicom_dictionary_std::tags;
+
+    fn dataset(elements: &[(Tag, VR, &str)]) -> InMemDicomObject {
+        let mut dataset = InMemDicomObject::new_empty();
+        for (tag, vr, value) in elements {
+            let values: Vec<String> = value.split('\\').map(str::to_string).collect();
+            dataset.put(DataElement::new(*tag, *vr, PrimitiveValue::Strs(values.into())));
+        }
+        dataset
+    }
+
+    fn matches(expression: &str, dataset: &InMemDicomObject) -> bool {
+        TagPredicate::parse(expression).unwrap().matches(dataset)
+    }
+
+    #[test]
+    fn keywords_ignore_ascii_case_next_to_non_ascii_values() {
+        let ct = dataset(&[
+            (tags::MODALITY, VR::CS, "CT"),
+            (tags::INSTITUTION_NAME, VR::LO, "São Paulo"),
+            (tags::PATIENT_NAME, VR::PN, "Ünal^Öz"),
+        ]);
+        assert!(matches("Modality EXISTS", &ct));
+        assert!(matches("ContrastBolusAgent Missing", &ct));
+        assert!(matches("Modality In (CT,MR)", &ct));
+        assert!(matches("InstitutionName in (\"São Paulo\")", &ct));
+        assert!(!matches("InstitutionName NOT IN (São Paulo,Köln)", &ct));
+        assert!(matches("PatientName Not In (Çelik^Ay)", &ct));
+        assert!(matches("PatientName=ünal^öz", &ct));
+        assert!(matches("PatientName ~ ^Ü", &ct));
+        assert!(TagPredicate::parse("Modality inside (CT)").is_err());
+        assert!(TagPredicate::parse("Modality in CT").is_err());
+        assert!(TagPredicate::parse("Modality ÿ").is_err());
+    }
+
+    #[test]
+    fn comparisons_ranges_and_negations() {
+        let mr = dataset(&[
+            (tags::SLICE_THICKNESS, VR::DS, "1.000"),
+            (tags::STUDY_DATE, VR::DA, "20230615"),
+            (tags::ACQUISITION_TIME, VR::TM, "130059.5"),
+            (tags::ACQUISITION_DATE_TIME, VR::DT, "20230615130059-0500"),
+            (tags::IMAGE_TYPE, VR::CS, "ORIGINAL\\PRIMARY"),
+        ]);
+        assert!(matches("SliceThickness=1", &mr));
+        assert!(matches("SliceThickness<=1.0", &mr));
+        assert!(!matches("SliceThickness>1", &mr));
+        assert!(matches("StudyDate=20230101-20231231", &mr));
+        assert!(matches("StudyDate=-20230615", &mr));
+        assert!(!matches("StudyDate=20230616-", &mr));
+        assert!(matches("AcquisitionTime=1200-1300", &mr));
+        assert!(matches("AcquisitionDateTime=20230615130059-0500", &mr));
+        assert!(matches("AcquisitionDateTime=20230615-20230615130059-0500", &mr));
+        assert!(matches("ImageType=PRIMARY", &mr));
+        assert!(!matches("ImageType!=PRIMARY", &mr));
+        assert!(matches("Modality!=CT", &mr));
+        assert!(matches("SeriesDescription!~(?i)t1", &mr));
+        assert!(TagPredicate::parse("SeriesDescription~(").is_err());
+    }
+
+    #[test]
+    fn only_numeric_vrs_compare_as_numbers() {
+        let ct = dataset(&[
+            (tags::STUDY_INSTANCE_UID, VR::UI, "1.20"),
+            (tags::PATIENT_ID, VR::LO, "0123"),
+            (tags::SERIES_NUMBER, VR::IS, "0123"),
+            (tags::PIXEL_SPACING, VR::DS, "0.5\\0.50"),
+        ]);
+        assert!(!matches("StudyInstanceUID=1.2", &ct));
+        assert!(matches("StudyInstanceUID!=1.2", &ct));
+        assert!(!matches("StudyInstanceUID in (1.2,1.200)", &ct));
+        assert!(!matches("PatientID=123", &ct));
+        assert!(matches("PatientID=0123", &ct));
+        // Text compares character by character: "0123" sorts before "2"
+        assert!(matches("PatientID<2", &ct));
+        assert!(matches("SeriesNumber=123", &ct));
+        assert!(!matches("SeriesNumber<2", &ct));
+        assert!(matches("PixelSpacing=.5", &ct));
+    }
+}
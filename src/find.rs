@@ -0,0 +1,181 @@
+//! `find` subcommand: C-FIND SCU against a remote AE, with the matches written in
+//! the same output formats as converted files.
+
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+use dicom_core::{DataElement, PrimitiveValue, Tag, VR, dictionary::DataDictionary};
+use dicom_dictionary_std::{StandardDataDictionary, tags};
+use dicom_object::InMemDicomObject;
+use crate::dimse::{self, Association, Command, Message, PresentationContext};
+use crate::{DicomInstance, DicomProcessor, write_results};
+
+/// Query/Retrieve information model
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum QueryRoot {
+    Patient,
+    Study,
+}
+
+/// Query/Retrieve level
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum FindLevel {
+    Study,
+    Series,
+    Image,
+}
+
+impl QueryRoot {
+    fn sop_class(self) -> &'static str {
+        match self {
+            QueryRoot::Patient => "1.2.840.10008.5.1.4.1.2.1.1",
+            QueryRoot::Study => "1.2.840.10008.5.1.4.1.2.2.1",
+        }
+    }
+}
+
+impl FindLevel {
+    fn name(self) -> &'static str {
+        match self {
+            FindLevel::Study => "STUDY",
+            FindLevel::Series => "SERIES",
+            FindLevel::Image => "IMAGE",
+        }
+    }
+
+    /// Attributes returned when not given as keys
+    fn return_keys(self) -> &'static [Tag] {
+        match self {
+            FindLevel::Study => &[
+                tags::PATIENT_NAME, tags::PATIENT_ID, tags::PATIENT_BIRTH_DATE, tags::PATIENT_SEX,
+                tags::STUDY_INSTANCE_UID, tags::STUDY_DATE, tags::STUDY_TIME, tags::STUDY_ID,
+                tags::ACCESSION_NUMBER, tags::STUDY_DESCRIPTION, tags::REFERRING_PHYSICIAN_NAME,
+                tags::MODALITIES_IN_STUDY, tags::NUMBER_OF_STUDY_RELATED_SERIES, tags::NUMBER_OF_STUDY_RELATED_INSTANCES,
+            ],
+            FindLevel::Series => &[
+                tags::STUDY_INSTANCE_UID, tags::SERIES_INSTANCE_UID, tags::MODALITY, tags::SERIES_NUMBER,
+                tags::SERIES_DESCRIPTION, tags::SERIES_DATE, tags::BODY_PART_EXAMINED,
+                tags::NUMBER_OF_SERIES_RELATED_INSTANCES,
+            ],
+            FindLevel::Image => &[
+                tags::STUDY_INSTANCE_UID, tags::SERIES_INSTANCE_UID, tags::SOP_INSTANCE_UID,
+                tags::SOP_CLASS_UID, tags::INSTANCE_NUMBER,
+            ],
+        }
+    }
+}
+
+pub struct FindRequest<'a> {
+    pub address: &'a str,
+    pub calling_ae: &'a str,
+    pub called_ae: &'a str,
+    pub root: QueryRoot,
+    pub level: FindLevel,
+    pub keys: &'a [String],
+}
+
+pub fn run(processor: &DicomProcessor, request: FindRequest, output_dir: &std::path::Path) -> Result<()> {
+    let identifier = build_identifier(&request)?;
+    let matches = find(processor, &request, &identifier)?;
+
+    if processor.cli.verbose {
+        println!(
+            "🔎 {} {} matches from {} at {}",
+            matches.len(), request.level.name().to_lowercase(), request.called_ae, request.address
+        );
+    }
+    if matches.is_empty() {
+        return Ok(());
+    }
+    std::fs::create_dir_all(output_dir)?;
+    write_results(&matches, output_dir, processor, None)?;
+    Ok(())
+}
+
+/// Query keys: the level, the default return keys and the `KEYWORD=VALUE` keys given
+fn build_identifier(request: &FindRequest) -> Result<InMemDicomObject> {
+    let mut identifier = InMemDicomObject::new_empty();
+    identifier.put(DataElement::new(tags::QUERY_RETRIEVE_LEVEL, VR::CS, PrimitiveValue::from(request.level.name())));
+
+    let mut return_keys = request.level.return_keys().to_vec();
+    if request.root == QueryRoot::Patient {
+        return_keys.extend([tags::PATIENT_ID, tags::PATIENT_NAME]);
+    }
+    for tag in return_keys {
+        let vr = StandardDataDictionary.by_tag(tag).map_or(VR::UN, |entry| entry.vr);
+        identifier.put(DataElement::new(tag, vr, PrimitiveValue::Empty));
+    }
+
+    for key in request.keys {
+        let (keyword, value) = key.split_once('=')
+            .with_context(|| format!("C-FIND keys are KEYWORD=VALUE: {}", key))?;
+        let tag = StandardDataDictionary.parse_tag(keyword.trim())
+            .with_context(|| format!("Unknown attribute in C-FIND key: {}", keyword))?;
+        let vr = StandardDataDictionary.by_tag(tag).map_or(VR::UN, |entry| entry.vr);
+        if vr == VR::SQ {
+            bail!("Sequence matching is not supported: {}", keyword);
+        }
+        // UID lists may be comma separated on the command line
+        let value = value.trim();
+        let value = if vr == VR::UI { value.replace(',', "\\") } else { value.to_string() };
+        let value = if value.is_empty() { PrimitiveValue::Empty } else { PrimitiveValue::from(value) };
+        identifier.put(DataElement::new(tag, vr, value));
+    }
+    Ok(identifier)
+}
+
+fn find(processor: &DicomProcessor, request: &FindRequest, identifier: &InMemDicomObject) -> Result<Vec<DicomInstance>> {
+    let proposed = [PresentationContext {
+        id: 1,
+        abstract_syntax: request.root.sop_class().to_string(),
+        transfer_syntaxes: vec![dimse::EXPLICIT_VR_LE.to_string(), dimse::IMPLICIT_VR_LE.to_string()],
+    }];
+    let mut association = Association::request(
+        request.address, request.calling_ae, request.called_ae, &proposed, processor.cli.dimse_timeout(),
+    )?;
+    let Some(transfer_syntax) = association.contexts.first().and_then(|c| c.transfer_syntaxes.first()).cloned() else {
+        association.abort();
+        bail!("{} does not support {:?} root C-FIND", request.called_ae, request.root);
+    };
+
+    let mut command = Command::new(dimse::C_FIND_RQ, dimse::MESSAGE_ID, 1);
+    command.set_str(dimse::AFFECTED_SOP_CLASS_UID, request.root.sop_class());
+    command.set_u16(dimse::PRIORITY, 0);
+    command.set_has_data_set(true);
+    let data = dimse::encode_dataset(identifier, &transfer_syntax)?;
+    association.send(&Message { context_id: 1, command, data: Some(data) })?;
+
+    let source = format!("dicom://{}@{}", request.called_ae, request.address);
+    let mut matches = Vec::new();
+    loop {
+        let Some(response) = association.receive()? else {
+            bail!("{} released the association before the C-FIND completed", request.called_ae);
+        };
+        if response.command.u16(dimse::COMMAND_FIELD) != Some(dimse::C_FIND_RSP) {
+            association.abort();
+            bail!("Unexpected DIMSE message during C-FIND");
+        }
+
+        match response.command.u16(dimse::STATUS) {
+            Some(dimse::STATUS_PENDING | dimse::STATUS_PENDING_WARNING) => {
+                let Some(data) = &response.data else {
+                    continue;
+                };
+                let dataset = dimse::decode_dataset(data, &transfer_syntax)?;
+                if processor.predicates.iter().all(|predicate| predicate.matches(&dataset)) {
+                    matches.push(processor.convert_dataset(&dataset, Some(transfer_syntax.clone()), source.clone())?);
+                }
+            }
+            Some(dimse::STATUS_SUCCESS) => break,
+            status => {
+                let comment = response.command.str(dimse::ERROR_COMMENT)
+                    .map(|comment| format!(": {}", comment))
+                    .unwrap_or_default();
+                association.abort();
+                bail!("C-FIND failed with status {:#06x}{}", status.unwrap_or(0xFFFF), comment);
+            }
+        }
+    }
+
+    association.release()?;
+    Ok(matches)
+}
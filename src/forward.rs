@@ -0,0 +1,197 @@
+//! `--forward`: send converted files to a storage SCP with C-STORE, optionally
+//! de-identified first, and report the status of every instance.
+
+use std::collections::BTreeSet;
+use anyhow::{Context, Result, bail};
+use dicom_object::OpenFileOptions;
+use crate::anonymize::Anonymizer;
+use crate::dimse::{self, Association, Command, Message, PresentationContext};
+use crate::{DicomInstance, DicomProcessor};
+
+/// Transfer syntaxes a data set can be re-encoded into without decoding pixel data
+const NATIVE_TRANSFER_SYNTAXES: &[&str] = &[dimse::EXPLICIT_VR_LE, dimse::IMPLICIT_VR_LE, "1.2.840.10008.1.2.2"];
+
+/// Presentation context IDs are odd numbers up to 255
+const MAX_CONTEXTS: usize = 128;
+
+/// Outcome of sending one instance
+pub struct ForwardStatus {
+    pub sop_instance_uid: String,
+    pub file_path: String,
+    /// C-STORE response status, or why no response was received
+    pub outcome: Result<u16, String>,
+}
+
+impl ForwardStatus {
+    pub fn is_stored(&self) -> bool {
+        matches!(self.outcome, Ok(status) if status == dimse::STATUS_SUCCESS || is_warning(status))
+    }
+
+    pub fn describe(&self) -> String {
+        match &self.outcome {
+            Ok(dimse::STATUS_SUCCESS) => "stored".to_string(),
+            Ok(status) if is_warning(*status) => format!("stored with warning {:04X}", status),
+            Ok(status) => format!("failed with status {:04X}", status),
+            Err(e) => format!("not sent: {}", e),
+        }
+    }
+}
+
+/// Warning statuses: coercion of data elements, elements discarded, data set does not match SOP class
+fn is_warning(status: u16) -> bool {
+    matches!(status, 0xB000 | 0xB006 | 0xB007)
+}
+
+/// Send the instances' files to the `--forward` destination
+pub fn forward(processor: &DicomProcessor, instances: &[DicomInstance]) -> Result<Vec<ForwardStatus>> {
+    let cli = &processor.cli;
+    let address = cli.forward.as_deref().context("No --forward destination")?;
+    let anonymizer = cli.anonymize.then(|| Anonymizer::new(cli.anonymize_key.as_deref()));
+
+    // One context per SOP class and transfer syntax, with uncompressed alternatives where
+    // the data set can be re-encoded
+    let pairs: BTreeSet<(String, String)> = instances.iter()
+        .filter_map(|instance| Some((instance.metadata.sop_class_uid.clone()?, instance.metadata.transfer_syntax.clone()?)))
+        .collect();
+    let pairs: Vec<(String, String)> = pairs.into_iter().collect();
+
+    let mut statuses = Vec::new();
+    for batch in pairs.chunks(MAX_CONTEXTS) {
+        let batch_instances: Vec<&DicomInstance> = instances.iter()
+            .filter(|instance| batch.iter().any(|(class, ts)| {
+                instance.metadata.sop_class_uid.as_ref() == Some(class) && instance.metadata.transfer_syntax.as_ref() == Some(ts)
+            }))
+            .collect();
+        let proposed: Vec<PresentationContext> = batch.iter().enumerate()
+            .map(|(index, (class, ts))| {
+                let mut transfer_syntaxes = vec![ts.clone()];
+                if NATIVE_TRANSFER_SYNTAXES.contains(&ts.as_str()) {
+                    transfer_syntaxes.extend(NATIVE_TRANSFER_SYNTAXES.iter().filter(|native| **native != ts).map(|native| native.to_string()));
+                }
+                PresentationContext { id: (index * 2 + 1) as u8, abstract_syntax: class.clone(), transfer_syntaxes }
+            })
+            .collect();
+
+        match Association::request(address, &cli.forward_calling_ae, &cli.forward_ae, &proposed, cli.dimse_timeout()) {
+            Ok(mut association) => {
+                let sent = send_all(&mut association, &batch_instances, anonymizer.as_ref());
+                let unsent = &batch_instances[sent.len()..];
+                statuses.extend(sent);
+                if unsent.is_empty() {
+                    if let Err(e) = association.release() {
+                        eprintln!("⚠️  Release of the association with {} failed: {}", cli.forward_ae, e);
+                    }
+                } else {
+                    association.abort();
+                    statuses.extend(unsent.iter().map(|i| status(i, Err("association lost".to_string()))));
+                }
+            }
+            Err(e) => {
+                let e = format!("{:#}", e);
+                statuses.extend(batch_instances.iter().map(|i| status(i, Err(e.clone()))));
+            }
+        }
+    }
+
+    // Instances that could not even be proposed
+    statuses.extend(instances.iter()
+        .filter(|i| i.metadata.sop_class_uid.is_none() || i.metadata.transfer_syntax.is_none())
+        .map(|i| status(i, Err("no SOP Class or Transfer Syntax UID".to_string()))));
+
+    Ok(statuses)
+}
+
+/// Send each instance in turn; stops early when the association breaks
+fn send_all(association: &mut Association, instances: &[&DicomInstance], anonymizer: Option<&Anonymizer>) -> Vec<ForwardStatus> {
+    let mut statuses = Vec::new();
+    for (index, instance) in instances.iter().enumerate() {
+        let Some(context) = choose_context(association, instance) else {
+            let ts = instance.metadata.transfer_syntax.clone().unwrap_or_default();
+            statuses.push(status(instance, Err(format!("no presentation context accepted for transfer syntax {}", ts))));
+            continue;
+        };
+        let message_id = (index % 0xFFFF) as u16 + 1;
+        match store(association, &context, instance, anonymizer, message_id) {
+            Ok(outcome) => statuses.push(status(instance, outcome)),
+            Err(e) => {
+                statuses.push(status(instance, Err(format!("{:#}", e))));
+                break;
+            }
+        }
+    }
+    statuses
+}
+
+/// Context of the instance's SOP class accepted with its own transfer syntax, or failing
+/// that with an uncompressed one it can be re-encoded into
+fn choose_context(association: &Association, instance: &DicomInstance) -> Option<PresentationContext> {
+    let class = instance.metadata.sop_class_uid.as_deref()?;
+    let ts = instance.metadata.transfer_syntax.as_deref()?;
+    let candidates = || association.contexts.iter().filter(|c| c.abstract_syntax == class);
+    candidates().find(|c| c.transfer_syntaxes.iter().any(|t| t == ts))
+        .or_else(|| {
+            NATIVE_TRANSFER_SYNTAXES.contains(&ts).then(|| {
+                candidates().find(|c| c.transfer_syntaxes.iter().any(|t| NATIVE_TRANSFER_SYNTAXES.contains(&t.as_str())))
+            })?
+        })
+        .cloned()
+}
+
+/// One C-STORE. The outer error means the association can no longer be used; the inner
+/// one that this instance could not be sent.
+fn store(
+    association: &mut Association,
+    context: &PresentationContext,
+    instance: &DicomInstance,
+    anonymizer: Option<&Anonymizer>,
+    message_id: u16,
+) -> Result<Result<u16, String>> {
+    let transfer_syntax = &context.transfer_syntaxes[0];
+    let prepared = OpenFileOptions::new()
+        .open_file(&instance.file_path)
+        .with_context(|| format!("Failed to open DICOM file: {:?}", instance.file_path))
+        .and_then(|file| {
+            let mut dataset = file.into_inner();
+            if let Some(anonymizer) = anonymizer {
+                anonymizer.apply(&mut dataset);
+            }
+            let sop_instance = dataset.element(dicom_dictionary_std::tags::SOP_INSTANCE_UID)?.to_str()?.to_string();
+            Ok((dimse::encode_dataset(&dataset, transfer_syntax)?, sop_instance))
+        });
+    let (data, sop_instance) = match prepared {
+        Ok(prepared) => prepared,
+        Err(e) => return Ok(Err(format!("{:#}", e))),
+    };
+
+    let mut command = Command::new(dimse::C_STORE_RQ, dimse::MESSAGE_ID, message_id);
+    command.set_str(dimse::AFFECTED_SOP_CLASS_UID, &context.abstract_syntax);
+    command.set_str(dimse::AFFECTED_SOP_INSTANCE_UID, sop_instance.trim_end_matches(['\0', ' ']));
+    command.set_u16(dimse::PRIORITY, 0);
+    command.set_has_data_set(true);
+    association.send(&Message { context_id: context.id, command, data: Some(data) })?;
+
+    let Some(response) = association.receive()? else {
+        bail!("{} released the association during C-STORE", association.called_ae);
+    };
+    if response.command.u16(dimse::COMMAND_FIELD) != Some(dimse::C_STORE_RSP) {
+        bail!("Unexpected DIMSE message in answer to C-STORE");
+    }
+    Ok(Ok(response.command.u16(dimse::STATUS).unwrap_or(0xFFFF)))
+}
+
+fn status(instance: &DicomInstance, outcome: Result<u16, String>) -> ForwardStatus {
+    ForwardStatus {
+        sop_instance_uid: instance.sop_instance_uid.clone(),
+        file_path: instance.file_path.clone(),
+        outcome,
+    }
+}
+
+/// Per-instance lines for the processing summary
+pub fn print_summary(statuses: &[ForwardStatus], destination: &str) {
+    let stored = statuses.iter().filter(|s| s.is_stored()).count();
+    println!("   Forwarded to {}: {} of {} instances", destination, stored, statuses.len());
+    for status in statuses {
+        println!("     - {} {}", status.sop_instance_uid, status.describe());
+    }
+}
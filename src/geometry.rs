@@ -0,0 +1,307 @@
+//! Spatial ordering and volume geometry of image series, derived from
+//! Image Position (Patient) and Image Orientation (Patient).
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use dicom_core::Tag;
+use dicom_dictionary_std::tags;
+use crate::{DicomInstance, TagInfo, get_tag_value};
+
+/// Positions closer than this along the slice normal are reported as duplicates (mm)
+const DUPLICATE_TOLERANCE: f64 = 1e-3;
+/// Orientation cosines differing by more than this are treated as a different plane
+const ORIENTATION_TOLERANCE: f64 = 1e-3;
+/// A spacing larger than this multiple of the nominal spacing is reported as a gap
+const GAP_FACTOR: f64 = 1.5;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SeriesGeometry {
+    /// Columns, rows, slices
+    pub dimensions: [usize; 3],
+    /// Column spacing, row spacing, slice spacing (mm)
+    pub voxel_size: [f64; 3],
+    pub slice_normal: [f64; 3],
+    /// Median distance between consecutive slices along the normal (mm)
+    pub slice_spacing: Option<f64>,
+    pub min_slice_spacing: Option<f64>,
+    pub max_slice_spacing: Option<f64>,
+    pub uniform_spacing: bool,
+    /// Angle between the slice normal and the direction the slices are stacked in (degrees)
+    pub gantry_tilt_degrees: f64,
+    pub gaps: Vec<SliceGap>,
+    pub duplicate_positions: Vec<DuplicatePosition>,
+    /// Voxel index (column, row, slice, 1) to patient LPS coordinates, row-major
+    pub affine_lps: [[f64; 4]; 4],
+    /// Voxel index (column, row, slice, 1) to patient RAS coordinates, row-major
+    pub affine_ras: [[f64; 4]; 4],
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SliceGap {
+    pub after_sop_instance_uid: String,
+    pub before_sop_instance_uid: String,
+    pub spacing: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DuplicatePosition {
+    pub sop_instance_uids: [String; 2],
+    pub position: [f64; 3],
+}
+
+/// Sort the instances of a series spatially and describe the volume they form.
+///
+/// Instances are ordered along the slice normal when every instance has a position
+/// and all share one orientation; otherwise they are ordered by Instance Number.
+/// Geometry is only returned for the spatially ordered case with a known Pixel Spacing.
+pub fn sort_and_analyze(instances: &mut [DicomInstance]) -> Option<SeriesGeometry> {
+    let planes: Option<Vec<([f64; 3], [f64; 6])>> = instances.iter()
+        .map(|i| Some((
+            parse_vector::<3>(&i.metadata.tags, tags::IMAGE_POSITION_PATIENT)?,
+            parse_vector::<6>(&i.metadata.tags, tags::IMAGE_ORIENTATION_PATIENT)?,
+        )))
+        .collect();
+
+    let orientation = planes.as_ref()
+        .and_then(|p| p.first())
+        .map(|(_, o)| *o)
+        .filter(|o| planes.iter().flatten().all(|(_, other)| {
+            o.iter().zip(other).all(|(a, b)| (a - b).abs() <= ORIENTATION_TOLERANCE)
+        }));
+
+    let Some(orientation) = orientation else {
+        instances.sort_by_key(|i| instance_number(i).unwrap_or(i64::MAX));
+        return None;
+    };
+
+    let row_cosine = [orientation[0], orientation[1], orientation[2]];
+    let column_cosine = [orientation[3], orientation[4], orientation[5]];
+    let normal = normalize(cross(row_cosine, column_cosine));
+
+    instances.sort_by(|a, b| {
+        let pa = dot(parse_vector::<3>(&a.metadata.tags, tags::IMAGE_POSITION_PATIENT).unwrap_or_default(), normal);
+        let pb = dot(parse_vector::<3>(&b.metadata.tags, tags::IMAGE_POSITION_PATIENT).unwrap_or_default(), normal);
+        pa.total_cmp(&pb)
+    });
+
+    let first = instances.first()?;
+    let pixel_spacing = parse_vector::<2>(&first.metadata.tags, tags::PIXEL_SPACING)?;
+    let rows = parse_number(&first.metadata.tags, tags::ROWS)? as usize;
+    let columns = parse_number(&first.metadata.tags, tags::COLUMNS)? as usize;
+
+    let positions: Vec<[f64; 3]> = instances.iter()
+        .filter_map(|i| parse_vector::<3>(&i.metadata.tags, tags::IMAGE_POSITION_PATIENT))
+        .collect();
+
+    let mut gaps = Vec::new();
+    let mut duplicate_positions = Vec::new();
+    let mut spacings = Vec::new();
+    for (index, pair) in positions.windows(2).enumerate() {
+        let spacing = dot(pair[1], normal) - dot(pair[0], normal);
+        if spacing < DUPLICATE_TOLERANCE {
+            duplicate_positions.push(DuplicatePosition {
+                sop_instance_uids: [
+                    instances[index].sop_instance_uid.clone(),
+                    instances[index + 1].sop_instance_uid.clone(),
+                ],
+                position: pair[1],
+            });
+        } else {
+            spacings.push((index, spacing));
+        }
+    }
+
+    let slice_spacing = median(spacings.iter().map(|(_, s)| *s).collect());
+    let min_slice_spacing = spacings.iter().map(|(_, s)| *s).reduce(f64::min);
+    let max_slice_spacing = spacings.iter().map(|(_, s)| *s).reduce(f64::max);
+
+    let mut uniform_spacing = true;
+    if let Some(nominal) = slice_spacing {
+        let tolerance = (nominal * 0.01).max(0.01);
+        uniform_spacing = spacings.iter().all(|(_, s)| (s - nominal).abs() <= tolerance);
+
+        for (index, spacing) in &spacings {
+            if *spacing > nominal * GAP_FACTOR {
+                gaps.push(SliceGap {
+                    after_sop_instance_uid: instances[*index].sop_instance_uid.clone(),
+                    before_sop_instance_uid: instances[index + 1].sop_instance_uid.clone(),
+                    spacing: *spacing,
+                });
+            }
+        }
+    }
+
+    let origin = positions[0];
+    let last = positions[positions.len() - 1];
+    let stack = [last[0] - origin[0], last[1] - origin[1], last[2] - origin[2]];
+    let stack_length = norm(stack);
+    let stack_cosine = if stack_length > DUPLICATE_TOLERANCE { dot(stack, normal).abs() / stack_length } else { 0.0 };
+
+    let through_plane = slice_spacing
+        .or_else(|| parse_number(&first.metadata.tags, tags::SLICE_THICKNESS))
+        .unwrap_or(1.0);
+
+    let gantry_tilt_degrees = if stack_length > DUPLICATE_TOLERANCE {
+        stack_cosine.min(1.0).acos().to_degrees()
+    } else {
+        0.0
+    };
+
+    // Step between slices follows the stacking direction so tilted stacks stay exact
+    let slice_step = if stack_cosine > 1e-6 {
+        let along_stack = through_plane / stack_cosine;
+        [
+            stack[0] / stack_length * along_stack,
+            stack[1] / stack_length * along_stack,
+            stack[2] / stack_length * along_stack,
+        ]
+    } else {
+        [normal[0] * through_plane, normal[1] * through_plane, normal[2] * through_plane]
+    };
+
+    let mut affine_lps = [[0.0; 4]; 4];
+    for axis in 0..3 {
+        affine_lps[axis] = [
+            row_cosine[axis] * pixel_spacing[1],
+            column_cosine[axis] * pixel_spacing[0],
+            slice_step[axis],
+            origin[axis],
+        ];
+    }
+    affine_lps[3] = [0.0, 0.0, 0.0, 1.0];
+
+    let mut affine_ras = affine_lps;
+    for row in affine_ras.iter_mut().take(2) {
+        for value in row.iter_mut().filter(|v| **v != 0.0) {
+            *value = -*value;
+        }
+    }
+
+    Some(SeriesGeometry {
+        dimensions: [columns, rows, instances.len()],
+        voxel_size: [pixel_spacing[1], pixel_spacing[0], through_plane],
+        slice_normal: normal,
+        slice_spacing,
+        min_slice_spacing,
+        max_slice_spacing,
+        uniform_spacing,
+        gantry_tilt_degrees,
+        gaps,
+        duplicate_positions,
+        affine_lps,
+        affine_ras,
+    })
+}
+
+fn instance_number(instance: &DicomInstance) -> Option<i64> {
+    instance.instance_number.as_deref()?.trim().parse().ok()
+}
+
+fn parse_number(tags: &HashMap<String, TagInfo>, tag: Tag) -> Option<f64> {
+    parse_vector::<1>(tags, tag).map(|v| v[0])
+}
+
+pub fn parse_vector<const N: usize>(tags: &HashMap<String, TagInfo>, tag: Tag) -> Option<[f64; N]> {
+    let raw = get_tag_value(tags, tag)?;
+    let mut values = [0.0; N];
+    let mut parts = raw.split('\\');
+    for value in values.iter_mut() {
+        *value = parts.next()?.trim().parse().ok()?;
+    }
+    Some(values)
+}
+
+fn median(mut values: Vec<f64>) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    Some(if values.len().is_multiple_of(2) { (values[mid - 1] + values[mid]) / 2.0 } else { values[mid] })
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn norm(a: [f64; 3]) -> f64 {
+    dot(a, a).sqrt()
+}
+
+fn normalize(a: [f64; 3]) -> [f64; 3] {
+    let length = norm(a);
+    if length == 0.0 { a } else { [a[0] / length, a[1] / length, a[2] / length] }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn slice(uid: &str, number: &str, z: f64) -> DicomInstance {
+        let position = format!("-100\\-120\\{}", z);
+        let mut instance = testutil::instance(uid, &[
+            (tags::IMAGE_POSITION_PATIENT, "DS", &position),
+            (tags::IMAGE_ORIENTATION_PATIENT, "DS", "1\\0\\0\\0\\1\\0"),
+            (tags::PIXEL_SPACING, "DS", "0.5\\0.75"),
+            (tags::ROWS, "US", "256"),
+            (tags::COLUMNS, "US", "512"),
+        ]);
+        instance.instance_number = Some(number.to_string());
+        instance
+    }
+
+    #[test]
+    fn sorts_along_the_normal_and_reports_gaps_and_duplicates() {
+        let mut instances = vec![
+            slice("c", "1", 10.0),
+            slice("a", "2", 0.0),
+            slice("e", "3", 25.0),
+            slice("b", "4", 5.0),
+            slice("d", "5", 15.0),
+            slice("d2", "6", 15.0),
+        ];
+        let geometry = sort_and_analyze(&mut instances).unwrap();
+
+        let order: Vec<&str> = instances.iter().map(|i| i.sop_instance_uid.as_str()).collect();
+        assert_eq!(order[..4], ["a", "b", "c", "d"]);
+        assert_eq!(order[5], "e");
+        assert_eq!(geometry.dimensions, [512, 256, 6]);
+        assert_eq!(geometry.voxel_size, [0.75, 0.5, 5.0]);
+        assert_eq!(geometry.slice_spacing, Some(5.0));
+        assert!(!geometry.uniform_spacing);
+        assert_eq!(geometry.gaps.len(), 1);
+        assert_eq!(geometry.gaps[0].spacing, 10.0);
+        assert_eq!(geometry.duplicate_positions.len(), 1);
+        assert_eq!(geometry.affine_lps[0], [0.75, 0.0, 0.0, -100.0]);
+        assert_eq!(geometry.affine_lps[2], [0.0, 0.0, 5.0, 0.0]);
+        assert_eq!(geometry.affine_ras[0], [-0.75, 0.0, 0.0, 100.0]);
+        assert_eq!(geometry.gantry_tilt_degrees, 0.0);
+    }
+
+    #[test]
+    fn falls_back_to_instance_number_without_positions() {
+        let mut instances = vec![slice("b", "2", 0.0), slice("a", "1", 5.0)];
+        instances[0].metadata.tags.remove(&crate::tag_key(tags::IMAGE_POSITION_PATIENT));
+
+        assert!(sort_and_analyze(&mut instances).is_none());
+        assert_eq!(instances[0].sop_instance_uid, "a");
+    }
+
+    #[test]
+    fn reports_gantry_tilt() {
+        let tilted = |uid: &str, k: f64| {
+            let mut instance = slice(uid, "1", 0.0);
+            let position = testutil::tags(&[(tags::IMAGE_POSITION_PATIENT, "DS", &format!("0\\{}\\{}", k, 5.0 * k))]);
+            instance.metadata.tags.extend(position);
+            instance
+        };
+        let mut instances = vec![tilted("a", 0.0), tilted("b", 1.0), tilted("c", 2.0)];
+        let geometry = sort_and_analyze(&mut instances).unwrap();
+        assert!((geometry.gantry_tilt_degrees - 1f64.atan2(5.0).to_degrees()).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,232 @@
+//! HL7 v2.5 ORU^R01 / ORM^O01 messages built from aggregated studies.
+
+use chrono::Utc;
+use clap::ValueEnum;
+use dicom_core::Tag;
+use dicom_dictionary_std::tags;
+use uuid::Uuid;
+use crate::{DicomSeries, DicomStudy};
+
+const SEGMENT_SEPARATOR: &str = "\r";
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Hl7MessageType {
+    /// Observation result with one OBX per series
+    OruR01,
+    /// Order message without observations
+    OrmO01,
+}
+
+/// One message for a study, segments terminated by carriage returns
+pub fn create_message(study: &DicomStudy, message_type: Hl7MessageType) -> String {
+    let tag = |tag: Tag| study.series.values().find_map(|s| s.tag_value(tag)).unwrap_or_default();
+    let accession = tag(tags::ACCESSION_NUMBER);
+    let study_datetime = hl7_datetime(
+        study.study_date.as_deref().unwrap_or_default(),
+        study.study_time.as_deref().unwrap_or_default(),
+    );
+
+    let (message_code, order_control, result_status) = match message_type {
+        Hl7MessageType::OruR01 => ("ORU^R01^ORU_R01", "RE", "F"),
+        Hl7MessageType::OrmO01 => ("ORM^O01^ORM_O01", "NW", ""),
+    };
+
+    let mut segments = vec![
+        segment(&[
+            "MSH",
+            "^~\\&",
+            "DICOM-JSON",
+            &escape(&tag(tags::INSTITUTION_NAME)),
+            "",
+            "",
+            &Utc::now().format("%Y%m%d%H%M%S").to_string(),
+            "",
+            message_code,
+            &Uuid::new_v4().simple().to_string()[..20],
+            "P",
+            "2.5",
+        ]),
+        segment(&[
+            "PID",
+            "1",
+            "",
+            &components(&[
+                study.patient_info.patient_id.as_deref().unwrap_or_default(),
+                "",
+                "",
+                &tag(tags::ISSUER_OF_PATIENT_ID),
+            ]),
+            "",
+            &person_name(study.patient_info.patient_name.as_deref().unwrap_or_default()),
+            "",
+            &escape(study.patient_info.patient_birth_date.as_deref().unwrap_or_default().trim()),
+            &administrative_sex(study.patient_info.patient_sex.as_deref().unwrap_or_default()),
+        ]),
+        segment(&["ORC", order_control, &escape(&accession), &escape(&accession), "", "CM"]),
+    ];
+
+    let procedure_id = Some(tag(tags::REQUESTED_PROCEDURE_ID))
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| tag(tags::STUDY_ID));
+    let procedure_text = study.study_description.clone()
+        .filter(|v| !v.trim().is_empty())
+        .unwrap_or_else(|| tag(tags::REQUESTED_PROCEDURE_DESCRIPTION));
+    let modality = study_modalities(study).into_iter().next().unwrap_or_default();
+
+    let mut obr = vec![
+        "OBR".to_string(),
+        "1".to_string(),
+        escape(&accession),
+        escape(&accession),
+        if procedure_id.is_empty() && procedure_text.trim().is_empty() {
+            String::new()
+        } else {
+            components(&[&procedure_id, &procedure_text, "L"])
+        },
+        String::new(),
+        String::new(),
+        study_datetime,
+    ];
+    obr.resize(19, String::new());
+    obr[18] = escape(&accession);
+    obr.resize(26, String::new());
+    obr[24] = modality;
+    obr[25] = result_status.to_string();
+    segments.push(obr.join("|"));
+
+    if message_type == Hl7MessageType::OruR01 {
+        segments.push(segment(&[
+            "OBX", "1", "ST", "113014^DICOM Study^DCM", "", &escape(&study.study_instance_uid),
+            "", "", "", "", "", "F",
+        ]));
+
+        let mut set_id = 1;
+        let mut series: Vec<&DicomSeries> = study.series.values().collect();
+        series.sort_by_key(|s| s.series_number.as_deref().and_then(|n| n.trim().parse::<i64>().ok()).unwrap_or(i64::MAX));
+        for (index, series) in series.iter().enumerate() {
+            let sub_id = (index + 1).to_string();
+            let summary = [
+                series.series_number.as_deref().unwrap_or_default(),
+                series.modality.as_deref().unwrap_or_default(),
+                series.series_description.as_deref().unwrap_or_default(),
+                &format!("({} instances)", series.instances.len()),
+            ].iter().map(|v| v.trim()).filter(|v| !v.is_empty()).collect::<Vec<_>>().join(" ");
+
+            set_id += 1;
+            segments.push(segment(&[
+                "OBX", &set_id.to_string(), "ST", "112002^Series Instance UID^DCM", &sub_id,
+                &escape(&series.series_instance_uid), "", "", "", "", "", "F",
+            ]));
+            set_id += 1;
+            segments.push(segment(&[
+                "OBX", &set_id.to_string(), "TX", "121106^Comment^DCM", &sub_id,
+                &escape(&summary), "", "", "", "", "", "F",
+            ]));
+        }
+    }
+
+    let mut message = segments.join(SEGMENT_SEPARATOR);
+    message.push_str(SEGMENT_SEPARATOR);
+    message
+}
+
+fn segment(fields: &[&str]) -> String {
+    fields.join("|")
+}
+
+fn components(values: &[&str]) -> String {
+    let joined = values.iter().map(|v| escape(v.trim())).collect::<Vec<_>>().join("^");
+    joined.trim_end_matches('^').to_string()
+}
+
+/// Escape HL7 delimiters in a field value
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\E\\"),
+            '|' => escaped.push_str("\\F\\"),
+            '^' => escaped.push_str("\\S\\"),
+            '&' => escaped.push_str("\\T\\"),
+            '~' => escaped.push_str("\\R\\"),
+            '\r' => escaped.push_str("\\X0D\\"),
+            '\n' => escaped.push_str("\\X0A\\"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// DICOM PN (Family^Given^Middle^Prefix^Suffix) as HL7 XPN (Family^Given^Middle^Suffix^Prefix)
+fn person_name(name: &str) -> String {
+    let parts: Vec<&str> = name.split('=').next().unwrap_or_default().split('^').collect();
+    let part = |i: usize| parts.get(i).copied().unwrap_or_default();
+    components(&[part(0), part(1), part(2), part(4), part(3)])
+}
+
+fn administrative_sex(sex: &str) -> String {
+    match sex.trim() {
+        "M" | "F" | "O" => sex.trim().to_string(),
+        _ => "U".to_string(),
+    }
+}
+
+/// DICOM DA + TM as HL7 DTM (YYYYMMDDHHMMSS)
+fn hl7_datetime(date: &str, time: &str) -> String {
+    let date = date.trim();
+    if date.len() != 8 {
+        return String::new();
+    }
+    let time: String = time.trim().chars().take_while(|c| c.is_ascii_digit()).take(6).collect();
+    format!("{}{}", date, time)
+}
+
+fn study_modalities(study: &DicomStudy) -> Vec<String> {
+    let mut modalities: Vec<String> = study.series.values()
+        .filter_map(|s| s.modality.as_deref().map(str::trim).filter(|m| !m.is_empty()).map(escape))
+        .collect();
+    modalities.sort();
+    modalities.dedup();
+    modalities
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn escapes_delimiters() {
+        assert_eq!(escape("a|b^c&d~e\\f\r\ng"), "a\\F\\b\\S\\c\\T\\d\\R\\e\\E\\f\\X0D\\\\X0A\\g");
+        assert_eq!(person_name("O'NEIL^MARY|ANN^^DR^JR=ideographic"), "O'NEIL^MARY\\F\\ANN^^JR^DR");
+    }
+
+    #[test]
+    fn oru_message_has_one_segment_per_line_and_escaped_values() {
+        let instance = testutil::instance("1.2.3.1", &[
+            (tags::STUDY_INSTANCE_UID, "UI", "1.2"),
+            (tags::SERIES_INSTANCE_UID, "UI", "1.2.3"),
+            (tags::PATIENT_ID, "LO", "P|1"),
+            (tags::PATIENT_NAME, "PN", "DOE^JOHN"),
+            (tags::STUDY_DATE, "DA", "20240102"),
+            (tags::STUDY_TIME, "TM", "093015.25"),
+            (tags::ACCESSION_NUMBER, "SH", "A&1"),
+            (tags::SERIES_DESCRIPTION, "LO", "Head ^ Neck"),
+            (tags::MODALITY, "CS", "CT"),
+        ]);
+        let studies = crate::build_studies(&[instance]);
+        let message = create_message(&studies["1.2"], Hl7MessageType::OruR01);
+
+        let segments: Vec<&str> = message.trim_end_matches('\r').split('\r').collect();
+        let names: Vec<&str> = segments.iter().map(|s| &s[..3]).collect();
+        assert_eq!(names, ["MSH", "PID", "ORC", "OBR", "OBX", "OBX", "OBX"]);
+        assert!(segments[0].starts_with("MSH|^~\\&|DICOM-JSON|"));
+        assert_eq!(segments[1].split('|').nth(3), Some("P\\F\\1"));
+        assert_eq!(segments[1].split('|').nth(5), Some("DOE^JOHN"));
+        let obr: Vec<&str> = segments[3].split('|').collect();
+        assert_eq!(obr[2], "A\\T\\1");
+        assert_eq!(obr[7], "20240102093015");
+        assert_eq!(obr[24], "CT");
+        assert!(segments[6].contains("Head \\S\\ Neck"));
+    }
+}
@@ -0,0 +1,278 @@
+//! SQLite catalog of processed instances with normalized patient/study/series/instance
+//! tables and a generic tag table. Instances are upserted by SOP Instance UID, so
+//! re-running over the same archive updates the catalog in place. Instances missing a
+//! SOP, Study or Series Instance UID are skipped rather than merged under a placeholder.
+
+use std::path::Path;
+use anyhow::{Context, Result, bail};
+use chrono::Utc;
+use dicom_core::dictionary::DataDictionary;
+use dicom_dictionary_std::{StandardDataDictionary, tags};
+use rusqlite::{Connection, params};
+use crate::{DicomInstance, get_tag_value, table};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS patients (
+    patient_key TEXT PRIMARY KEY,
+    patient_id TEXT,
+    patient_name TEXT,
+    birth_date TEXT,
+    sex TEXT
+);
+CREATE TABLE IF NOT EXISTS studies (
+    study_instance_uid TEXT PRIMARY KEY,
+    patient_key TEXT NOT NULL REFERENCES patients(patient_key),
+    study_date TEXT,
+    study_time TEXT,
+    study_description TEXT,
+    accession_number TEXT
+);
+CREATE TABLE IF NOT EXISTS series (
+    series_instance_uid TEXT PRIMARY KEY,
+    study_instance_uid TEXT NOT NULL REFERENCES studies(study_instance_uid),
+    series_number INTEGER,
+    modality TEXT,
+    series_description TEXT
+);
+CREATE TABLE IF NOT EXISTS instances (
+    sop_instance_uid TEXT PRIMARY KEY,
+    series_instance_uid TEXT NOT NULL REFERENCES series(series_instance_uid),
+    sop_class_uid TEXT,
+    instance_number INTEGER,
+    file_path TEXT NOT NULL,
+    transfer_syntax TEXT,
+    has_pixel_data INTEGER NOT NULL,
+    indexed_at TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS tags (
+    sop_instance_uid TEXT NOT NULL REFERENCES instances(sop_instance_uid) ON DELETE CASCADE,
+    tag TEXT NOT NULL,
+    keyword TEXT,
+    vr TEXT NOT NULL,
+    value TEXT,
+    PRIMARY KEY (sop_instance_uid, tag)
+);
+CREATE INDEX IF NOT EXISTS patients_id ON patients(patient_id);
+CREATE INDEX IF NOT EXISTS studies_patient ON studies(patient_key);
+CREATE INDEX IF NOT EXISTS studies_date ON studies(study_date);
+CREATE INDEX IF NOT EXISTS series_study ON series(study_instance_uid);
+CREATE INDEX IF NOT EXISTS series_modality ON series(modality);
+CREATE INDEX IF NOT EXISTS instances_series ON instances(series_instance_uid);
+CREATE INDEX IF NOT EXISTS tags_keyword_value ON tags(keyword, value);
+";
+
+pub struct CatalogIndex {
+    connection: Connection,
+}
+
+/// Rows touched by one update
+#[derive(Default)]
+pub struct IndexStats {
+    pub inserted: usize,
+    pub updated: usize,
+    /// Instances without a SOP, Study or Series Instance UID
+    pub skipped: usize,
+}
+
+impl CatalogIndex {
+    /// Open the database, creating the tables on first use
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        let connection = Connection::open(path)
+            .with_context(|| format!("Failed to open index database: {:?}", path))?;
+        connection.execute_batch("PRAGMA foreign_keys = ON; PRAGMA journal_mode = WAL;")?;
+        connection.execute_batch(SCHEMA)?;
+        let keyed = connection.prepare("SELECT patient_key FROM patients LIMIT 0").is_ok();
+        if !keyed {
+            bail!("Index database {:?} was written by an older version without patient keys; remove it to rebuild", path);
+        }
+        Ok(Self { connection })
+    }
+
+    /// Insert or update the instances and their patient, study and series rows in one transaction
+    pub fn upsert(&mut self, instances: &[DicomInstance]) -> Result<IndexStats> {
+        let mut stats = IndexStats::default();
+        let transaction = self.connection.transaction()?;
+        let indexed_at = Utc::now().to_rfc3339();
+
+        for instance in instances {
+            let tags_map = &instance.metadata.tags;
+            let value = |tag| get_tag_value(tags_map, tag).map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+            let number = |tag| value(tag).and_then(|v| v.parse::<i64>().ok());
+
+            let (Some(sop_uid), Some(study_uid), Some(series_uid)) =
+                (value(tags::SOP_INSTANCE_UID), value(tags::STUDY_INSTANCE_UID), value(tags::SERIES_INSTANCE_UID))
+            else {
+                stats.skipped += 1;
+                continue;
+            };
+            let patient_id = value(tags::PATIENT_ID);
+            let patient_key = patient_key(patient_id.as_deref(), value(tags::PATIENT_NAME), value(tags::PATIENT_BIRTH_DATE), &study_uid);
+
+            transaction.execute(
+                "INSERT INTO patients (patient_key, patient_id, patient_name, birth_date, sex) VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT(patient_key) DO UPDATE SET
+                    patient_name = COALESCE(excluded.patient_name, patient_name),
+                    birth_date = COALESCE(excluded.birth_date, birth_date),
+                    sex = COALESCE(excluded.sex, sex)",
+                params![patient_key, patient_id, value(tags::PATIENT_NAME), value(tags::PATIENT_BIRTH_DATE), value(tags::PATIENT_SEX)],
+            )?;
+            transaction.execute(
+                "INSERT INTO studies (study_instance_uid, patient_key, study_date, study_time, study_description, accession_number)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                 ON CONFLICT(study_instance_uid) DO UPDATE SET
+                    patient_key = excluded.patient_key,
+                    study_date = COALESCE(excluded.study_date, study_date),
+                    study_time = COALESCE(excluded.study_time, study_time),
+                    study_description = COALESCE(excluded.study_description, study_description),
+                    accession_number = COALESCE(excluded.accession_number, accession_number)",
+                params![
+                    study_uid, patient_key, value(tags::STUDY_DATE), value(tags::STUDY_TIME),
+                    value(tags::STUDY_DESCRIPTION), value(tags::ACCESSION_NUMBER),
+                ],
+            )?;
+            transaction.execute(
+                "INSERT INTO series (series_instance_uid, study_instance_uid, series_number, modality, series_description)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT(series_instance_uid) DO UPDATE SET
+                    study_instance_uid = excluded.study_instance_uid,
+                    series_number = COALESCE(excluded.series_number, series_number),
+                    modality = COALESCE(excluded.modality, modality),
+                    series_description = COALESCE(excluded.series_description, series_description)",
+                params![series_uid, study_uid, number(tags::SERIES_NUMBER), value(tags::MODALITY), value(tags::SERIES_DESCRIPTION)],
+            )?;
+
+            // Absolute paths keep the catalog valid whatever directory later runs start from
+            let file_path = std::fs::canonicalize(&instance.file_path)
+                .map(|p| p.to_string_lossy().to_string())
+                .unwrap_or_else(|_| instance.file_path.clone());
+
+            let existed = transaction.query_row(
+                "SELECT COUNT(*) FROM instances WHERE sop_instance_uid = ?1",
+                params![sop_uid],
+                |row| row.get::<_, i64>(0),
+            )? > 0;
+            transaction.execute(
+                "INSERT INTO instances (sop_instance_uid, series_instance_uid, sop_class_uid, instance_number,
+                                        file_path, transfer_syntax, has_pixel_data, indexed_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                 ON CONFLICT(sop_instance_uid) DO UPDATE SET
+                    series_instance_uid = excluded.series_instance_uid,
+                    sop_class_uid = excluded.sop_class_uid,
+                    instance_number = excluded.instance_number,
+                    file_path = excluded.file_path,
+                    transfer_syntax = excluded.transfer_syntax,
+                    has_pixel_data = excluded.has_pixel_data,
+                    indexed_at = excluded.indexed_at",
+                params![
+                    sop_uid, series_uid, instance.metadata.sop_class_uid,
+                    instance.instance_number.as_deref().and_then(|n| n.trim().parse::<i64>().ok()),
+                    file_path, instance.metadata.transfer_syntax, instance.has_pixel_data, indexed_at,
+                ],
+            )?;
+            if existed {
+                stats.updated += 1;
+            } else {
+                stats.inserted += 1;
+            }
+
+            transaction.execute("DELETE FROM tags WHERE sop_instance_uid = ?1", params![sop_uid])?;
+            let mut insert_tag = transaction.prepare_cached(
+                "INSERT INTO tags (sop_instance_uid, tag, keyword, vr, value) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for tag_info in tags_map.values() {
+                let keyword = StandardDataDictionary.parse_tag(&tag_info.tag)
+                    .filter(|tag| tag.group() % 2 == 0)
+                    .and_then(|tag| StandardDataDictionary.by_tag(tag))
+                    .map(|entry| entry.alias);
+                let value = (!table::BINARY_VRS.contains(&tag_info.vr.as_str()))
+                    .then(|| table::tag_values(tag_info).join("\\"));
+                insert_tag.execute(params![sop_uid, tag_info.tag, keyword, tag_info.vr, value])?;
+            }
+        }
+
+        transaction.commit()?;
+        Ok(stats)
+    }
+}
+
+/// Patients row key: the Patient ID, or for patients without one their name and birth date,
+/// falling back to the study, so unrelated anonymous patients are not merged. The prefixes
+/// keep a Patient ID from ever matching the key of a patient without one.
+fn patient_key(patient_id: Option<&str>, name: Option<String>, birth_date: Option<String>, study_uid: &str) -> String {
+    match (patient_id, name, birth_date) {
+        (Some(id), ..) => format!("id:{}", id),
+        (None, Some(name), Some(birth_date)) => format!("name:{}|{}", name, birth_date),
+        _ => format!("study:{}", study_uid),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn instance(sop_uid: &str, study_uid: &str, patient: &[(dicom_core::Tag, &str, &str)]) -> DicomInstance {
+        let mut entries = vec![
+            (tags::SOP_INSTANCE_UID, "UI", sop_uid),
+            (tags::STUDY_INSTANCE_UID, "UI", study_uid),
+            (tags::SERIES_INSTANCE_UID, "UI", "1.2.3"),
+        ];
+        entries.extend_from_slice(patient);
+        testutil::instance(sop_uid, &entries)
+    }
+
+    fn column(index: &CatalogIndex, sql: &str) -> Vec<String> {
+        let mut statement = index.connection.prepare(sql).unwrap();
+        statement.query_map([], |row| row.get(0)).unwrap().map(|value| value.unwrap()).collect()
+    }
+
+    #[test]
+    fn instances_without_uids_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut index = CatalogIndex::open(&dir.path().join("catalog.db")).unwrap();
+        let instances = [
+            // No SOP Instance UID: the converter falls back to "unknown" for these
+            testutil::instance("unknown", &[(tags::STUDY_INSTANCE_UID, "UI", "1.2"), (tags::SERIES_INSTANCE_UID, "UI", "1.2.3")]),
+            testutil::instance("unknown", &[(tags::STUDY_INSTANCE_UID, "UI", "1.2"), (tags::SERIES_INSTANCE_UID, "UI", "1.2.3")]),
+            testutil::instance("1.2.3.4", &[(tags::SOP_INSTANCE_UID, "UI", "1.2.3.4"), (tags::STUDY_INSTANCE_UID, "UI", "1.2")]),
+            instance("1.2.3.5", "1.2", &[]),
+        ];
+
+        let stats = index.upsert(&instances).unwrap();
+        assert_eq!((stats.inserted, stats.updated, stats.skipped), (1, 0, 3));
+        assert_eq!(column(&index, "SELECT sop_instance_uid FROM instances"), ["1.2.3.5"]);
+        assert_eq!(column(&index, "SELECT DISTINCT sop_instance_uid FROM tags"), ["1.2.3.5"]);
+    }
+
+    #[test]
+    fn patients_without_id_are_not_merged() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut index = CatalogIndex::open(&dir.path().join("catalog.db")).unwrap();
+        let instances = [
+            instance("1.1", "1.10", &[(tags::PATIENT_ID, "LO", "P1"), (tags::PATIENT_NAME, "PN", "Doe^Jane")]),
+            instance("1.2", "1.20", &[(tags::PATIENT_NAME, "PN", "Roe^Rick"), (tags::PATIENT_BIRTH_DATE, "DA", "19800101")]),
+            instance("1.3", "1.30", &[(tags::PATIENT_NAME, "PN", "Roe^Rick"), (tags::PATIENT_BIRTH_DATE, "DA", "19800101")]),
+            instance("1.4", "1.40", &[(tags::PATIENT_NAME, "PN", "Poe^Pam")]),
+            instance("1.5", "1.50", &[(tags::PATIENT_ID, "LO", " ")]),
+        ];
+        index.upsert(&instances).unwrap();
+
+        assert_eq!(
+            column(&index, "SELECT patient_key FROM patients ORDER BY patient_key"),
+            ["id:P1", "name:Roe^Rick|19800101", "study:1.40", "study:1.50"],
+        );
+        assert_eq!(
+            column(&index, "SELECT patient_key FROM studies ORDER BY study_instance_uid"),
+            ["id:P1", "name:Roe^Rick|19800101", "name:Roe^Rick|19800101", "study:1.40", "study:1.50"],
+        );
+        // Only real Patient IDs are stored as such
+        let ids: Vec<Option<String>> = {
+            let mut statement = index.connection.prepare("SELECT patient_id FROM patients ORDER BY patient_key").unwrap();
+            statement.query_map([], |row| row.get(0)).unwrap().map(|value| value.unwrap()).collect()
+        };
+        assert_eq!(ids, [Some("P1".to_string()), None, None, None]);
+    }
+}
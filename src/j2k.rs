@@ -0,0 +1,1577 @@
+//! JPEG 2000 Part 1 decoder for the JPEG 2000 transfer syntaxes: codestream and tile-part
+//! headers, tier-2 packet headers, EBCOT tier-1 code-block decoding, dequantization and the
+//! inverse wavelet and component transforms.
+
+use std::collections::HashSet;
+use anyhow::{Context, Result, bail};
+
+const SOC: u16 = 0xFF4F;
+const SIZ: u16 = 0xFF51;
+const COD: u16 = 0xFF52;
+const COC: u16 = 0xFF53;
+const QCD: u16 = 0xFF5C;
+const QCC: u16 = 0xFF5D;
+const RGN: u16 = 0xFF5E;
+const POC: u16 = 0xFF5F;
+const PPM: u16 = 0xFF60;
+const PPT: u16 = 0xFF61;
+const SOT: u16 = 0xFF90;
+const SOD: u16 = 0xFF93;
+const EOC: u16 = 0xFFD9;
+
+const TRUNCATED: &str = "Truncated JPEG 2000 codestream";
+
+/// Code-block style flags
+const BYPASS: u8 = 0x01;
+const RESET: u8 = 0x02;
+const TERMALL: u8 = 0x04;
+const VERTICALLY_CAUSAL: u8 = 0x08;
+const SEGMENTATION_SYMBOLS: u8 = 0x20;
+
+/// Decoded image: one full-resolution plane per component
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub components: Vec<Vec<i32>>,
+    pub signed: bool,
+}
+
+pub fn decode(data: &[u8]) -> Result<Image> {
+    // DICOM pads odd-length fragments with a zero byte after EOC
+    let data = codestream(data)?;
+    let data = data.strip_suffix(&[0]).filter(|data| data.ends_with(&[0xFF, 0xD9])).unwrap_or(data);
+    let mut reader = Reader { data, pos: 0 };
+    if reader.u16()? != SOC {
+        bail!("Not a JPEG 2000 codestream");
+    }
+    if reader.u16()? != SIZ {
+        bail!("JPEG 2000 codestream without SIZ marker");
+    }
+    let length = reader.u16()? as usize;
+    let siz = Siz::parse(reader.bytes(length.saturating_sub(2))?)?;
+
+    let mut main = Header::new(siz.components.len());
+    loop {
+        let marker = reader.u16()?;
+        if marker == SOT {
+            break;
+        }
+        let length = reader.u16()? as usize;
+        main.parse(marker, reader.bytes(length.saturating_sub(2))?, siz.components.len())?;
+    }
+    reader.pos -= 2;
+
+    // Tile-parts, gathered per tile
+    let tile_count = siz.tiles_wide() * siz.tiles_high();
+    let mut tiles: Vec<Option<(Header, Vec<u8>)>> = (0..tile_count).map(|_| None).collect();
+    loop {
+        let start = reader.pos;
+        match reader.u16()? {
+            SOT => {}
+            EOC => break,
+            marker => bail!("Unexpected marker {:04X} in JPEG 2000 codestream", marker),
+        }
+        reader.u16()?;
+        let index = reader.u16()? as usize;
+        let part_length = reader.u32()? as usize;
+        reader.u16()?;
+        let (header, data) = tiles.get_mut(index)
+            .context("Tile index out of range in JPEG 2000 codestream")?
+            .get_or_insert_with(|| (Header::new(siz.components.len()), Vec::new()));
+        loop {
+            let marker = reader.u16()?;
+            if marker == SOD {
+                break;
+            }
+            let length = reader.u16()? as usize;
+            header.parse(marker, reader.bytes(length.saturating_sub(2))?, siz.components.len())?;
+        }
+        let end = if part_length == 0 {
+            // Last tile-part, running up to EOC
+            if !reader.data.ends_with(&[0xFF, 0xD9]) {
+                bail!(TRUNCATED);
+            }
+            reader.data.len() - 2
+        } else if start + part_length > reader.data.len() {
+            bail!(TRUNCATED);
+        } else {
+            start + part_length
+        };
+        if end < reader.pos {
+            bail!("Invalid tile-part length in JPEG 2000 codestream");
+        }
+        data.extend_from_slice(&reader.data[reader.pos..end]);
+        reader.pos = end;
+    }
+
+    let first = &siz.components[0];
+    let mut image = Image {
+        width: (siz.width - siz.x0) as usize,
+        height: (siz.height - siz.y0) as usize,
+        components: vec![vec![0; ((siz.width - siz.x0) * (siz.height - siz.y0)) as usize]; siz.components.len()],
+        signed: first.signed,
+    };
+    for (index, tile) in tiles.iter().enumerate() {
+        let Some((header, data)) = tile else {
+            continue;
+        };
+        Tile::new(&siz, &main, header, index)?.decode(data, &mut image)?;
+    }
+    Ok(image)
+}
+
+/// The codestream, unwrapped from the JP2 file format when present
+fn codestream(data: &[u8]) -> Result<&[u8]> {
+    if !data.starts_with(&[0, 0, 0, 0x0C, b'j', b'P', b' ', b' ']) {
+        return Ok(data);
+    }
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let mut length = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+        let mut header = 8;
+        if length == 1 {
+            let extended = data.get(pos + 8..pos + 16).context(TRUNCATED)?;
+            length = u64::from_be_bytes(extended.try_into().unwrap()) as usize;
+            header = 16;
+        } else if length == 0 {
+            length = data.len() - pos;
+        }
+        if &data[pos + 4..pos + 8] == b"jp2c" {
+            return data.get(pos + header..pos + length.max(header)).context(TRUNCATED);
+        }
+        if length < header {
+            break;
+        }
+        pos += length;
+    }
+    bail!("JP2 file without a codestream box")
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Result<u8> {
+        let byte = *self.data.get(self.pos).context(TRUNCATED)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from(self.u8()?) << 8 | u16::from(self.u8()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from(self.u16()?) << 16 | u32::from(self.u16()?))
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos + count).context(TRUNCATED)?;
+        self.pos += count;
+        Ok(bytes)
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len().saturating_sub(self.pos)
+    }
+}
+
+struct ComponentSiz {
+    precision: u8,
+    signed: bool,
+    dx: u32,
+    dy: u32,
+}
+
+struct Siz {
+    width: u32,
+    height: u32,
+    x0: u32,
+    y0: u32,
+    tile_width: u32,
+    tile_height: u32,
+    tile_x0: u32,
+    tile_y0: u32,
+    components: Vec<ComponentSiz>,
+}
+
+impl Siz {
+    fn parse(segment: &[u8]) -> Result<Self> {
+        let mut r = Reader { data: segment, pos: 0 };
+        r.u16()?;
+        let mut siz = Siz {
+            width: r.u32()?,
+            height: r.u32()?,
+            x0: r.u32()?,
+            y0: r.u32()?,
+            tile_width: r.u32()?,
+            tile_height: r.u32()?,
+            tile_x0: r.u32()?,
+            tile_y0: r.u32()?,
+            components: Vec::new(),
+        };
+        let count = r.u16()?;
+        for _ in 0..count {
+            let depth = r.u8()?;
+            let component = ComponentSiz { precision: (depth & 0x7F) + 1, signed: depth & 0x80 != 0, dx: r.u8()?.into(), dy: r.u8()?.into() };
+            if component.dx != 1 || component.dy != 1 {
+                bail!("Subsampled JPEG 2000 components are not supported");
+            }
+            if component.precision > 30 {
+                bail!("JPEG 2000 precision of {} bits is not supported", component.precision);
+            }
+            siz.components.push(component);
+        }
+        if siz.components.is_empty() || siz.width <= siz.x0 || siz.height <= siz.y0
+            || siz.tile_width == 0 || siz.tile_height == 0 || siz.tile_x0 > siz.x0 || siz.tile_y0 > siz.y0
+        {
+            bail!("Invalid JPEG 2000 image and tile size");
+        }
+        Ok(siz)
+    }
+
+    fn tiles_wide(&self) -> usize {
+        (self.width - self.tile_x0).div_ceil(self.tile_width) as usize
+    }
+
+    fn tiles_high(&self) -> usize {
+        (self.height - self.tile_y0).div_ceil(self.tile_height) as usize
+    }
+}
+
+#[derive(Clone)]
+struct ComponentStyle {
+    levels: usize,
+    cb_width: u8,
+    cb_height: u8,
+    cb_style: u8,
+    reversible: bool,
+    /// Precinct size exponents per resolution level
+    precincts: Vec<(u8, u8)>,
+}
+
+impl ComponentStyle {
+    fn parse(r: &mut Reader, precincts_defined: bool) -> Result<Self> {
+        let levels = r.u8()? as usize;
+        let cb_width = r.u8()? + 2;
+        let cb_height = r.u8()? + 2;
+        if levels > 32 || cb_width > 10 || cb_height > 10 || cb_width + cb_height > 12 {
+            bail!("Invalid JPEG 2000 coding style");
+        }
+        let cb_style = r.u8()?;
+        let reversible = r.u8()? == 1;
+        let precincts = (0..=levels)
+            .map(|_| Ok(if precincts_defined { let b = r.u8()?; (b & 0x0F, b >> 4) } else { (15, 15) }))
+            .collect::<Result<Vec<_>>>()?;
+        if precincts.iter().skip(1).any(|&(ppx, ppy)| ppx == 0 || ppy == 0) {
+            bail!("Invalid JPEG 2000 precinct size");
+        }
+        Ok(Self { levels, cb_width, cb_height, cb_style, reversible, precincts })
+    }
+}
+
+#[derive(Clone)]
+struct Cod {
+    sop: bool,
+    eph: bool,
+    progression: u8,
+    layers: u16,
+    mct: bool,
+    style: ComponentStyle,
+}
+
+#[derive(Clone)]
+struct Quantization {
+    /// 0: none, 1: scalar derived, 2: scalar expounded
+    style: u8,
+    guard_bits: u8,
+    /// Exponent and mantissa per subband
+    steps: Vec<(u8, u16)>,
+}
+
+#[derive(Clone, Copy)]
+struct Poc {
+    res_start: usize,
+    comp_start: usize,
+    layer_end: usize,
+    res_end: usize,
+    comp_end: usize,
+    order: u8,
+}
+
+/// Coding parameters of the main header or of one tile's tile-part headers
+#[derive(Clone)]
+struct Header {
+    cod: Option<Cod>,
+    coc: Vec<Option<ComponentStyle>>,
+    qcd: Option<Quantization>,
+    qcc: Vec<Option<Quantization>>,
+    pocs: Vec<Poc>,
+}
+
+impl Header {
+    fn new(components: usize) -> Self {
+        Self { cod: None, coc: vec![None; components], qcd: None, qcc: vec![None; components], pocs: Vec::new() }
+    }
+
+    fn parse(&mut self, marker: u16, segment: &[u8], components: usize) -> Result<()> {
+        let mut r = Reader { data: segment, pos: 0 };
+        let component = |r: &mut Reader| -> Result<usize> {
+            let index = if components < 257 { r.u8()? as usize } else { r.u16()? as usize };
+            if index >= components {
+                bail!("Component index out of range in JPEG 2000 codestream");
+            }
+            Ok(index)
+        };
+        match marker {
+            COD => {
+                let scod = r.u8()?;
+                let progression = r.u8()?;
+                let layers = r.u16()?;
+                let mct = r.u8()? != 0;
+                let style = ComponentStyle::parse(&mut r, scod & 1 != 0)?;
+                if progression > 4 || layers == 0 {
+                    bail!("Invalid JPEG 2000 coding style");
+                }
+                self.cod = Some(Cod { sop: scod & 2 != 0, eph: scod & 4 != 0, progression, layers, mct, style });
+            }
+            COC => {
+                let index = component(&mut r)?;
+                let scoc = r.u8()?;
+                self.coc[index] = Some(ComponentStyle::parse(&mut r, scoc & 1 != 0)?);
+            }
+            QCD => self.qcd = Some(parse_quantization(&mut r)?),
+            QCC => {
+                let index = component(&mut r)?;
+                self.qcc[index] = Some(parse_quantization(&mut r)?);
+            }
+            POC => {
+                while r.remaining() > 0 {
+                    let res_start = r.u8()? as usize;
+                    let comp_start = if components < 257 { r.u8()? as usize } else { r.u16()? as usize };
+                    let layer_end = r.u16()? as usize;
+                    let res_end = r.u8()? as usize;
+                    let comp_end = match if components < 257 { r.u8()? as usize } else { r.u16()? as usize } {
+                        0 => 256,
+                        end => end,
+                    };
+                    let order = r.u8()?;
+                    if order > 4 {
+                        bail!("Invalid JPEG 2000 progression order change");
+                    }
+                    self.pocs.push(Poc { res_start, comp_start, layer_end, res_end, comp_end, order });
+                }
+            }
+            RGN => bail!("JPEG 2000 region of interest coding is not supported"),
+            PPM | PPT => bail!("JPEG 2000 packed packet headers are not supported"),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn parse_quantization(r: &mut Reader) -> Result<Quantization> {
+    let sqcd = r.u8()?;
+    let style = sqcd & 0x1F;
+    let mut steps = Vec::new();
+    match style {
+        0 => while r.remaining() > 0 {
+            steps.push((r.u8()? >> 3, 0));
+        },
+        1 | 2 => while r.remaining() >= 2 {
+            let value = r.u16()?;
+            steps.push(((value >> 11) as u8, value & 0x7FF));
+        },
+        _ => bail!("Invalid JPEG 2000 quantization style"),
+    }
+    if steps.is_empty() {
+        bail!("JPEG 2000 quantization without step sizes");
+    }
+    Ok(Quantization { style, guard_bits: sqcd >> 5, steps })
+}
+
+fn ceil_div(a: i64, b: i64) -> i64 {
+    (a + b - 1).div_euclid(b)
+}
+
+/// Codeword segment of a code-block: its bytes and the coding passes they hold
+struct Segment {
+    data: Vec<u8>,
+    passes: u32,
+    max_passes: u32,
+}
+
+struct CodeBlock {
+    x0: i64,
+    y0: i64,
+    x1: i64,
+    y1: i64,
+    included: bool,
+    lblock: u32,
+    zero_planes: u32,
+    segments: Vec<Segment>,
+}
+
+impl CodeBlock {
+    /// Maximum number of passes of the next codeword segment
+    fn next_segment_passes(&self, cb_style: u8) -> u32 {
+        if cb_style & TERMALL != 0 {
+            1
+        } else if cb_style & BYPASS != 0 {
+            match self.segments.last() {
+                None => 10,
+                Some(last) if last.max_passes == 2 => 1,
+                Some(_) => 2,
+            }
+        } else {
+            u32::MAX
+        }
+    }
+}
+
+/// The code-blocks of one band that fall in one precinct
+struct PrecinctBand {
+    width: usize,
+    blocks: Vec<usize>,
+    inclusion: TagTree,
+    zero_planes: TagTree,
+}
+
+struct Band {
+    /// 0: LL, 1: HL, 2: LH, 3: HH
+    orientation: usize,
+    x0: i64,
+    y0: i64,
+    x1: i64,
+    y1: i64,
+    magnitude_bits: u32,
+    step: f64,
+    blocks: Vec<CodeBlock>,
+    precincts: Vec<PrecinctBand>,
+}
+
+struct Resolution {
+    x0: i64,
+    y0: i64,
+    x1: i64,
+    y1: i64,
+    ppx: u8,
+    ppy: u8,
+    precincts_wide: usize,
+    precincts_high: usize,
+    bands: Vec<Band>,
+}
+
+struct TileComponent {
+    x0: i64,
+    y0: i64,
+    x1: i64,
+    style: ComponentStyle,
+    resolutions: Vec<Resolution>,
+}
+
+struct Tile<'a> {
+    siz: &'a Siz,
+    x0: i64,
+    y0: i64,
+    cod: Cod,
+    pocs: Vec<Poc>,
+    components: Vec<TileComponent>,
+}
+
+impl<'a> Tile<'a> {
+    fn new(siz: &'a Siz, main: &Header, header: &Header, index: usize) -> Result<Self> {
+        let p = (index % siz.tiles_wide()) as i64;
+        let q = (index / siz.tiles_wide()) as i64;
+        let (tw, th) = (i64::from(siz.tile_width), i64::from(siz.tile_height));
+        let x0 = (i64::from(siz.tile_x0) + p * tw).max(siz.x0.into());
+        let y0 = (i64::from(siz.tile_y0) + q * th).max(siz.y0.into());
+        let x1 = (i64::from(siz.tile_x0) + (p + 1) * tw).min(siz.width.into());
+        let y1 = (i64::from(siz.tile_y0) + (q + 1) * th).min(siz.height.into());
+
+        let cod = header.cod.as_ref().or(main.cod.as_ref())
+            .context("JPEG 2000 codestream without COD marker")?
+            .clone();
+        let pocs = if header.pocs.is_empty() { main.pocs.clone() } else { header.pocs.clone() };
+
+        let mut components = Vec::new();
+        for (c, component) in siz.components.iter().enumerate() {
+            let style = header.coc[c].as_ref()
+                .or(header.cod.as_ref().map(|cod| &cod.style))
+                .or(main.coc[c].as_ref())
+                .unwrap_or(&cod.style)
+                .clone();
+            let quantization = header.qcc[c].as_ref()
+                .or(header.qcd.as_ref())
+                .or(main.qcc[c].as_ref())
+                .or(main.qcd.as_ref())
+                .context("JPEG 2000 codestream without QCD marker")?;
+            components.push(TileComponent::new((x0, y0, x1, y1), style, quantization, component.precision)?);
+        }
+        Ok(Self { siz, x0, y0, cod, pocs, components })
+    }
+
+    fn decode(mut self, data: &[u8], image: &mut Image) -> Result<()> {
+        self.read_packets(data)?;
+
+        let mut planes = self.components.iter()
+            .map(|component| component.reconstruct())
+            .collect::<Result<Vec<_>>>()?;
+
+        if self.cod.mct && planes.len() >= 3 {
+            if self.components[..3].iter().any(|c| c.x1 - c.x0 != self.components[0].x1 - self.components[0].x0) {
+                bail!("JPEG 2000 multiple component transform on components of different sizes");
+            }
+            inverse_component_transform(&mut planes, self.components[0].style.reversible);
+        }
+
+        for (c, plane) in planes.iter().enumerate() {
+            let component = &self.siz.components[c];
+            let tc = &self.components[c];
+            let (min, max, shift) = if component.signed {
+                (-(1i64 << (component.precision - 1)), (1i64 << (component.precision - 1)) - 1, 0)
+            } else {
+                (0, (1i64 << component.precision) - 1, 1i64 << (component.precision - 1))
+            };
+            let width = (tc.x1 - tc.x0) as usize;
+            for (row, line) in plane.chunks(width.max(1)).enumerate() {
+                let y = (tc.y0 - i64::from(self.siz.y0)) as usize + row;
+                let x = (tc.x0 - i64::from(self.siz.x0)) as usize;
+                let start = y * image.width + x;
+                for (out, value) in image.components[c][start..start + line.len()].iter_mut().zip(line) {
+                    *out = (value.round() as i64 + shift).clamp(min, max) as i32;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_packets(&mut self, data: &[u8]) -> Result<()> {
+        let layers = self.cod.layers as usize;
+        let max_resolutions = self.components.iter().map(|c| c.resolutions.len()).max().unwrap_or(0);
+        let progressions = if self.pocs.is_empty() {
+            vec![Poc {
+                res_start: 0,
+                comp_start: 0,
+                layer_end: layers,
+                res_end: max_resolutions,
+                comp_end: self.components.len(),
+                order: self.cod.progression,
+            }]
+        } else {
+            self.pocs.clone()
+        };
+
+        let mut seen = HashSet::new();
+        let mut pos = 0;
+        for poc in progressions {
+            let layer_end = poc.layer_end.min(layers);
+            let comp_end = poc.comp_end.min(self.components.len());
+            let res_end = poc.res_end.min(max_resolutions);
+            for (layer, r, c, k) in self.packet_order(&poc, layer_end, res_end, comp_end) {
+                if pos >= data.len() {
+                    return Ok(());
+                }
+                if seen.insert((layer, r, c, k)) {
+                    self.read_packet(data, &mut pos, layer, r, c, k)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Packets of one progression in codestream order, as (layer, resolution, component, precinct)
+    fn packet_order(&self, poc: &Poc, layer_end: usize, res_end: usize, comp_end: usize) -> Vec<(usize, usize, usize, usize)> {
+        let mut order = Vec::new();
+        let precincts = |r: usize, c: usize| -> usize {
+            self.components[c].resolutions.get(r).map_or(0, |res| res.precincts_wide * res.precincts_high)
+        };
+        let comps = poc.comp_start..comp_end;
+        let resolutions = poc.res_start..res_end;
+        match poc.order {
+            // LRCP
+            0 => for l in 0..layer_end {
+                for r in resolutions.clone() {
+                    for c in comps.clone() {
+                        order.extend((0..precincts(r, c)).map(|k| (l, r, c, k)));
+                    }
+                }
+            },
+            // RLCP
+            1 => for r in resolutions.clone() {
+                for l in 0..layer_end {
+                    for c in comps.clone() {
+                        order.extend((0..precincts(r, c)).map(|k| (l, r, c, k)));
+                    }
+                }
+            },
+            // RPCL, PCRL, CPRL: precincts visited in order of their position on the reference grid
+            _ => {
+                let mut positions = self.precinct_positions(poc, res_end, comp_end);
+                positions.sort_by_key(|&(y, x, r, c, _)| match poc.order {
+                    2 => (r as i64, y, x, c as i64),
+                    3 => (y, x, c as i64, r as i64),
+                    _ => (c as i64, y, x, r as i64),
+                });
+                for (_, _, r, c, k) in positions {
+                    order.extend((0..layer_end).map(|l| (l, r, c, k)));
+                }
+            }
+        }
+        order
+    }
+
+    /// Every (y, x, resolution, component, precinct) in range, with the reference grid
+    /// position at which the precinct starts
+    fn precinct_positions(&self, poc: &Poc, res_end: usize, comp_end: usize) -> Vec<(i64, i64, usize, usize, usize)> {
+        let mut positions = Vec::new();
+        for c in poc.comp_start..comp_end {
+            let tc = &self.components[c];
+            let levels = tc.style.levels;
+            for r in poc.res_start..res_end.min(tc.resolutions.len()) {
+                let res = &tc.resolutions[r];
+                if res.x1 <= res.x0 || res.y1 <= res.y0 {
+                    continue;
+                }
+                let shift = levels - r;
+                for j in 0..res.precincts_high {
+                    for i in 0..res.precincts_wide {
+                        // Precinct origin on the resolution grid, then on the tile's reference grid
+                        let px = (((res.x0 >> res.ppx) + i as i64) << res.ppx).max(res.x0);
+                        let py = (((res.y0 >> res.ppy) + j as i64) << res.ppy).max(res.y0);
+                        let x = (px << shift).max(self.x0);
+                        let y = (py << shift).max(self.y0);
+                        positions.push((y, x, r, c, i + j * res.precincts_wide));
+                    }
+                }
+            }
+        }
+        positions
+    }
+
+    fn read_packet(&mut self, data: &[u8], pos: &mut usize, layer: usize, r: usize, c: usize, k: usize) -> Result<()> {
+        let (sop, eph) = (self.cod.sop, self.cod.eph);
+        if sop && data[*pos..].starts_with(&[0xFF, 0x91]) {
+            *pos += 6;
+        }
+
+        let cb_style = self.components[c].style.cb_style;
+        let resolution = &mut self.components[c].resolutions[r];
+        let mut bits = BitReader::new(data, *pos);
+        // (band, block, segment, length) of each contribution
+        let mut contributions: Vec<(usize, usize, usize, usize)> = Vec::new();
+        if bits.bit()? == 1 {
+            for (b, band) in resolution.bands.iter_mut().enumerate() {
+                let Some(precinct) = band.precincts.get_mut(k) else {
+                    continue;
+                };
+                for (index, &block_index) in precinct.blocks.iter().enumerate() {
+                    let (bx, by) = (index % precinct.width, index / precinct.width);
+                    let block = &mut band.blocks[block_index];
+                    let included = if block.included {
+                        bits.bit()? == 1
+                    } else {
+                        precinct.inclusion.decode(&mut bits, bx, by, layer as u32 + 1)?
+                    };
+                    if !included {
+                        continue;
+                    }
+                    if !block.included {
+                        let mut threshold = 1;
+                        while !precinct.zero_planes.decode(&mut bits, bx, by, threshold)? {
+                            threshold += 1;
+                        }
+                        block.zero_planes = precinct.zero_planes.value(bx, by);
+                        block.included = true;
+                        block.lblock = 3;
+                    }
+
+                    let mut passes = read_pass_count(&mut bits)?;
+                    while bits.bit()? == 1 {
+                        block.lblock += 1;
+                    }
+                    while passes > 0 {
+                        let open = block.segments.last().is_some_and(|s| s.passes < s.max_passes);
+                        if !open {
+                            let max_passes = block.next_segment_passes(cb_style);
+                            block.segments.push(Segment { data: Vec::new(), passes: 0, max_passes });
+                        }
+                        let segment = block.segments.last_mut().unwrap();
+                        let taken = passes.min(segment.max_passes - segment.passes);
+                        segment.passes += taken;
+                        passes -= taken;
+                        let length = bits.bits(block.lblock + taken.ilog2())? as usize;
+                        contributions.push((b, block_index, block.segments.len() - 1, length));
+                    }
+                }
+            }
+        }
+        *pos = bits.align();
+        if eph && data[(*pos).min(data.len())..].starts_with(&[0xFF, 0x92]) {
+            *pos += 2;
+        }
+
+        for (b, block, segment, length) in contributions {
+            let end = *pos + length;
+            if end > data.len() {
+                bail!("Truncated JPEG 2000 packet data");
+            }
+            resolution.bands[b].blocks[block].segments[segment].data.extend_from_slice(&data[*pos..end]);
+            *pos = end;
+        }
+        Ok(())
+    }
+}
+
+impl TileComponent {
+    fn new(tile: (i64, i64, i64, i64), style: ComponentStyle, quantization: &Quantization, precision: u8) -> Result<Self> {
+        let (x0, y0, x1, y1) = tile;
+        let levels = style.levels;
+        let mut resolutions = Vec::new();
+        for r in 0..=levels {
+            let scale = 1i64 << (levels - r);
+            let (rx0, ry0, rx1, ry1) = (ceil_div(x0, scale), ceil_div(y0, scale), ceil_div(x1, scale), ceil_div(y1, scale));
+            let (ppx, ppy) = style.precincts[r];
+            let (precincts_wide, precincts_high) = if rx1 > rx0 && ry1 > ry0 {
+                (
+                    (ceil_div(rx1, 1 << ppx) - (rx0 >> ppx)) as usize,
+                    (ceil_div(ry1, 1 << ppy) - (ry0 >> ppy)) as usize,
+                )
+            } else {
+                (0, 0)
+            };
+
+            let orientations: &[usize] = if r == 0 { &[0] } else { &[1, 2, 3] };
+            let mut bands = Vec::new();
+            for &orientation in orientations {
+                let nb = if r == 0 { levels } else { levels - r + 1 };
+                let (xo, yo) = ((orientation & 1) as i64, (orientation >> 1) as i64);
+                let half = if nb == 0 { 0 } else { 1i64 << (nb - 1) };
+                let scale = 1i64 << nb;
+                let (bx0, by0) = (ceil_div(x0 - xo * half, scale), ceil_div(y0 - yo * half, scale));
+                let (bx1, by1) = (ceil_div(x1 - xo * half, scale), ceil_div(y1 - yo * half, scale));
+
+                let step_index = if r == 0 { 0 } else { 3 * (r - 1) + orientation };
+                let (exponent, mantissa) = match quantization.style {
+                    1 => {
+                        let (exponent, mantissa) = quantization.steps[0];
+                        ((i64::from(exponent) - levels as i64 + nb as i64).max(0) as u8, mantissa)
+                    }
+                    _ => *quantization.steps.get(step_index).context("Missing JPEG 2000 quantization step")?,
+                };
+                let magnitude_bits = (u32::from(quantization.guard_bits) + u32::from(exponent)).saturating_sub(1);
+                if magnitude_bits > 31 {
+                    bail!("JPEG 2000 coefficients wider than 31 bits are not supported");
+                }
+                let gain = [0, 1, 1, 2][orientation];
+                let step = 2f64.powi(i32::from(precision) + gain - i32::from(exponent)) * (1.0 + f64::from(mantissa) / 2048.0);
+
+                // Precinct and code-block partitions of the band
+                let (pbx, pby) = if r == 0 { (ppx, ppy) } else { (ppx - 1, ppy - 1) };
+                let (cbw, cbh) = (style.cb_width.min(pbx), style.cb_height.min(pby));
+                let mut blocks = Vec::new();
+                let mut precincts = Vec::new();
+                for j in 0..precincts_high {
+                    for i in 0..precincts_wide {
+                        let ox = ((rx0 >> ppx) + i as i64) << pbx;
+                        let oy = ((ry0 >> ppy) + j as i64) << pby;
+                        let (px0, py0) = (bx0.max(ox), by0.max(oy));
+                        let (px1, py1) = (bx1.min(ox + (1 << pbx)), by1.min(oy + (1 << pby)));
+                        let (width, height) = if px1 > px0 && py1 > py0 {
+                            ((ceil_div(px1, 1 << cbw) - (px0 >> cbw)) as usize, (ceil_div(py1, 1 << cbh) - (py0 >> cbh)) as usize)
+                        } else {
+                            (0, 0)
+                        };
+                        let mut indices = Vec::new();
+                        for cy in 0..height as i64 {
+                            for cx in 0..width as i64 {
+                                let gx = (px0 >> cbw) + cx;
+                                let gy = (py0 >> cbh) + cy;
+                                indices.push(blocks.len());
+                                blocks.push(CodeBlock {
+                                    x0: (gx << cbw).max(px0),
+                                    y0: (gy << cbh).max(py0),
+                                    x1: ((gx + 1) << cbw).min(px1),
+                                    y1: ((gy + 1) << cbh).min(py1),
+                                    included: false,
+                                    lblock: 3,
+                                    zero_planes: 0,
+                                    segments: Vec::new(),
+                                });
+                            }
+                        }
+                        precincts.push(PrecinctBand {
+                            width,
+                            blocks: indices,
+                            inclusion: TagTree::new(width, height),
+                            zero_planes: TagTree::new(width, height),
+                        });
+                    }
+                }
+                bands.push(Band { orientation, x0: bx0, y0: by0, x1: bx1, y1: by1, magnitude_bits, step, blocks, precincts });
+            }
+            resolutions.push(Resolution { x0: rx0, y0: ry0, x1: rx1, y1: ry1, ppx, ppy, precincts_wide, precincts_high, bands });
+        }
+        Ok(Self { x0, y0, x1, style, resolutions })
+    }
+
+    /// Decode the code-blocks, dequantize and apply the inverse wavelet transform
+    fn reconstruct(&self) -> Result<Vec<f64>> {
+        let reversible = self.style.reversible;
+        let mut bands: Vec<Vec<Vec<f64>>> = Vec::new();
+        for resolution in &self.resolutions {
+            let mut decoded = Vec::new();
+            for band in &resolution.bands {
+                let width = (band.x1 - band.x0).max(0) as usize;
+                let height = (band.y1 - band.y0).max(0) as usize;
+                let mut coefficients = vec![0.0; width * height];
+                for block in band.blocks.iter().filter(|b| b.included) {
+                    let values = decode_block(block, band, self.style.cb_style, reversible)?;
+                    let block_width = (block.x1 - block.x0) as usize;
+                    for (row, line) in values.chunks(block_width).enumerate() {
+                        let y = (block.y0 - band.y0) as usize + row;
+                        let x = (block.x0 - band.x0) as usize;
+                        coefficients[y * width + x..y * width + x + line.len()].copy_from_slice(line);
+                    }
+                }
+                decoded.push(coefficients);
+            }
+            bands.push(decoded);
+        }
+
+        // Resolution 0 is the LL band; each further level interleaves and synthesizes
+        let mut current = std::mem::take(&mut bands[0][0]);
+        for (r, resolution) in self.resolutions.iter().enumerate().skip(1) {
+            let (x0, y0) = (resolution.x0, resolution.y0);
+            let width = (resolution.x1 - x0).max(0) as usize;
+            let height = (resolution.y1 - y0).max(0) as usize;
+            let previous = &self.resolutions[r - 1];
+            let low_width = (previous.x1 - previous.x0).max(0) as usize;
+            let high_width = (resolution.bands[0].x1 - resolution.bands[0].x0).max(0) as usize;
+            let mut image = vec![0.0; width * height];
+            for y in 0..height {
+                let ay = y0 + y as i64;
+                let (low_row, row) = if ay % 2 == 0 {
+                    (true, (ay / 2 - ceil_div(y0, 2)) as usize)
+                } else {
+                    (false, (ay.div_euclid(2) - y0.div_euclid(2)) as usize)
+                };
+                for x in 0..width {
+                    let ax = x0 + x as i64;
+                    let value = if ax % 2 == 0 {
+                        let column = (ax / 2 - ceil_div(x0, 2)) as usize;
+                        if low_row { current[row * low_width + column] } else { bands[r][1][row * low_width + column] }
+                    } else {
+                        let column = (ax.div_euclid(2) - x0.div_euclid(2)) as usize;
+                        if low_row { bands[r][0][row * high_width + column] } else { bands[r][2][row * high_width + column] }
+                    };
+                    image[y * width + x] = value;
+                }
+            }
+
+            let mut line = Vec::with_capacity(width.max(height));
+            for y in 0..height {
+                line.clear();
+                line.extend_from_slice(&image[y * width..(y + 1) * width]);
+                synthesize(&mut line, x0, reversible);
+                image[y * width..(y + 1) * width].copy_from_slice(&line);
+            }
+            for x in 0..width {
+                line.clear();
+                line.extend((0..height).map(|y| image[y * width + x]));
+                synthesize(&mut line, y0, reversible);
+                for (y, value) in line.iter().enumerate() {
+                    image[y * width + x] = *value;
+                }
+            }
+            current = image;
+        }
+        Ok(current)
+    }
+}
+
+/// One-dimensional inverse wavelet transform of a line starting at grid coordinate `start`
+fn synthesize(line: &mut [f64], start: i64, reversible: bool) {
+    let n = line.len();
+    let even_first = start % 2 == 0;
+    if n == 1 {
+        if !even_first {
+            line[0] = if reversible { (line[0] / 2.0).trunc() } else { line[0] / 2.0 };
+        }
+        return;
+    }
+    // Positions holding low-pass (even grid index) or high-pass samples
+    let low = if even_first { 0 } else { 1 };
+    let high = 1 - low;
+    let lift = |line: &mut [f64], first: usize, update: &dyn Fn(f64, f64) -> f64| {
+        let mirror = |i: isize| -> usize {
+            let n = n as isize;
+            (if i < 0 { -i } else if i >= n { 2 * (n - 1) - i } else { i }) as usize
+        };
+        let mut j = first;
+        while j < n {
+            let (left, right) = (line[mirror(j as isize - 1)], line[mirror(j as isize + 1)]);
+            line[j] = update(line[j], left + right);
+            j += 2;
+        }
+    };
+    if reversible {
+        lift(line, low, &|x, sum| x - ((sum + 2.0) / 4.0).floor());
+        lift(line, high, &|x, sum| x + (sum / 2.0).floor());
+    } else {
+        const K: f64 = 1.230_174_104_914_001;
+        const ALPHA: f64 = -1.586_134_342_059_924;
+        const BETA: f64 = -0.052_980_118_572_961;
+        const GAMMA: f64 = 0.882_911_075_530_934;
+        const DELTA: f64 = 0.443_506_852_043_971;
+        for (j, value) in line.iter_mut().enumerate() {
+            if j % 2 == low { *value *= K } else { *value /= K }
+        }
+        lift(line, low, &|x, sum| x - DELTA * sum);
+        lift(line, high, &|x, sum| x - GAMMA * sum);
+        lift(line, low, &|x, sum| x - BETA * sum);
+        lift(line, high, &|x, sum| x - ALPHA * sum);
+    }
+}
+
+/// Inverse RCT (reversible) or ICT of the first three components, in place
+fn inverse_component_transform(planes: &mut [Vec<f64>], reversible: bool) {
+    let (first, rest) = planes.split_at_mut(1);
+    let (second, third) = rest.split_at_mut(1);
+    for ((y0, y1), y2) in first[0].iter_mut().zip(second[0].iter_mut()).zip(third[0].iter_mut()) {
+        let (r, g, b) = if reversible {
+            let g = *y0 - ((*y2 + *y1) / 4.0).floor();
+            (*y2 + g, g, *y1 + g)
+        } else {
+            (*y0 + 1.402 * *y2, *y0 - 0.344_13 * *y1 - 0.714_14 * *y2, *y0 + 1.772 * *y1)
+        };
+        (*y0, *y1, *y2) = (r, g, b);
+    }
+}
+
+/// Number of new coding passes (Table B.4)
+fn read_pass_count(bits: &mut BitReader) -> Result<u32> {
+    if bits.bit()? == 0 {
+        return Ok(1);
+    }
+    if bits.bit()? == 0 {
+        return Ok(2);
+    }
+    let value = bits.bits(2)?;
+    if value != 3 {
+        return Ok(3 + value);
+    }
+    let value = bits.bits(5)?;
+    if value != 31 {
+        return Ok(6 + value);
+    }
+    Ok(37 + bits.bits(7)?)
+}
+
+/// Packet header bits, with a stuffed zero bit after every 0xFF byte
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    byte: u8,
+    left: u8,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Self { data, pos, byte: 0, left: 0 }
+    }
+
+    fn bit(&mut self) -> Result<u32> {
+        if self.left == 0 {
+            let stuffed = self.byte == 0xFF;
+            self.byte = *self.data.get(self.pos).context("Truncated JPEG 2000 packet header")?;
+            self.pos += 1;
+            self.left = if stuffed { 7 } else { 8 };
+        }
+        self.left -= 1;
+        Ok(u32::from(self.byte >> self.left) & 1)
+    }
+
+    fn bits(&mut self, count: u32) -> Result<u32> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = value << 1 | self.bit()?;
+        }
+        Ok(value)
+    }
+
+    /// Byte position after the header, including the byte stuffed after a final 0xFF
+    fn align(&mut self) -> usize {
+        if self.byte == 0xFF {
+            self.pos += 1;
+        }
+        self.pos
+    }
+}
+
+struct TagNode {
+    value: u32,
+    low: u32,
+    parent: Option<usize>,
+}
+
+/// Tag tree of Annex B.10.2, coding a value per code-block of a precinct band
+struct TagTree {
+    width: usize,
+    nodes: Vec<TagNode>,
+}
+
+impl TagTree {
+    fn new(width: usize, height: usize) -> Self {
+        let mut levels = vec![(width, height)];
+        while let Some(&(w, h)) = levels.last() {
+            if w <= 1 && h <= 1 {
+                break;
+            }
+            levels.push((w.div_ceil(2), h.div_ceil(2)));
+        }
+        let mut offsets = Vec::new();
+        let mut total = 0;
+        for &(w, h) in &levels {
+            offsets.push(total);
+            total += w * h;
+        }
+        let mut nodes = Vec::with_capacity(total);
+        for (level, &(w, h)) in levels.iter().enumerate() {
+            for y in 0..h {
+                for x in 0..w {
+                    let parent = levels.get(level + 1).map(|&(pw, _)| offsets[level + 1] + (y / 2) * pw + x / 2);
+                    nodes.push(TagNode { value: u32::MAX, low: 0, parent });
+                }
+            }
+        }
+        Self { width, nodes }
+    }
+
+    fn value(&self, x: usize, y: usize) -> u32 {
+        self.nodes[y * self.width + x].value
+    }
+
+    /// Whether the leaf's value is below `threshold`, reading as many bits as needed
+    fn decode(&mut self, bits: &mut BitReader, x: usize, y: usize, threshold: u32) -> Result<bool> {
+        let leaf = y * self.width + x;
+        let mut path = vec![leaf];
+        while let Some(parent) = self.nodes[*path.last().unwrap()].parent {
+            path.push(parent);
+        }
+        let mut low = 0;
+        for &index in path.iter().rev() {
+            let node = &mut self.nodes[index];
+            if low > node.low {
+                node.low = low;
+            } else {
+                low = node.low;
+            }
+            while low < threshold && low < node.value {
+                if bits.bit()? == 1 {
+                    node.value = low;
+                } else {
+                    low += 1;
+                }
+            }
+            node.low = low;
+        }
+        Ok(self.nodes[leaf].value < threshold)
+    }
+}
+
+/// MQ coder probability states (Table C.2): Qe, next index after MPS, after LPS, switch
+const MQ_STATES: [(u32, u8, u8, bool); 47] = [
+    (0x5601, 1, 1, true), (0x3401, 2, 6, false), (0x1801, 3, 9, false), (0x0AC1, 4, 12, false),
+    (0x0521, 5, 29, false), (0x0221, 38, 33, false), (0x5601, 7, 6, true), (0x5401, 8, 14, false),
+    (0x4801, 9, 14, false), (0x3801, 10, 14, false), (0x3001, 11, 17, false), (0x2401, 12, 18, false),
+    (0x1C01, 13, 20, false), (0x1601, 29, 21, false), (0x5601, 15, 14, true), (0x5401, 16, 14, false),
+    (0x5101, 17, 15, false), (0x4801, 18, 16, false), (0x3801, 19, 17, false), (0x3401, 20, 18, false),
+    (0x3001, 21, 19, false), (0x2801, 22, 19, false), (0x2401, 23, 20, false), (0x2201, 24, 21, false),
+    (0x1C01, 25, 22, false), (0x1801, 26, 23, false), (0x1601, 27, 24, false), (0x1401, 28, 25, false),
+    (0x1201, 29, 26, false), (0x1101, 30, 27, false), (0x0AC1, 31, 28, false), (0x09C1, 32, 29, false),
+    (0x08A1, 33, 30, false), (0x0521, 34, 31, false), (0x0441, 35, 32, false), (0x02A1, 36, 33, false),
+    (0x0221, 37, 34, false), (0x0141, 38, 35, false), (0x0111, 39, 36, false), (0x0085, 40, 37, false),
+    (0x0049, 41, 38, false), (0x0025, 42, 39, false), (0x0015, 43, 40, false), (0x0009, 44, 41, false),
+    (0x0005, 45, 42, false), (0x0001, 45, 43, false), (0x5601, 46, 46, false),
+];
+
+const RUN_CONTEXT: usize = 17;
+const UNIFORM_CONTEXT: usize = 18;
+
+/// MQ arithmetic decoder (Annex C)
+struct MqDecoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    a: u32,
+    c: u32,
+    ct: u32,
+}
+
+impl<'a> MqDecoder<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        let mut mq = Self { data, pos: 0, a: 0x8000, c: 0, ct: 0 };
+        mq.c = mq.byte(0) << 16;
+        mq.byte_in();
+        mq.c <<= 7;
+        mq.ct -= 7;
+        mq
+    }
+
+    /// Bytes past the end read as 0xFF
+    fn byte(&self, index: usize) -> u32 {
+        u32::from(self.data.get(index).copied().unwrap_or(0xFF))
+    }
+
+    fn byte_in(&mut self) {
+        if self.byte(self.pos) == 0xFF {
+            if self.byte(self.pos + 1) > 0x8F {
+                self.c += 0xFF00;
+                self.ct = 8;
+            } else {
+                self.pos += 1;
+                self.c += self.byte(self.pos) << 9;
+                self.ct = 7;
+            }
+        } else {
+            self.pos += 1;
+            self.c += self.byte(self.pos) << 8;
+            self.ct = 8;
+        }
+    }
+
+    fn decode(&mut self, contexts: &mut [(u8, u8); 19], cx: usize) -> u32 {
+        let (index, mps) = contexts[cx];
+        let (qe, nmps, nlps, switch) = MQ_STATES[index as usize];
+        self.a -= qe;
+        let d;
+        if (self.c >> 16) < qe {
+            // LPS exchange
+            if self.a < qe {
+                d = mps;
+                contexts[cx].0 = nmps;
+            } else {
+                d = 1 - mps;
+                contexts[cx] = (nlps, if switch { 1 - mps } else { mps });
+            }
+            self.a = qe;
+        } else {
+            self.c -= qe << 16;
+            if self.a & 0x8000 != 0 {
+                return u32::from(mps);
+            }
+            // MPS exchange
+            if self.a < qe {
+                d = 1 - mps;
+                contexts[cx] = (nlps, if switch { 1 - mps } else { mps });
+            } else {
+                d = mps;
+                contexts[cx].0 = nmps;
+            }
+        }
+        while self.a & 0x8000 == 0 {
+            if self.ct == 0 {
+                self.byte_in();
+            }
+            self.a <<= 1;
+            self.c <<= 1;
+            self.ct -= 1;
+        }
+        u32::from(d)
+    }
+}
+
+/// Raw (bypass) bits of the selective arithmetic coding bypass mode
+struct RawDecoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    c: u32,
+    ct: u32,
+}
+
+impl RawDecoder<'_> {
+    fn bit(&mut self) -> u32 {
+        if self.ct == 0 {
+            let next = u32::from(self.data.get(self.pos).copied().unwrap_or(0xFF));
+            if self.c == 0xFF {
+                if next > 0x8F {
+                    self.c = 0xFF;
+                    self.ct = 8;
+                } else {
+                    self.c = next;
+                    self.pos += 1;
+                    self.ct = 7;
+                }
+            } else {
+                self.c = next;
+                self.pos += 1;
+                self.ct = 8;
+            }
+        }
+        self.ct -= 1;
+        (self.c >> self.ct) & 1
+    }
+}
+
+enum Coder<'a> {
+    Mq(MqDecoder<'a>),
+    Raw(RawDecoder<'a>),
+}
+
+impl Coder<'_> {
+    fn decode(&mut self, contexts: &mut [(u8, u8); 19], cx: usize) -> u32 {
+        match self {
+            Coder::Mq(mq) => mq.decode(contexts, cx),
+            Coder::Raw(raw) => raw.bit(),
+        }
+    }
+}
+
+const SIGNIFICANT: u8 = 1;
+const VISITED: u8 = 2;
+const REFINED: u8 = 4;
+const NEGATIVE: u8 = 8;
+
+fn initial_contexts() -> [(u8, u8); 19] {
+    let mut contexts = [(0, 0); 19];
+    contexts[0] = (4, 0);
+    contexts[RUN_CONTEXT] = (3, 0);
+    contexts[UNIFORM_CONTEXT] = (46, 0);
+    contexts
+}
+
+/// Tier-1 state of one code-block
+struct BlockDecoder {
+    width: usize,
+    height: usize,
+    stride: usize,
+    /// Flags with a one-sample border
+    flags: Vec<u8>,
+    magnitudes: Vec<u32>,
+    /// Bit plane of the last coding step of each sample
+    planes: Vec<u8>,
+    orientation: usize,
+    causal: bool,
+    contexts: [(u8, u8); 19],
+}
+
+impl BlockDecoder {
+    fn index(&self, x: usize, y: usize) -> usize {
+        (y + 1) * self.stride + x + 1
+    }
+
+    fn is(&self, i: usize, flag: u8) -> u32 {
+        u32::from(self.flags[i] & flag != 0)
+    }
+
+    /// Significant horizontal, vertical and diagonal neighbours
+    fn neighbours(&self, x: usize, y: usize) -> (u32, u32, u32) {
+        let i = self.index(x, y);
+        let s = self.stride;
+        let below = !(self.causal && y % 4 == 3);
+        let h = self.is(i - 1, SIGNIFICANT) + self.is(i + 1, SIGNIFICANT);
+        let mut v = self.is(i - s, SIGNIFICANT);
+        let mut d = self.is(i - s - 1, SIGNIFICANT) + self.is(i - s + 1, SIGNIFICANT);
+        if below {
+            v += self.is(i + s, SIGNIFICANT);
+            d += self.is(i + s - 1, SIGNIFICANT) + self.is(i + s + 1, SIGNIFICANT);
+        }
+        (h, v, d)
+    }
+
+    /// Zero coding context (Table D.1)
+    fn zero_context(&self, x: usize, y: usize) -> usize {
+        let (mut h, mut v, d) = self.neighbours(x, y);
+        match self.orientation {
+            3 => {
+                let hv = h + v;
+                match d {
+                    0 => [0, 1, 2][hv.min(2) as usize],
+                    1 => [3, 4, 5][hv.min(2) as usize],
+                    2 => if hv == 0 { 6 } else { 7 },
+                    _ => 8,
+                }
+            }
+            orientation => {
+                if orientation == 1 {
+                    std::mem::swap(&mut h, &mut v);
+                }
+                match (h, v, d) {
+                    (2, _, _) => 8,
+                    (1, v, _) if v >= 1 => 7,
+                    (1, 0, d) if d >= 1 => 6,
+                    (1, _, _) => 5,
+                    (0, 2, _) => 4,
+                    (0, 1, _) => 3,
+                    (0, 0, d) if d >= 2 => 2,
+                    (0, 0, 1) => 1,
+                    _ => 0,
+                }
+            }
+        }
+    }
+
+    fn decode_sign(&mut self, coder: &mut Coder, x: usize, y: usize) -> bool {
+        let i = self.index(x, y);
+        let s = self.stride;
+        let contribution = |j: usize| -> i32 {
+            if self.flags[j] & SIGNIFICANT == 0 { 0 } else if self.flags[j] & NEGATIVE != 0 { -1 } else { 1 }
+        };
+        let below = !(self.causal && y % 4 == 3);
+        let h = (contribution(i - 1) + contribution(i + 1)).clamp(-1, 1);
+        let v = (contribution(i - s) + if below { contribution(i + s) } else { 0 }).clamp(-1, 1);
+        let (context, xor) = match (h, v) {
+            (1, 1) => (13, 0),
+            (1, 0) => (12, 0),
+            (1, _) => (11, 0),
+            (0, 1) => (10, 0),
+            (0, 0) => (9, 0),
+            (0, _) => (10, 1),
+            (_, 1) => (11, 1),
+            (_, 0) => (12, 1),
+            _ => (13, 1),
+        };
+        match coder {
+            Coder::Raw(raw) => raw.bit() == 1,
+            Coder::Mq(mq) => mq.decode(&mut self.contexts, context) ^ xor == 1,
+        }
+    }
+
+    fn become_significant(&mut self, coder: &mut Coder, x: usize, y: usize, plane: u8) {
+        let negative = self.decode_sign(coder, x, y);
+        let i = self.index(x, y);
+        self.flags[i] |= SIGNIFICANT | if negative { NEGATIVE } else { 0 };
+        self.magnitudes[y * self.width + x] = 1 << plane;
+        self.planes[y * self.width + x] = plane;
+    }
+
+    fn significance_pass(&mut self, coder: &mut Coder, plane: u8) {
+        for stripe in (0..self.height).step_by(4) {
+            for x in 0..self.width {
+                for y in stripe..(stripe + 4).min(self.height) {
+                    let i = self.index(x, y);
+                    if self.flags[i] & SIGNIFICANT != 0 {
+                        continue;
+                    }
+                    let context = self.zero_context(x, y);
+                    if context == 0 {
+                        continue;
+                    }
+                    self.flags[i] |= VISITED;
+                    if coder.decode(&mut self.contexts, context) == 1 {
+                        self.become_significant(coder, x, y, plane);
+                    }
+                }
+            }
+        }
+    }
+
+    fn refinement_pass(&mut self, coder: &mut Coder, plane: u8) {
+        for stripe in (0..self.height).step_by(4) {
+            for x in 0..self.width {
+                for y in stripe..(stripe + 4).min(self.height) {
+                    let i = self.index(x, y);
+                    if self.flags[i] & (SIGNIFICANT | VISITED) != SIGNIFICANT {
+                        continue;
+                    }
+                    let context = if self.flags[i] & REFINED != 0 {
+                        16
+                    } else {
+                        let (h, v, d) = self.neighbours(x, y);
+                        if h + v + d > 0 { 15 } else { 14 }
+                    };
+                    let bit = coder.decode(&mut self.contexts, context);
+                    self.magnitudes[y * self.width + x] |= bit << plane;
+                    self.planes[y * self.width + x] = plane;
+                    self.flags[i] |= REFINED;
+                }
+            }
+        }
+    }
+
+    fn cleanup_pass(&mut self, coder: &mut Coder, plane: u8) {
+        for stripe in (0..self.height).step_by(4) {
+            for x in 0..self.width {
+                let mut start = stripe;
+                let end = (stripe + 4).min(self.height);
+                let run = end - stripe == 4 && (stripe..end).all(|y| {
+                    self.flags[self.index(x, y)] & (SIGNIFICANT | VISITED) == 0 && self.zero_context(x, y) == 0
+                });
+                if run {
+                    if coder.decode(&mut self.contexts, RUN_CONTEXT) == 0 {
+                        continue;
+                    }
+                    let offset = coder.decode(&mut self.contexts, UNIFORM_CONTEXT) << 1
+                        | coder.decode(&mut self.contexts, UNIFORM_CONTEXT);
+                    let y = stripe + offset as usize;
+                    self.become_significant(coder, x, y, plane);
+                    start = y + 1;
+                }
+                for y in start..end {
+                    let i = self.index(x, y);
+                    if self.flags[i] & (SIGNIFICANT | VISITED) != 0 {
+                        continue;
+                    }
+                    let context = self.zero_context(x, y);
+                    if coder.decode(&mut self.contexts, context) == 1 {
+                        self.become_significant(coder, x, y, plane);
+                    }
+                }
+            }
+        }
+        for flag in &mut self.flags {
+            *flag &= !VISITED;
+        }
+    }
+}
+
+/// Decode one code-block to dequantized coefficients, row by row
+fn decode_block(block: &CodeBlock, band: &Band, cb_style: u8, reversible: bool) -> Result<Vec<f64>> {
+    let width = (block.x1 - block.x0) as usize;
+    let height = (block.y1 - block.y0) as usize;
+    let mut decoder = BlockDecoder {
+        width,
+        height,
+        stride: width + 2,
+        flags: vec![0; (width + 2) * (height + 2)],
+        magnitudes: vec![0; width * height],
+        planes: vec![0; width * height],
+        orientation: band.orientation,
+        causal: cb_style & VERTICALLY_CAUSAL != 0,
+        contexts: initial_contexts(),
+    };
+
+    let total_passes: u32 = block.segments.iter().map(|s| s.passes).sum();
+    let mut plane = band.magnitude_bits as i64 - 1 - i64::from(block.zero_planes);
+    if total_passes > 0 && plane < 0 {
+        bail!("Corrupt JPEG 2000 code-block: more zero bit planes than magnitude bits");
+    }
+
+    // Passes cycle cleanup, significance propagation, magnitude refinement
+    let mut kind = 2;
+    let mut pass = 0;
+    'segments: for segment in &block.segments {
+        let raw = cb_style & BYPASS != 0 && pass >= 10 && kind != 2;
+        let mut coder = if raw {
+            Coder::Raw(RawDecoder { data: &segment.data, pos: 0, c: 0, ct: 0 })
+        } else {
+            Coder::Mq(MqDecoder::new(&segment.data))
+        };
+        for _ in 0..segment.passes {
+            if plane < 0 {
+                break 'segments;
+            }
+            match kind {
+                0 => decoder.significance_pass(&mut coder, plane as u8),
+                1 => decoder.refinement_pass(&mut coder, plane as u8),
+                _ => {
+                    decoder.cleanup_pass(&mut coder, plane as u8);
+                    if cb_style & SEGMENTATION_SYMBOLS != 0 {
+                        for _ in 0..4 {
+                            coder.decode(&mut decoder.contexts, UNIFORM_CONTEXT);
+                        }
+                    }
+                }
+            }
+            if cb_style & RESET != 0 {
+                decoder.contexts = initial_contexts();
+            }
+            kind = (kind + 1) % 3;
+            if kind == 0 {
+                plane -= 1;
+            }
+            pass += 1;
+        }
+    }
+
+    let mut values = vec![0.0; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let magnitude = decoder.magnitudes[y * width + x];
+            if magnitude == 0 {
+                continue;
+            }
+            let plane = decoder.planes[y * width + x];
+            // Reconstruct at the middle of the interval left by the undecoded bit planes
+            let value = if reversible {
+                f64::from(magnitude) + if plane > 0 { f64::from(1u32 << (plane - 1)) } else { 0.0 }
+            } else {
+                (f64::from(magnitude) + f64::from(1u32 << plane) / 2.0) * band.step
+            };
+            let negative = decoder.flags[decoder.index(x, y)] & NEGATIVE != 0;
+            values[y * width + x] = if negative { -value } else { value };
+        }
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicom_dictionary_std::tags;
+    use dicom_object::OpenFileOptions;
+
+    /// First Pixel Data fragment of a file under data/
+    fn fixture(name: &str) -> Vec<u8> {
+        let obj = OpenFileOptions::new().open_file(format!("{}/data/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap();
+        obj.element(tags::PIXEL_DATA).unwrap().value().fragments().unwrap()[0].clone()
+    }
+
+    #[test]
+    fn lossless_decode_matches_reference_statistics() {
+        let image = decode(&fixture("693_J2KR.dcm")).unwrap();
+        assert_eq!((image.width, image.height, image.components.len(), image.signed), (512, 512, 1, true));
+        let samples = &image.components[0];
+        assert_eq!(samples.iter().min(), Some(&-2000));
+        assert_eq!(samples.iter().max(), Some(&2492));
+        assert_eq!(samples.iter().map(|&value| i64::from(value)).sum::<i64>(), -3031175);
+        // Padding outside the circular field of view
+        assert_eq!(samples.iter().filter(|&&value| value == -2000).count(), 55772);
+    }
+
+    #[test]
+    fn lossy_decode_stays_close_to_lossless() {
+        // The same slice at about 340:1 (0.04 bits per pixel). At that rate the flat padding
+        // around the field of view comes back scattered around -2000 and sharp edges ring, so
+        // the minimum and the exact padding count differ; the image itself must not.
+        let lossless = decode(&fixture("693_J2KR.dcm")).unwrap().components.remove(0);
+        let lossy = decode(&fixture("693_J2KI.dcm")).unwrap().components.remove(0);
+        assert_eq!(lossy.len(), lossless.len());
+
+        let squared: f64 = lossless.iter().zip(&lossy).map(|(&a, &b)| f64::from(a - b).powi(2)).sum();
+        assert!((squared / lossless.len() as f64).sqrt() < 130.0);
+        for block in 0..256 {
+            let (bx, by) = (block % 16 * 32, block / 16 * 32);
+            let difference: i32 = (0..32 * 32).map(|i| (by + i / 32) * 512 + bx + i % 32).map(|p| lossless[p] - lossy[p]).sum();
+            assert!(difference.abs() / 1024 < 80, "block {} differs by {} on average", block, difference / 1024);
+        }
+        let padding: Vec<i32> = lossless.iter().zip(&lossy).filter(|(a, _)| **a == -2000).map(|(_, &b)| b).collect();
+        let mean = padding.iter().map(|&value| f64::from(value)).sum::<f64>() / padding.len() as f64;
+        assert!((mean + 2000.0).abs() < 20.0);
+        assert!(padding.iter().filter(|&&value| (value + 2000).abs() <= 100).count() * 5 > padding.len() * 4);
+    }
+
+    #[test]
+    fn truncated_codestreams_are_rejected() {
+        // The fragment ends with EOC and a padding byte
+        let data = fixture("693_J2KI.dcm");
+        assert!(decode(&data[..data.len() - 1]).is_ok());
+        for cut in 0..data.len() - 2 {
+            assert!(decode(&data[..cut]).is_err(), "decoded {} of {} bytes", cut, data.len());
+        }
+    }
+
+    #[test]
+    fn corrupt_codestreams_do_not_panic() {
+        let data = fixture("693_J2KI.dcm");
+        for pos in (0..data.len()).step_by(29) {
+            for flip in [0xFF, 0x01, 0x80] {
+                let mut corrupt = data.clone();
+                corrupt[pos] ^= flip;
+                let _ = decode(&corrupt);
+            }
+        }
+    }
+}
@@ -0,0 +1,500 @@
+//! JPEG-LS (ITU-T T.87) decoder for the JPEG-LS transfer syntaxes: lossless and
+//! near-lossless scans, with components in separate scans or line interleaved.
+
+use anyhow::{Context, Result, bail};
+
+const SOI: u8 = 0xD8;
+const EOI: u8 = 0xD9;
+const SOF55: u8 = 0xF7;
+const LSE: u8 = 0xF8;
+const SOS: u8 = 0xDA;
+const DRI: u8 = 0xDD;
+
+const TRUNCATED: &str = "Truncated JPEG-LS data";
+
+/// Golomb code order increase per run length index (Table A.1 `J`)
+const RUN_ORDER: [u32; 32] = [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+/// Decoded image, samples pixel-interleaved
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub components: usize,
+    pub precision: u8,
+    pub samples: Vec<u32>,
+}
+
+/// Preset coding parameters (LSE id 1); zero means the default
+#[derive(Default, Clone, Copy)]
+struct Presets {
+    maxval: i32,
+    t1: i32,
+    t2: i32,
+    t3: i32,
+    reset: i32,
+}
+
+pub fn decode(data: &[u8]) -> Result<Image> {
+    if !data.starts_with(&[0xFF, SOI]) {
+        bail!("Not a JPEG-LS stream");
+    }
+    let mut pos = 2;
+    let mut image: Option<Image> = None;
+    let mut component_ids = Vec::new();
+    let mut presets = Presets::default();
+    loop {
+        // Markers may be preceded by fill bytes
+        while data.get(pos) == Some(&0xFF) && data.get(pos + 1) == Some(&0xFF) {
+            pos += 1;
+        }
+        if data.get(pos) != Some(&0xFF) {
+            bail!("Expected a marker in JPEG-LS stream");
+        }
+        let marker = *data.get(pos + 1).context(TRUNCATED)?;
+        pos += 2;
+        if marker == EOI {
+            break;
+        }
+        let length = u16::from_be_bytes(data.get(pos..pos + 2).context(TRUNCATED)?.try_into().unwrap()) as usize;
+        let segment = data.get(pos + 2..pos + length).context(TRUNCATED)?;
+        pos += length;
+        match marker {
+            SOF55 => {
+                if segment.len() < 6 {
+                    bail!("Invalid JPEG-LS frame header");
+                }
+                let precision = segment[0];
+                let height = u16::from_be_bytes([segment[1], segment[2]]) as usize;
+                let width = u16::from_be_bytes([segment[3], segment[4]]) as usize;
+                let count = segment[5] as usize;
+                if !(2..=16).contains(&precision) || width == 0 || height == 0 || count == 0 || segment.len() < 6 + 3 * count {
+                    bail!("Invalid JPEG-LS frame header");
+                }
+                for c in 0..count {
+                    component_ids.push(segment[6 + 3 * c]);
+                    if segment[7 + 3 * c] != 0x11 {
+                        bail!("Subsampled JPEG-LS components are not supported");
+                    }
+                }
+                image = Some(Image { width, height, components: count, precision, samples: vec![0; width * height * count] });
+            }
+            LSE => match segment.first() {
+                Some(1) if segment.len() >= 11 => {
+                    let value = |i: usize| i32::from(u16::from_be_bytes([segment[i], segment[i + 1]]));
+                    presets = Presets { maxval: value(1), t1: value(3), t2: value(5), t3: value(7), reset: value(9) };
+                }
+                _ => bail!("JPEG-LS mapping tables are not supported"),
+            },
+            DRI if segment.len() >= 2 && u16::from_be_bytes([segment[0], segment[1]]) != 0 => {
+                bail!("JPEG-LS restart intervals are not supported");
+            }
+            SOS => {
+                let image = image.as_mut().context("JPEG-LS scan before the frame header")?;
+                let count = *segment.first().context("Invalid JPEG-LS scan header")? as usize;
+                if segment.len() < 4 + 2 * count {
+                    bail!("Invalid JPEG-LS scan header");
+                }
+                let components = (0..count)
+                    .map(|i| {
+                        if segment[2 + 2 * i] != 0 {
+                            bail!("JPEG-LS mapping tables are not supported");
+                        }
+                        component_ids.iter().position(|&id| id == segment[1 + 2 * i])
+                            .context("JPEG-LS scan of an unknown component")
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let near = i32::from(segment[1 + 2 * count]);
+                let interleave = segment[2 + 2 * count];
+                let point_transform = u32::from(segment[3 + 2 * count] & 0x0F);
+                if interleave == 2 {
+                    bail!("Sample-interleaved JPEG-LS is not supported");
+                }
+                if interleave == 0 && count != 1 {
+                    bail!("Invalid JPEG-LS scan header");
+                }
+                let params = Params::new(image.precision, near, presets);
+                pos = decode_scan(data, pos, image, &components, &params, point_transform)?;
+            }
+            _ => {}
+        }
+    }
+    image.context("JPEG-LS stream without a frame header")
+}
+
+struct Params {
+    maxval: i32,
+    near: i32,
+    range: i32,
+    qbpp: u32,
+    limit: u32,
+    t1: i32,
+    t2: i32,
+    t3: i32,
+    reset: i32,
+}
+
+impl Params {
+    fn new(precision: u8, near: i32, presets: Presets) -> Self {
+        let maxval = if presets.maxval > 0 { presets.maxval } else { (1 << precision) - 1 };
+        let range = (maxval + 2 * near) / (2 * near + 1) + 1;
+        let qbpp = bits_for(range);
+        let bpp = bits_for(maxval + 1).max(2);
+        let limit = 2 * (bpp + bpp.max(8));
+
+        // Default thresholds (C.2.4.1.1)
+        let clamp = |value: i32, low: i32| if value > maxval || value < low { low } else { value };
+        let (t1, t2, t3) = if maxval >= 128 {
+            let factor = (maxval.min(4095) + 128) / 256;
+            let t1 = clamp(factor + 2 + 3 * near, near + 1);
+            let t2 = clamp(factor * (7 - 3) + 3 + 5 * near, t1);
+            (t1, t2, clamp(factor * (21 - 4) + 4 + 7 * near, t2))
+        } else {
+            let factor = 256 / (maxval + 1);
+            let t1 = clamp((3 / factor).max(2) + 3 * near, near + 1);
+            let t2 = clamp((7 / factor).max(3) + 5 * near, t1);
+            (t1, t2, clamp((21 / factor).max(4) + 7 * near, t2))
+        };
+        Self {
+            maxval,
+            near,
+            range,
+            qbpp,
+            limit,
+            t1: if presets.t1 > 0 { presets.t1 } else { t1 },
+            t2: if presets.t2 > 0 { presets.t2 } else { t2 },
+            t3: if presets.t3 > 0 { presets.t3 } else { t3 },
+            reset: if presets.reset > 0 { presets.reset } else { 64 },
+        }
+    }
+
+    fn quantize_gradient(&self, d: i32) -> i32 {
+        if d <= -self.t3 { -4 }
+        else if d <= -self.t2 { -3 }
+        else if d <= -self.t1 { -2 }
+        else if d < -self.near { -1 }
+        else if d <= self.near { 0 }
+        else if d < self.t1 { 1 }
+        else if d < self.t2 { 2 }
+        else if d < self.t3 { 3 }
+        else { 4 }
+    }
+
+    /// Undo the modulo reduction and clamp to the sample range
+    fn reconstruct(&self, prediction: i32, error: i32) -> i32 {
+        let mut value = prediction + error * (2 * self.near + 1);
+        if value < -self.near {
+            value += self.range * (2 * self.near + 1);
+        } else if value > self.maxval + self.near {
+            value -= self.range * (2 * self.near + 1);
+        }
+        value.clamp(0, self.maxval)
+    }
+}
+
+/// Number of bits needed for values below `value`
+fn bits_for(value: i32) -> u32 {
+    let mut bits = 0;
+    while (1i64 << bits) < i64::from(value) {
+        bits += 1;
+    }
+    bits
+}
+
+/// Context variables: 365 regular contexts and two run interruption contexts
+struct Contexts {
+    a: Vec<i32>,
+    b: Vec<i32>,
+    c: Vec<i32>,
+    n: Vec<i32>,
+    nn: [i32; 2],
+}
+
+impl Contexts {
+    fn new(params: &Params) -> Self {
+        let a = ((params.range + 32) / 64).max(2);
+        Self { a: vec![a; 367], b: vec![0; 365], c: vec![0; 365], n: vec![1; 367], nn: [0; 2] }
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    byte: u8,
+    left: u8,
+}
+
+impl BitReader<'_> {
+    /// Bits MSB first, with a stuffed zero bit after every 0xFF byte
+    fn bit(&mut self) -> Result<u32> {
+        if self.left == 0 {
+            let stuffed = self.byte == 0xFF;
+            self.byte = *self.data.get(self.pos).context(TRUNCATED)?;
+            if stuffed && self.byte >= 0x80 {
+                bail!(TRUNCATED);
+            }
+            self.pos += 1;
+            self.left = if stuffed { 7 } else { 8 };
+        }
+        self.left -= 1;
+        Ok(u32::from(self.byte >> self.left) & 1)
+    }
+
+    fn bits(&mut self, count: u32) -> Result<i32> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = value << 1 | self.bit()?;
+        }
+        Ok(value as i32)
+    }
+
+    /// Limited-length Golomb code (A.5.3). Mapped errors of a valid stream stay within
+    /// twice the sample range, larger values only come from corrupt data.
+    fn golomb(&mut self, k: u32, limit: u32, qbpp: u32) -> Result<i32> {
+        let mut zeros = 0;
+        while self.bit()? == 0 {
+            zeros += 1;
+            if zeros > limit {
+                bail!("Corrupt JPEG-LS data");
+            }
+        }
+        if zeros >= limit.saturating_sub(qbpp + 1) {
+            return Ok(self.bits(qbpp)? + 1);
+        }
+        let value = (i64::from(zeros) << k) + i64::from(self.bits(k)?);
+        if value > 2 << qbpp {
+            bail!("Corrupt JPEG-LS data");
+        }
+        Ok(value as i32)
+    }
+}
+
+/// Decode one scan starting at `pos`, returning the position of the next marker
+fn decode_scan(data: &[u8], pos: usize, image: &mut Image, components: &[usize], params: &Params, point_transform: u32) -> Result<usize> {
+    let width = image.width;
+    let mut contexts = Contexts::new(params);
+    let mut bits = BitReader { data, pos, byte: 0, left: 0 };
+    // Previous and current line of each component, with one sample of padding at each end
+    let mut lines: Vec<(Vec<i32>, Vec<i32>)> = components.iter().map(|_| (vec![0; width + 2], vec![0; width + 2])).collect();
+    let mut run_indices = vec![0usize; components.len()];
+
+    for y in 0..image.height {
+        for (s, &component) in components.iter().enumerate() {
+            let (previous, current) = &mut lines[s];
+            decode_line(&mut bits, params, &mut contexts, previous, current, &mut run_indices[s])?;
+            for x in 0..width {
+                image.samples[(y * width + x) * image.components + component] = (current[x + 1] as u32) << point_transform;
+            }
+            std::mem::swap(previous, current);
+        }
+    }
+
+    let mut pos = bits.pos;
+    while pos + 1 < data.len() && !(data[pos] == 0xFF && data[pos + 1] >= 0x80) {
+        pos += 1;
+    }
+    Ok(pos)
+}
+
+fn decode_line(
+    bits: &mut BitReader,
+    params: &Params,
+    contexts: &mut Contexts,
+    previous: &mut [i32],
+    current: &mut [i32],
+    run_index: &mut usize,
+) -> Result<()> {
+    let width = current.len() - 2;
+    current[0] = previous[1];
+    previous[width + 1] = previous[width];
+    let mut x = 1;
+    while x <= width {
+        let (ra, rb, rc, rd) = (current[x - 1], previous[x], previous[x - 1], previous[x + 1]);
+        let (d1, d2, d3) = (rd - rb, rb - rc, rc - ra);
+        if d1.abs() <= params.near && d2.abs() <= params.near && d3.abs() <= params.near {
+            x = decode_run(bits, params, contexts, previous, current, x, run_index)?;
+            continue;
+        }
+
+        // Regular mode
+        let (mut q1, mut q2, mut q3) = (params.quantize_gradient(d1), params.quantize_gradient(d2), params.quantize_gradient(d3));
+        let negative = q1 < 0 || (q1 == 0 && q2 < 0) || (q1 == 0 && q2 == 0 && q3 < 0);
+        if negative {
+            (q1, q2, q3) = (-q1, -q2, -q3);
+        }
+        let q = (81 * q1 + 9 * q2 + q3) as usize;
+
+        let mut prediction = if rc >= ra.max(rb) {
+            ra.min(rb)
+        } else if rc <= ra.min(rb) {
+            ra.max(rb)
+        } else {
+            ra + rb - rc
+        };
+        prediction += if negative { -contexts.c[q] } else { contexts.c[q] };
+        prediction = prediction.clamp(0, params.maxval);
+
+        let mut k = 0;
+        while (i64::from(contexts.n[q]) << k) < i64::from(contexts.a[q]) {
+            k += 1;
+        }
+        let mapped = bits.golomb(k, params.limit, params.qbpp)?;
+        let mut error = if mapped & 1 == 0 { mapped >> 1 } else { -((mapped + 1) >> 1) };
+        if k == 0 && params.near == 0 && 2 * contexts.b[q] + contexts.n[q] - 1 < 0 {
+            error = -error - 1;
+        }
+
+        // Context update and bias correction (A.6)
+        contexts.b[q] += error * (2 * params.near + 1);
+        contexts.a[q] = contexts.a[q].saturating_add(error.abs());
+        if contexts.n[q] == params.reset {
+            contexts.a[q] >>= 1;
+            contexts.b[q] >>= 1;
+            contexts.n[q] >>= 1;
+        }
+        contexts.n[q] += 1;
+        if contexts.b[q] + contexts.n[q] <= 0 {
+            contexts.b[q] += contexts.n[q];
+            if contexts.b[q] <= -contexts.n[q] {
+                contexts.b[q] = -contexts.n[q] + 1;
+            }
+            if contexts.c[q] > -128 {
+                contexts.c[q] -= 1;
+            }
+        } else if contexts.b[q] > 0 {
+            contexts.b[q] -= contexts.n[q];
+            if contexts.b[q] > 0 {
+                contexts.b[q] = 0;
+            }
+            if contexts.c[q] < 127 {
+                contexts.c[q] += 1;
+            }
+        }
+
+        current[x] = params.reconstruct(prediction, if negative { -error } else { error });
+        x += 1;
+    }
+    Ok(())
+}
+
+/// Run mode (A.7): the run of samples equal to the left neighbour, then the sample
+/// interrupting it. Returns the position after the run.
+fn decode_run(
+    bits: &mut BitReader,
+    params: &Params,
+    contexts: &mut Contexts,
+    previous: &[i32],
+    current: &mut [i32],
+    start: usize,
+    run_index: &mut usize,
+) -> Result<usize> {
+    let width = current.len() - 2;
+    let value = current[start - 1];
+    let remaining = width + 1 - start;
+    let mut count = 0;
+    while bits.bit()? == 1 {
+        let block = 1usize << RUN_ORDER[*run_index];
+        let length = block.min(remaining - count);
+        count += length;
+        if length == block && *run_index < 31 {
+            *run_index += 1;
+        }
+        if count == remaining {
+            break;
+        }
+    }
+    if count == remaining {
+        current[start..start + count].fill(value);
+        return Ok(start + count);
+    }
+    count += bits.bits(RUN_ORDER[*run_index])? as usize;
+    if count >= remaining {
+        bail!("Corrupt JPEG-LS run");
+    }
+    current[start..start + count].fill(value);
+
+    // Run interruption sample
+    let x = start + count;
+    let (ra, rb) = (current[x - 1], previous[x]);
+    let interruption = usize::from((ra - rb).abs() <= params.near);
+    let q = 365 + interruption;
+    let temp = if interruption == 1 { contexts.a[q].saturating_add(contexts.n[q] >> 1) } else { contexts.a[q] };
+    let mut k = 0;
+    while (i64::from(contexts.n[q]) << k) < i64::from(temp) {
+        k += 1;
+    }
+    let limit = params.limit - RUN_ORDER[*run_index] - 1;
+    let mapped = bits.golomb(k, limit, params.qbpp)?;
+
+    let temp = mapped + interruption as i32;
+    let map = temp & 1 == 1;
+    let magnitude = (temp + i32::from(map)) / 2;
+    let error = if (k != 0 || 2 * contexts.nn[interruption] >= contexts.n[q]) == map { -magnitude } else { magnitude };
+
+    if error < 0 {
+        contexts.nn[interruption] += 1;
+    }
+    contexts.a[q] = contexts.a[q].saturating_add((mapped + 1 - interruption as i32) >> 1);
+    if contexts.n[q] == params.reset {
+        contexts.a[q] >>= 1;
+        contexts.n[q] >>= 1;
+        contexts.nn[interruption] >>= 1;
+    }
+    contexts.n[q] += 1;
+
+    current[x] = if interruption == 1 {
+        params.reconstruct(ra, error)
+    } else {
+        params.reconstruct(rb, if rb >= ra { error } else { -error })
+    };
+    if *run_index > 0 {
+        *run_index -= 1;
+    }
+    Ok(x + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 8-bit 4x2 image [0 0 0 5] [0 0 2 3], coded by hand: a run of three interrupted by
+    /// 5 (RItype 1), then a run of two, 2 (RItype 1) and 3 in regular mode (context 17)
+    const STREAM: [u8; 30] = [
+        0xFF, 0xD8,
+        0xFF, 0xF7, 0x00, 0x0B, 0x08, 0x00, 0x02, 0x00, 0x04, 0x01, 0x01, 0x11, 0x00,
+        0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00,
+        0b1110_0010, 0b1110_0101, 0b1111_0000,
+        0xFF, 0xD9,
+    ];
+
+    #[test]
+    fn run_interruption_and_regular_samples() {
+        let image = decode(&STREAM).unwrap();
+        assert_eq!((image.width, image.height, image.components, image.precision), (4, 2, 1, 8));
+        assert_eq!(image.samples, [0, 0, 0, 5, 0, 0, 2, 3]);
+    }
+
+    #[test]
+    fn truncated_streams_and_short_segments_are_rejected() {
+        let data = &STREAM;
+        for cut in 0..data.len() {
+            assert!(decode(&data[..cut]).is_err(), "decoded {} of {} bytes", cut, data.len());
+        }
+        assert!(decode(&[0xFF, 0xD8, 0xFF, 0xF7, 0x00, 0x03, 0x08, 0xFF, 0xD9]).is_err());
+        assert!(decode(&[0xFF, 0xD8, 0xFF, 0xDA, 0x00, 0x02, 0xFF, 0xD9]).is_err());
+        // A unary prefix longer than LIMIT
+        let mut corrupt = data.to_vec();
+        corrupt.splice(25..28, [0b0111_1111, 0, 0, 0, 0, 0]);
+        assert!(decode(&corrupt).is_err());
+    }
+
+    #[test]
+    fn corrupt_streams_do_not_panic() {
+        for pos in 0..STREAM.len() {
+            for bit in 0..8 {
+                let mut corrupt = STREAM.to_vec();
+                corrupt[pos] ^= 1 << bit;
+                let _ = decode(&corrupt);
+            }
+        }
+    }
+}
@@ -1,9 +1,10 @@
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, hash_map::Entry};
 use std::fs::{self, File};
 use std::io::{BufReader, Read};
+use std::sync::Mutex;
 use anyhow::{Result, Context, bail};
 use dicom_object::OpenFileOptions;
 use dicom_core::{Tag, header::Header, dictionary::DataDictionary};
@@ -14,26 +15,76 @@ use rayon::prelude::*;
 use indicatif::{ProgressBar, ProgressStyle};
 use chrono::{DateTime, Utc};
 use uuid::Uuid;
+use sha2::{Digest, Sha256};
+
+mod anonymize;
+mod bids;
+mod columnar;
+mod dicomweb;
+mod dimse;
+mod duplicates;
+mod filter;
+mod find;
+mod fhir;
+mod forward;
+mod geometry;
+mod hl7;
+mod index;
+mod j2k;
+mod jpegls;
+mod manifest;
+mod pixels;
+mod query;
+mod render;
+mod rle;
+mod scp;
+mod server;
+mod stats;
+mod stow;
+mod table;
+#[cfg(test)]
+mod testutil;
+mod thumbnail;
+mod volume;
+mod watch;
+mod xml;
+
+use geometry::SeriesGeometry;
+use hl7::Hl7MessageType;
+use filter::TagPredicate;
+use find::{FindLevel, QueryRoot};
+use index::CatalogIndex;
+use manifest::Manifest;
+use pixels::{PixelData, PixelSummary};
+use query::QueryLevel;
+use scp::OutputGrouping;
+use table::TableOptions;
+use thumbnail::{SeriesThumbnails, ThumbnailOptions};
+use volume::SeriesVolume;
 
 #[derive(Parser)]
 #[command(name = "dicom-json")]
 #[command(about = "Advanced DICOM to JSON converter with comprehensive metadata extraction")]
 #[command(version = "1.0.0")]
+#[command(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Input path: DICOM file, directory, or ZIP archive
-    #[arg(value_name = "INPUT")]
-    input: PathBuf,
+    #[arg(value_name = "INPUT", required = true)]
+    input: Option<PathBuf>,
 
     /// Output directory (defaults to current directory)
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     output: Option<PathBuf>,
 
     /// Output format
-    #[arg(short, long, default_value = "comprehensive")]
+    #[arg(short, long, default_value = "comprehensive", global = true)]
     format: OutputFormat,
 
     /// Pretty print JSON output
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pretty: bool,
 
     /// Process files in parallel (faster for large datasets)
@@ -44,19 +95,274 @@ struct Cli {
     #[arg(long)]
     include_private: bool,
 
-    /// Organize output by study/series hierarchy
+    /// Organize output by study/series hierarchy (same as --split study)
     #[arg(long)]
     organize_hierarchy: bool,
 
+    /// Split output into one file per study, series or instance
+    #[arg(long, value_enum, global = true)]
+    split: Option<SplitLevel>,
+
+    /// Output path template over tag keywords, relative to the output directory
+    /// (e.g. "{StudyInstanceUID}/{SeriesInstanceUID}/{SOPInstanceUID}.json")
+    #[arg(long, value_name = "TEMPLATE", global = true)]
+    name_template: Option<String>,
+
+    /// WADO-RS base URL for the FHIR Endpoint resource (a placeholder is used otherwise)
+    #[arg(long, value_name = "URL", global = true)]
+    fhir_endpoint: Option<String>,
+
+    /// HL7 message type for --format hl7
+    #[arg(long, value_enum, default_value = "oru-r01", global = true)]
+    hl7_message: Hl7MessageType,
+
+    /// Tag keywords to export as CSV/TSV/Parquet columns (default: every tag encountered)
+    #[arg(long, value_name = "KEYWORDS", value_delimiter = ',', global = true)]
+    columns: Vec<String>,
+
+    /// Separator for the values of multi-valued tags in CSV/TSV cells
+    #[arg(long, value_name = "SEP", default_value = "|", global = true)]
+    multi_value_delimiter: String,
+
+    /// Only emit instances matching the predicate, e.g. "Modality in (CT,MR)" or
+    /// "SliceThickness<=1.0" (repeatable; all must match)
+    #[arg(long = "where", value_name = "PREDICATE", global = true)]
+    where_predicates: Vec<String>,
+
+    /// SQLite catalog to create or update with the processed instances
+    #[arg(long, value_name = "DB")]
+    index: Option<PathBuf>,
+
+    /// Hoist tags identical across all instances of a series into a shared `common` block
+    #[arg(long, global = true)]
+    factor_series: bool,
+
+    /// Only convert files added or changed since the last run into the output directory,
+    /// merging them into the existing outputs
+    #[arg(long)]
+    incremental: bool,
+
+    /// Keep running and convert files as they land in the input directory
+    #[arg(long)]
+    watch: bool,
+
+    /// Poll the watched directory instead of relying on file system events (network shares)
+    #[arg(long)]
+    poll: bool,
+
+    /// Seconds a watched file's size and modification time must stay unchanged before it is converted
+    #[arg(long, value_name = "SECONDS", default_value = "2")]
+    settle: f64,
+
+    /// Move converted originals here, keeping their path relative to the watched directory
+    #[arg(long, value_name = "DIR")]
+    archive_dir: Option<PathBuf>,
+
+    /// Move originals that fail to convert here, each with an .error.txt note
+    #[arg(long, value_name = "DIR")]
+    quarantine_dir: Option<PathBuf>,
+
+    /// Send the converted files to a storage SCP at HOST:PORT with C-STORE
+    #[arg(long, value_name = "HOST:PORT")]
+    forward: Option<String>,
+
+    /// Application Entity title of the --forward destination
+    #[arg(long, value_name = "AE", default_value = "ANY-SCP")]
+    forward_ae: String,
+
+    /// Our Application Entity title when forwarding
+    #[arg(long, value_name = "AE", default_value = "DICOMJSON")]
+    forward_calling_ae: String,
+
+    /// Seconds to wait for a DICOM peer to connect or answer (--forward and find), and for an
+    /// association request after a connection to scp
+    #[arg(long, value_name = "SECONDS", default_value = "30", global = true)]
+    dimse_timeout: f64,
+
+    /// De-identify the forwarded copies (the converted output and originals are left as they are)
+    #[arg(long)]
+    anonymize: bool,
+
+    /// Secret for --anonymize pseudonyms and UIDs, so they stay the same across runs
+    #[arg(long, value_name = "SECRET")]
+    anonymize_key: Option<String>,
+
+    /// Decode the Pixel Data of every instance, failing files whose pixels cannot be decoded
+    #[arg(long)]
+    decode_pixels: bool,
+
+    /// Add per-frame pixel statistics (min, max, mean, std, histogram, padding share, values
+    /// beyond Bits Stored) in stored and rescaled units
+    #[arg(long)]
+    pixel_stats: bool,
+
+    /// Number of histogram bins for --pixel-stats
+    #[arg(long, value_name = "N", default_value = "16")]
+    histogram_bins: usize,
+
+    /// Add SHA-256 hashes of the decoded and of the stored Pixel Data to every instance, and report
+    /// duplicate images and reused SOP Instance UIDs in duplicates.json in the output directory
+    #[arg(long)]
+    hash_pixels: bool,
+
+    /// Export frames as PNG below DIR: <study>/<series>/<instance>.png, or <instance>_<frame>.png
+    /// for multi-frame images
+    #[arg(long, value_name = "DIR")]
+    export_png: Option<PathBuf>,
+
+    /// Only export this frame (1-based) with --export-png
+    #[arg(long, value_name = "N")]
+    png_frame: Option<usize>,
+
+    /// Write 16-bit instead of 8-bit PNGs
+    #[arg(long)]
+    png_16bit: bool,
+
+    /// Window for exported images and thumbnails: CENTER,WIDTH in rescaled units or a preset (brain,
+    /// subdural, soft-tissue, mediastinum, liver, lung, bone); defaults to the file's VOI LUT or window
+    #[arg(long, value_name = "PRESET|CENTER,WIDTH", allow_hyphen_values = true)]
+    window: Option<String>,
+
+    /// Write a thumbnail of each series' middle slice to thumbnails/<study>/<series>/ in the
+    /// output directory (with --organize-hierarchy or --split)
+    #[arg(long)]
+    thumbnails: bool,
+
+    /// Longest side of series thumbnails and contact sheet tiles, in pixels
+    #[arg(long, value_name = "PX", default_value = "128")]
+    thumbnail_size: usize,
+
+    /// Also write a contact sheet of up to 36 evenly spaced slices of each series (with --thumbnails)
+    #[arg(long)]
+    contact_sheet: bool,
+
+    /// Write each series as a spatially sorted NumPy volume with a JSON sidecar of its spacing,
+    /// origin and direction to volumes/<study>/<series>/ in the output directory (with
+    /// --organize-hierarchy or --split)
+    #[arg(long)]
+    export_npy: bool,
+
+    /// Apply the Modality LUT or Rescale Slope/Intercept to grayscale --export-npy volumes,
+    /// writing float32 instead of the stored values
+    #[arg(long)]
+    npy_rescale: bool,
+
     /// Maximum recursion depth for directory processing
     #[arg(long, default_value = "10")]
     max_depth: usize,
 
     /// Verbose output
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     verbose: bool,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Find studies, series or instances in an index or JSON output with QIDO-RS style matching
+    Query {
+        /// SQLite index written with --index, or JSON output (file or directory) written with --format comprehensive
+        #[arg(value_name = "SOURCE")]
+        source: PathBuf,
+
+        /// Match keys, e.g. Modality=CT StudyDate=20230101-20231231 PatientName=DOE*
+        #[arg(value_name = "KEYWORD=VALUE")]
+        filters: Vec<String>,
+
+        /// Level at which matches are returned; all instances of a matching study or series are output
+        #[arg(long, value_enum, default_value = "instance")]
+        level: QueryLevel,
+    },
+
+    /// Serve a directory or index over DICOMweb: QIDO-RS search and WADO-RS metadata
+    Serve {
+        /// DICOM file, directory or ZIP archive, or SQLite index written with --index
+        #[arg(value_name = "SOURCE")]
+        source: PathBuf,
+
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+
+        /// Port to listen on
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+
+        /// Directory for STOW-RS uploads (defaults to SOURCE when it is a directory)
+        #[arg(long, value_name = "DIR")]
+        store_dir: Option<PathBuf>,
+    },
+
+    /// Query a remote AE with C-FIND and write the matches like converted files
+    Find {
+        /// Address of the remote AE
+        #[arg(value_name = "HOST:PORT")]
+        address: String,
+
+        /// Match and return keys, e.g. PatientName=DOE* StudyDate=20230101-20231231 StudyDescription=
+        #[arg(value_name = "KEYWORD=VALUE")]
+        keys: Vec<String>,
+
+        /// Application Entity title of the remote AE
+        #[arg(long, default_value = "ANY-SCP")]
+        called_ae: String,
+
+        /// Our Application Entity title
+        #[arg(long, default_value = "DICOMJSON")]
+        calling_ae: String,
+
+        /// Query/Retrieve information model
+        #[arg(long, value_enum, default_value = "study")]
+        root: QueryRoot,
+
+        /// Query/Retrieve level
+        #[arg(long, value_enum, default_value = "study")]
+        level: FindLevel,
+    },
+
+    /// Receive instances over DICOM (C-STORE SCP, with C-ECHO) and convert them as they arrive
+    Scp {
+        /// Directory received instances are kept in, as <study>/<series>/<instance>.dcm
+        #[arg(value_name = "STORE_DIR")]
+        store_dir: PathBuf,
+
+        /// Application Entity title callers must address
+        #[arg(long, default_value = "DICOMJSON")]
+        ae_title: String,
+
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+
+        /// Port to listen on
+        #[arg(long, default_value_t = 11112)]
+        port: u16,
+
+        /// Transfer syntax UIDs to accept, in order of preference (default: uncompressed,
+        /// JPEG, JPEG-LS, JPEG 2000 and RLE)
+        #[arg(long, value_name = "UIDS", value_delimiter = ',')]
+        transfer_syntaxes: Vec<String>,
+
+        /// Whether outputs are written per association or merged per study
+        #[arg(long, value_enum, default_value = "study")]
+        output_per: OutputGrouping,
+
+        /// Associations served at once; further requests are rejected until one ends
+        #[arg(long, value_name = "N", default_value_t = 8)]
+        max_associations: usize,
+    },
+}
+
+impl Cli {
+    fn split_level(&self) -> SplitLevel {
+        self.split
+            .unwrap_or(if self.organize_hierarchy { SplitLevel::Study } else { self.format.default_split() })
+    }
+
+    fn dimse_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs_f64(self.dimse_timeout)
+    }
+}
+
 #[derive(ValueEnum, Clone, Debug)]
 enum OutputFormat {
     /// Basic tag extraction
@@ -67,9 +373,149 @@ enum OutputFormat {
     Medical,
     /// Raw DICOM format
     Raw,
+    /// BIDS JSON sidecar per MR/PET series
+    Bids,
+    /// FHIR R4 Bundle with Patient and ImagingStudy resources
+    Fhir,
+    /// HL7 v2.5 message per study
+    Hl7,
+    /// PS3.19 Native DICOM Model XML per instance
+    Xml,
+    /// Comma-separated table, one row per instance
+    Csv,
+    /// Tab-separated table, one row per instance
+    Tsv,
+    /// Parquet table with typed columns, one row per instance
+    Parquet,
+}
+
+impl OutputFormat {
+    /// Split level used when neither --split nor --organize-hierarchy is given
+    fn default_split(&self) -> SplitLevel {
+        match self {
+            OutputFormat::Bids => SplitLevel::Series,
+            OutputFormat::Fhir | OutputFormat::Hl7 => SplitLevel::Study,
+            OutputFormat::Xml => SplitLevel::Instance,
+            _ => SplitLevel::Single,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Hl7 => "hl7",
+            OutputFormat::Xml => "xml",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Tsv => "tsv",
+            OutputFormat::Parquet => "parquet",
+            _ => "json",
+        }
+    }
+
+    /// Whether tags carry their dictionary name (not for basic/raw and non-JSON formats)
+    fn includes_tag_names(&self) -> bool {
+        match self {
+            OutputFormat::Basic | OutputFormat::Raw | OutputFormat::Hl7 | OutputFormat::Xml
+            | OutputFormat::Csv | OutputFormat::Tsv | OutputFormat::Parquet => false,
+            OutputFormat::Comprehensive | OutputFormat::Medical | OutputFormat::Bids | OutputFormat::Fhir => true,
+        }
+    }
+
+    fn supports_split(&self, split: SplitLevel) -> bool {
+        match self {
+            OutputFormat::Bids => split == SplitLevel::Series,
+            OutputFormat::Fhir | OutputFormat::Hl7 => matches!(split, SplitLevel::Single | SplitLevel::Study),
+            OutputFormat::Xml => split == SplitLevel::Instance,
+            OutputFormat::Csv | OutputFormat::Tsv => split != SplitLevel::Instance,
+            OutputFormat::Parquet => split == SplitLevel::Single,
+            _ => true,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum SplitLevel {
+    /// One file for all instances
+    Single,
+    /// One file per study
+    Study,
+    /// One file per series
+    Series,
+    /// One file per instance
+    Instance,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl SplitLevel {
+    fn default_template(self, extension: &str) -> String {
+        match self {
+            SplitLevel::Single => format!("dicom_data.{}", extension),
+            SplitLevel::Study => format!("study_{{StudyInstanceUID}}/study.{}", extension),
+            SplitLevel::Series => format!("study_{{StudyInstanceUID}}/series_{{SeriesInstanceUID}}/series.{}", extension),
+            SplitLevel::Instance => format!("study_{{StudyInstanceUID}}/series_{{SeriesInstanceUID}}/{{SOPInstanceUID}}.{}", extension),
+        }
+    }
+}
+
+/// Output file path template with `{Keyword}` placeholders resolved per output file
+struct OutputTemplate {
+    parts: Vec<TemplatePart>,
+}
+
+enum TemplatePart {
+    Literal(String),
+    Tag(Tag),
+}
+
+impl OutputTemplate {
+    fn parse(template: &str) -> Result<Self> {
+        let path = Path::new(template);
+        if path.is_absolute() || path.components().any(|c| matches!(c, std::path::Component::ParentDir)) {
+            bail!("Output template must be a relative path without '..': {}", template);
+        }
+
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..].find('}')
+                .map(|i| start + i)
+                .with_context(|| format!("Unclosed '{{' in output template: {}", template))?;
+            if rest[..start].contains('}') {
+                bail!("Unmatched '}}' in output template: {}", template);
+            }
+            if start > 0 {
+                parts.push(TemplatePart::Literal(rest[..start].to_string()));
+            }
+
+            let keyword = &rest[start + 1..end];
+            let tag = dicom_dictionary_std::StandardDataDictionary
+                .parse_tag(keyword)
+                .with_context(|| format!("Unknown tag keyword in output template: {{{}}}", keyword))?;
+            parts.push(TemplatePart::Tag(tag));
+            rest = &rest[end + 1..];
+        }
+        if rest.contains('}') {
+            bail!("Unmatched '}}' in output template: {}", template);
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Literal(rest.to_string()));
+        }
+
+        Ok(Self { parts })
+    }
+
+    fn render(&self, tags: &HashMap<String, TagInfo>) -> PathBuf {
+        let rendered: String = self.parts.iter().map(|part| match part {
+            TemplatePart::Literal(text) => text.clone(),
+            TemplatePart::Tag(tag) => get_tag_value(tags, *tag)
+                .map(|v| sanitize_filename(v.trim()))
+                .filter(|v| !v.is_empty())
+                .unwrap_or_else(|| "unknown".to_string()),
+        }).collect();
+
+        PathBuf::from(rendered)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DicomStudy {
     pub study_instance_uid: String,
     pub study_date: Option<String>,
@@ -80,15 +526,57 @@ pub struct DicomStudy {
     pub processing_info: ProcessingInfo,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DicomSeries {
     pub series_instance_uid: String,
     pub series_number: Option<String>,
     pub series_description: Option<String>,
     pub modality: Option<String>,
+    /// Tags shared by every instance; instance tags hold only what differs
+    #[serde(rename = "common", default, skip_serializing_if = "HashMap::is_empty")]
+    pub common_tags: HashMap<String, TagInfo>,
+    /// Volume geometry when instances form a spatially ordered stack
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geometry: Option<SeriesGeometry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnails: Option<SeriesThumbnails>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume: Option<SeriesVolume>,
     pub instances: Vec<DicomInstance>,
 }
 
+impl DicomSeries {
+    /// First non-empty value of a tag in the series, including factored common tags
+    fn tag_value(&self, tag: Tag) -> Option<String> {
+        let key = tag_key(tag);
+        self.common_tags.get(&key)
+            .or_else(|| self.instances.iter().find_map(|i| i.metadata.tags.get(&key)))
+            .and_then(|t| t.raw_value.clone())
+            .filter(|v| !v.trim().is_empty())
+    }
+
+    /// Move tags whose value is identical in every instance into `common_tags`.
+    /// An instance's full tag set is `common_tags` merged with its own `tags`.
+    fn factor_common_tags(&mut self) {
+        let Some((first, rest)) = self.instances.split_first() else {
+            return;
+        };
+        if rest.is_empty() {
+            return;
+        }
+
+        let common: HashMap<String, TagInfo> = first.metadata.tags.iter()
+            .filter(|(key, tag_info)| rest.iter().all(|i| i.metadata.tags.get(*key) == Some(*tag_info)))
+            .map(|(key, tag_info)| (key.clone(), tag_info.clone()))
+            .collect();
+
+        for instance in &mut self.instances {
+            instance.metadata.tags.retain(|key, _| !common.contains_key(key));
+        }
+        self.common_tags.extend(common);
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DicomInstance {
     pub sop_instance_uid: String,
@@ -96,9 +584,11 @@ pub struct DicomInstance {
     pub file_path: String,
     pub metadata: DicomMetadata,
     pub has_pixel_data: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pixels: Option<PixelSummary>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PatientInfo {
     pub patient_id: Option<String>,
     pub patient_name: Option<String>,
@@ -115,7 +605,7 @@ pub struct DicomMetadata {
     pub file_meta_information: HashMap<String, TagInfo>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct TagInfo {
     pub tag: String,
     pub vr: String,
@@ -125,7 +615,7 @@ pub struct TagInfo {
     pub is_private: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ProcessingInfo {
     pub processing_id: String,
     pub timestamp: DateTime<Utc>,
@@ -136,7 +626,7 @@ pub struct ProcessingInfo {
     pub extraction_summary: ExtractionSummary,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ExtractionSummary {
     pub files_with_pixel_data: usize,
     pub unique_modalities: Vec<String>,
@@ -146,18 +636,79 @@ pub struct ExtractionSummary {
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
+    match &cli.command {
+        Some(Command::Query { source, filters, level }) => {
+            let (source, filters, level) = (source.clone(), filters.clone(), *level);
+            return query::run(&DicomProcessor::new(cli)?, &source, &filters, level);
+        }
+        Some(Command::Serve { source, host, port, store_dir }) => {
+            let (source, host, port, store_dir) = (source.clone(), host.clone(), *port, store_dir.clone());
+            return server::run(&DicomProcessor::new(cli)?, &source, &host, port, store_dir);
+        }
+        Some(Command::Find { address, keys, called_ae, calling_ae, root, level }) => {
+            let (address, keys, called_ae, calling_ae, root, level) =
+                (address.clone(), keys.clone(), called_ae.clone(), calling_ae.clone(), *root, *level);
+            let output_dir = cli.output.clone()
+                .unwrap_or_else(|| std::env::current_dir().unwrap());
+            let request = find::FindRequest {
+                address: &address, calling_ae: &calling_ae, called_ae: &called_ae, root, level, keys: &keys,
+            };
+            return find::run(&DicomProcessor::new(cli)?, request, &output_dir);
+        }
+        Some(Command::Scp { store_dir, ae_title, host, port, transfer_syntaxes, output_per, max_associations }) => {
+            let (store_dir, ae_title, host, port, grouping, max_associations) =
+                (store_dir.clone(), ae_title.clone(), host.clone(), *port, *output_per, *max_associations);
+            if max_associations == 0 {
+                bail!("--max-associations must be at least 1");
+            }
+            let mut transfer_syntaxes = transfer_syntaxes.clone();
+            if transfer_syntaxes.is_empty() {
+                transfer_syntaxes = scp::DEFAULT_TRANSFER_SYNTAXES.iter().map(|ts| ts.to_string()).collect();
+            }
+            let output_dir = cli.output.clone()
+                .unwrap_or_else(|| std::env::current_dir().unwrap());
+
+            // Per-study outputs need a per-study split where the format would write a single file
+            let mut cli = cli;
+            if grouping == OutputGrouping::Study && cli.split.is_none() && !cli.organize_hierarchy
+                && cli.format.default_split() == SplitLevel::Single && cli.format.supports_split(SplitLevel::Study)
+            {
+                cli.split = Some(SplitLevel::Study);
+            }
+
+            let processor = DicomProcessor::new(cli)?;
+            let scp = scp::Scp {
+                processor: &processor, ae_title, store_dir, output_dir, transfer_syntaxes, grouping, max_associations,
+            };
+            return scp::run(scp, &host, port);
+        }
+        None => {}
+    }
+
+    let Some(input) = cli.input.clone() else {
+        bail!("No input given");
+    };
+
     if cli.verbose {
         println!("🏥 Advanced DICOM-JSON Converter v1.0.0");
-        println!("📁 Processing: {:?}", cli.input);
+        println!("📁 Processing: {:?}", input);
     }
 
-    let output_dir = cli.output.as_ref()
-        .map(|p| p.clone())
+    let output_dir = cli.output.clone()
         .unwrap_or_else(|| std::env::current_dir().unwrap());
 
     fs::create_dir_all(&output_dir)?;
 
-    let files = collect_dicom_files(&cli.input, cli.max_depth, cli.verbose)?;
+    let processor = DicomProcessor::new(cli)?;
+    let cli = &processor.cli;
+
+    let mut index = cli.index.as_deref().map(CatalogIndex::open).transpose()?;
+
+    if cli.watch {
+        return watch::run(&processor, &input, &output_dir, index.as_mut());
+    }
+
+    let (files, archive_entries) = collect_input_files(&input, cli.max_depth, cli.verbose)?;
     
     if files.is_empty() {
         bail!("No DICOM files found in the specified input");
@@ -167,6 +718,27 @@ fn main() -> Result<()> {
         println!("📊 Found {} DICOM files to process", files.len());
     }
 
+    let mut manifest = cli.incremental
+        .then(|| Manifest::load(&output_dir, processor.manifest_options()))
+        .transpose()?;
+    let (files, cached) = match &mut manifest {
+        Some(manifest) => {
+            manifest.set_archive_entries(archive_entries);
+            if cli.verbose && manifest.invalidated {
+                println!("♻️  Options changed since the last run, converting all files");
+            }
+            let plan = manifest.plan(files)?;
+            if cli.verbose {
+                println!(
+                    "♻️  Incremental: {} unchanged, {} new or changed, {} removed files",
+                    plan.unchanged_files, plan.to_process.len(), plan.removed_files
+                );
+            }
+            (plan.to_process, plan.unchanged)
+        }
+        None => (files, Vec::new()),
+    };
+
     let progress_bar = if cli.verbose {
         let pb = ProgressBar::new(files.len() as u64);
         pb.set_style(ProgressStyle::default_bar()
@@ -178,7 +750,17 @@ fn main() -> Result<()> {
         None
     };
 
-    let processor = DicomProcessor::new(cli);
+    // Forwarding and the duplicate report need the converted instances, which streaming does not keep
+    if matches!(processor.cli.format, OutputFormat::Parquet) && manifest.is_none() && processor.cli.forward.is_none() && !processor.cli.hash_pixels {
+        stream_parquet(&processor, files, &output_dir, &progress_bar, &mut index)?;
+        if let Some(pb) = &progress_bar {
+            pb.finish_with_message("✅ Processing complete!");
+        }
+        print_filtered_out(&processor);
+        return Ok(());
+    }
+
+    let processed = if manifest.is_some() { files.clone() } else { Vec::new() };
     let results = if processor.cli.parallel && files.len() > 1 {
         process_files_parallel(&processor, files, &progress_bar)?
     } else {
@@ -188,15 +770,64 @@ fn main() -> Result<()> {
     if let Some(pb) = &progress_bar {
         pb.finish_with_message("✅ Processing complete!");
     }
+    print_filtered_out(&processor);
 
-    if processor.cli.organize_hierarchy {
-        organize_by_hierarchy(&results, &output_dir, &processor)?;
-    } else {
-        save_results(&results, &output_dir, &processor)?;
+    if let Some(manifest) = &mut manifest {
+        manifest.record(&processed, &results, &processor.filtered_out.lock().unwrap(), processor.cli.parallel)?;
+    }
+    let mut all_results = cached;
+    all_results.extend_from_slice(&results);
+
+    match &manifest {
+        Some(manifest) if !manifest.has_changes() => {
+            if processor.cli.verbose {
+                println!("✅ No changes since the last run, outputs left as they are");
+            }
+        }
+        _ => {
+            let outputs = write_results(&all_results, &output_dir, &processor, manifest.as_ref().map(Manifest::affected_studies))?;
+            if let Some(manifest) = &mut manifest {
+                remove_stale_outputs(&output_dir, &manifest.replace_outputs(&output_dir, &outputs), processor.cli.verbose);
+            }
+        }
+    }
+
+    let forwarded = processor.cli.forward.is_some()
+        .then(|| forward::forward(&processor, &results))
+        .transpose()?;
+    if let (Some(manifest), Some(forwarded)) = (&mut manifest, &forwarded) {
+        for status in forwarded.iter().filter(|status| !status.is_stored()) {
+            manifest.retry(Path::new(&status.file_path));
+        }
+    }
+
+    // Saved after the outputs so an interrupted run converts the same files again
+    if let Some(manifest) = &manifest {
+        manifest.save()?;
+    }
+
+    if let Some(index) = &mut index {
+        update_index(index, &results, &processor)?;
     }
 
     if processor.cli.verbose {
-        print_summary(&results);
+        print_summary(&all_results);
+    }
+
+    if let Some(forwarded) = &forwarded {
+        let destination = format!("{}@{}", processor.cli.forward_ae, processor.cli.forward.as_deref().unwrap_or_default());
+        if processor.cli.verbose {
+            forward::print_summary(forwarded, &destination);
+        }
+        let failed: Vec<_> = forwarded.iter().filter(|status| !status.is_stored()).collect();
+        if !failed.is_empty() {
+            if !processor.cli.verbose {
+                for status in &failed {
+                    eprintln!("❌ {} {}", status.file_path, status.describe());
+                }
+            }
+            bail!("{} of {} instances could not be forwarded to {}", failed.len(), forwarded.len(), destination);
+        }
     }
 
     Ok(())
@@ -204,29 +835,166 @@ fn main() -> Result<()> {
 
 struct DicomProcessor {
     cli: Cli,
+    output_template: OutputTemplate,
+    columns: Vec<Tag>,
+    predicates: Vec<TagPredicate>,
+    window: Option<render::Window>,
+    /// Files skipped because they did not match the --where predicates
+    filtered_out: Mutex<Vec<PathBuf>>,
 }
 
 impl DicomProcessor {
-    fn new(cli: Cli) -> Self {
-        Self { cli }
+    fn new(cli: Cli) -> Result<Self> {
+        if !cli.format.supports_split(cli.split_level()) {
+            bail!(
+                "--format {} cannot be written with --split {}",
+                format!("{:?}", cli.format).to_lowercase(), format!("{:?}", cli.split_level()).to_lowercase()
+            );
+        }
+        if cli.factor_series && !matches!(cli.split_level(), SplitLevel::Study | SplitLevel::Series) {
+            bail!("--factor-series requires --split study or --split series");
+        }
+
+        if !cli.watch && (cli.poll || cli.archive_dir.is_some() || cli.quarantine_dir.is_some()) {
+            bail!("--poll, --archive-dir and --quarantine-dir require --watch");
+        }
+
+        if cli.forward.is_none() && (cli.anonymize || cli.anonymize_key.is_some()) {
+            bail!("--anonymize and --anonymize-key require --forward");
+        }
+
+        if !(cli.dimse_timeout > 0.0 && cli.dimse_timeout.is_finite()) {
+            bail!("--dimse-timeout must be a positive number of seconds");
+        }
+        let mut ae_titles = vec![("--forward-ae", &cli.forward_ae), ("--forward-calling-ae", &cli.forward_calling_ae)];
+        match &cli.command {
+            Some(Command::Find { called_ae, calling_ae, .. }) => {
+                ae_titles.extend([("--called-ae", called_ae), ("--calling-ae", calling_ae)]);
+            }
+            Some(Command::Scp { ae_title, .. }) => ae_titles.push(("--ae-title", ae_title)),
+            _ => {}
+        }
+        for (option, title) in ae_titles {
+            dimse::check_ae_title(title).with_context(|| format!("Invalid {}", option))?;
+        }
+
+        if cli.export_png.is_none() && (cli.png_frame.is_some() || cli.png_16bit) {
+            bail!("--png-frame and --png-16bit require --export-png");
+        }
+        if cli.export_png.is_none() && !cli.thumbnails && cli.window.is_some() {
+            bail!("--window requires --export-png or --thumbnails");
+        }
+        if cli.thumbnails && cli.split_level() == SplitLevel::Single {
+            bail!("--thumbnails requires --organize-hierarchy or --split");
+        }
+        if !cli.thumbnails && cli.contact_sheet {
+            bail!("--contact-sheet requires --thumbnails");
+        }
+        if cli.export_npy && cli.split_level() == SplitLevel::Single {
+            bail!("--export-npy requires --organize-hierarchy or --split");
+        }
+        if !cli.export_npy && cli.npy_rescale {
+            bail!("--npy-rescale requires --export-npy");
+        }
+        if cli.histogram_bins == 0 {
+            bail!("--histogram-bins must be at least 1");
+        }
+        if cli.thumbnail_size == 0 {
+            bail!("--thumbnail-size must be at least 1");
+        }
+        let window = cli.window.as_deref().map(render::Window::parse).transpose()?;
+
+        if !cli.columns.is_empty() && !matches!(cli.format, OutputFormat::Csv | OutputFormat::Tsv | OutputFormat::Parquet) {
+            bail!("--columns requires --format csv, tsv or parquet");
+        }
+
+        let output_template = match &cli.name_template {
+            Some(template) => OutputTemplate::parse(template)?,
+            None => OutputTemplate::parse(&cli.split_level().default_template(cli.format.extension()))?,
+        };
+
+        let columns = cli.columns.iter()
+            .map(|keyword| dicom_dictionary_std::StandardDataDictionary
+                .parse_tag(keyword.trim())
+                .with_context(|| format!("Unknown tag keyword in --columns: {}", keyword)))
+            .collect::<Result<Vec<_>>>()?;
+
+        let predicates = cli.where_predicates.iter()
+            .map(|predicate| TagPredicate::parse(predicate))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { cli, output_template, columns, predicates, window, filtered_out: Mutex::new(Vec::new()) })
+    }
+
+    /// Options that change the converted instances or their outputs, stored with the
+    /// --incremental manifest
+    fn manifest_options(&self) -> serde_json::Value {
+        serde_json::json!({
+            "format": format!("{:?}", self.cli.format).to_lowercase(),
+            "split": format!("{:?}", self.cli.split_level()).to_lowercase(),
+            "nameTemplate": self.cli.name_template,
+            "factorSeries": self.cli.factor_series,
+            "pretty": self.cli.pretty,
+            "columns": self.cli.columns,
+            "multiValueDelimiter": self.cli.multi_value_delimiter,
+            "hl7Message": format!("{:?}", self.cli.hl7_message).to_lowercase(),
+            "fhirEndpoint": self.cli.fhir_endpoint,
+            "includePrivate": self.cli.include_private,
+            "where": self.cli.where_predicates,
+            "decodePixels": self.cli.decode_pixels,
+            "pixelStats": self.cli.pixel_stats,
+            "histogramBins": self.cli.histogram_bins,
+            "hashPixels": self.cli.hash_pixels,
+            "exportPng": self.cli.export_png,
+            "pngFrame": self.cli.png_frame,
+            "png16Bit": self.cli.png_16bit,
+            "window": self.cli.window,
+            "thumbnails": self.cli.thumbnails,
+            "thumbnailSize": self.cli.thumbnail_size,
+            "contactSheet": self.cli.contact_sheet,
+            "exportNpy": self.cli.export_npy,
+            "npyRescale": self.cli.npy_rescale,
+        })
     }
 
-    fn process_file(&self, file_path: &Path) -> Result<DicomInstance> {
-        let obj = OpenFileOptions::new()
+    fn table_options(&self) -> TableOptions<'_> {
+        TableOptions {
+            delimiter: if matches!(self.cli.format, OutputFormat::Tsv) { b'\t' } else { b',' },
+            multi_value_delimiter: &self.cli.multi_value_delimiter,
+            columns: &self.columns,
+        }
+    }
+
+    /// Convert one file, or `None` when it does not match the --where predicates
+    /// With `--where`, the predicates run on the tags before Pixel Data, so skipped files never
+    /// load their pixels; matching files are read again in full for Pixel Data and `has_pixel_data`
+    fn process_file(&self, file_path: &Path) -> Result<Option<DicomInstance>> {
+        let open = |options: OpenFileOptions| options
             .open_file(file_path)
-            .with_context(|| format!("Failed to open DICOM file: {:?}", file_path))?;
+            .with_context(|| format!("Failed to open DICOM file: {:?}", file_path));
+
+        if !self.predicates.is_empty() {
+            let header = open(OpenFileOptions::new().read_until(tags::PIXEL_DATA))?;
+            if !self.predicates.iter().all(|predicate| predicate.matches(&header)) {
+                self.filtered_out.lock().unwrap().push(file_path.to_path_buf());
+                return Ok(None);
+            }
+        }
+        let obj = open(OpenFileOptions::new())?;
 
+        let transfer_syntax = obj.meta().transfer_syntax().to_string();
+        self.convert_dataset(&obj, Some(transfer_syntax), file_path.to_string_lossy().to_string()).map(Some)
+    }
+
+    /// Build the instance record of a data set read from a file or received over the network
+    fn convert_dataset(&self, obj: &dicom_object::InMemDicomObject, transfer_syntax: Option<String>, file_path: String) -> Result<DicomInstance> {
         let mut metadata = DicomMetadata {
             tags: HashMap::new(),
-            transfer_syntax: None,
+            transfer_syntax,
             sop_class_uid: None,
             file_meta_information: HashMap::new(),
         };
 
-        // Process file meta information
-        let meta = obj.meta();
-        metadata.transfer_syntax = Some(meta.transfer_syntax.to_string());
-
         // Process main dataset
         for element in obj.iter() {
             // Skip private tags if not requested
@@ -235,18 +1003,16 @@ impl DicomProcessor {
             }
 
             let tag_info = self.create_tag_info(element)?;
-            let tag_string = format!("({:04X},{:04X})", element.tag().group(), element.tag().element());
-            metadata.tags.insert(tag_string, tag_info);
+            metadata.tags.insert(tag_key(element.tag()), tag_info);
 
             // Capture SOP Class UID
-            if element.tag() == tags::SOP_CLASS_UID {
-                if let Ok(sop) = element.to_str() {
-                    metadata.sop_class_uid = Some(sop.to_string());
-                }
+            if element.tag() == tags::SOP_CLASS_UID
+                && let Ok(sop) = element.to_str() {
+                metadata.sop_class_uid = Some(sop.to_string());
             }
         }
 
-        let has_pixel_data = obj.element_opt(tags::PIXEL_DATA).is_ok();
+        let has_pixel_data = matches!(obj.element_opt(tags::PIXEL_DATA), Ok(Some(_)));
 
         let sop_instance_uid = obj.element_opt(tags::SOP_INSTANCE_UID)
             .ok()
@@ -261,28 +1027,94 @@ impl DicomProcessor {
             .and_then(|elem| elem.to_str().ok())
             .map(|s| s.to_string());
 
-        Ok(DicomInstance {
+        let mut instance = DicomInstance {
             sop_instance_uid,
             instance_number,
-            file_path: file_path.to_string_lossy().to_string(),
+            file_path,
             metadata,
             has_pixel_data,
-        })
+            pixels: None,
+        };
+
+        if has_pixel_data && (self.decodes_every_frame() || self.cli.export_png.is_some()) {
+            let transfer_syntax = instance.metadata.transfer_syntax.as_deref()
+                .context("Cannot decode Pixel Data without a transfer syntax")?;
+            let pixels = self.decode_pixels(obj, transfer_syntax, &instance).context("Failed to decode Pixel Data")?;
+            instance.pixels = Some(pixels);
+        }
+
+        Ok(instance)
+    }
+
+    fn decodes_every_frame(&self) -> bool {
+        self.cli.decode_pixels || self.cli.pixel_stats || self.cli.hash_pixels
+    }
+
+    /// Decode every frame for --decode-pixels, --pixel-stats and --hash-pixels, or the frames
+    /// to export for --export-png
+    fn decode_pixels(&self, obj: &dicom_object::InMemDicomObject, transfer_syntax: &str, instance: &DicomInstance) -> Result<PixelSummary> {
+        let pixels = PixelData::new(obj, transfer_syntax)?;
+        let mut summary = PixelSummary::new(&pixels);
+        let exported = match self.cli.png_frame {
+            Some(frame) if frame == 0 || frame > pixels.number_of_frames => {
+                bail!("--png-frame {} is out of range ({} frames)", frame, pixels.number_of_frames);
+            }
+            Some(frame) => frame - 1..frame,
+            None => 0..pixels.number_of_frames,
+        };
+        let mut hasher = self.cli.hash_pixels.then(Sha256::new);
+
+        for index in 0..pixels.number_of_frames {
+            let export_dir = self.cli.export_png.as_deref().filter(|_| exported.contains(&index));
+            if !self.decodes_every_frame() && export_dir.is_none() {
+                continue;
+            }
+            let (frame, outside_bits_stored) = pixels.frame_checked(index).with_context(|| format!("Frame {}", index + 1))?;
+
+            if let Some(hasher) = &mut hasher {
+                for sample in &frame {
+                    hasher.update(sample.to_le_bytes());
+                }
+            }
+
+            if self.cli.pixel_stats {
+                let statistics = stats::frame_statistics(obj, &pixels, index, &frame, outside_bits_stored, self.cli.histogram_bins)
+                    .with_context(|| format!("Frame {}", index + 1))?;
+                summary.statistics.push(statistics);
+            }
+
+            if let Some(dir) = export_dir {
+                let mut path = stow::storage_path(dir, instance).with_extension("png");
+                if pixels.number_of_frames > 1 {
+                    path.set_file_name(format!("{}_{}.png", sanitize_filename(&instance.sop_instance_uid), index + 1));
+                }
+                let bit_depth = if self.cli.png_16bit { 16 } else { 8 };
+                let image = render::render(obj, &pixels, index, &frame, self.window, bit_depth)
+                    .with_context(|| format!("Frame {}", index + 1))?;
+                render::write_png(&path, &image)?;
+                summary.png.push(path.to_string_lossy().to_string());
+            }
+        }
+
+        if let Some(hasher) = hasher {
+            summary.sha256 = Some(format!("{:x}", hasher.finalize()));
+            summary.stored_sha256 = Some(pixels::stored_sha256(obj)?);
+        }
+        Ok(summary)
     }
 
     fn create_tag_info(&self, element: &dicom_core::DataElement<dicom_object::InMemDicomObject>) -> Result<TagInfo> {
         let tag = element.tag();
         let vr = element.vr().to_string();
-        let tag_string = format!("({:04X},{:04X})", tag.group(), tag.element());
+        let tag_string = tag_key(tag);
         
         // Get human-readable name from dictionary based on format
-        let name = match self.cli.format {
-            OutputFormat::Basic | OutputFormat::Raw => None, // No names for basic/raw
-            OutputFormat::Comprehensive | OutputFormat::Medical => {
-                dicom_dictionary_std::StandardDataDictionary
-                    .by_tag(tag)
-                    .map(|entry| entry.alias.to_string())
-            }
+        let name = if self.cli.format.includes_tag_names() {
+            dicom_dictionary_std::StandardDataDictionary
+                .by_tag(tag)
+                .map(|entry| entry.alias.to_string())
+        } else {
+            None
         };
 
         let is_private = tag.group() % 2 == 1;
@@ -428,7 +1260,13 @@ impl DicomProcessor {
 }
 
 fn collect_dicom_files(input: &Path, max_depth: usize, verbose: bool) -> Result<Vec<PathBuf>> {
+    Ok(collect_input_files(input, max_depth, verbose)?.0)
+}
+
+/// Input files, and the `archive!/entry` names of those extracted from a ZIP archive
+fn collect_input_files(input: &Path, max_depth: usize, verbose: bool) -> Result<(Vec<PathBuf>, HashMap<PathBuf, String>)> {
     let mut files = Vec::new();
+    let mut archive_entries = HashMap::new();
 
     if input.is_file() {
         if let Some(ext) = input.extension() {
@@ -437,7 +1275,10 @@ fn collect_dicom_files(input: &Path, max_depth: usize, verbose: bool) -> Result<
                 if verbose {
                     println!("📦 Extracting ZIP archive...");
                 }
-                files.extend(extract_zip_files(input)?);
+                for (file, entry) in extract_zip_files(input)? {
+                    files.push(file.clone());
+                    archive_entries.insert(file, entry);
+                }
             } else {
                 files.push(input.to_path_buf());
             }
@@ -462,10 +1303,11 @@ fn collect_dicom_files(input: &Path, max_depth: usize, verbose: bool) -> Result<
         bail!("Input path does not exist: {:?}", input);
     }
 
-    Ok(files)
+    Ok((files, archive_entries))
 }
 
-fn extract_zip_files(zip_path: &Path) -> Result<Vec<PathBuf>> {
+fn extract_zip_files(zip_path: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let archive_path = fs::canonicalize(zip_path).unwrap_or_else(|_| zip_path.to_path_buf());
     let file = File::open(zip_path)?;
     let mut archive = ZipArchive::new(BufReader::new(file))?;
     let mut extracted_files = Vec::new();
@@ -486,7 +1328,7 @@ fn extract_zip_files(zip_path: &Path) -> Result<Vec<PathBuf>> {
             std::io::copy(&mut file, &mut output)?;
 
             if is_likely_dicom_file(&file_path) {
-                extracted_files.push(file_path);
+                extracted_files.push((file_path, format!("{}!/{}", archive_path.display(), file.name())));
             }
         }
     }
@@ -527,10 +1369,11 @@ fn process_files_sequential(
         }
 
         match processor.process_file(&file) {
-            Ok(instance) => results.push(instance),
+            Ok(Some(instance)) => results.push(instance),
+            Ok(None) => {}
             Err(e) => {
                 if processor.cli.verbose {
-                    eprintln!("❌ Failed to process {:?}: {}", file, e);
+                    eprintln!("❌ Failed to process {:?}: {:#}", file, e);
                 }
             }
         }
@@ -556,10 +1399,10 @@ fn process_files_parallel(
                 pb.inc(1);
             }
             match result {
-                Ok(instance) => Some(instance),
+                Ok(instance) => instance,
                 Err(e) => {
                     if processor.cli.verbose {
-                        eprintln!("❌ Failed to process {:?}: {}", file, e);
+                        eprintln!("❌ Failed to process {:?}: {:#}", file, e);
                     }
                     None
                 }
@@ -570,17 +1413,12 @@ fn process_files_parallel(
     Ok(results)
 }
 
-fn organize_by_hierarchy(
-    results: &[DicomInstance], 
-    output_dir: &Path, 
-    processor: &DicomProcessor
-) -> Result<()> {
+fn build_studies(results: &[DicomInstance]) -> HashMap<String, DicomStudy> {
     let mut studies: HashMap<String, DicomStudy> = HashMap::new();
 
     for instance in results {
-        let study_uid = get_tag_value(&instance.metadata.tags, tags::STUDY_INSTANCE_UID)
-            .unwrap_or_else(|| "unknown_study".to_string());
-        
+        let study_uid = study_uid(instance);
+
         let series_uid = get_tag_value(&instance.metadata.tags, tags::SERIES_INSTANCE_UID)
             .unwrap_or_else(|| "unknown_series".to_string());
 
@@ -618,6 +1456,10 @@ fn organize_by_hierarchy(
                 series_number: get_tag_value(&instance.metadata.tags, tags::SERIES_NUMBER),
                 series_description: get_tag_value(&instance.metadata.tags, tags::SERIES_DESCRIPTION),
                 modality: get_tag_value(&instance.metadata.tags, tags::MODALITY),
+                common_tags: HashMap::new(),
+                geometry: None,
+                thumbnails: None,
+                volume: None,
                 instances: Vec::new(),
             }
         });
@@ -625,31 +1467,265 @@ fn organize_by_hierarchy(
         series.instances.push(instance.clone());
     }
 
-    // Save organized studies
-    for (study_uid, study) in studies {
-        let study_dir = output_dir.join(format!("study_{}", sanitize_filename(&study_uid)));
-        fs::create_dir_all(&study_dir)?;
+    for series in studies.values_mut().flat_map(|s| s.series.values_mut()) {
+        series.geometry = geometry::sort_and_analyze(&mut series.instances);
+    }
 
-        let study_output = match processor.cli.format {
-            OutputFormat::Basic => create_basic_study_output(&study),
-            OutputFormat::Medical => create_medical_study_output(&study),
-            OutputFormat::Raw => create_raw_study_output(&study),
-            OutputFormat::Comprehensive => serde_json::to_value(&study)?,
-        };
+    studies
+}
 
-        let json_content = if processor.cli.pretty {
-            serde_json::to_string_pretty(&study_output)?
-        } else {
-            serde_json::to_string(&study_output)?
+/// With `only_studies`, output paths are still claimed for every study but only
+/// the listed studies are written
+fn organize_by_hierarchy(
+    results: &[DicomInstance], 
+    output_dir: &Path, 
+    processor: &DicomProcessor,
+    only_studies: Option<&HashSet<String>>,
+) -> Result<Vec<PathBuf>> {
+    let mut studies = build_studies(results);
+
+    // Side outputs of every series, including those of studies not rewritten
+    let mut side_outputs = Vec::new();
+    for (study_uid, study) in &studies {
+        for series_uid in study.series.keys() {
+            let series_dir = Path::new(&sanitize_filename(study_uid)).join(sanitize_filename(series_uid));
+            if processor.cli.thumbnails {
+                side_outputs.push(output_dir.join("thumbnails").join(&series_dir));
+            }
+            if processor.cli.export_npy {
+                side_outputs.push(output_dir.join("volumes").join(&series_dir));
+            }
+        }
+    }
+
+    if processor.cli.thumbnails {
+        let options = ThumbnailOptions {
+            size: processor.cli.thumbnail_size,
+            contact_sheet: processor.cli.contact_sheet,
+            window: processor.window,
         };
+        for (study_uid, study) in studies.iter_mut() {
+            if only_studies.is_some_and(|only| !only.contains(study_uid)) {
+                continue;
+            }
+            for series in study.series.values_mut() {
+                match thumbnail::write(output_dir, study_uid, series, &options) {
+                    Ok(thumbnails) => series.thumbnails = thumbnails,
+                    Err(e) => eprintln!("⚠️  No thumbnail for series {}: {:#}", series.series_instance_uid, e),
+                }
+            }
+        }
+    }
+
+    if processor.cli.export_npy {
+        for (study_uid, study) in studies.iter_mut() {
+            if only_studies.is_some_and(|only| !only.contains(study_uid)) {
+                continue;
+            }
+            for series in study.series.values_mut() {
+                match volume::write(output_dir, study_uid, series, processor.cli.npy_rescale) {
+                    Ok(volume) => series.volume = volume,
+                    Err(e) => eprintln!("⚠️  No volume for series {}: {:#}", series.series_instance_uid, e),
+                }
+            }
+        }
+    }
 
-        let json_file = study_dir.join("study.json");
-        fs::write(json_file, json_content)?;
+    let mut written: HashMap<PathBuf, String> = HashMap::new();
+    // First file of each SOP Instance UID, for re-sent copies of an instance
+    let mut first_copies: HashMap<String, String> = HashMap::new();
 
-        if processor.cli.verbose {
-            println!("📄 Study saved: {:?}/study.json", study_dir);
+    // Output paths and side outputs read the instance tags, so shared tags are only
+    // factored out right before each output is written
+    let factor = |series: &mut DicomSeries| if processor.cli.factor_series {
+        series.factor_common_tags();
+    };
+
+    for (study_uid, study) in studies.iter_mut() {
+        let skip = only_studies.is_some_and(|only| !only.contains(study_uid));
+        match processor.cli.split_level() {
+            SplitLevel::Single => unreachable!("single-file output is handled by save_results"),
+            SplitLevel::Study => {
+                let Some(first) = study.series.values().flat_map(|s| &s.instances).next() else {
+                    continue;
+                };
+                let output_file = processor.output_template.render(&first.metadata.tags);
+                let output_file = claim_output_path(&mut written, output_dir.join(output_file), study_uid)?;
+                if skip {
+                    continue;
+                }
+                study.series.values_mut().for_each(factor);
+                write_output(&output_file, &create_study_output(study, processor)?)?;
+
+                if processor.cli.verbose {
+                    println!("📄 Study saved: {:?}", output_file);
+                }
+            }
+            SplitLevel::Series => {
+                let series_uids: Vec<String> = study.series.keys().cloned().collect();
+                for series_uid in &series_uids {
+                    let series = &study.series[series_uid];
+                    let Some(first) = series.instances.first() else {
+                        continue;
+                    };
+                    if matches!(processor.cli.format, OutputFormat::Bids) && !bids::is_supported(series) {
+                        if processor.cli.verbose {
+                            println!("⏭️  Skipping non MR/PET series for BIDS: {}", series_uid);
+                        }
+                        continue;
+                    }
+
+                    let output_file = match processor.cli.format {
+                        OutputFormat::Bids if processor.cli.name_template.is_none() => bids::suggested_path(study, series),
+                        _ => processor.output_template.render(&first.metadata.tags),
+                    };
+                    let output_file = claim_output_path(&mut written, output_dir.join(output_file), series_uid)?;
+                    if skip {
+                        continue;
+                    }
+                    if let Some(series) = study.series.get_mut(series_uid) {
+                        factor(series);
+                    }
+                    write_output(&output_file, &create_series_output(study, &study.series[series_uid], processor)?)?;
+
+                    if processor.cli.verbose {
+                        println!("📄 Series saved: {:?}", output_file);
+                    }
+                }
+            }
+            SplitLevel::Instance => {
+                for instance in study.series.values().flat_map(|s| &s.instances) {
+                    if get_tag_value(&instance.metadata.tags, tags::SOP_INSTANCE_UID).is_some() {
+                        match first_copies.entry(instance.sop_instance_uid.clone()) {
+                            Entry::Occupied(first) => {
+                                if !skip {
+                                    eprintln!(
+                                        "⚠️  Skipping {}: SOP Instance UID {} was already written from {}",
+                                        instance.file_path, instance.sop_instance_uid, first.get()
+                                    );
+                                }
+                                continue;
+                            }
+                            Entry::Vacant(entry) => {
+                                entry.insert(instance.file_path.clone());
+                            }
+                        }
+                    }
+                    let output_file = processor.output_template.render(&instance.metadata.tags);
+                    let output_file = claim_output_path(&mut written, output_dir.join(output_file), &instance.file_path)?;
+                    if skip {
+                        continue;
+                    }
+                    let instance_output = match processor.cli.format {
+                        OutputFormat::Xml => xml::create_document(
+                            Path::new(&instance.file_path), &output_file, processor.cli.include_private
+                        )?,
+                        _ => create_output(std::slice::from_ref(instance), processor)?,
+                    };
+                    write_output(&output_file, &instance_output)?;
+
+                    if processor.cli.verbose {
+                        println!("📄 Instance saved: {:?}", output_file);
+                    }
+                }
+            }
+        }
+    }
+
+    side_outputs.extend(written.into_keys());
+    Ok(side_outputs)
+}
+
+/// Make sure no other study/series/instance was written to the same output path
+fn claim_output_path(written: &mut HashMap<PathBuf, String>, output_file: PathBuf, key: &str) -> Result<PathBuf> {
+    if let Some(previous) = written.insert(output_file.clone(), key.to_string())
+        && previous != key {
+        bail!(
+            "Output template maps both {} and {} to {:?}; add a more specific tag keyword to --name-template",
+            previous, key, output_file
+        );
+    }
+
+    Ok(output_file)
+}
+
+fn create_study_output(study: &DicomStudy, processor: &DicomProcessor) -> Result<String> {
+    let output = match processor.cli.format {
+        OutputFormat::Basic => create_basic_study_output(study),
+        OutputFormat::Medical => create_medical_study_output(study),
+        OutputFormat::Raw => create_raw_study_output(study),
+        OutputFormat::Comprehensive => serde_json::to_value(study)?,
+        OutputFormat::Fhir => fhir::create_bundle([study], processor.cli.fhir_endpoint.as_deref()),
+        OutputFormat::Hl7 => return Ok(hl7::create_message(study, processor.cli.hl7_message)),
+        OutputFormat::Csv | OutputFormat::Tsv => {
+            let mut series: Vec<&DicomSeries> = study.series.values().collect();
+            series.sort_by_key(|s| s.series_number.as_deref().and_then(|n| n.trim().parse::<i64>().ok()).unwrap_or(i64::MAX));
+            let rows = series.into_iter().flat_map(|s| s.instances.iter().map(move |i| (i, &s.common_tags)));
+            return table::create_table(rows, &processor.table_options());
+        }
+        OutputFormat::Bids => bail!("BIDS sidecars are written per series"),
+        OutputFormat::Xml => bail!("XML documents are written per instance"),
+        OutputFormat::Parquet => bail!("Parquet tables are written as a single file"),
+    };
+
+    to_json_string(&output, processor)
+}
+
+fn create_series_output(study: &DicomStudy, series: &DicomSeries, processor: &DicomProcessor) -> Result<String> {
+    if let OutputFormat::Bids = processor.cli.format {
+        return to_json_string(&bids::create_sidecar(series), processor);
+    }
+
+    let series_study = DicomStudy {
+        study_instance_uid: study.study_instance_uid.clone(),
+        study_date: study.study_date.clone(),
+        study_time: study.study_time.clone(),
+        study_description: study.study_description.clone(),
+        patient_info: study.patient_info.clone(),
+        series: HashMap::from([(series.series_instance_uid.clone(), series.clone())]),
+        processing_info: study.processing_info.clone(),
+    };
+    create_study_output(&series_study, processor)
+}
+
+fn create_output(results: &[DicomInstance], processor: &DicomProcessor) -> Result<String> {
+    let output = match processor.cli.format {
+        OutputFormat::Basic => create_basic_output(results),
+        OutputFormat::Comprehensive => create_comprehensive_output(results),
+        OutputFormat::Medical => create_medical_output(results),
+        OutputFormat::Raw => create_raw_output(results),
+        OutputFormat::Fhir => fhir::create_bundle(build_studies(results).values(), processor.cli.fhir_endpoint.as_deref()),
+        OutputFormat::Hl7 => {
+            return Ok(build_studies(results).values()
+                .map(|study| hl7::create_message(study, processor.cli.hl7_message))
+                .collect());
         }
+        OutputFormat::Csv | OutputFormat::Tsv => {
+            let no_common = HashMap::new();
+            return table::create_table(results.iter().map(|i| (i, &no_common)), &processor.table_options());
+        }
+        OutputFormat::Bids => bail!("BIDS sidecars are written per series"),
+        OutputFormat::Xml => bail!("XML documents are written per instance"),
+        OutputFormat::Parquet => bail!("Parquet tables are streamed while processing"),
+    };
+
+    to_json_string(&output, processor)
+}
+
+fn to_json_string(value: &serde_json::Value, processor: &DicomProcessor) -> Result<String> {
+    Ok(if processor.cli.pretty {
+        serde_json::to_string_pretty(value)?
+    } else {
+        serde_json::to_string(value)?
+    })
+}
+
+fn write_output(output_file: &Path, content: &str) -> Result<()> {
+    if let Some(parent) = output_file.parent() {
+        fs::create_dir_all(parent)?;
     }
+    fs::write(output_file, content)
+        .with_context(|| format!("Failed to write output file: {:?}", output_file))?;
 
     Ok(())
 }
@@ -658,30 +1734,178 @@ fn save_results(
     results: &[DicomInstance], 
     output_dir: &Path, 
     processor: &DicomProcessor
-) -> Result<()> {
-    let output_data = match processor.cli.format {
-        OutputFormat::Basic => create_basic_output(results),
-        OutputFormat::Comprehensive => create_comprehensive_output(results),
-        OutputFormat::Medical => create_medical_output(results),
-        OutputFormat::Raw => create_raw_output(results),
+) -> Result<PathBuf> {
+    let output_data = create_output(results, processor)?;
+
+    let output_file = match results.first() {
+        Some(first) => output_dir.join(processor.output_template.render(&first.metadata.tags)),
+        None => output_dir.join(SplitLevel::Single.default_template(processor.cli.format.extension())),
     };
+    write_output(&output_file, &output_data)?;
 
-    let json_content = if processor.cli.pretty {
-        serde_json::to_string_pretty(&output_data)?
+    if processor.cli.verbose {
+        println!("📄 Results saved to: {:?}", output_file);
+    }
+
+    Ok(output_file)
+}
+
+/// Write every output, or with `only_studies` only the per-study, series or instance
+/// files of those studies (single-file and Parquet outputs are always rewritten). Returns
+/// every file and side-output directory making up the output, written now or before.
+fn write_results(
+    results: &[DicomInstance],
+    output_dir: &Path,
+    processor: &DicomProcessor,
+    only_studies: Option<&HashSet<String>>,
+) -> Result<Vec<PathBuf>> {
+    let mut outputs = if matches!(processor.cli.format, OutputFormat::Parquet) {
+        let output_file = parquet_path(results, output_dir, processor);
+        let mut writer = columnar::ParquetTableWriter::create(&output_file, results, &processor.columns)?;
+        for chunk in results.chunks(columnar::ROW_GROUP_SIZE) {
+            writer.write_row_group(chunk)?;
+        }
+        finish_parquet(writer, &output_file, processor.cli.verbose)?;
+        vec![output_file]
+    } else if processor.cli.split_level() == SplitLevel::Single {
+        vec![save_results(results, output_dir, processor)?]
     } else {
-        serde_json::to_string(&output_data)?
+        organize_by_hierarchy(results, output_dir, processor, only_studies)?
     };
+    outputs.extend(write_duplicate_report(results, output_dir, processor)?);
+    Ok(outputs)
+}
 
-    let output_file = output_dir.join("dicom_data.json");
-    fs::write(&output_file, json_content)?;
+/// Remove outputs of a previous run that this run no longer produces, e.g. of studies
+/// whose files were removed, and the directories they leave empty
+fn remove_stale_outputs(output_dir: &Path, stale: &[PathBuf], verbose: bool) {
+    for path in stale {
+        let removed = if path.is_dir() { fs::remove_dir_all(path) } else { fs::remove_file(path) };
+        match removed {
+            Ok(()) => {
+                if verbose {
+                    println!("🗑️  Removed stale output: {:?}", path);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => eprintln!("⚠️  Failed to remove stale output {:?}: {}", path, e),
+        }
+        for parent in path.ancestors().skip(1).take_while(|parent| *parent != output_dir && parent.starts_with(output_dir)) {
+            if fs::remove_dir(parent).is_err() {
+                break;
+            }
+        }
+    }
+}
 
+fn write_duplicate_report(results: &[DicomInstance], output_dir: &Path, processor: &DicomProcessor) -> Result<Option<PathBuf>> {
+    if !processor.cli.hash_pixels {
+        return Ok(None);
+    }
+    let report = duplicates::DuplicateReport::new(results);
+    let path = report.write(output_dir)?;
     if processor.cli.verbose {
-        println!("📄 Results saved to: {:?}", output_file);
+        println!(
+            "🧬 {} images found under several SOP Instance UIDs, {} SOP Instance UIDs with different images: {:?}",
+            report.same_pixels.len(), report.same_sop_instance_uid.len(), path
+        );
     }
+    Ok(Some(path))
+}
+
+fn print_filtered_out(processor: &DicomProcessor) {
+    let filtered_out = processor.filtered_out.lock().unwrap().len();
+    if processor.cli.verbose && filtered_out > 0 {
+        println!("🔍 {} files did not match --where and were skipped", filtered_out);
+    }
+}
 
+fn update_index(index: &mut CatalogIndex, results: &[DicomInstance], processor: &DicomProcessor) -> Result<()> {
+    let stats = index.upsert(results)?;
+    if stats.skipped > 0 {
+        eprintln!("⚠️  {} instances without a SOP, Study or Series Instance UID were not indexed", stats.skipped);
+    }
+    if processor.cli.verbose {
+        println!("🗂️  Index updated: {} new, {} updated instances", stats.inserted, stats.updated);
+    }
     Ok(())
 }
 
+/// Process files in chunks and write each chunk as a Parquet row group,
+/// so memory stays bounded however many instances there are
+fn stream_parquet(
+    processor: &DicomProcessor,
+    files: Vec<PathBuf>,
+    output_dir: &Path,
+    progress_bar: &Option<ProgressBar>,
+    index: &mut Option<CatalogIndex>,
+) -> Result<()> {
+    let mut writer: Option<(columnar::ParquetTableWriter, PathBuf)> = None;
+
+    for chunk in files.chunks(columnar::ROW_GROUP_SIZE) {
+        let results = if processor.cli.parallel && chunk.len() > 1 {
+            process_files_parallel(processor, chunk.to_vec(), progress_bar)?
+        } else {
+            process_files_sequential(processor, chunk.to_vec(), progress_bar)?
+        };
+        if results.is_empty() {
+            continue;
+        }
+
+        let (writer, _) = match &mut writer {
+            Some(writer) => writer,
+            None => {
+                let output_file = parquet_path(&results, output_dir, processor);
+                let table_writer = columnar::ParquetTableWriter::create(&output_file, &results, &processor.columns)?;
+                writer.insert((table_writer, output_file))
+            }
+        };
+        writer.write_row_group(&results)?;
+        if let Some(index) = index {
+            update_index(index, &results, processor)?;
+        }
+    }
+
+    let (writer, output_file) = match writer {
+        Some(writer) => writer,
+        None => {
+            let output_file = parquet_path(&[], output_dir, processor);
+            (columnar::ParquetTableWriter::create(&output_file, &[], &processor.columns)?, output_file)
+        }
+    };
+    finish_parquet(writer, &output_file, processor.cli.verbose)
+}
+
+/// Parquet output path rendered from the first instance; without any, an empty table goes
+/// where a single-file output would
+fn parquet_path(results: &[DicomInstance], output_dir: &Path, processor: &DicomProcessor) -> PathBuf {
+    match results.first() {
+        Some(first) => output_dir.join(processor.output_template.render(&first.metadata.tags)),
+        None => output_dir.join(SplitLevel::Single.default_template(processor.cli.format.extension())),
+    }
+}
+
+/// Report values lost to the schema fixed by the first row group, and close the file
+fn finish_parquet(writer: columnar::ParquetTableWriter, output_file: &Path, verbose: bool) -> Result<()> {
+    if verbose {
+        println!("📄 {} rows saved to: {:?}", writer.rows_written, output_file);
+    }
+    if writer.truncated_values > 0 {
+        eprintln!(
+            "⚠️  {} multi-valued values cut to their first item; their columns were single-valued in the first row group",
+            writer.truncated_values
+        );
+    }
+    if !writer.dropped_columns.is_empty() {
+        let names: Vec<String> = writer.dropped_columns.iter().map(|key| table::column_name(key)).collect();
+        eprintln!(
+            "⚠️  {} tags first seen after the first row group have no column: {}; select them with --columns",
+            names.len(), names.join(", ")
+        );
+    }
+    writer.close()
+}
+
 fn create_basic_output(results: &[DicomInstance]) -> serde_json::Value {
     let basic_instances: Vec<_> = results.iter().map(|instance| {
         serde_json::json!({
@@ -760,8 +1984,12 @@ fn create_medical_output(results: &[DicomInstance]) -> serde_json::Value {
                 "rows": get_tag_value(&instance.metadata.tags, tags::ROWS),
                 "columns": get_tag_value(&instance.metadata.tags, tags::COLUMNS),
                 "bits_allocated": get_tag_value(&instance.metadata.tags, tags::BITS_ALLOCATED),
+                "bits_stored": get_tag_value(&instance.metadata.tags, tags::BITS_STORED),
                 "photometric_interpretation": get_tag_value(&instance.metadata.tags, tags::PHOTOMETRIC_INTERPRETATION),
                 "transfer_syntax": instance.metadata.transfer_syntax.clone(),
+                "png": instance.pixels.as_ref().map(|pixels| &pixels.png),
+                "pixel_statistics": instance.pixels.as_ref().map(|pixels| &pixels.statistics),
+                "pixel_sha256": instance.pixels.as_ref().and_then(|pixels| pixels.sha256.as_ref()),
             }
         })
     }).collect();
@@ -824,7 +2052,11 @@ fn create_medical_study_output(study: &DicomStudy) -> serde_json::Value {
             "description": series.series_description,
             "modality": series.modality,
             "instance_count": series.instances.len(),
-            "has_images": series.instances.iter().any(|i| i.has_pixel_data)
+            "has_images": series.instances.iter().any(|i| i.has_pixel_data),
+            "geometry": series.geometry,
+            "thumbnail": series.thumbnails.as_ref().map(|t| &t.thumbnail),
+            "contact_sheet": series.thumbnails.as_ref().and_then(|t| t.contact_sheet.as_ref()),
+            "volume": series.volume,
         })
     }).collect();
 
@@ -864,9 +2096,19 @@ fn create_raw_study_output(study: &DicomStudy) -> serde_json::Value {
     })
 }
 
+/// Key of a tag in `DicomMetadata::tags`, e.g. "(0010,0010)"
+fn tag_key(tag: Tag) -> String {
+    format!("({:04X},{:04X})", tag.group(), tag.element())
+}
+
+/// Study Instance UID used to group an instance, `unknown_study` when absent
+fn study_uid(instance: &DicomInstance) -> String {
+    get_tag_value(&instance.metadata.tags, tags::STUDY_INSTANCE_UID)
+        .unwrap_or_else(|| "unknown_study".to_string())
+}
+
 fn get_tag_value(tags: &HashMap<String, TagInfo>, tag: Tag) -> Option<String> {
-    let tag_string = format!("({:04X},{:04X})", tag.group(), tag.element());
-    tags.get(&tag_string)?.raw_value.clone()
+    tags.get(&tag_key(tag))?.raw_value.clone()
 }
 
 fn extract_patient_info(tags: &HashMap<String, TagInfo>) -> PatientInfo {
@@ -898,4 +2140,28 @@ fn print_summary(results: &[DicomInstance]) {
     for modality in &modalities {
         println!("     - {}", modality);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn output_template_renders_sanitized_tag_values() {
+        let template = OutputTemplate::parse("{PatientID}/{StudyDate}_{Modality}/{SOPInstanceUID}.json").unwrap();
+        let tags = testutil::tags(&[
+            (tags::PATIENT_ID, "LO", "A/B 1"),
+            (tags::STUDY_DATE, "DA", "20240102"),
+            (tags::SOP_INSTANCE_UID, "UI", "1.2.3"),
+        ]);
+        assert_eq!(template.render(&tags), PathBuf::from("A_B_1/20240102_unknown/1_2_3.json"));
+    }
+
+    #[test]
+    fn output_template_rejects_bad_templates() {
+        for template in ["../x.json", "/abs/{PatientID}.json", "{NotAKeyword}.json", "{PatientID.json", "x}.json", "a}b/{SOPInstanceUID}.json"] {
+            assert!(OutputTemplate::parse(template).is_err(), "{} was accepted", template);
+        }
+    }
+}
@@ -0,0 +1,328 @@
+//! Manifest of processed files kept in the output directory for `--incremental` runs.
+//! Files whose size and modification time (or, failing that, content hash) are unchanged
+//! are served from the cached instances instead of being parsed again. ZIP entries are keyed
+//! by archive and entry name, as they are extracted to a new directory on every run, and
+//! their cached instances are pointed at the current extraction. The output files of the
+//! last run are listed too, so outputs that are no longer produced can be removed.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::{DicomInstance, study_uid};
+
+/// Directory inside the output directory holding the manifest and the instance cache
+pub const MANIFEST_DIR: &str = ".dicom-json";
+const MANIFEST_FILE: &str = "manifest.json";
+const CACHE_FILE: &str = "instances.jsonl";
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Clone)]
+struct ManifestEntry {
+    size: u64,
+    mtime_ns: u64,
+    sha256: String,
+    /// `None` for files that did not match --where
+    sop_instance_uid: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManifestFile {
+    version: u32,
+    /// Options that change the converted instances; the cache is dropped when they differ
+    options: serde_json::Value,
+    files: BTreeMap<String, ManifestEntry>,
+    /// Output files and side-output directories, relative to the output directory
+    #[serde(default)]
+    outputs: BTreeSet<String>,
+}
+
+pub struct Manifest {
+    dir: PathBuf,
+    options: serde_json::Value,
+    files: BTreeMap<String, ManifestEntry>,
+    outputs: BTreeSet<String>,
+    /// Manifest keys of files extracted from ZIP archives
+    archive_entries: HashMap<PathBuf, String>,
+    /// Cached instances by manifest key
+    instances: HashMap<String, DicomInstance>,
+    /// Studies with new, changed or removed instances since the last run
+    affected_studies: HashSet<String>,
+    /// Set when a manifest existed but was written with different options
+    pub invalidated: bool,
+}
+
+/// Result of comparing the input files with the manifest
+pub struct IncrementalPlan {
+    pub unchanged: Vec<DicomInstance>,
+    pub to_process: Vec<PathBuf>,
+    pub unchanged_files: usize,
+    pub removed_files: usize,
+}
+
+impl Manifest {
+    /// Load the manifest and instance cache from `output_dir`, starting empty when there
+    /// is none or it was written with other options
+    pub fn load(output_dir: &Path, options: serde_json::Value) -> Result<Self> {
+        let dir = output_dir.join(MANIFEST_DIR);
+        let mut manifest = Self {
+            dir,
+            options,
+            files: BTreeMap::new(),
+            outputs: BTreeSet::new(),
+            archive_entries: HashMap::new(),
+            instances: HashMap::new(),
+            affected_studies: HashSet::new(),
+            invalidated: false,
+        };
+
+        let manifest_path = manifest.dir.join(MANIFEST_FILE);
+        if !manifest_path.exists() {
+            return Ok(manifest);
+        }
+        let stored: ManifestFile = serde_json::from_reader(BufReader::new(File::open(&manifest_path)?))
+            .with_context(|| format!("Failed to read manifest: {:?}", manifest_path))?;
+        if stored.version != VERSION {
+            manifest.invalidated = true;
+            return Ok(manifest);
+        }
+        // Outputs written with other options are still removed once no longer produced
+        manifest.outputs = stored.outputs;
+        if stored.options != manifest.options {
+            manifest.invalidated = true;
+            return Ok(manifest);
+        }
+
+        let cache_path = manifest.dir.join(CACHE_FILE);
+        if cache_path.exists() {
+            for line in BufReader::new(File::open(&cache_path)?).lines() {
+                let line = line?;
+                if line.is_empty() {
+                    continue;
+                }
+                let (key, instance): (String, DicomInstance) = serde_json::from_str(&line)
+                    .with_context(|| format!("Failed to read instance cache: {:?}", cache_path))?;
+                manifest.instances.insert(key, instance);
+            }
+        }
+        // Entries whose cached instance went missing are treated as new files
+        manifest.files = stored.files.into_iter()
+            .filter(|(key, entry)| entry.sop_instance_uid.is_none() || manifest.instances.contains_key(key))
+            .collect();
+
+        Ok(manifest)
+    }
+
+    /// Key the files extracted from ZIP archives by their `archive!/entry` names
+    pub fn set_archive_entries(&mut self, archive_entries: HashMap<PathBuf, String>) {
+        self.archive_entries = archive_entries;
+    }
+
+    /// Split the input files into cached instances and files to convert. Files in the
+    /// manifest that are no longer part of the input are dropped from it.
+    pub fn plan(&mut self, files: Vec<PathBuf>) -> Result<IncrementalPlan> {
+        let mut plan = IncrementalPlan { unchanged: Vec::new(), to_process: Vec::new(), unchanged_files: 0, removed_files: 0 };
+        let mut seen = HashSet::new();
+
+        for file in files {
+            let key = self.key(&file);
+            seen.insert(key.clone());
+
+            if !self.is_unchanged(&file)? {
+                plan.to_process.push(file);
+                continue;
+            }
+
+            plan.unchanged_files += 1;
+            // ZIP entries are extracted to a new directory each run; outputs such as XML read the file again
+            if let Some(instance) = self.instances.get_mut(&key) {
+                instance.file_path = file.to_string_lossy().to_string();
+                plan.unchanged.push(instance.clone());
+            }
+        }
+
+        // Instances of files left to retry have no file entry
+        let removed: BTreeSet<String> = self.files.keys().chain(self.instances.keys())
+            .filter(|key| !seen.contains(*key))
+            .cloned()
+            .collect();
+        plan.removed_files = removed.len();
+        for key in removed {
+            self.forget(&key);
+        }
+
+        Ok(plan)
+    }
+
+    /// Whether the file is in the manifest with the same size and modification time or,
+    /// when only the time differs, the same content
+    pub fn is_unchanged(&mut self, file: &Path) -> Result<bool> {
+        let key = self.key(file);
+        let Some(entry) = self.files.get_mut(&key) else {
+            return Ok(false);
+        };
+        let (size, mtime_ns) = stat(file)?;
+        let unchanged = size == entry.size && (mtime_ns == entry.mtime_ns || {
+            // Touched or copied without modification: the content decides
+            sha256_file(file)? == entry.sha256
+        });
+        if unchanged {
+            entry.mtime_ns = mtime_ns;
+        }
+        Ok(unchanged)
+    }
+
+    /// Record the files converted in this run, `results` being the instances they produced
+    /// and `filtered_out` the ones that did not match --where. Files that failed are left
+    /// out so the next run retries them.
+    pub fn record(&mut self, processed: &[PathBuf], results: &[DicomInstance], filtered_out: &[PathBuf], parallel: bool) -> Result<()> {
+        let results: HashMap<&str, &DicomInstance> = results.iter().map(|i| (i.file_path.as_str(), i)).collect();
+        let filtered_out: HashSet<&PathBuf> = filtered_out.iter().collect();
+
+        let describe = |file: &PathBuf| -> Result<Option<(String, ManifestEntry, Option<DicomInstance>)>> {
+            let instance = results.get(file.to_string_lossy().as_ref()).copied();
+            if instance.is_none() && !filtered_out.contains(file) {
+                return Ok(None);
+            }
+            let (size, mtime_ns) = stat(file)?;
+            let entry = ManifestEntry {
+                size,
+                mtime_ns,
+                sha256: sha256_file(file)?,
+                sop_instance_uid: instance.map(|i| i.sop_instance_uid.clone()),
+            };
+            Ok(Some((self.key(file), entry, instance.cloned())))
+        };
+        let described: Vec<_> = if parallel {
+            processed.par_iter().map(describe).collect::<Result<_>>()?
+        } else {
+            processed.iter().map(describe).collect::<Result<_>>()?
+        };
+
+        for file in processed {
+            let key = self.key(file);
+            self.forget(&key);
+        }
+        for (key, entry, instance) in described.into_iter().flatten() {
+            if let Some(instance) = instance {
+                self.affected_studies.insert(study_uid(&instance));
+                self.instances.insert(key.clone(), instance);
+            }
+            self.files.insert(key, entry);
+        }
+
+        Ok(())
+    }
+
+    /// Keep the file's instance but convert the file again on the next run, e.g. when
+    /// forwarding it failed
+    pub fn retry(&mut self, file: &Path) {
+        let key = self.key(file);
+        self.files.remove(&key);
+    }
+
+    /// Move a recorded file and its instance to the key of `to`, e.g. when it is archived
+    pub fn rename(&mut self, from: &Path, to: &Path) {
+        let (from, to) = (self.key(from), manifest_key(to));
+        if let Some(entry) = self.files.remove(&from) {
+            self.files.insert(to.clone(), entry);
+        }
+        if let Some(mut instance) = self.instances.remove(&from) {
+            instance.file_path = to.clone();
+            self.instances.insert(to, instance);
+        }
+    }
+
+    /// Whether anything was added, changed or removed since the last run
+    pub fn has_changes(&self) -> bool {
+        !self.affected_studies.is_empty()
+    }
+
+    pub fn affected_studies(&self) -> &HashSet<String> {
+        &self.affected_studies
+    }
+
+    /// Affected studies so far, starting a new change set
+    pub fn take_affected_studies(&mut self) -> HashSet<String> {
+        std::mem::take(&mut self.affected_studies)
+    }
+
+    /// Every cached instance, i.e. the current content of the outputs
+    pub fn instances(&self) -> impl Iterator<Item = &DicomInstance> {
+        self.instances.values()
+    }
+
+    /// Replace the recorded outputs with the files and directories of this run, all below
+    /// `output_dir`; the previous ones no longer produced are returned for removal
+    pub fn replace_outputs(&mut self, output_dir: &Path, outputs: &[PathBuf]) -> Vec<PathBuf> {
+        let outputs: BTreeSet<String> = outputs.iter()
+            .map(|path| path.strip_prefix(output_dir).unwrap_or(path).to_string_lossy().to_string())
+            .collect();
+        let stale = self.outputs.difference(&outputs).map(|path| output_dir.join(path)).collect();
+        self.outputs = outputs;
+        stale
+    }
+
+    /// Write the manifest and instance cache, replacing the previous ones only once complete
+    pub fn save(&self) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        let cache_path = self.dir.join(CACHE_FILE);
+        let temp_path = cache_path.with_extension("jsonl.tmp");
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        for (key, instance) in &self.instances {
+            serde_json::to_writer(&mut writer, &(key, instance))?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&temp_path, &cache_path)?;
+
+        let manifest_path = self.dir.join(MANIFEST_FILE);
+        let temp_path = manifest_path.with_extension("json.tmp");
+        let stored = ManifestFile {
+            version: VERSION,
+            options: self.options.clone(),
+            files: self.files.clone(),
+            outputs: self.outputs.clone(),
+        };
+        serde_json::to_writer_pretty(BufWriter::new(File::create(&temp_path)?), &stored)?;
+        fs::rename(&temp_path, &manifest_path)?;
+
+        Ok(())
+    }
+
+    /// Drop a file from the manifest, marking the study of its cached instance as changed
+    fn forget(&mut self, key: &str) {
+        self.files.remove(key);
+        if let Some(instance) = self.instances.remove(key) {
+            self.affected_studies.insert(study_uid(&instance));
+        }
+    }
+
+    fn key(&self, file: &Path) -> String {
+        self.archive_entries.get(file).cloned().unwrap_or_else(|| manifest_key(file))
+    }
+}
+
+/// Absolute path, so runs started from different directories share entries
+fn manifest_key(file: &Path) -> String {
+    fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf()).to_string_lossy().to_string()
+}
+
+fn stat(file: &Path) -> Result<(u64, u64)> {
+    let metadata = fs::metadata(file).with_context(|| format!("Failed to read file metadata: {:?}", file))?;
+    let mtime_ns = metadata.modified()?.duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+    Ok((metadata.len(), mtime_ns))
+}
+
+fn sha256_file(file: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut BufReader::new(File::open(file)?), &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
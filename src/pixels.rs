@@ -0,0 +1,395 @@
+//! Pixel Data decoding for the features that work on the image itself: native and
+//! encapsulated (JPEG, JPEG-LS, JPEG 2000, RLE) frames to pixel-interleaved integer samples.
+
+use anyhow::{Context, Result, anyhow, bail};
+use dicom::encoding::TransferSyntaxIndex;
+use dicom::transfer_syntax::TransferSyntaxRegistry;
+use dicom_core::{PrimitiveValue, Tag, value::Value};
+use dicom_dictionary_std::tags;
+use dicom_object::InMemDicomObject;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::stats::FrameStatistics;
+use crate::{j2k, jpegls, rle};
+
+/// How the frames of a transfer syntax are stored
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    Native,
+    Jpeg,
+    JpegLossless,
+    JpegLs,
+    Jpeg2000,
+    Rle,
+}
+
+impl Codec {
+    fn for_transfer_syntax(uid: &str) -> Result<Self> {
+        Ok(match uid {
+            "1.2.840.10008.1.2" | "1.2.840.10008.1.2.1" | "1.2.840.10008.1.2.1.99" | "1.2.840.10008.1.2.2" => Codec::Native,
+            "1.2.840.10008.1.2.4.50" | "1.2.840.10008.1.2.4.51" => Codec::Jpeg,
+            "1.2.840.10008.1.2.4.57" | "1.2.840.10008.1.2.4.70" => Codec::JpegLossless,
+            "1.2.840.10008.1.2.4.80" | "1.2.840.10008.1.2.4.81" => Codec::JpegLs,
+            "1.2.840.10008.1.2.4.90" | "1.2.840.10008.1.2.4.91" => Codec::Jpeg2000,
+            "1.2.840.10008.1.2.5" => Codec::Rle,
+            _ => {
+                let name = TransferSyntaxRegistry.get(uid).map_or("an unknown transfer syntax", |ts| ts.name());
+                bail!("Pixel Data in {} ({}) cannot be decoded", name, uid);
+            }
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Native => "native",
+            Codec::Jpeg => "JPEG",
+            Codec::JpegLossless => "JPEG Lossless",
+            Codec::JpegLs => "JPEG-LS",
+            Codec::Jpeg2000 => "JPEG 2000",
+            Codec::Rle => "RLE",
+        }
+    }
+}
+
+enum Frames {
+    /// Little endian bytes of every frame
+    Native(Vec<u8>),
+    /// One compressed bit stream per frame
+    Encapsulated(Vec<Vec<u8>>),
+}
+
+/// Image Pixel attributes and the stored frames of one instance
+pub struct PixelData {
+    pub rows: usize,
+    pub columns: usize,
+    pub samples_per_pixel: usize,
+    pub bits_allocated: u16,
+    pub bits_stored: u16,
+    pub signed: bool,
+    /// Photometric Interpretation of the decoded frames: YBR data converted by the codec is RGB
+    pub photometric_interpretation: String,
+    pub codec: Codec,
+    pub number_of_frames: usize,
+    stored_photometric_interpretation: String,
+    planar: bool,
+    frames: Frames,
+}
+
+impl PixelData {
+    pub fn new(obj: &InMemDicomObject, transfer_syntax: &str) -> Result<Self> {
+        let codec = Codec::for_transfer_syntax(transfer_syntax.trim_end_matches(['\0', ' ']))?;
+        let element = obj.element_opt(tags::PIXEL_DATA).ok().flatten().context("No Pixel Data")?;
+
+        let rows = uint(obj, tags::ROWS).context("Missing Rows")? as usize;
+        let columns = uint(obj, tags::COLUMNS).context("Missing Columns")? as usize;
+        let bits_allocated = uint(obj, tags::BITS_ALLOCATED).context("Missing Bits Allocated")? as u16;
+        let bits_stored = uint(obj, tags::BITS_STORED).map_or(bits_allocated, |bits| bits as u16);
+        let samples_per_pixel = uint(obj, tags::SAMPLES_PER_PIXEL).unwrap_or(1) as usize;
+        let signed = uint(obj, tags::PIXEL_REPRESENTATION) == Some(1);
+        let planar = uint(obj, tags::PLANAR_CONFIGURATION) == Some(1);
+        let number_of_frames = uint(obj, tags::NUMBER_OF_FRAMES).unwrap_or(1).max(1) as usize;
+        let stored_photometric_interpretation = obj.element_opt(tags::PHOTOMETRIC_INTERPRETATION).ok().flatten()
+            .and_then(|e| e.to_str().ok().map(|s| s.trim_end_matches(['\0', ' ']).to_string()))
+            .unwrap_or_else(|| "MONOCHROME2".to_string());
+
+        if rows == 0 || columns == 0 {
+            bail!("Rows and Columns must not be zero");
+        }
+        if !matches!(bits_allocated, 1 | 8 | 16 | 32) || bits_stored == 0 || bits_stored > bits_allocated {
+            bail!("Unsupported Bits Allocated {} / Bits Stored {}", bits_allocated, bits_stored);
+        }
+        if !matches!(samples_per_pixel, 1 | 3) {
+            bail!("Unsupported Samples per Pixel {}", samples_per_pixel);
+        }
+
+        let photometric = stored_photometric_interpretation.as_str();
+        let photometric_interpretation = match codec {
+            Codec::Jpeg if samples_per_pixel == 3 && photometric.starts_with("YBR") => "RGB",
+            Codec::Jpeg2000 if matches!(photometric, "YBR_RCT" | "YBR_ICT") => "RGB",
+            Codec::Native if photometric == "YBR_FULL_422" => "YBR_FULL",
+            _ => photometric,
+        }.to_string();
+
+        let frames = match element.value() {
+            Value::Primitive(value) => {
+                if codec != Codec::Native {
+                    bail!("Pixel Data is not encapsulated although the transfer syntax is {}", codec.name());
+                }
+                Frames::Native(native_bytes(value)?)
+            }
+            Value::PixelSequence(_) => {
+                if codec == Codec::Native {
+                    bail!("Pixel Data is encapsulated although the transfer syntax is native");
+                }
+                let fragments = element.value().fragments().unwrap_or_default();
+                let offsets = element.value().offset_table().unwrap_or_default();
+                Frames::Encapsulated(split_frames(fragments, offsets, number_of_frames)?)
+            }
+            Value::Sequence(_) => bail!("Pixel Data holds a sequence"),
+        };
+
+        let pixels = Self {
+            rows, columns, samples_per_pixel, bits_allocated, bits_stored, signed, photometric_interpretation,
+            codec, number_of_frames, stored_photometric_interpretation, planar, frames,
+        };
+        if let Frames::Native(data) = &pixels.frames {
+            let needed = (pixels.native_frame_bits() * number_of_frames).div_ceil(8);
+            if data.len() < needed {
+                bail!("Pixel Data holds {} bytes where {} frames need {}", data.len(), number_of_frames, needed);
+            }
+        }
+        Ok(pixels)
+    }
+
+    /// Decode one frame (0-based) to samples, pixel-interleaved, sign-extended where
+    /// Pixel Representation is signed
+    pub fn frame(&self, index: usize) -> Result<Vec<i32>> {
+        self.frame_checked(index).map(|(samples, _)| samples)
+    }
+
+    /// `frame`, with the number of samples whose stored value does not fit Bits Stored: unsigned
+    /// values above its range, signed values whose bits above it are not a sign extension
+    pub fn frame_checked(&self, index: usize) -> Result<(Vec<i32>, usize)> {
+        if index >= self.number_of_frames {
+            bail!("Frame {} out of range ({} frames)", index + 1, self.number_of_frames);
+        }
+        let data = match &self.frames {
+            Frames::Native(data) => return self.to_samples(self.native_frame(data, index)),
+            Frames::Encapsulated(frames) => &frames[index],
+        };
+        let pixels = self.rows * self.columns;
+        match self.codec {
+            Codec::Jpeg | Codec::JpegLossless => {
+                let (samples, width, height, components) = self.decode_jpeg(data)?;
+                self.check_size(width, height, components)?;
+                self.to_samples(samples)
+            }
+            Codec::JpegLs => {
+                let image = jpegls::decode(data)?;
+                self.check_size(image.width, image.height, image.components)?;
+                self.to_samples(image.samples)
+            }
+            Codec::Jpeg2000 => {
+                let image = j2k::decode(data)?;
+                self.check_size(image.width, image.height, image.components.len())?;
+                let mut samples = Vec::with_capacity(pixels * self.samples_per_pixel);
+                for pixel in 0..pixels {
+                    samples.extend(image.components.iter().map(|component| component[pixel]));
+                }
+                if image.signed {
+                    let (low, high) = (-(1i64 << (self.bits_stored - 1)), (1i64 << (self.bits_stored - 1)) - 1);
+                    let outside = samples.iter().filter(|&&value| !(low..=high).contains(&i64::from(value))).count();
+                    Ok((samples, outside))
+                } else {
+                    self.to_samples(samples.into_iter().map(|value| value as u32).collect())
+                }
+            }
+            Codec::Rle => {
+                let bytes = usize::from(self.bits_allocated.max(8) / 8);
+                self.to_samples(rle::decode(data, pixels, self.samples_per_pixel, bytes)?)
+            }
+            Codec::Native => unreachable!(),
+        }
+    }
+
+    /// Bits one native frame occupies; YBR_FULL_422 keeps two bytes per pixel
+    fn native_frame_bits(&self) -> usize {
+        let samples = if self.stored_photometric_interpretation == "YBR_FULL_422" { 2 } else { self.samples_per_pixel };
+        self.rows * self.columns * samples * usize::from(self.bits_allocated)
+    }
+
+    fn native_frame(&self, data: &[u8], index: usize) -> Vec<u32> {
+        let pixels = self.rows * self.columns;
+        let count = self.native_frame_bits() / usize::from(self.bits_allocated);
+        let start = index * count;
+        let mut samples: Vec<u32> = match self.bits_allocated {
+            1 => (start..start + count).map(|bit| u32::from(data[bit / 8] >> (bit % 8)) & 1).collect(),
+            8 => data[start..start + count].iter().map(|&b| u32::from(b)).collect(),
+            16 => data[start * 2..(start + count) * 2].chunks(2).map(|b| u32::from(u16::from_le_bytes([b[0], b[1]]))).collect(),
+            _ => data[start * 4..(start + count) * 4].chunks(4).map(|b| u32::from_le_bytes(b.try_into().unwrap())).collect(),
+        };
+
+        if self.stored_photometric_interpretation == "YBR_FULL_422" {
+            // Y1 Y2 Cb Cr per pair of pixels
+            samples = samples.chunks(4)
+                .flat_map(|quad| [quad[0], quad[2], quad[3], quad[1], quad[2], quad[3]])
+                .collect();
+        } else if self.planar && self.samples_per_pixel > 1 {
+            samples = (0..pixels * self.samples_per_pixel)
+                .map(|i| samples[(i % self.samples_per_pixel) * pixels + i / self.samples_per_pixel])
+                .collect();
+        }
+        samples
+    }
+
+    fn decode_jpeg(&self, data: &[u8]) -> Result<(Vec<u32>, usize, usize, usize)> {
+        let mut decoder = jpeg_decoder::Decoder::new(data);
+        if self.samples_per_pixel == 3 {
+            decoder.set_color_transform(if self.stored_photometric_interpretation.starts_with("YBR") {
+                jpeg_decoder::ColorTransform::YCbCr
+            } else {
+                jpeg_decoder::ColorTransform::RGB
+            });
+        }
+        let bytes = decoder.decode().map_err(|e| anyhow!("JPEG decoding failed: {}", e))?;
+        let info = decoder.info().context("JPEG without a frame header")?;
+        let components = match info.pixel_format {
+            jpeg_decoder::PixelFormat::L8 | jpeg_decoder::PixelFormat::L16 => 1,
+            jpeg_decoder::PixelFormat::RGB24 => 3,
+            jpeg_decoder::PixelFormat::CMYK32 => 4,
+        };
+        let (width, height) = (usize::from(info.width), usize::from(info.height));
+        // Samples above 8 bits come as native endian 16-bit values
+        let samples = if bytes.len() == width * height * components * 2 {
+            bytes.chunks(2).map(|b| u32::from(u16::from_ne_bytes([b[0], b[1]]))).collect()
+        } else {
+            bytes.into_iter().map(u32::from).collect()
+        };
+        Ok((samples, width, height, components))
+    }
+
+    fn check_size(&self, width: usize, height: usize, components: usize) -> Result<()> {
+        if (width, height, components) != (self.columns, self.rows, self.samples_per_pixel) {
+            bail!(
+                "{} frame is {}x{} with {} components where the data set says {}x{} with {}",
+                self.codec.name(), width, height, components, self.columns, self.rows, self.samples_per_pixel
+            );
+        }
+        Ok(())
+    }
+
+    /// Stored bit patterns to sample values, with the number that do not fit Bits Stored
+    fn to_samples(&self, raw: Vec<u32>) -> Result<(Vec<i32>, usize)> {
+        let stored_mask = (1u64 << self.bits_stored) - 1;
+        if self.signed {
+            let upper_mask = (((1u64 << self.bits_allocated) - 1) & !stored_mask) as u32;
+            let sign_bit = 1u32 << (self.bits_stored - 1);
+            let outside = raw.iter()
+                .filter(|&&value| {
+                    let upper = value & upper_mask;
+                    upper != 0 && (upper != upper_mask || value & sign_bit == 0)
+                })
+                .count();
+            let shift = 32 - u32::from(self.bits_stored);
+            return Ok((raw.into_iter().map(|value| ((value << shift) as i32) >> shift).collect(), outside));
+        }
+        if raw.iter().any(|&value| value > i32::MAX as u32) {
+            bail!("Unsigned pixel values of 2^31 and above are not supported");
+        }
+        let outside = raw.iter().filter(|&&value| u64::from(value) > stored_mask).count();
+        Ok((raw.into_iter().map(|value| value as i32).collect(), outside))
+    }
+}
+
+/// What `--decode-pixels`, `--pixel-stats` and `--export-png` found in and made of the Pixel Data
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PixelSummary {
+    pub codec: String,
+    pub frames: usize,
+    /// Photometric Interpretation of the decoded frames
+    pub photometric_interpretation: String,
+    /// Exported PNG files, in frame order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub png: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub statistics: Vec<FrameStatistics>,
+    /// SHA-256 of the decoded samples of every frame, as 32-bit little endian integers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// SHA-256 of the Pixel Data as stored, see `stored_sha256`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stored_sha256: Option<String>,
+}
+
+impl PixelSummary {
+    pub fn new(pixels: &PixelData) -> Self {
+        Self {
+            codec: pixels.codec.name().to_string(),
+            frames: pixels.number_of_frames,
+            photometric_interpretation: pixels.photometric_interpretation.clone(),
+            png: Vec::new(),
+            statistics: Vec::new(),
+            sha256: None,
+            stored_sha256: None,
+        }
+    }
+}
+
+/// SHA-256 of the Pixel Data as stored: the encapsulated fragments one after another without
+/// the Basic Offset Table, or the little endian bytes of native data
+pub fn stored_sha256(obj: &InMemDicomObject) -> Result<String> {
+    let element = obj.element_opt(tags::PIXEL_DATA).ok().flatten().context("No Pixel Data")?;
+    let mut hasher = Sha256::new();
+    match element.value() {
+        Value::Primitive(value) => hasher.update(native_bytes(value)?),
+        Value::PixelSequence(_) => {
+            for fragment in element.value().fragments().unwrap_or_default() {
+                hasher.update(fragment);
+            }
+        }
+        Value::Sequence(_) => bail!("Pixel Data holds a sequence"),
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn uint(obj: &InMemDicomObject, tag: Tag) -> Option<u32> {
+    obj.element_opt(tag).ok().flatten()?.to_int::<u32>().ok()
+}
+
+fn native_bytes(value: &PrimitiveValue) -> Result<Vec<u8>> {
+    Ok(match value {
+        PrimitiveValue::U8(values) => values.to_vec(),
+        PrimitiveValue::U16(values) => values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        PrimitiveValue::I16(values) => values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        PrimitiveValue::U32(values) => values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        PrimitiveValue::I32(values) => values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        PrimitiveValue::Empty => bail!("Pixel Data is empty"),
+        _ => bail!("Unexpected Pixel Data value"),
+    })
+}
+
+/// Group the fragments of encapsulated Pixel Data into frames
+fn split_frames(fragments: &[Vec<u8>], offsets: &[u32], frames: usize) -> Result<Vec<Vec<u8>>> {
+    if fragments.is_empty() {
+        bail!("Encapsulated Pixel Data without fragments");
+    }
+    if frames == 1 {
+        return Ok(vec![fragments.concat()]);
+    }
+    if fragments.len() == frames {
+        return Ok(fragments.to_vec());
+    }
+
+    // Basic Offset Table: offsets of each frame's first fragment item, from the first item
+    if offsets.len() == frames {
+        let mut starts = Vec::with_capacity(fragments.len());
+        let mut position = 0u64;
+        for fragment in fragments {
+            starts.push(position);
+            position += 8 + fragment.len() as u64;
+        }
+        return Ok((0..frames)
+            .map(|frame| {
+                let (begin, end) = (u64::from(offsets[frame]), offsets.get(frame + 1).map_or(u64::MAX, |&o| u64::from(o)));
+                fragments.iter().zip(&starts)
+                    .filter(|(_, start)| (begin..end).contains(*start))
+                    .flat_map(|(fragment, _)| fragment.iter().copied())
+                    .collect()
+            })
+            .collect());
+    }
+
+    // Otherwise a new frame starts with each JPEG (SOI) or JPEG 2000 (SOC) marker
+    let mut grouped: Vec<Vec<u8>> = Vec::new();
+    for fragment in fragments {
+        let starts_frame = fragment.starts_with(&[0xFF, 0xD8]) || fragment.starts_with(&[0xFF, 0x4F]);
+        match grouped.last_mut() {
+            Some(frame) if !starts_frame => frame.extend_from_slice(fragment),
+            _ => grouped.push(fragment.clone()),
+        }
+    }
+    if grouped.len() != frames {
+        bail!("Cannot tell {} frames apart in {} Pixel Data fragments", frames, fragments.len());
+    }
+    Ok(grouped)
+}
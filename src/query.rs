@@ -0,0 +1,535 @@
+//! `query` subcommand: QIDO-RS style attribute matching over a SQLite index or
+//! previously written comprehensive JSON output.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+use dicom_core::{VR, dictionary::DataDictionary};
+use dicom_dictionary_std::{StandardDataDictionary, tags};
+use rusqlite::{Connection, OpenFlags};
+use walkdir::WalkDir;
+use crate::{DicomInstance, DicomMetadata, DicomProcessor, DicomStudy, OutputFormat, SplitLevel, TagInfo, get_tag_value, table};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum QueryLevel {
+    Study,
+    Series,
+    Instance,
+}
+
+/// One `Keyword=value` match key
+pub struct AttributeFilter {
+    /// Tag key of the matched attribute
+    pub key: String,
+    /// Dictionary keyword, the `tags.keyword` column of an index
+    keyword: Option<&'static str>,
+    matcher: Matcher,
+    /// Person names match case-insensitively
+    ignore_case: bool,
+}
+
+enum Matcher {
+    /// Empty value, matches any instance
+    Universal,
+    Single(String),
+    /// `*` and `?` wildcards
+    Wildcard(String),
+    /// Date/time range, either bound may be open
+    Range(Option<String>, Option<String>),
+    /// UID list matching, comma or backslash separated
+    List(Vec<String>),
+}
+
+impl AttributeFilter {
+    pub fn parse(filter: &str) -> Result<Self> {
+        let (keyword, value) = filter.split_once('=')
+            .with_context(|| format!("Query filters are KEYWORD=VALUE: {}", filter))?;
+        let tag = StandardDataDictionary.parse_tag(keyword.trim())
+            .with_context(|| format!("Unknown attribute in query: {}", keyword))?;
+        let entry = StandardDataDictionary.by_tag(tag).filter(|_| tag.group() % 2 == 0);
+        let vr = entry.map(|entry| entry.vr);
+        let value = value.trim();
+
+        let matcher = if value.is_empty() {
+            Matcher::Universal
+        } else if let Some(separator) = range_separator(vr, value) {
+            let bound = |b: &str| Some(b.trim().to_string()).filter(|b| !b.is_empty());
+            Matcher::Range(bound(&value[..separator]), bound(&value[separator + 1..]))
+        } else if vr == Some(VR::UI) && value.contains([',', '\\']) {
+            Matcher::List(value.split([',', '\\']).map(|v| v.trim().to_string()).collect())
+        } else if value.contains(['*', '?']) {
+            Matcher::Wildcard(value.to_string())
+        } else {
+            Matcher::Single(value.to_string())
+        };
+
+        Ok(Self { key: crate::tag_key(tag), keyword: entry.map(|entry| entry.alias), matcher, ignore_case: vr == Some(VR::PN) })
+    }
+
+    /// SQL condition on the `tags` row `t` of an index, selecting a superset of the rows
+    /// `matches` accepts; `None` for universal matching. Ranges and UID lists pass every
+    /// multi-valued row, stored joined with `\`, on to `matches`.
+    fn sql_condition(&self, params: &mut Vec<String>) -> Option<String> {
+        if let Matcher::Universal = self.matcher {
+            return None;
+        }
+        let column = match self.keyword {
+            Some(keyword) => {
+                params.push(keyword.to_string());
+                "t.keyword = ?"
+            }
+            None => {
+                params.push(self.key.clone());
+                "t.tag = ?"
+            }
+        };
+        // Case-insensitive matching compares ASCII upper case, the only case SQLite folds
+        let folded = |pattern: &str| self.ignore_case.then(|| pattern.to_ascii_uppercase());
+        let value = match (&self.matcher, self.ignore_case) {
+            (Matcher::Universal, _) => unreachable!(),
+            (Matcher::Single(pattern) | Matcher::Wildcard(pattern), true) if !pattern.is_ascii() => return Some(column.to_string()),
+            (Matcher::Single(pattern), _) => {
+                params.push(folded(pattern).unwrap_or_else(|| pattern.clone()));
+                if self.ignore_case { "instr(upper(t.value), ?) > 0" } else { "instr(t.value, ?) > 0" }.to_string()
+            }
+            (Matcher::Wildcard(pattern), _) => {
+                let pattern = folded(pattern).unwrap_or_else(|| pattern.clone());
+                params.push(format!("*{}*", pattern.replace('[', "[[]")));
+                if self.ignore_case { "upper(t.value) GLOB ?" } else { "t.value GLOB ?" }.to_string()
+            }
+            (Matcher::Range(from, to), _) => {
+                let mut bounds = vec!["t.value <> ''".to_string()];
+                if let Some(from) = from {
+                    params.push(from.clone());
+                    bounds.push("t.value >= ?".to_string());
+                }
+                if let Some(to) = to {
+                    params.push(to.clone());
+                    params.push(to.clone());
+                    bounds.push("substr(t.value, 1, length(?)) <= ?".to_string());
+                }
+                format!("(instr(t.value, '\\') > 0 OR ({}))", bounds.join(" AND "))
+            }
+            (Matcher::List(uids), _) => {
+                params.extend(uids.iter().cloned());
+                format!("(instr(t.value, '\\') > 0 OR t.value IN ({}))", vec!["?"; uids.len()].join(", "))
+            }
+        };
+        Some(format!("{} AND {}", column, value))
+    }
+
+    /// Matches when any value of the attribute matches; absent attributes only match universally
+    fn matches(&self, instance: &DicomInstance) -> bool {
+        if let Matcher::Universal = self.matcher {
+            return true;
+        }
+        let Some(tag_info) = instance.metadata.tags.get(&self.key) else {
+            return false;
+        };
+
+        table::tag_values(tag_info).iter().any(|value| {
+            let value = value.trim();
+            let (value, pattern) = match &self.matcher {
+                Matcher::Single(pattern) | Matcher::Wildcard(pattern) if self.ignore_case => {
+                    (value.to_uppercase(), pattern.to_uppercase())
+                }
+                Matcher::Single(pattern) | Matcher::Wildcard(pattern) => (value.to_string(), pattern.clone()),
+                Matcher::Range(from, to) => {
+                    return !value.is_empty()
+                        && from.as_ref().is_none_or(|from| value >= from.as_str())
+                        // Compare the upper bound at its own precision, so 1300 includes 130059
+                        && to.as_ref().is_none_or(|to| value.get(..to.len()).unwrap_or(value) <= to.as_str());
+                }
+                Matcher::List(uids) => return uids.iter().any(|uid| uid == value),
+                Matcher::Universal => return true,
+            };
+            match self.matcher {
+                Matcher::Wildcard(_) => {
+                    wildcard_match(&pattern.chars().collect::<Vec<_>>(), &value.chars().collect::<Vec<_>>())
+                }
+                _ => value == pattern,
+            }
+        })
+    }
+}
+
+/// Position of the `-` separating a date or time range. Date-time bounds may carry `-hhmm`
+/// UTC offsets: `20200101120000-0500` is one value, `2020-2021` a range.
+pub fn range_separator(vr: Option<VR>, value: &str) -> Option<usize> {
+    match vr {
+        Some(VR::DA | VR::TM) => value.find('-'),
+        Some(VR::DT) => dt_range_separator(value),
+        _ => None,
+    }
+}
+
+fn dt_range_separator(value: &str) -> Option<usize> {
+    let is_offset = |index: usize| {
+        let digits = value.get(index + 1..index + 5).filter(|digits| digits.bytes().all(|b| b.is_ascii_digit()));
+        let ends = matches!(value.as_bytes().get(index + 5), None | Some(b'-'));
+        index > 0 && ends && digits.is_some_and(|digits| &digits[..2] <= "14" && &digits[2..] < "60")
+    };
+    value.match_indices('-').map(|(index, _)| index).find(|&index| !is_offset(index))
+}
+
+/// Load the source, select matching instances and write them like converted files.
+/// Without --output, single-file formats are printed to stdout.
+pub fn run(processor: &DicomProcessor, source: &Path, filters: &[String], level: QueryLevel) -> Result<()> {
+    let mut limit = None;
+    let mut offset = 0;
+    let mut attribute_filters = Vec::new();
+    for filter in filters {
+        match filter.split_once('=') {
+            Some(("limit", value)) => limit = Some(value.trim().parse::<usize>().context("limit must be a number")?),
+            Some(("offset", value)) => offset = value.trim().parse::<usize>().context("offset must be a number")?,
+            _ => attribute_filters.push(AttributeFilter::parse(filter)?),
+        }
+    }
+
+    let instances = load_instances(source, &attribute_filters, level, processor.cli.format.includes_tag_names())?;
+    let total = instances.len();
+    let matched: Vec<DicomInstance> = select(&instances, &attribute_filters, level, offset, limit)
+        .into_iter()
+        .cloned()
+        .collect();
+
+    if processor.cli.verbose {
+        eprintln!("🔎 {} of {} instances match at {:?} level", matched.len(), total, level);
+    }
+
+    let to_stdout = processor.cli.output.is_none()
+        && processor.cli.split_level() == SplitLevel::Single
+        && !matches!(processor.cli.format, OutputFormat::Parquet);
+    if to_stdout {
+        let output = crate::create_output(&matched, processor)?;
+        print!("{}", output);
+        if !output.ends_with('\n') {
+            println!();
+        }
+        return Ok(());
+    }
+
+    let output_dir = processor.cli.output.clone()
+        .unwrap_or_else(|| std::env::current_dir().unwrap());
+    fs::create_dir_all(&output_dir)?;
+    crate::write_results(&matched, &output_dir, processor, None)?;
+    Ok(())
+}
+
+/// Instances of the studies, series or single instances for which every filter is
+/// matched by at least one of their instances. `offset` and `limit` count matches
+/// at the query level.
+pub fn select<'a>(
+    instances: &'a [DicomInstance],
+    filters: &[AttributeFilter],
+    level: QueryLevel,
+    offset: usize,
+    limit: Option<usize>,
+) -> Vec<&'a DicomInstance> {
+    let group_key = |index: usize, instance: &DicomInstance| match level {
+        QueryLevel::Study => get_tag_value(&instance.metadata.tags, tags::STUDY_INSTANCE_UID).unwrap_or_default(),
+        QueryLevel::Series => get_tag_value(&instance.metadata.tags, tags::SERIES_INSTANCE_UID).unwrap_or_default(),
+        QueryLevel::Instance => index.to_string(),
+    };
+
+    let mut groups: Vec<String> = Vec::new();
+    let mut matched_filters: HashMap<String, Vec<bool>> = HashMap::new();
+    for (index, instance) in instances.iter().enumerate() {
+        let key = group_key(index, instance);
+        let matched = matched_filters.entry(key.clone()).or_insert_with(|| {
+            groups.push(key.clone());
+            vec![false; filters.len()]
+        });
+        for (filter, matched) in filters.iter().zip(matched.iter_mut()) {
+            *matched = *matched || filter.matches(instance);
+        }
+    }
+
+    let selected: HashSet<String> = groups.into_iter()
+        .filter(|key| matched_filters[key].iter().all(|m| *m))
+        .skip(offset)
+        .take(limit.unwrap_or(usize::MAX))
+        .collect();
+
+    instances.iter().enumerate()
+        .filter(|(index, instance)| selected.contains(&group_key(*index, instance)))
+        .map(|(_, instance)| instance)
+        .collect()
+}
+
+/// Instances of the source; from an index, only those of studies, series or instances
+/// that may match every filter are read
+fn load_instances(source: &Path, filters: &[AttributeFilter], level: QueryLevel, with_names: bool) -> Result<Vec<DicomInstance>> {
+    let mut instances = if is_sqlite(source) {
+        load_index_matching(source, filters, level)?
+    } else {
+        load_json(source)?
+    };
+
+    // Instance-split output repeats instances that are also in study files
+    let mut seen = HashSet::new();
+    instances.retain(|instance| seen.insert(instance.sop_instance_uid.clone()));
+
+    for tag_info in instances.iter_mut().flat_map(|i| i.metadata.tags.values_mut()) {
+        tag_info.name = if with_names {
+            StandardDataDictionary.parse_tag(&tag_info.tag)
+                .filter(|tag| tag.group() % 2 == 0)
+                .and_then(|tag| StandardDataDictionary.by_tag(tag))
+                .map(|entry| entry.alias.to_string())
+        } else {
+            None
+        };
+    }
+
+    Ok(instances)
+}
+
+pub fn is_sqlite(path: &Path) -> bool {
+    let mut header = [0u8; 16];
+    path.is_file()
+        && fs::File::open(path).and_then(|mut f| f.read_exact(&mut header)).is_ok()
+        && &header == b"SQLite format 3\0"
+}
+
+pub fn load_index(path: &Path) -> Result<Vec<DicomInstance>> {
+    load_index_matching(path, &[], QueryLevel::Instance)
+}
+
+/// Instances whose study, series or own tag rows have a candidate match for every filter,
+/// found through the `tags_keyword_value` index instead of reading every tag row
+fn load_index_matching(path: &Path, filters: &[AttributeFilter], level: QueryLevel) -> Result<Vec<DicomInstance>> {
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("Failed to open index database: {:?}", path))?;
+
+    let group = |alias: &str| match level {
+        QueryLevel::Study => format!("s{}.study_instance_uid", alias),
+        QueryLevel::Series => format!("i{}.series_instance_uid", alias),
+        QueryLevel::Instance => format!("i{}.sop_instance_uid", alias),
+    };
+    let mut params = Vec::new();
+    let conditions: Vec<String> = filters.iter()
+        .filter_map(|filter| filter.sql_condition(&mut params))
+        .map(|condition| format!(
+            "{} IN (SELECT {} FROM tags t
+                    JOIN instances i2 ON i2.sop_instance_uid = t.sop_instance_uid
+                    JOIN series s2 ON s2.series_instance_uid = i2.series_instance_uid
+                    WHERE {})",
+            group(""), group("2"), condition,
+        ))
+        .collect();
+    let selected = format!(
+        "FROM instances i JOIN series s ON s.series_instance_uid = i.series_instance_uid WHERE {}",
+        if conditions.is_empty() { "1".to_string() } else { conditions.join(" AND ") },
+    );
+
+    let mut statement = connection.prepare(&format!(
+        "SELECT i.sop_instance_uid, i.instance_number, i.file_path, i.transfer_syntax, i.sop_class_uid, i.has_pixel_data
+         {} ORDER BY i.rowid",
+        selected,
+    ))?;
+    let mut instances: Vec<DicomInstance> = statement.query_map(rusqlite::params_from_iter(&params), |row| {
+        Ok(DicomInstance {
+            sop_instance_uid: row.get(0)?,
+            instance_number: row.get::<_, Option<i64>>(1)?.map(|n| n.to_string()),
+            file_path: row.get(2)?,
+            metadata: DicomMetadata {
+                tags: HashMap::new(),
+                transfer_syntax: row.get(3)?,
+                sop_class_uid: row.get(4)?,
+                file_meta_information: HashMap::new(),
+            },
+            has_pixel_data: row.get(5)?,
+            pixels: None,
+        })
+    })?.collect::<rusqlite::Result<_>>()?;
+
+    let positions: HashMap<String, usize> = instances.iter().enumerate()
+        .map(|(index, instance)| (instance.sop_instance_uid.clone(), index))
+        .collect();
+
+    let mut statement = connection.prepare(&format!(
+        "SELECT t.sop_instance_uid, t.tag, t.vr, t.value FROM tags t WHERE t.sop_instance_uid IN (SELECT i.sop_instance_uid {})",
+        selected,
+    ))?;
+    let mut rows = statement.query(rusqlite::params_from_iter(&params))?;
+    while let Some(row) = rows.next()? {
+        let sop_instance_uid: String = row.get(0)?;
+        let Some(&position) = positions.get(&sop_instance_uid) else {
+            continue;
+        };
+        let tag: String = row.get(1)?;
+        let raw_value: Option<String> = row.get(3)?;
+        let value = match &raw_value {
+            Some(raw) if raw.contains('\\') => raw.split('\\').map(|v| serde_json::Value::String(v.to_string())).collect(),
+            Some(raw) => serde_json::Value::String(raw.clone()),
+            None => serde_json::Value::Null,
+        };
+        let is_private = u16::from_str_radix(tag.get(1..5).unwrap_or_default(), 16).is_ok_and(|group| group % 2 == 1);
+
+        instances[position].metadata.tags.insert(tag.clone(), TagInfo {
+            tag,
+            vr: row.get(2)?,
+            name: None,
+            value,
+            raw_value,
+            is_private,
+        });
+    }
+
+    Ok(instances)
+}
+
+/// Instances from comprehensive output: `instances` arrays of single-file and
+/// instance-split output, and serialized studies of study/series-split output
+fn load_json(source: &Path) -> Result<Vec<DicomInstance>> {
+    let files: Vec<_> = if source.is_dir() {
+        WalkDir::new(source).into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file() && entry.path().extension().is_some_and(|e| e == "json"))
+            .map(|entry| entry.into_path())
+            .collect()
+    } else if source.is_file() {
+        vec![source.to_path_buf()]
+    } else {
+        bail!("Query source not found: {:?}", source);
+    };
+
+    let mut instances = Vec::new();
+    for file in files {
+        let content = fs::read_to_string(&file)
+            .with_context(|| format!("Failed to read {:?}", file))?;
+        let Ok(value) = serde_json::from_str::<serde_json::Value>(&content) else {
+            continue;
+        };
+
+        if let Some(items) = value.get("instances").and_then(|i| i.as_array()) {
+            let parsed: Option<Vec<DicomInstance>> = items.iter()
+                .map(|item| serde_json::from_value(item.clone()).ok())
+                .collect();
+            instances.extend(parsed.unwrap_or_default());
+        } else if let Ok(study) = serde_json::from_value::<DicomStudy>(value) {
+            for series in study.series.into_values() {
+                for mut instance in series.instances {
+                    for (key, tag_info) in &series.common_tags {
+                        instance.metadata.tags.entry(key.clone()).or_insert_with(|| tag_info.clone());
+                    }
+                    instances.push(instance);
+                }
+            }
+        }
+    }
+
+    if instances.is_empty() {
+        bail!("No instances found in {:?}; query an --index database or JSON written with --format comprehensive", source);
+    }
+    Ok(instances)
+}
+
+/// DICOM wildcard matching: `*` matches any sequence, `?` any single character
+pub fn wildcard_match(pattern: &[char], value: &[char]) -> bool {
+    let (mut p, mut v) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while v < value.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == value[v]) {
+            p += 1;
+            v += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, v));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            v = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::CatalogIndex;
+    use crate::testutil;
+
+    fn filters(filters: &[&str]) -> Vec<AttributeFilter> {
+        filters.iter().map(|filter| AttributeFilter::parse(filter).unwrap()).collect()
+    }
+
+    fn is_single(filter: &str, expected: &str) -> bool {
+        matches!(&filters(&[filter])[0].matcher, Matcher::Single(value) if value == expected)
+    }
+
+    fn range(filter: &str) -> (Option<String>, Option<String>) {
+        match &filters(&[filter])[0].matcher {
+            Matcher::Range(from, to) => (from.clone(), to.clone()),
+            _ => panic!("{} is not a range", filter),
+        }
+    }
+
+    #[test]
+    fn date_time_offsets_are_not_range_separators() {
+        let some = |value: &str| Some(value.to_string());
+        assert!(is_single("AcquisitionDateTime=20200101120000-0500", "20200101120000-0500"));
+        assert!(is_single("AcquisitionDateTime=20200101120000+0100", "20200101120000+0100"));
+        assert_eq!(range("AcquisitionDateTime=2020-2021"), (some("2020"), some("2021")));
+        assert_eq!(range("AcquisitionDateTime=20200101120000-0500-20200102"), (some("20200101120000-0500"), some("20200102")));
+        assert_eq!(range("AcquisitionDateTime=20200101-20200102120000-0500"), (some("20200101"), some("20200102120000-0500")));
+        assert_eq!(range("AcquisitionDateTime=-20200102"), (None, some("20200102")));
+        assert_eq!(range("AcquisitionDateTime=20200101-"), (some("20200101"), None));
+        assert_eq!(range("StudyDate=20200101-20201231"), (some("20200101"), some("20201231")));
+        assert!(is_single("PatientID=AB-1234", "AB-1234"));
+    }
+
+    #[test]
+    fn index_queries_read_only_candidate_instances() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("catalog.db");
+        let instance = |sop_uid: &str, study_uid: &str, entries: &[(dicom_core::Tag, &str, &str)]| {
+            let mut entries = entries.to_vec();
+            entries.extend([
+                (tags::SOP_INSTANCE_UID, "UI", sop_uid),
+                (tags::STUDY_INSTANCE_UID, "UI", study_uid),
+                (tags::SERIES_INSTANCE_UID, "UI", study_uid),
+            ]);
+            testutil::instance(sop_uid, &entries)
+        };
+        let instances = [
+            instance("1.1", "1", &[(tags::MODALITY, "CS", "CT"), (tags::PATIENT_NAME, "PN", "Doe^Jane"), (tags::STUDY_DATE, "DA", "20200105")]),
+            instance("1.2", "1", &[(tags::MODALITY, "CS", "SR"), (tags::IMAGE_TYPE, "CS", "ORIGINAL\\PRIMARY")]),
+            instance("2.1", "2", &[(tags::MODALITY, "CS", "MR"), (tags::PATIENT_NAME, "PN", "Roe^Rick"), (tags::STUDY_DATE, "DA", "20210105")]),
+            instance("3.1", "3", &[(tags::MODALITY, "CS", "CT"), (tags::PATIENT_NAME, "PN", "DOE^JOHN"), (tags::IMAGE_TYPE, "CS", "DERIVED\\SECONDARY")]),
+        ];
+        CatalogIndex::open(&path).unwrap().upsert(&instances).unwrap();
+        let all = load_index(&path).unwrap();
+        assert_eq!(all.len(), 4);
+
+        let cases: &[(&[&str], QueryLevel, &[&str])] = &[
+            (&["Modality=CT"], QueryLevel::Instance, &["1.1", "3.1"]),
+            (&["Modality=CT", "ImageType=PRIMARY"], QueryLevel::Study, &["1.1", "1.2"]),
+            (&["Modality=CT", "ImageType=PRIMARY"], QueryLevel::Instance, &[]),
+            (&["PatientName=doe*"], QueryLevel::Instance, &["1.1", "3.1"]),
+            (&["PatientName=*[x]*"], QueryLevel::Instance, &[]),
+            (&["StudyDate=-20201231"], QueryLevel::Instance, &["1.1"]),
+            (&["StudyDate=2021-"], QueryLevel::Series, &["2.1"]),
+            (&["SOPInstanceUID=1.2,2.1"], QueryLevel::Instance, &["1.2", "2.1"]),
+            (&["ImageType=DERIVED", "Modality="], QueryLevel::Instance, &["3.1"]),
+        ];
+        for (query, level, expected) in cases {
+            let filters = filters(query);
+            let candidates = load_index_matching(&path, &filters, *level).unwrap();
+            let uids = |instances: Vec<&DicomInstance>| instances.iter().map(|i| i.sop_instance_uid.clone()).collect::<Vec<_>>();
+            assert_eq!(uids(select(&candidates, &filters, *level, 0, None)), *expected, "{:?}", query);
+            assert_eq!(uids(select(&all, &filters, *level, 0, None)), *expected, "{:?}", query);
+            assert!(candidates.iter().all(|instance| instance.metadata.tags.len() >= 3));
+        }
+
+        // Pushed-down filters leave the tag rows of other instances unread
+        let candidates = load_index_matching(&path, &filters(&["Modality=MR"]), QueryLevel::Instance).unwrap();
+        assert_eq!(candidates.len(), 1);
+    }
+}
@@ -0,0 +1,347 @@
+//! Rendering of decoded frames for display (Modality LUT, VOI LUT or windowing,
+//! Photometric Interpretation) and PNG export.
+
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+use anyhow::{Context, Result, bail};
+use dicom_core::Tag;
+use dicom_dictionary_std::tags;
+use dicom_object::InMemDicomObject;
+use crate::pixels::PixelData;
+
+/// --window presets: name, center and width in Hounsfield units
+const PRESETS: &[(&str, f64, f64)] = &[
+    ("brain", 40.0, 80.0),
+    ("subdural", 75.0, 215.0),
+    ("soft-tissue", 40.0, 400.0),
+    ("mediastinum", 50.0, 350.0),
+    ("liver", 30.0, 150.0),
+    ("lung", -600.0, 1500.0),
+    ("bone", 400.0, 1800.0),
+];
+
+/// A window over modality values
+#[derive(Clone, Copy, Debug)]
+pub struct Window {
+    pub center: f64,
+    pub width: f64,
+}
+
+impl Window {
+    /// A preset name or "CENTER,WIDTH"
+    pub fn parse(spec: &str) -> Result<Self> {
+        let spec = spec.trim();
+        if let Some(&(_, center, width)) = PRESETS.iter().find(|(name, ..)| name.eq_ignore_ascii_case(spec)) {
+            return Ok(Self { center, width });
+        }
+        let names = PRESETS.iter().map(|(name, ..)| *name).collect::<Vec<_>>().join(", ");
+        let (center, width) = spec.split_once(',')
+            .with_context(|| format!("Unknown window \"{}\": use CENTER,WIDTH or one of {}", spec, names))?;
+        let center = center.trim().parse().with_context(|| format!("Invalid window center \"{}\"", center))?;
+        let width: f64 = width.trim().parse().with_context(|| format!("Invalid window width \"{}\"", width))?;
+        if width < 1.0 {
+            bail!("Window width must be at least 1");
+        }
+        Ok(Self { center, width })
+    }
+}
+
+/// A frame ready for display, samples scaled to the full range of `bit_depth`
+pub struct Rendered {
+    pub width: usize,
+    pub height: usize,
+    /// 1 for grayscale, 3 for RGB
+    pub channels: usize,
+    pub bit_depth: u8,
+    pub samples: Vec<u16>,
+}
+
+/// Render one decoded frame: grayscale through the modality and VOI transforms (`window`
+/// replacing the file's VOI), color converted to RGB
+pub fn render(obj: &InMemDicomObject, pixels: &PixelData, index: usize, frame: &[i32], window: Option<Window>, bit_depth: u8) -> Result<Rendered> {
+    let max = f64::from((1u32 << bit_depth) - 1);
+    let scale = |value: f64| (value.clamp(0.0, 1.0) * max).round() as u16;
+    let stored_max = f64::from((1u32 << pixels.bits_stored) - 1);
+
+    let (channels, samples): (usize, Vec<u16>) = match pixels.photometric_interpretation.as_str() {
+        photometric @ ("MONOCHROME1" | "MONOCHROME2") => {
+            let modality = ModalityLut::new(obj, pixels, index)?;
+            let values: Vec<f64> = frame.iter().map(|&value| modality.apply(value)).collect();
+            let voi = match window {
+                Some(window) => Voi::Window { window, function: VoiFunction::Linear },
+                None => Voi::new(obj, pixels, index, &values)?,
+            };
+            let invert = photometric == "MONOCHROME1";
+            (1, values.iter().map(|&value| {
+                let value = voi.apply(value);
+                scale(if invert { 1.0 - value } else { value })
+            }).collect())
+        }
+        "RGB" => (3, frame.iter().map(|&value| scale(f64::from(value) / stored_max)).collect()),
+        "YBR_FULL" => {
+            let half = f64::from(1u32 << (pixels.bits_stored - 1));
+            (3, frame.chunks(3).flat_map(|ybr| {
+                let (y, cb, cr) = (f64::from(ybr[0]), f64::from(ybr[1]) - half, f64::from(ybr[2]) - half);
+                [y + 1.402 * cr, y - 0.344_136 * cb - 0.714_136 * cr, y + 1.772 * cb]
+            }).map(|value| scale(value / stored_max)).collect())
+        }
+        "PALETTE COLOR" => {
+            if obj.element_opt(Tag(0x0028, 0x1221)).ok().flatten().is_some() {
+                bail!("Segmented palette color lookup tables are not supported");
+            }
+            let palette = [
+                (tags::RED_PALETTE_COLOR_LOOKUP_TABLE_DESCRIPTOR, tags::RED_PALETTE_COLOR_LOOKUP_TABLE_DATA),
+                (tags::GREEN_PALETTE_COLOR_LOOKUP_TABLE_DESCRIPTOR, tags::GREEN_PALETTE_COLOR_LOOKUP_TABLE_DATA),
+                (tags::BLUE_PALETTE_COLOR_LOOKUP_TABLE_DESCRIPTOR, tags::BLUE_PALETTE_COLOR_LOOKUP_TABLE_DATA),
+            ].map(|(descriptor, data)| Lut::read(obj, descriptor, data, pixels.signed)
+                .and_then(|lut| lut.context("Missing palette color lookup table")));
+            let palette = palette.into_iter().collect::<Result<Vec<_>>>()?;
+            (3, frame.iter().flat_map(|&value| palette.iter().map(move |lut| lut.lookup_normalized(value)))
+                .map(scale).collect())
+        }
+        photometric => bail!("Photometric Interpretation {} cannot be rendered", photometric),
+    };
+
+    if samples.len() != pixels.rows * pixels.columns * channels {
+        bail!("{} samples per pixel cannot be rendered as {}", pixels.samples_per_pixel, pixels.photometric_interpretation);
+    }
+    Ok(Rendered { width: pixels.columns, height: pixels.rows, channels, bit_depth, samples })
+}
+
+pub fn write_png(path: &Path, image: &Rendered) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let file = File::create(path).with_context(|| format!("Failed to create {:?}", path))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), image.width as u32, image.height as u32);
+    encoder.set_color(if image.channels == 3 { png::ColorType::Rgb } else { png::ColorType::Grayscale });
+    encoder.set_depth(if image.bit_depth == 16 { png::BitDepth::Sixteen } else { png::BitDepth::Eight });
+
+    let data: Vec<u8> = if image.bit_depth == 16 {
+        image.samples.iter().flat_map(|sample| sample.to_be_bytes()).collect()
+    } else {
+        image.samples.iter().map(|&sample| sample as u8).collect()
+    };
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish().with_context(|| format!("Failed to write {:?}", path))
+}
+
+/// Stored values to modality values (e.g. Hounsfield units): Modality LUT or Rescale Slope/Intercept
+pub enum ModalityLut {
+    Rescale { slope: f64, intercept: f64 },
+    Lut(Lut),
+}
+
+impl ModalityLut {
+    pub fn new(obj: &InMemDicomObject, pixels: &PixelData, index: usize) -> Result<Self> {
+        if let Some(item) = first_item(obj, tags::MODALITY_LUT_SEQUENCE)
+            && let Some(lut) = Lut::read(item, tags::LUT_DESCRIPTOR, tags::LUT_DATA, pixels.signed)? {
+            return Ok(ModalityLut::Lut(lut));
+        }
+        let item = functional_group(obj, index, tags::PIXEL_VALUE_TRANSFORMATION_SEQUENCE);
+        let float = |tag| item.element_opt(tag).ok().flatten().and_then(|e| e.to_float64().ok());
+        Ok(ModalityLut::Rescale {
+            slope: float(tags::RESCALE_SLOPE).unwrap_or(1.0),
+            intercept: float(tags::RESCALE_INTERCEPT).unwrap_or(0.0),
+        })
+    }
+
+    pub fn apply(&self, value: i32) -> f64 {
+        match self {
+            ModalityLut::Rescale { slope, intercept } => f64::from(value) * slope + intercept,
+            ModalityLut::Lut(lut) => f64::from(lut.lookup(value)),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum VoiFunction {
+    Linear,
+    LinearExact,
+    Sigmoid,
+}
+
+/// Modality values to display values in [0, 1]
+enum Voi {
+    Lut(Lut),
+    Window { window: Window, function: VoiFunction },
+}
+
+impl Voi {
+    /// The file's VOI LUT, else its first Window Center/Width, else the range of `values`
+    fn new(obj: &InMemDicomObject, pixels: &PixelData, index: usize, values: &[f64]) -> Result<Self> {
+        if let Some(item) = first_item(obj, tags::VOILUT_SEQUENCE)
+            && let Some(lut) = Lut::read(item, tags::LUT_DESCRIPTOR, tags::LUT_DATA, pixels.signed)? {
+            return Ok(Voi::Lut(lut));
+        }
+
+        let item = functional_group(obj, index, tags::FRAME_VOILUT_SEQUENCE);
+        let first = |tag| item.element_opt(tag).ok().flatten()
+            .and_then(|e| e.to_multi_float64().ok())
+            .and_then(|values| values.first().copied());
+        if let (Some(center), Some(width)) = (first(tags::WINDOW_CENTER), first(tags::WINDOW_WIDTH)) {
+            let function = match item.element_opt(tags::VOILUT_FUNCTION).ok().flatten().and_then(|e| e.to_str().ok()).as_deref().map(str::trim) {
+                Some("LINEAR_EXACT") => VoiFunction::LinearExact,
+                Some("SIGMOID") => VoiFunction::Sigmoid,
+                _ => VoiFunction::Linear,
+            };
+            return Ok(Voi::Window { window: Window { center, width }, function });
+        }
+
+        let (min, max) = values.iter().fold((f64::MAX, f64::MIN), |(min, max), &v| (min.min(v), max.max(v)));
+        let window = Window { center: (min + max) / 2.0, width: (max - min).max(1.0) };
+        Ok(Voi::Window { window, function: VoiFunction::LinearExact })
+    }
+
+    /// PS3.3 C.11.2.1.2 and C.11.2.1.3
+    fn apply(&self, value: f64) -> f64 {
+        let (center, width, function) = match self {
+            Voi::Lut(lut) => return lut.lookup_normalized(value.round() as i32),
+            Voi::Window { window, function } => (window.center, window.width, *function),
+        };
+        match function {
+            VoiFunction::Linear => {
+                let width = width.max(1.0);
+                if value <= center - 0.5 - (width - 1.0) / 2.0 {
+                    0.0
+                } else if value > center - 0.5 + (width - 1.0) / 2.0 {
+                    1.0
+                } else {
+                    (value - (center - 0.5)) / (width - 1.0) + 0.5
+                }
+            }
+            VoiFunction::LinearExact => ((value - center) / width + 0.5).clamp(0.0, 1.0),
+            VoiFunction::Sigmoid => 1.0 / (1.0 + (-4.0 * (value - center) / width).exp()),
+        }
+    }
+}
+
+/// A lookup table from a LUT Descriptor (entries, first mapped value, bits per entry) and its data
+pub struct Lut {
+    first: i32,
+    bits: u32,
+    data: Vec<u16>,
+}
+
+impl Lut {
+    /// `None` when the descriptor is absent; a first mapped value above 32767 is negative for signed input
+    fn read(item: &InMemDicomObject, descriptor: Tag, data: Tag, signed: bool) -> Result<Option<Self>> {
+        let Some(descriptor) = item.element_opt(descriptor).ok().flatten() else {
+            return Ok(None);
+        };
+        let values = descriptor.to_multi_int::<i32>().context("Invalid LUT Descriptor")?;
+        let [entries, first, bits] = values[..] else {
+            bail!("LUT Descriptor must have 3 values");
+        };
+        let entries = match entries & 0xFFFF {
+            0 => 65536,
+            entries => entries as usize,
+        };
+        let first = match first & 0xFFFF {
+            first if signed && first > 0x7FFF => first - 0x10000,
+            first => first,
+        };
+        if !(1..=16).contains(&bits) {
+            bail!("LUT Descriptor with {} bits per entry", bits);
+        }
+
+        let mut data: Vec<u16> = item.element_opt(data).ok().flatten()
+            .context("Missing LUT Data")?
+            .to_multi_int::<i32>().context("Invalid LUT Data")?
+            .into_iter().map(|value| (value & 0xFFFF) as u16)
+            .collect();
+        // 8-bit entries may come two to a 16-bit word
+        if bits == 8 && data.len() < entries && data.len() * 2 >= entries {
+            data = data.iter().flat_map(|word| [word & 0xFF, word >> 8]).collect();
+        }
+        if data.len() < entries {
+            bail!("LUT Data has {} entries where the descriptor says {}", data.len(), entries);
+        }
+        data.truncate(entries);
+        Ok(Some(Self { first, bits: bits as u32, data }))
+    }
+
+    fn lookup(&self, value: i32) -> u16 {
+        let index = (i64::from(value) - i64::from(self.first)).clamp(0, self.data.len() as i64 - 1);
+        self.data[index as usize]
+    }
+
+    fn lookup_normalized(&self, value: i32) -> f64 {
+        f64::from(self.lookup(value)) / f64::from((1u32 << self.bits) - 1)
+    }
+}
+
+fn first_item(obj: &InMemDicomObject, sequence: Tag) -> Option<&InMemDicomObject> {
+    obj.element_opt(sequence).ok().flatten()?.items()?.first()
+}
+
+/// The functional group item of `sequence` that applies to a frame of an enhanced multi-frame
+/// image, or the data set itself for other images
+fn functional_group(obj: &InMemDicomObject, index: usize, sequence: Tag) -> &InMemDicomObject {
+    let group = |groups: Tag, index: usize| {
+        let item = obj.element_opt(groups).ok().flatten()?.items()?.get(index)?;
+        first_item(item, sequence)
+    };
+    group(tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE, index)
+        .or_else(|| group(tags::SHARED_FUNCTIONAL_GROUPS_SEQUENCE, 0))
+        .unwrap_or(obj)
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom_core::{DataElement, PrimitiveValue, VR};
+    use super::*;
+
+    /// 2x2 signed 16-bit CT frame with a rescale intercept of -1024
+    fn ct(photometric: &str, stored: [i16; 4]) -> (InMemDicomObject, PixelData) {
+        let mut obj = InMemDicomObject::new_empty();
+        obj.put(DataElement::new(tags::PHOTOMETRIC_INTERPRETATION, VR::CS, PrimitiveValue::from(photometric)));
+        for (tag, value) in [(tags::ROWS, 2u16), (tags::COLUMNS, 2), (tags::BITS_ALLOCATED, 16), (tags::BITS_STORED, 16), (tags::PIXEL_REPRESENTATION, 1)] {
+            obj.put(DataElement::new(tag, VR::US, PrimitiveValue::from(value)));
+        }
+        obj.put(DataElement::new(tags::RESCALE_INTERCEPT, VR::DS, PrimitiveValue::from("-1024")));
+        obj.put(DataElement::new(tags::PIXEL_DATA, VR::OW, PrimitiveValue::I16(stored.into_iter().collect())));
+        let pixels = PixelData::new(&obj, "1.2.840.10008.1.2.1").unwrap();
+        (obj, pixels)
+    }
+
+    #[test]
+    fn windows_are_parsed_from_presets_and_center_width() {
+        let window = Window::parse(" Lung ").unwrap();
+        assert_eq!((window.center, window.width), (-600.0, 1500.0));
+        let window = Window::parse("40, 400.5").unwrap();
+        assert_eq!((window.center, window.width), (40.0, 400.5));
+
+        let error = Window::parse("liverish").unwrap_err().to_string();
+        assert!(error.contains("CENTER,WIDTH") && error.contains("soft-tissue"), "{}", error);
+        assert!(Window::parse("40,wide").is_err());
+        assert!(Window::parse("x,400").is_err());
+        assert!(Window::parse("40,0.5").is_err());
+    }
+
+    #[test]
+    fn frames_are_rescaled_windowed_and_inverted() {
+        // -1024, 0, 40 and 1000 HU through the brain window (40/80)
+        let (obj, pixels) = ct("MONOCHROME2", [0, 1024, 1064, 2024]);
+        let frame = pixels.frame(0).unwrap();
+        let rendered = render(&obj, &pixels, 0, &frame, Some(Window::parse("brain").unwrap()), 8).unwrap();
+        assert_eq!((rendered.width, rendered.height, rendered.channels), (2, 2, 1));
+        assert_eq!(rendered.samples, [0, 0, 129, 255]);
+
+        let (obj, pixels) = ct("MONOCHROME1", [0, 1024, 1064, 2024]);
+        let rendered = render(&obj, &pixels, 0, &frame, Some(Window::parse("brain").unwrap()), 8).unwrap();
+        assert_eq!(rendered.samples, [255, 255, 126, 0]);
+    }
+
+    #[test]
+    fn the_file_window_applies_without_an_override() {
+        let (mut obj, _) = ct("MONOCHROME2", [0, 1024, 1064, 2024]);
+        obj.put(DataElement::new(tags::WINDOW_CENTER, VR::DS, PrimitiveValue::from("500")));
+        obj.put(DataElement::new(tags::WINDOW_WIDTH, VR::DS, PrimitiveValue::from("1001")));
+        let pixels = PixelData::new(&obj, "1.2.840.10008.1.2.1").unwrap();
+        let rendered = render(&obj, &pixels, 0, &pixels.frame(0).unwrap(), None, 16).unwrap();
+        assert_eq!(rendered.samples, [0, 33, 2654, 65535]);
+    }
+}
@@ -0,0 +1,120 @@
+//! RLE Lossless (PS3.5 Annex G) frame decoder.
+
+use anyhow::{Result, bail};
+
+/// Decode one frame to sample values, pixel-interleaved. Each sample's bytes are stored
+/// in their own segment, most significant byte first.
+pub fn decode(data: &[u8], pixels: usize, samples_per_pixel: usize, bytes_per_sample: usize) -> Result<Vec<u32>> {
+    if data.len() < 64 {
+        bail!("RLE frame without a header");
+    }
+    let header: Vec<usize> = data[..64].chunks(4)
+        .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()) as usize)
+        .collect();
+    let segments = header[0];
+    if segments > 15 {
+        bail!("RLE frame header lists {} segments, at most 15 are allowed", segments);
+    }
+    if segments != samples_per_pixel * bytes_per_sample {
+        bail!("RLE frame has {} segments where {} were expected", segments, samples_per_pixel * bytes_per_sample);
+    }
+
+    let mut values = vec![0u32; pixels * samples_per_pixel];
+    for segment in 0..segments {
+        let start = header[segment + 1];
+        let end = if segment + 1 < segments { header[segment + 2] } else { data.len() };
+        if start < 64 || start > end || end > data.len() {
+            bail!("Invalid RLE segment offsets");
+        }
+        let bytes = unpack(&data[start..end], pixels)?;
+        let (sample, byte) = (segment / bytes_per_sample, segment % bytes_per_sample);
+        let shift = 8 * (bytes_per_sample - 1 - byte);
+        for (pixel, value) in bytes.into_iter().enumerate() {
+            values[pixel * samples_per_pixel + sample] |= u32::from(value) << shift;
+        }
+    }
+    Ok(values)
+}
+
+/// PackBits decoding of one segment
+fn unpack(segment: &[u8], expected: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(expected);
+    let mut pos = 0;
+    while pos < segment.len() && out.len() < expected {
+        let header = segment[pos] as i8;
+        pos += 1;
+        match header {
+            0..=127 => {
+                let count = header as usize + 1;
+                let Some(literal) = segment.get(pos..pos + count) else {
+                    bail!("Truncated RLE segment");
+                };
+                out.extend_from_slice(literal);
+                pos += count;
+            }
+            -127..=-1 => {
+                let Some(&value) = segment.get(pos) else {
+                    bail!("Truncated RLE segment");
+                };
+                out.extend(std::iter::repeat_n(value, (1 - header as isize) as usize));
+                pos += 1;
+            }
+            -128 => {}
+        }
+    }
+    if out.len() < expected {
+        bail!("RLE segment decodes to {} bytes where {} were expected", out.len(), expected);
+    }
+    out.truncate(expected);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frame of the given segments behind a 64-byte header
+    fn frame(segments: &[&[u8]]) -> Vec<u8> {
+        let mut header = vec![segments.len() as u32];
+        let mut offset = 64;
+        for segment in segments {
+            header.push(offset);
+            offset += segment.len() as u32;
+        }
+        header.resize(16, 0);
+        let mut data: Vec<u8> = header.into_iter().flat_map(u32::to_le_bytes).collect();
+        data.extend(segments.concat());
+        data
+    }
+
+    #[test]
+    fn literal_replicate_and_no_op_runs() {
+        let data = frame(&[&[0x02, 10, 20, 30, 0x80, 0xFC, 7]]);
+        assert_eq!(decode(&data, 8, 1, 1).unwrap(), [10, 20, 30, 7, 7, 7, 7, 7]);
+    }
+
+    #[test]
+    fn segments_hold_most_significant_bytes_first() {
+        let data = frame(&[&[0xFF, 0x01], &[0x01, 0x02, 0x03]]);
+        assert_eq!(decode(&data, 2, 1, 2).unwrap(), [0x0102, 0x0103]);
+        let data = frame(&[&[0xFF, 1], &[0xFF, 2], &[0x01, 3, 4]]);
+        assert_eq!(decode(&data, 2, 3, 1).unwrap(), [1, 2, 3, 1, 2, 4]);
+    }
+
+    #[test]
+    fn truncated_and_corrupt_frames_are_rejected() {
+        let data = frame(&[&[0x02, 10, 20, 30, 0x80, 0xFC, 7]]);
+        for cut in 0..data.len() {
+            assert!(decode(&data[..cut], 8, 1, 1).is_err(), "decoded {} of {} bytes", cut, data.len());
+        }
+        assert!(decode(&frame(&[&[0x04, 1, 2]]), 5, 1, 1).is_err());
+        assert!(decode(&data, 8, 1, 2).is_err());
+
+        let mut corrupt = data.clone();
+        corrupt[..4].copy_from_slice(&16u32.to_le_bytes());
+        assert!(decode(&corrupt, 2, 4, 4).is_err());
+        let mut corrupt = data;
+        corrupt[4..8].copy_from_slice(&60u32.to_le_bytes());
+        assert!(decode(&corrupt, 8, 1, 1).is_err());
+    }
+}
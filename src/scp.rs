@@ -0,0 +1,309 @@
+//! `scp` subcommand: a C-STORE SCP that receives instances from modalities or PACS,
+//! keeps them below the store directory and converts them as they arrive.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use anyhow::{Context, Result, bail};
+use chrono::Utc;
+use clap::ValueEnum;
+use dicom_object::meta::FileMetaTableBuilder;
+use uuid::Uuid;
+use crate::dimse::{self, Association, Command, Message, Rejection};
+use crate::manifest::Manifest;
+use crate::stow::storage_path;
+use crate::{DicomInstance, DicomProcessor, remove_stale_outputs, sanitize_filename, write_results};
+
+/// Transfer syntaxes accepted when none are given, in order of preference
+pub const DEFAULT_TRANSFER_SYNTAXES: &[&str] = &[
+    dimse::EXPLICIT_VR_LE,
+    dimse::IMPLICIT_VR_LE,
+    "1.2.840.10008.1.2.2",    // Explicit VR Big Endian
+    "1.2.840.10008.1.2.4.50", // JPEG Baseline
+    "1.2.840.10008.1.2.4.51", // JPEG Extended
+    "1.2.840.10008.1.2.4.57", // JPEG Lossless
+    "1.2.840.10008.1.2.4.70", // JPEG Lossless, First-Order Prediction
+    "1.2.840.10008.1.2.4.80", // JPEG-LS Lossless
+    "1.2.840.10008.1.2.4.81", // JPEG-LS Near-Lossless
+    "1.2.840.10008.1.2.4.90", // JPEG 2000 Lossless
+    "1.2.840.10008.1.2.4.91", // JPEG 2000
+    "1.2.840.10008.1.2.5",    // RLE Lossless
+];
+
+/// Prefix of the storage SOP classes
+const STORAGE_SOP_CLASS_PREFIX: &str = "1.2.840.10008.5.1.4.1.1.";
+
+/// Associations idle for longer are dropped
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum OutputGrouping {
+    /// Outputs of each association in their own `<time>_<calling AE>` directory
+    Association,
+    /// Outputs merged per study in the output directory, rewritten as instances arrive
+    Study,
+}
+
+pub struct Scp<'a> {
+    pub processor: &'a DicomProcessor,
+    pub ae_title: String,
+    pub store_dir: PathBuf,
+    pub output_dir: PathBuf,
+    pub transfer_syntaxes: Vec<String>,
+    pub grouping: OutputGrouping,
+    /// Associations served at once; further requests are rejected as transient
+    pub max_associations: usize,
+}
+
+pub fn run(scp: Scp, host: &str, port: u16) -> Result<()> {
+    let verbose = scp.processor.cli.verbose;
+    for transfer_syntax in &scp.transfer_syntaxes {
+        if !transfer_syntax.chars().all(|c| c.is_ascii_digit() || c == '.') {
+            bail!("Not a transfer syntax UID: {}", transfer_syntax);
+        }
+    }
+    fs::create_dir_all(&scp.store_dir)?;
+    fs::create_dir_all(&scp.output_dir)?;
+
+    // Per-study outputs merge across associations through the --incremental manifest
+    let manifest = match scp.grouping {
+        OutputGrouping::Study => Some(Mutex::new(Manifest::load(&scp.output_dir, scp.processor.manifest_options())?)),
+        OutputGrouping::Association => None,
+    };
+
+    let listener = TcpListener::bind((host, port))
+        .with_context(|| format!("Failed to listen on {}:{}", host, port))?;
+    if verbose {
+        println!("📡 Storage SCP {} listening on {}:{}", scp.ae_title, host, port);
+    }
+
+    // ARTIM: connections that send no A-ASSOCIATE-RQ in time are dropped
+    let artim_timeout = scp.processor.cli.dimse_timeout();
+    let active = AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    if verbose {
+                        eprintln!("⚠️  Connection failed: {}", e);
+                    }
+                    continue;
+                }
+            };
+            if let Err(e) = stream.set_read_timeout(Some(artim_timeout))
+                .and_then(|_| stream.set_write_timeout(Some(artim_timeout)))
+            {
+                if verbose {
+                    eprintln!("⚠️  Connection failed: {}", e);
+                }
+                continue;
+            }
+
+            let (scp, manifest, active) = (&scp, manifest.as_ref(), &active);
+            if active.fetch_add(1, Ordering::SeqCst) >= scp.max_associations {
+                active.fetch_sub(1, Ordering::SeqCst);
+                scope.spawn(move || {
+                    let peer = stream.peer_addr().map(|peer| peer.to_string()).unwrap_or_default();
+                    let refused = Association::refuse(stream, Rejection::LocalLimitExceeded);
+                    if verbose {
+                        match refused {
+                            Ok(()) => eprintln!("⚠️  Rejected association from {}: {} associations already open", peer, scp.max_associations),
+                            Err(e) => eprintln!("❌ Association failed: {:#}", e),
+                        }
+                    }
+                });
+                continue;
+            }
+            scope.spawn(move || {
+                let served = scp.serve_association(stream, manifest);
+                active.fetch_sub(1, Ordering::SeqCst);
+                if let Err(e) = served
+                    && verbose
+                {
+                    eprintln!("❌ Association failed: {:#}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Instances received on one association
+#[derive(Default)]
+struct Received {
+    instances: Vec<DicomInstance>,
+    files: Vec<PathBuf>,
+    filtered_out: Vec<PathBuf>,
+}
+
+impl Scp<'_> {
+    fn serve_association(&self, stream: TcpStream, manifest: Option<&Mutex<Manifest>>) -> Result<()> {
+        let verbose = self.processor.cli.verbose;
+        let peer = stream.peer_addr()?;
+
+        let accepts = |abstract_syntax: &str| {
+            abstract_syntax == dimse::VERIFICATION || abstract_syntax.starts_with(STORAGE_SOP_CLASS_PREFIX)
+        };
+        let mut association = Association::accept(stream, &self.ae_title, accepts, &self.transfer_syntaxes)?;
+        association.set_read_timeout(IDLE_TIMEOUT)?;
+        if verbose {
+            println!(
+                "🤝 Association from {} at {} ({} presentation contexts accepted)",
+                association.calling_ae, peer, association.contexts.len()
+            );
+        }
+
+        let mut received = Received::default();
+        let outcome = self.receive_all(&mut association, &mut received);
+        if outcome.is_err() {
+            association.abort();
+        }
+
+        if !received.files.is_empty() {
+            self.write_outputs(&association.calling_ae, &received, manifest)?;
+        }
+        if verbose && outcome.is_ok() {
+            println!(
+                "👋 Association from {} released: {} instances converted, {} skipped by --where",
+                association.calling_ae, received.instances.len(), received.filtered_out.len()
+            );
+        }
+        outcome
+    }
+
+    fn receive_all(&self, association: &mut Association, received: &mut Received) -> Result<()> {
+        while let Some(message) = association.receive()? {
+            let response = match message.command.u16(dimse::COMMAND_FIELD) {
+                Some(dimse::C_ECHO_RQ) => Command::response(&message.command, dimse::C_ECHO_RSP, dimse::STATUS_SUCCESS),
+                Some(dimse::C_STORE_RQ) => {
+                    let status = self.store(association, &message, received);
+                    Command::response(&message.command, dimse::C_STORE_RSP, status)
+                }
+                other => bail!("Unsupported DIMSE command {:#06x}", other.unwrap_or(0)),
+            };
+            association.send(&Message { context_id: message.context_id, command: response, data: None })?;
+        }
+        Ok(())
+    }
+
+    /// Keep and convert one C-STORE data set, answering with the status to send back
+    fn store(&self, association: &Association, message: &Message, received: &mut Received) -> u16 {
+        let incoming = self.store_dir.join(format!(".incoming-{}.dcm", Uuid::new_v4()));
+        let (Some(context), Some(data)) = (association.context(message.context_id), &message.data) else {
+            return dimse::STATUS_CANNOT_UNDERSTAND;
+        };
+        let Some(transfer_syntax) = context.transfer_syntaxes.first() else {
+            return dimse::STATUS_CANNOT_UNDERSTAND;
+        };
+        if message.command.str(dimse::AFFECTED_SOP_CLASS_UID).as_deref() != Some(context.abstract_syntax.as_str()) {
+            return dimse::STATUS_SOP_CLASS_MISMATCH;
+        }
+
+        if let Err(e) = write_part10(&incoming, &message.command, transfer_syntax, association, data) {
+            self.report(&message.command, &e);
+            let _ = fs::remove_file(&incoming);
+            return dimse::STATUS_OUT_OF_RESOURCES;
+        }
+
+        let instance = match self.processor.process_file(&incoming) {
+            Ok(instance) => instance,
+            Err(e) => {
+                self.report(&message.command, &e);
+                let _ = fs::remove_file(&incoming);
+                return dimse::STATUS_CANNOT_UNDERSTAND;
+            }
+        };
+        self.processor.filtered_out.lock().unwrap().clear();
+
+        // Instances skipped by --where are still kept, under their SOP Instance UID
+        let destination = match &instance {
+            Some(instance) => storage_path(&self.store_dir, instance),
+            None => {
+                let sop_instance = message.command.str(dimse::AFFECTED_SOP_INSTANCE_UID).unwrap_or_default();
+                self.store_dir.join(format!("{}.dcm", sanitize_filename(&sop_instance)))
+            }
+        };
+        let moved = destination.parent().map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::rename(&incoming, &destination));
+        if let Err(e) = moved {
+            self.report(&message.command, &e.into());
+            let _ = fs::remove_file(&incoming);
+            return dimse::STATUS_OUT_OF_RESOURCES;
+        }
+
+        match instance {
+            Some(mut instance) => {
+                instance.file_path = destination.to_string_lossy().to_string();
+                if self.processor.cli.verbose {
+                    println!("📥 Stored {}", instance.sop_instance_uid);
+                }
+                received.instances.push(instance);
+            }
+            None => received.filtered_out.push(destination.clone()),
+        }
+        received.files.push(destination);
+        dimse::STATUS_SUCCESS
+    }
+
+    fn report(&self, command: &Command, e: &anyhow::Error) {
+        if self.processor.cli.verbose {
+            let sop_instance = command.str(dimse::AFFECTED_SOP_INSTANCE_UID).unwrap_or_default();
+            eprintln!("❌ Failed to store {}: {:#}", sop_instance, e);
+        }
+    }
+
+    fn write_outputs(&self, calling_ae: &str, received: &Received, manifest: Option<&Mutex<Manifest>>) -> Result<()> {
+        match manifest {
+            Some(manifest) => {
+                let mut manifest = manifest.lock().unwrap();
+                manifest.record(&received.files, &received.instances, &received.filtered_out, false)?;
+                let affected: HashSet<String> = manifest.take_affected_studies();
+                if !affected.is_empty() {
+                    let instances: Vec<DicomInstance> = manifest.instances().cloned().collect();
+                    let outputs = write_results(&instances, &self.output_dir, self.processor, Some(&affected))?;
+                    let stale = manifest.replace_outputs(&self.output_dir, &outputs);
+                    remove_stale_outputs(&self.output_dir, &stale, self.processor.cli.verbose);
+                }
+                manifest.save()
+            }
+            None => {
+                if received.instances.is_empty() {
+                    return Ok(());
+                }
+                let name = format!("{}_{}", Utc::now().format("%Y%m%dT%H%M%S%.3f"), sanitize_filename(calling_ae));
+                let output_dir = self.output_dir.join(name);
+                fs::create_dir_all(&output_dir)?;
+                write_results(&received.instances, &output_dir, self.processor, None)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Write a received data set as a DICOM Part 10 file: preamble, `DICM`, file meta group, data set
+fn write_part10(path: &Path, command: &Command, transfer_syntax: &str, association: &Association, data: &[u8]) -> Result<()> {
+    let meta = FileMetaTableBuilder::new()
+        .media_storage_sop_class_uid(command.str(dimse::AFFECTED_SOP_CLASS_UID).unwrap_or_default())
+        .media_storage_sop_instance_uid(command.str(dimse::AFFECTED_SOP_INSTANCE_UID).unwrap_or_default())
+        .transfer_syntax(transfer_syntax)
+        .implementation_class_uid(dimse::IMPLEMENTATION_CLASS_UID)
+        .implementation_version_name(dimse::IMPLEMENTATION_VERSION_NAME)
+        .source_application_entity_title(association.called_ae.as_str())
+        .sending_application_entity_title(association.calling_ae.as_str())
+        .receiving_application_entity_title(association.called_ae.as_str())
+        .build()?;
+
+    let mut writer = BufWriter::new(File::create(path).with_context(|| format!("Failed to create {:?}", path))?);
+    writer.write_all(&[0; 128])?;
+    writer.write_all(b"DICM")?;
+    meta.write(&mut writer)?;
+    writer.write_all(data)?;
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,401 @@
+//! `serve` subcommand: a local DICOMweb server with QIDO-RS search, WADO-RS
+//! metadata and STOW-RS uploads over a directory of DICOM files or an --index database.
+
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::thread;
+use anyhow::{Result, anyhow};
+use dicom_core::dictionary::DataDictionary;
+use dicom_dictionary_std::{StandardDataDictionary, tags};
+use serde_json::{Map, Value, json};
+use tiny_http::{Header, Method, Request, Response, Server};
+use crate::index::CatalogIndex;
+use crate::query::{self, AttributeFilter, QueryLevel};
+use crate::stow::Store;
+use crate::{DicomInstance, DicomProcessor, dicomweb, get_tag_value, tag_key};
+
+/// Requests handled at once, so a slow upload does not hold up searches
+const WORKERS: usize = 4;
+
+/// Query parameters that are neither match keys nor supported options, such as the `_`
+/// cache buster some clients add; they are ignored rather than rejected
+const IGNORED_PARAMETERS: &[&str] = &["fuzzymatching", "accept", "charset", "orderby"];
+
+/// Attributes returned for each study, series or instance match besides match keys and `includefield`
+const STUDY_ATTRIBUTES: &[dicom_core::Tag] = &[
+    tags::SPECIFIC_CHARACTER_SET, tags::STUDY_DATE, tags::STUDY_TIME, tags::ACCESSION_NUMBER,
+    tags::REFERRING_PHYSICIAN_NAME, tags::STUDY_DESCRIPTION, tags::PATIENT_NAME, tags::PATIENT_ID,
+    tags::PATIENT_BIRTH_DATE, tags::PATIENT_SEX, tags::STUDY_INSTANCE_UID, tags::STUDY_ID,
+];
+const SERIES_ATTRIBUTES: &[dicom_core::Tag] = &[
+    tags::SPECIFIC_CHARACTER_SET, tags::MODALITY, tags::SERIES_DESCRIPTION, tags::STUDY_INSTANCE_UID,
+    tags::SERIES_INSTANCE_UID, tags::SERIES_NUMBER, tags::PERFORMED_PROCEDURE_STEP_START_DATE,
+    tags::PERFORMED_PROCEDURE_STEP_START_TIME,
+];
+const INSTANCE_ATTRIBUTES: &[dicom_core::Tag] = &[
+    tags::SPECIFIC_CHARACTER_SET, tags::SOP_CLASS_UID, tags::SOP_INSTANCE_UID, tags::STUDY_INSTANCE_UID,
+    tags::SERIES_INSTANCE_UID, tags::INSTANCE_NUMBER, tags::ROWS, tags::COLUMNS, tags::BITS_ALLOCATED,
+    tags::NUMBER_OF_FRAMES,
+];
+
+struct Reply {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn json(value: &Value) -> Self {
+        Self { status: 200, content_type: dicomweb::MEDIA_TYPE, body: value.to_string().into_bytes() }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self { status, content_type: "text/plain", body: message.as_bytes().to_vec() }
+    }
+}
+
+/// Load the source and answer requests until the process is stopped. Uploads are stored
+/// below `store_dir`, or the source directory when none is given.
+pub fn run(processor: &DicomProcessor, source: &Path, host: &str, port: u16, store_dir: Option<PathBuf>) -> Result<()> {
+    let instances = load_instances(processor, source)?;
+
+    let is_index = query::is_sqlite(source);
+    let store_dir = store_dir.or_else(|| source.is_dir().then(|| source.to_path_buf()));
+    let store = match store_dir {
+        Some(dir) => Some(Store {
+            dir,
+            index: is_index.then(|| CatalogIndex::open(source)).transpose()?,
+            processor,
+        }),
+        None => None,
+    };
+
+    let server = Server::http((host, port))
+        .map_err(|e| anyhow!("Failed to listen on {}:{}: {}", host, port, e))?;
+    println!("🌐 DICOMweb server for {} instances at http://{}:{}/", instances.len(), host, port);
+
+    // Searches share the instances; uploads take them, and the store, for themselves
+    let instances = RwLock::new(instances);
+    let store = store.map(Mutex::new);
+    thread::scope(|scope| {
+        for _ in 0..WORKERS {
+            scope.spawn(|| {
+                for request in server.incoming_requests() {
+                    handle(processor, &instances, store.as_ref(), request);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+fn handle(processor: &DicomProcessor, instances: &RwLock<Vec<DicomInstance>>, store: Option<&Mutex<Store>>, mut request: Request) {
+    let reply = match request.method() {
+        Method::Options => Reply { status: 204, content_type: "text/plain", body: Vec::new() },
+        Method::Get | Method::Head => route(&instances.read().unwrap(), request.url()),
+        Method::Post => match store {
+            Some(store) => upload(store, instances, &mut request),
+            None => Reply::error(403, "Uploads to an index need --store-dir"),
+        },
+        _ => Reply::error(405, "Only GET, POST and OPTIONS requests are supported"),
+    };
+
+    if processor.cli.verbose {
+        println!("{} {} -> {}", request.method(), request.url(), reply.status);
+    }
+    respond(request, reply);
+}
+
+fn load_instances(processor: &DicomProcessor, source: &Path) -> Result<Vec<DicomInstance>> {
+    if query::is_sqlite(source) {
+        return query::load_index(source);
+    }
+
+    // An empty directory is fine: it fills up through STOW-RS
+    let files = crate::collect_dicom_files(source, processor.cli.max_depth, processor.cli.verbose)?;
+    if processor.cli.parallel && files.len() > 1 {
+        crate::process_files_parallel(processor, files, &None)
+    } else {
+        crate::process_files_sequential(processor, files, &None)
+    }
+}
+
+fn respond(request: Request, reply: Reply) {
+    let headers = [
+        ("Content-Type", reply.content_type),
+        // Browser viewers such as OHIF are served from another origin
+        ("Access-Control-Allow-Origin", "*"),
+        ("Access-Control-Allow-Headers", "*"),
+        ("Access-Control-Allow-Methods", "GET, POST, OPTIONS"),
+    ];
+    let mut response = Response::from_data(reply.body).with_status_code(reply.status);
+    for (name, value) in headers {
+        if let Ok(header) = Header::from_bytes(name, value) {
+            response.add_header(header);
+        }
+    }
+    // The client may have gone away; there is no one left to tell
+    let _ = request.respond(response);
+}
+
+/// Dispatch on the path below any prefix such as `/dicom-web`
+fn route(instances: &[DicomInstance], url: &str) -> Reply {
+    let (path, query_string) = url.split_once('?').unwrap_or((url, ""));
+    let segments: Vec<String> = path.split('/').filter(|s| !s.is_empty()).map(percent_decode).collect();
+    let start = segments.iter()
+        .position(|s| matches!(s.as_str(), "studies" | "series" | "instances"))
+        .unwrap_or(segments.len());
+    let segments: Vec<&str> = segments[start..].iter().map(String::as_str).collect();
+
+    let params: Vec<(String, String)> = query_string.split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (key, value) = p.split_once('=').unwrap_or((p, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect();
+
+    let uid = |keyword: &str, value: &str| format!("{}={}", keyword, value);
+    let result = match segments.as_slice() {
+        ["studies"] => search(instances, QueryLevel::Study, vec![], &params),
+        ["series"] => search(instances, QueryLevel::Series, vec![], &params),
+        ["instances"] => search(instances, QueryLevel::Instance, vec![], &params),
+        ["studies", study, "series"] => {
+            search(instances, QueryLevel::Series, vec![uid("StudyInstanceUID", study)], &params)
+        }
+        ["studies", study, "instances"] => {
+            search(instances, QueryLevel::Instance, vec![uid("StudyInstanceUID", study)], &params)
+        }
+        ["studies", study, "series", series, "instances"] => search(
+            instances, QueryLevel::Instance,
+            vec![uid("StudyInstanceUID", study), uid("SeriesInstanceUID", series)], &params,
+        ),
+        ["studies", study, "metadata"] => Ok(metadata(instances, study, None, None)),
+        ["studies", study, "series", series, "metadata"] => Ok(metadata(instances, study, Some(series), None)),
+        ["studies", study, "series", series, "instances", instance, "metadata"] => {
+            Ok(metadata(instances, study, Some(series), Some(instance)))
+        }
+        _ => return Reply::error(404, "Not found"),
+    };
+
+    result.unwrap_or_else(|e| Reply::error(400, &format!("{:#}", e)))
+}
+
+/// STOW-RS: `POST /studies` or `POST /studies/{study}`. Clients asking for `application/json`
+/// get the store response together with the converted instances.
+fn upload(store: &Mutex<Store>, instances: &RwLock<Vec<DicomInstance>>, request: &mut Request) -> Reply {
+    let path = request.url().split('?').next().unwrap_or_default();
+    let segments: Vec<String> = path.split('/').filter(|s| !s.is_empty()).map(percent_decode).collect();
+    let study = match segments.iter().position(|s| s == "studies").map(|start| &segments[start + 1..]) {
+        Some([]) => None,
+        Some([study]) => Some(study.clone()),
+        _ => return Reply::error(404, "Not found"),
+    };
+
+    let header = |name: &str| request.headers().iter()
+        .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+        .map(|h| h.value.as_str().to_string())
+        .unwrap_or_default();
+    let (content_type, accept) = (header("Content-Type"), header("Accept"));
+
+    let mut body = Vec::new();
+    if let Err(e) = request.as_reader().read_to_end(&mut body) {
+        return Reply::error(400, &format!("Failed to read request body: {}", e));
+    }
+    // The body is read before the store is locked, so slow clients only hold up themselves
+    let mut store = store.lock().unwrap();
+    let result = match store.store(&content_type, &body, study.as_deref()) {
+        Ok(result) => result,
+        Err(e) => return Reply::error(415, &format!("{:#}", e)),
+    };
+
+    let mut instances = instances.write().unwrap();
+    for instance in &result.stored {
+        instances.retain(|i| i.sop_instance_uid != instance.sop_instance_uid);
+        instances.push(instance.clone());
+    }
+    drop(instances);
+    if store.processor.cli.verbose {
+        println!("📥 Stored {} instances", result.stored.len());
+    }
+
+    let mut reply = if accept.contains("application/json") {
+        let body = json!({ "response": result.response, "instances": result.stored });
+        Reply { status: 200, content_type: "application/json", body: body.to_string().into_bytes() }
+    } else {
+        Reply::json(&result.response)
+    };
+    reply.status = result.status;
+    reply
+}
+
+/// QIDO-RS search: one dataset per matching study, series or instance
+fn search(instances: &[DicomInstance], level: QueryLevel, mut filters: Vec<String>, params: &[(String, String)]) -> Result<Reply> {
+    let mut limit = None;
+    let mut offset = 0;
+    let mut include_all = false;
+    let mut included: Vec<String> = Vec::new();
+
+    for (key, value) in params {
+        match key.as_str() {
+            "limit" => limit = Some(value.parse::<usize>().map_err(|_| anyhow!("limit must be a number"))?),
+            "offset" => offset = value.parse::<usize>().map_err(|_| anyhow!("offset must be a number"))?,
+            key if key.starts_with('_') || IGNORED_PARAMETERS.contains(&key) => {}
+            "includefield" => {
+                for field in value.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+                    // Attributes the dictionary does not know are skipped
+                    if field == "all" {
+                        include_all = true;
+                    } else if let Some(tag) = StandardDataDictionary.parse_tag(field) {
+                        included.push(tag_key(tag));
+                    }
+                }
+            }
+            _ => filters.push(format!("{}={}", key, value)),
+        }
+    }
+
+    let filters = filters.iter().map(|f| AttributeFilter::parse(f)).collect::<Result<Vec<_>>>()?;
+    let matched = query::select(instances, &filters, level, offset, limit);
+
+    let (attributes, group_tag) = match level {
+        QueryLevel::Study => (STUDY_ATTRIBUTES, tags::STUDY_INSTANCE_UID),
+        QueryLevel::Series => (SERIES_ATTRIBUTES, tags::SERIES_INSTANCE_UID),
+        QueryLevel::Instance => (INSTANCE_ATTRIBUTES, tags::SOP_INSTANCE_UID),
+    };
+    let mut keys: Vec<String> = attributes.iter().map(|tag| tag_key(*tag)).collect();
+    keys.extend(filters.iter().map(|f| f.key.clone()));
+    keys.extend(included);
+
+    // Matches in first-seen order, each with all of its instances
+    let mut groups: Vec<(String, Vec<&DicomInstance>)> = Vec::new();
+    for instance in matched {
+        let key = get_tag_value(&instance.metadata.tags, group_tag).unwrap_or_default();
+        match groups.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, members)) => members.push(instance),
+            None => groups.push((key, vec![instance])),
+        }
+    }
+
+    let results: Vec<Value> = groups.iter().map(|(_, members)| {
+        let first = &members[0].metadata.tags;
+        let mut dataset = if include_all {
+            dicomweb::dataset(first)
+        } else {
+            dicomweb::dataset_of(first, &keys)
+        };
+        add_counts(&mut dataset, level, members);
+        Value::Object(dataset)
+    }).collect();
+
+    Ok(Reply::json(&Value::Array(results)))
+}
+
+/// Computed study and series attributes
+fn add_counts(dataset: &mut Map<String, Value>, level: QueryLevel, members: &[&DicomInstance]) {
+    let value = |tag| members.iter().filter_map(move |i| get_tag_value(&i.metadata.tags, tag)).map(|v| v.trim().to_string());
+    let mut set = |tag, vr: &str, values: Vec<Value>| {
+        dataset.insert(dicomweb::attribute_name(&tag_key(tag)), json!({ "vr": vr, "Value": values }));
+    };
+
+    match level {
+        QueryLevel::Study => {
+            let modalities: BTreeSet<String> = value(tags::MODALITY).filter(|m| !m.is_empty()).collect();
+            let series: HashSet<String> = value(tags::SERIES_INSTANCE_UID).collect();
+            set(tags::MODALITIES_IN_STUDY, "CS", modalities.into_iter().map(Value::from).collect());
+            set(tags::NUMBER_OF_STUDY_RELATED_SERIES, "IS", vec![json!(series.len())]);
+            set(tags::NUMBER_OF_STUDY_RELATED_INSTANCES, "IS", vec![json!(members.len())]);
+        }
+        QueryLevel::Series => {
+            set(tags::NUMBER_OF_SERIES_RELATED_INSTANCES, "IS", vec![json!(members.len())]);
+        }
+        QueryLevel::Instance => {}
+    }
+}
+
+/// WADO-RS metadata: every attribute of every instance in scope
+fn metadata(instances: &[DicomInstance], study: &str, series: Option<&str>, instance: Option<&str>) -> Reply {
+    let uid_is = |i: &DicomInstance, tag, expected: Option<&str>| {
+        expected.is_none_or(|expected| get_tag_value(&i.metadata.tags, tag).is_some_and(|v| v.trim() == expected))
+    };
+    let datasets: Vec<Value> = instances.iter()
+        .filter(|i| uid_is(i, tags::STUDY_INSTANCE_UID, Some(study)))
+        .filter(|i| uid_is(i, tags::SERIES_INSTANCE_UID, series))
+        .filter(|i| instance.is_none_or(|sop| i.sop_instance_uid.trim() == sop))
+        .map(|i| Value::Object(dicomweb::dataset(&i.metadata.tags)))
+        .collect();
+
+    if datasets.is_empty() {
+        return Reply::error(404, "No matching instances");
+    }
+    Reply::json(&Value::Array(datasets))
+}
+
+/// Decode `%XX` escapes and `+` in URL components
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let escaped = std::str::from_utf8(&bytes[i + 1..i + 3]).ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match escaped {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 3;
+                        continue;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn instances() -> Vec<DicomInstance> {
+        ["1.1", "1.2"].iter().map(|study| testutil::instance(&format!("{}.1", study), &[
+            (tags::STUDY_INSTANCE_UID, "UI", study),
+            (tags::SERIES_INSTANCE_UID, "UI", &format!("{}.9", study)),
+            (tags::PATIENT_ID, "LO", if *study == "1.1" { "P1" } else { "P2" }),
+            (tags::STUDY_DESCRIPTION, "LO", "HEAD"),
+            (tags::MODALITY, "CS", "CT"),
+        ])).collect()
+    }
+
+    fn search(url: &str) -> (u16, Value) {
+        let reply = route(&instances(), url);
+        (reply.status, serde_json::from_slice(&reply.body).unwrap_or(Value::Null))
+    }
+
+    #[test]
+    fn client_parameters_are_ignored() {
+        let (status, results) = search("/dicom-web/studies?PatientID=P1&_=1699999999&fuzzymatching=true&charset=utf-8&includefield=StudyDescription,NoSuchKeyword");
+        assert_eq!(status, 200);
+        let results = results.as_array().unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["00081030"]["Value"][0], "HEAD");
+        assert_eq!(results[0]["00201206"]["Value"][0], 1);
+    }
+
+    #[test]
+    fn search_parameters_are_validated() {
+        assert_eq!(search("/studies?NoSuchKeyword=1").0, 400);
+        assert_eq!(search("/studies?limit=ten").0, 400);
+        assert_eq!(search("/studies?limit=1&offset=1").1.as_array().unwrap().len(), 1);
+        assert_eq!(search("/studies/1.2/series?Modality=CT%2A").1[0]["0020000E"]["Value"][0], "1.2.9");
+        assert_eq!(search("/nothing").0, 404);
+    }
+}
@@ -0,0 +1,161 @@
+//! Per-frame pixel statistics for quality checks of the image data.
+
+use anyhow::Result;
+use dicom_dictionary_std::tags;
+use dicom_object::InMemDicomObject;
+use serde::{Deserialize, Serialize};
+use crate::pixels::PixelData;
+use crate::render::ModalityLut;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FrameStatistics {
+    /// 1-based frame number
+    pub frame: usize,
+    /// Statistics of the stored values
+    pub stored: ValueStatistics,
+    /// Statistics after the Modality LUT or Rescale Slope/Intercept, for grayscale images
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rescaled: Option<ValueStatistics>,
+    /// Share of samples equal to the Pixel Padding Value, or within the padding range
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub padding_percent: Option<f64>,
+    /// Samples whose stored value does not fit Bits Stored
+    pub values_outside_bits_stored: usize,
+    pub exceeds_bits_stored: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ValueStatistics {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub std: f64,
+    /// Sample counts in equal-width bins from `min` to `max`
+    pub histogram: Vec<u64>,
+}
+
+impl ValueStatistics {
+    fn new(values: impl Iterator<Item = f64> + Clone, bins: usize) -> Self {
+        let (mut min, mut max, mut sum, mut count) = (f64::MAX, f64::MIN, 0.0, 0usize);
+        for value in values.clone() {
+            min = min.min(value);
+            max = max.max(value);
+            sum += value;
+            count += 1;
+        }
+        let mean = sum / count as f64;
+        let variance = values.clone().map(|value| (value - mean).powi(2)).sum::<f64>() / count as f64;
+
+        let mut histogram = vec![0u64; bins];
+        let width = (max - min) / bins as f64;
+        for value in values {
+            let bin = if width > 0.0 { ((value - min) / width) as usize } else { 0 };
+            histogram[bin.min(bins - 1)] += 1;
+        }
+        Self { min, max, mean, std: variance.sqrt(), histogram }
+    }
+}
+
+/// Statistics of one decoded frame over all of its samples
+pub fn frame_statistics(
+    obj: &InMemDicomObject,
+    pixels: &PixelData,
+    index: usize,
+    samples: &[i32],
+    outside_bits_stored: usize,
+    bins: usize,
+) -> Result<FrameStatistics> {
+    let stored = ValueStatistics::new(samples.iter().map(|&value| f64::from(value)), bins);
+    let rescaled = if pixels.photometric_interpretation.starts_with("MONOCHROME") {
+        let modality = ModalityLut::new(obj, pixels, index)?;
+        Some(ValueStatistics::new(samples.iter().map(|&value| modality.apply(value)), bins))
+    } else {
+        None
+    };
+
+    let padding_percent = padding_range(obj, pixels).map(|(low, high)| {
+        let padded = samples.iter().filter(|&&value| (low..=high).contains(&value)).count();
+        100.0 * padded as f64 / samples.len() as f64
+    });
+
+    Ok(FrameStatistics {
+        frame: index + 1,
+        stored,
+        rescaled,
+        padding_percent,
+        values_outside_bits_stored: outside_bits_stored,
+        exceeds_bits_stored: outside_bits_stored > 0,
+    })
+}
+
+/// Pixel Padding Value, up to Pixel Padding Range Limit when present, in stored values
+fn padding_range(obj: &InMemDicomObject, pixels: &PixelData) -> Option<(i32, i32)> {
+    // US or SS depending on Pixel Representation, which implicit VR files cannot tell
+    let value = |tag| {
+        let value = obj.element_opt(tag).ok().flatten()?.to_int::<i32>().ok()?;
+        Some(if pixels.signed && value > 0x7FFF { value - 0x10000 } else { value })
+    };
+    let padding = value(tags::PIXEL_PADDING_VALUE)?;
+    let limit = value(tags::PIXEL_PADDING_RANGE_LIMIT).unwrap_or(padding);
+    Some((padding.min(limit), padding.max(limit)))
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom_core::{DataElement, PrimitiveValue, VR};
+    use super::*;
+
+    /// 2x2 signed frame with 12 bits stored, rescaled by 2x - 1024
+    fn ct(stored: [i16; 4]) -> InMemDicomObject {
+        let mut obj = InMemDicomObject::new_empty();
+        obj.put(DataElement::new(tags::PHOTOMETRIC_INTERPRETATION, VR::CS, PrimitiveValue::from("MONOCHROME2")));
+        for (tag, value) in [(tags::ROWS, 2u16), (tags::COLUMNS, 2), (tags::BITS_ALLOCATED, 16), (tags::BITS_STORED, 12), (tags::PIXEL_REPRESENTATION, 1)] {
+            obj.put(DataElement::new(tag, VR::US, PrimitiveValue::from(value)));
+        }
+        obj.put(DataElement::new(tags::RESCALE_SLOPE, VR::DS, PrimitiveValue::from("2")));
+        obj.put(DataElement::new(tags::RESCALE_INTERCEPT, VR::DS, PrimitiveValue::from("-1024")));
+        obj.put(DataElement::new(tags::PIXEL_DATA, VR::OW, PrimitiveValue::I16(stored.into_iter().collect())));
+        obj
+    }
+
+    fn summarize(obj: &InMemDicomObject) -> FrameStatistics {
+        let pixels = PixelData::new(obj, "1.2.840.10008.1.2.1").unwrap();
+        let (samples, outside) = pixels.frame_checked(0).unwrap();
+        frame_statistics(obj, &pixels, 0, &samples, outside, 4).unwrap()
+    }
+
+    #[test]
+    fn stored_and_rescaled_values_are_summarized() {
+        let statistics = summarize(&ct([-2000, 0, 100, 300]));
+        assert_eq!(statistics.frame, 1);
+        let stored = &statistics.stored;
+        assert_eq!((stored.min, stored.max, stored.mean), (-2000.0, 300.0, -400.0));
+        assert_eq!(stored.std, 865_000f64.sqrt());
+        assert_eq!(stored.histogram, [1, 0, 0, 3]);
+        let rescaled = statistics.rescaled.unwrap();
+        assert_eq!((rescaled.min, rescaled.max, rescaled.mean), (-5024.0, -424.0, -1824.0));
+        assert_eq!(statistics.padding_percent, None);
+        assert!(!statistics.exceeds_bits_stored);
+    }
+
+    #[test]
+    fn constant_frames_fill_the_first_bin() {
+        let stored = ValueStatistics::new([7.0; 5].into_iter(), 3);
+        assert_eq!((stored.min, stored.max, stored.mean, stored.std), (7.0, 7.0, 7.0, 0.0));
+        assert_eq!(stored.histogram, [5, 0, 0]);
+    }
+
+    #[test]
+    fn padding_and_values_beyond_bits_stored_are_counted() {
+        // Pixel Padding Value -2000 stored as US, up to a range limit of -1000
+        let mut obj = ct([-2000, -1500, 0, 4096]);
+        obj.put(DataElement::new(tags::PIXEL_PADDING_VALUE, VR::US, PrimitiveValue::from(0xF830u16)));
+        let statistics = summarize(&obj);
+        assert_eq!(statistics.padding_percent, Some(25.0));
+        assert_eq!(statistics.values_outside_bits_stored, 1);
+        assert!(statistics.exceeds_bits_stored);
+
+        obj.put(DataElement::new(tags::PIXEL_PADDING_RANGE_LIMIT, VR::SS, PrimitiveValue::from(-1000i16)));
+        assert_eq!(summarize(&obj).padding_percent, Some(50.0));
+    }
+}
@@ -0,0 +1,268 @@
+//! STOW-RS for the `serve` subcommand: store `multipart/related; type="application/dicom"`
+//! uploads, convert them on receipt and answer with a per-instance store response.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result, bail};
+use dicom_dictionary_std::tags;
+use serde_json::{Value, json};
+use uuid::Uuid;
+use crate::index::CatalogIndex;
+use crate::{DicomInstance, DicomProcessor, get_tag_value, sanitize_filename, study_uid};
+
+/// Failure Reason (0008,1197) values
+const PROCESSING_FAILURE: u16 = 0x0110;
+const CANNOT_UNDERSTAND: u16 = 0xC000;
+const STUDY_MISMATCH: u16 = 0xA900;
+
+/// Where uploads go and who else hears about them
+pub struct Store<'a> {
+    pub dir: PathBuf,
+    /// Index the server was started on, kept in step with the served instances
+    pub index: Option<CatalogIndex>,
+    pub processor: &'a DicomProcessor,
+}
+
+/// Outcome of one upload
+pub struct StowResult {
+    /// 200 when every part was stored, 202 when some were, 409 when none were
+    pub status: u16,
+    /// Store response dataset
+    pub response: Value,
+    /// Extraction of the stored instances
+    pub stored: Vec<DicomInstance>,
+}
+
+struct Part<'a> {
+    content_type: Option<String>,
+    data: &'a [u8],
+}
+
+impl Store<'_> {
+    /// Store every `application/dicom` part of the body. With `study`, instances of other
+    /// studies are refused, as for `POST /studies/{study}`.
+    pub fn store(&mut self, content_type: &str, body: &[u8], study: Option<&str>) -> Result<StowResult> {
+        let (media_type, parameters) = parse_content_type(content_type);
+        if media_type != "multipart/related" {
+            bail!("Expected multipart/related content, got {}", media_type);
+        }
+        let boundary = parameters.iter()
+            .find(|(name, _)| name == "boundary")
+            .map(|(_, value)| value.clone())
+            .context("multipart/related content has no boundary")?;
+        if let Some((_, part_type)) = parameters.iter().find(|(name, _)| name == "type")
+            && part_type != "application/dicom"
+        {
+            bail!("Only application/dicom parts are supported, got {}", part_type);
+        }
+
+        let mut referenced = Vec::new();
+        let mut failed = Vec::new();
+        let mut stored = Vec::new();
+
+        for part in parse_multipart(body, &boundary) {
+            let part_type = part.content_type.as_deref().map(|t| parse_content_type(t).0);
+            if part_type.as_deref().is_some_and(|t| t != "application/dicom") {
+                failed.push(failed_item(None, CANNOT_UNDERSTAND));
+                continue;
+            }
+
+            match self.store_part(part.data, study) {
+                Ok(instance) => {
+                    referenced.push(json!({
+                        "00081150": { "vr": "UI", "Value": [instance.metadata.sop_class_uid.clone().unwrap_or_default()] },
+                        "00081155": { "vr": "UI", "Value": [instance.sop_instance_uid.clone()] },
+                    }));
+                    stored.push(instance);
+                }
+                Err((item, e)) => {
+                    if self.processor.cli.verbose {
+                        eprintln!("❌ STOW part rejected: {:#}", e);
+                    }
+                    failed.push(item);
+                }
+            }
+        }
+
+        if let Some(index) = &mut self.index {
+            index.upsert(&stored)?;
+        }
+
+        // Referenced SOP Sequence (0008,1199) and Failed SOP Sequence (0008,1198)
+        let mut response = serde_json::Map::new();
+        if !referenced.is_empty() {
+            response.insert("00081199".to_string(), json!({ "vr": "SQ", "Value": referenced }));
+        }
+        if !failed.is_empty() {
+            response.insert("00081198".to_string(), json!({ "vr": "SQ", "Value": failed }));
+        }
+        let status = match (stored.is_empty(), failed.is_empty()) {
+            (_, true) => 200,
+            (false, false) => 202,
+            (true, false) => 409,
+        };
+
+        Ok(StowResult { status, response: Value::Object(response), stored })
+    }
+
+    /// Write one part, convert it and move it to `<study>/<series>/<instance>.dcm`.
+    /// Rejected parts come back as their Failed SOP Sequence item.
+    fn store_part(&self, data: &[u8], study: Option<&str>) -> Result<DicomInstance, (Value, anyhow::Error)> {
+        let incoming = self.dir.join(format!(".incoming-{}.dcm", Uuid::new_v4()));
+        let reject = |instance: Option<&DicomInstance>, reason, e| {
+            let _ = fs::remove_file(&incoming);
+            (failed_item(instance, reason), e)
+        };
+
+        let mut instance = match self.convert(&incoming, data) {
+            Ok(instance) => instance,
+            Err(e) => return Err(reject(None, CANNOT_UNDERSTAND, e)),
+        };
+        if let Some(study) = study
+            && study_uid(&instance) != study
+        {
+            let e = anyhow::anyhow!("Instance belongs to study {}, not {}", study_uid(&instance), study);
+            return Err(reject(Some(&instance), STUDY_MISMATCH, e));
+        }
+
+        let destination = storage_path(&self.dir, &instance);
+        let moved = destination.parent().map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::rename(&incoming, &destination));
+        if let Err(e) = moved {
+            return Err(reject(Some(&instance), PROCESSING_FAILURE, e.into()));
+        }
+
+        instance.file_path = destination.to_string_lossy().to_string();
+        Ok(instance)
+    }
+
+    fn convert(&self, path: &Path, data: &[u8]) -> Result<DicomInstance> {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(path, data).with_context(|| format!("Failed to write {:?}", path))?;
+        self.processor.process_file(path)?
+            .context("Instance does not match --where")
+    }
+}
+
+/// Where a received instance is kept: `<study>/<series>/<instance>.dcm` below `dir`
+pub fn storage_path(dir: &Path, instance: &DicomInstance) -> PathBuf {
+    let series = get_tag_value(&instance.metadata.tags, tags::SERIES_INSTANCE_UID).unwrap_or_else(|| "unknown_series".to_string());
+    dir.join(sanitize_filename(&study_uid(instance)))
+        .join(sanitize_filename(&series))
+        .join(format!("{}.dcm", sanitize_filename(&instance.sop_instance_uid)))
+}
+
+/// Referenced SOP Class/Instance UIDs, when the part could be read, and the Failure Reason
+fn failed_item(instance: Option<&DicomInstance>, reason: u16) -> Value {
+    let mut item = serde_json::Map::new();
+    if let Some(instance) = instance {
+        if let Some(sop_class) = &instance.metadata.sop_class_uid {
+            item.insert("00081150".to_string(), json!({ "vr": "UI", "Value": [sop_class] }));
+        }
+        item.insert("00081155".to_string(), json!({ "vr": "UI", "Value": [instance.sop_instance_uid] }));
+    }
+    item.insert("00081197".to_string(), json!({ "vr": "US", "Value": [reason] }));
+    Value::Object(item)
+}
+
+/// Media type in lower case and its parameters with quotes removed
+fn parse_content_type(content_type: &str) -> (String, Vec<(String, String)>) {
+    let mut fields = content_type.split(';');
+    let media_type = fields.next().unwrap_or_default().trim().to_lowercase();
+    let parameters = fields
+        .filter_map(|field| field.split_once('='))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().trim_matches('"').to_string()))
+        .collect();
+    (media_type, parameters)
+}
+
+/// Body parts between `--boundary` delimiter lines, up to the closing `--boundary--`.
+/// After the first one, delimiters start with the CRLF ending the previous part, so part
+/// data that merely contains `--boundary` is not split.
+fn parse_multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<Part<'a>> {
+    let delimiter = format!("\r\n--{}", boundary).into_bytes();
+    // Index of the CRLF of the next delimiter, which is followed by `--`, or by optional
+    // white space and a line end
+    let find = |from: usize| {
+        let mut from = from;
+        while let Some(position) = body.get(from..)
+            .and_then(|rest| rest.windows(delimiter.len()).position(|w| w == delimiter.as_slice())) {
+            let at = from + position;
+            let rest = &body[at + delimiter.len()..];
+            let padding = rest.iter().take_while(|b| matches!(b, b' ' | b'\t')).count();
+            if rest.starts_with(b"--") || rest[padding..].is_empty() || rest[padding..].starts_with(b"\r\n") {
+                return Some(at);
+            }
+            from = at + 1;
+        }
+        None
+    };
+
+    let mut parts = Vec::new();
+    // The first delimiter may open the body, without a preamble and its CRLF
+    let first = if body.starts_with(&delimiter[2..]) { Some(0) } else { find(0).map(|at| at + 2) };
+    let Some(mut cursor) = first.map(|at| at + delimiter.len() - 2) else {
+        return parts;
+    };
+    loop {
+        if body[cursor..].starts_with(b"--") {
+            break;
+        }
+        let Some(line_end) = body[cursor..].windows(2).position(|w| w == b"\r\n") else {
+            break;
+        };
+        let part_start = cursor + line_end + 2;
+        let Some(end) = find(part_start - 2) else {
+            break;
+        };
+        let part = body.get(part_start..end).unwrap_or_default();
+
+        let (headers, data) = if let Some(data) = part.strip_prefix(b"\r\n") {
+            (&[][..], data)
+        } else {
+            match part.windows(4).position(|w| w == b"\r\n\r\n") {
+                Some(split) => (&part[..split], &part[split + 4..]),
+                None => (&[][..], part),
+            }
+        };
+        let content_type = String::from_utf8_lossy(headers).lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-type"))
+            .map(|(_, value)| value.trim().to_string());
+        parts.push(Part { content_type, data });
+        cursor = end + delimiter.len();
+    }
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(body: &[u8], boundary: &str) -> Vec<(Option<String>, Vec<u8>)> {
+        parse_multipart(body, boundary).into_iter().map(|part| (part.content_type, part.data.to_vec())).collect()
+    }
+
+    #[test]
+    fn boundary_text_inside_part_data_does_not_split_it() {
+        let body = b"--B\r\nContent-Type: application/dicom\r\n\r\nDICM--B--\r\n--Bogus\r\n--B \r\n\r\nsecond\r\n--B--\r\n";
+        assert_eq!(parts(body, "B"), [
+            (Some("application/dicom".to_string()), b"DICM--B--\r\n--Bogus".to_vec()),
+            (None, b"second".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn preamble_epilogue_and_empty_parts() {
+        let body = b"preamble --B\r\n--B\r\nContent-type:application/dicom\r\nX-Other: 1\r\n\r\n\r\n\r\n--B\r\n--B--\r\nepilogue\r\n--B\r\n\r\nignored\r\n";
+        assert_eq!(parts(body, "B"), [
+            (Some("application/dicom".to_string()), b"\r\n".to_vec()),
+            (None, Vec::new()),
+        ]);
+
+        // Without a closing delimiter the unterminated part is dropped
+        assert_eq!(parts(b"--B\r\n\r\none\r\n--B\r\n\r\ntwo", "B"), [(None, b"one".to_vec())]);
+        assert!(parts(b"no delimiter at all", "B").is_empty());
+        assert!(parts(b"--B", "B").is_empty());
+    }
+}
@@ -0,0 +1,137 @@
+//! CSV/TSV tables with one row per instance and one column per tag.
+
+use std::collections::{BTreeSet, HashMap};
+use anyhow::Result;
+use dicom_core::{Tag, dictionary::DataDictionary};
+use crate::{DicomInstance, TagInfo};
+
+/// VRs left out when columns are the union of all tags; their values are binary blobs
+pub const BINARY_VRS: &[&str] = &["OB", "OD", "OF", "OL", "OV", "OW", "UN", "SQ"];
+
+pub struct TableOptions<'a> {
+    /// Field separator, b',' for CSV or b'\t' for TSV
+    pub delimiter: u8,
+    /// Joins the values of multi-valued tags within one cell
+    pub multi_value_delimiter: &'a str,
+    /// Selected columns; every encountered tag when empty
+    pub columns: &'a [Tag],
+}
+
+/// Table over instances paired with the series tags factored out of them (empty if not factored)
+pub fn create_table<'a>(
+    rows: impl IntoIterator<Item = (&'a DicomInstance, &'a HashMap<String, TagInfo>)>,
+    options: &TableOptions,
+) -> Result<String> {
+    let rows: Vec<(&DicomInstance, &HashMap<String, TagInfo>)> = rows.into_iter().collect();
+
+    let columns: Vec<String> = if options.columns.is_empty() {
+        union_columns(&rows)
+    } else {
+        options.columns.iter().map(|tag| crate::tag_key(*tag)).collect()
+    };
+
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(options.delimiter)
+        .from_writer(Vec::new());
+
+    let mut header = vec!["FilePath".to_string()];
+    header.extend(columns.iter().map(|key| column_name(key)));
+    writer.write_record(&header)?;
+
+    for (instance, common) in rows {
+        let mut record = vec![instance.file_path.clone()];
+        record.extend(columns.iter().map(|key| {
+            instance.metadata.tags.get(key)
+                .or_else(|| common.get(key))
+                .map(|tag_info| cell_value(tag_info, options.multi_value_delimiter))
+                .unwrap_or_default()
+        }));
+        writer.write_record(&record)?;
+    }
+
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+/// Keys of every tag that has a value in any row, excluding binary values,
+/// sequences and group lengths
+pub fn union_columns(rows: &[(&DicomInstance, &HashMap<String, TagInfo>)]) -> Vec<String> {
+    // Tag keys are zero-padded hex, so sorting them sorts by tag
+    rows.iter()
+        .flat_map(|(instance, common)| instance.metadata.tags.values().chain(common.values()))
+        .filter(|tag_info| !BINARY_VRS.contains(&tag_info.vr.as_str()) && !tag_info.tag.ends_with(",0000)"))
+        .map(|tag_info| tag_info.tag.clone())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Dictionary keyword of a tag key, or the key itself for private and unknown tags
+pub fn column_name(key: &str) -> String {
+    dicom_dictionary_std::StandardDataDictionary
+        .parse_tag(key)
+        .filter(|tag| tag.group() % 2 == 0)
+        .and_then(|tag| dicom_dictionary_std::StandardDataDictionary.by_tag(tag))
+        .map(|entry| entry.alias.to_string())
+        .unwrap_or_else(|| key.to_string())
+}
+
+fn cell_value(tag_info: &TagInfo, multi_value_delimiter: &str) -> String {
+    tag_values(tag_info).join(multi_value_delimiter)
+}
+
+/// Individual values of a tag, trailing padding removed
+pub fn tag_values(tag_info: &TagInfo) -> Vec<String> {
+    match &tag_info.raw_value {
+        Some(raw) => raw.split('\\')
+            .map(|v| v.trim_end_matches([' ', '\0']).to_string())
+            .collect(),
+        None => match &tag_info.value {
+            serde_json::Value::String(value) => vec![value.clone()],
+            serde_json::Value::Array(values) => values.iter()
+                .map(|v| v.as_str().map(str::to_string).unwrap_or_else(|| v.to_string()))
+                .collect(),
+            value => vec![value.to_string()],
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicom_dictionary_std::tags;
+    use crate::testutil;
+
+    #[test]
+    fn columns_are_the_union_of_tags_sorted_by_tag() {
+        let first = testutil::instance("1", &[
+            (tags::PATIENT_NAME, "PN", "DOE^JOHN"),
+            (tags::PIXEL_DATA, "OW", "binary"),
+            (Tag(0x0008, 0x0000), "UL", "12"),
+        ]);
+        let second = testutil::instance("2", &[
+            (tags::MODALITY, "CS", "CT"),
+            (tags::IMAGE_TYPE, "CS", "ORIGINAL\\PRIMARY"),
+            (Tag(0x0009, 0x1001), "LO", "x, \"y\""),
+        ]);
+        let common = testutil::tags(&[(tags::STUDY_DATE, "DA", "20240102")]);
+        let none = HashMap::new();
+        let options = TableOptions { delimiter: b',', multi_value_delimiter: "|", columns: &[] };
+
+        let table = create_table([(&first, &none), (&second, &common)], &options).unwrap();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], "FilePath,ImageType,StudyDate,Modality,\"(0009,1001)\",PatientName");
+        assert_eq!(lines[1], "/data/1.dcm,,,,,DOE^JOHN");
+        assert_eq!(lines[2], "/data/2.dcm,ORIGINAL|PRIMARY,20240102,CT,\"x, \"\"y\"\"\",");
+    }
+
+    #[test]
+    fn selected_columns_keep_their_order_in_tsv() {
+        let instance = testutil::instance("1", &[(tags::MODALITY, "CS", "MR"), (tags::PATIENT_ID, "LO", "P1")]);
+        let none = HashMap::new();
+        let columns = [tags::PATIENT_ID, tags::MODALITY, tags::STUDY_DATE];
+        let options = TableOptions { delimiter: b'\t', multi_value_delimiter: "\\", columns: &columns };
+
+        let table = create_table([(&instance, &none)], &options).unwrap();
+        assert_eq!(table, "FilePath\tPatientID\tModality\tStudyDate\n/data/1.dcm\tP1\tMR\t\n");
+    }
+}
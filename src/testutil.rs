@@ -0,0 +1,38 @@
+//! Instances built from a few tags, for unit tests.
+
+use std::collections::HashMap;
+use dicom_core::Tag;
+use crate::{DicomInstance, DicomMetadata, TagInfo, tag_key};
+
+/// Tag map of (tag, VR, value) entries
+pub fn tags(entries: &[(Tag, &str, &str)]) -> HashMap<String, TagInfo> {
+    entries.iter()
+        .map(|&(tag, vr, value)| {
+            let key = tag_key(tag);
+            (key.clone(), TagInfo {
+                tag: key,
+                vr: vr.to_string(),
+                name: None,
+                value: serde_json::json!(value),
+                raw_value: Some(value.to_string()),
+                is_private: tag.group() % 2 == 1,
+            })
+        })
+        .collect()
+}
+
+pub fn instance(sop_instance_uid: &str, entries: &[(Tag, &str, &str)]) -> DicomInstance {
+    DicomInstance {
+        sop_instance_uid: sop_instance_uid.to_string(),
+        instance_number: None,
+        file_path: format!("/data/{}.dcm", sop_instance_uid),
+        metadata: DicomMetadata {
+            tags: tags(entries),
+            transfer_syntax: Some("1.2.840.10008.1.2.1".to_string()),
+            sop_class_uid: None,
+            file_meta_information: HashMap::new(),
+        },
+        has_pixel_data: false,
+        pixels: None,
+    }
+}
@@ -0,0 +1,182 @@
+//! Per-series thumbnails and contact sheets for study browsers.
+
+use std::path::Path;
+use anyhow::{Context, Result};
+use dicom_dictionary_std::tags;
+use dicom_object::{DefaultDicomObject, OpenFileOptions};
+use serde::{Deserialize, Serialize};
+use crate::pixels::PixelData;
+use crate::render::{self, Rendered, Window};
+use crate::{DicomInstance, DicomSeries, get_tag_value, sanitize_filename};
+
+/// Most slices shown on a contact sheet
+const CONTACT_SHEET_SLICES: usize = 36;
+
+/// Images written for a series, relative to the output directory
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SeriesThumbnails {
+    pub thumbnail: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contact_sheet: Option<String>,
+}
+
+pub struct ThumbnailOptions {
+    /// Longest side of the thumbnail and of each contact sheet tile
+    pub size: usize,
+    pub contact_sheet: bool,
+    pub window: Option<Window>,
+}
+
+/// Write `thumbnails/<study>/<series>/thumbnail.png` of the middle slice, and `contact_sheet.png`
+/// when asked, below `output_dir`; `None` for series without images
+pub fn write(output_dir: &Path, study_uid: &str, series: &DicomSeries, options: &ThumbnailOptions) -> Result<Option<SeriesThumbnails>> {
+    // Instances are in spatial order when the series forms a stack
+    let slices: Vec<(&DicomInstance, usize)> = series.instances.iter()
+        .filter(|instance| instance.has_pixel_data)
+        .flat_map(|instance| (0..frame_count(instance)).map(move |frame| (instance, frame)))
+        .collect();
+    if slices.is_empty() {
+        return Ok(None);
+    }
+    let dir = Path::new("thumbnails").join(sanitize_filename(study_uid)).join(sanitize_filename(&series.series_instance_uid));
+    let mut slice_renderer = SliceRenderer { open: None, window: options.window };
+
+    let (instance, frame) = slices[slices.len() / 2];
+    let thumbnail = fit(&slice_renderer.render(instance, frame)?, options.size);
+    let thumbnail_path = dir.join("thumbnail.png");
+    render::write_png(&output_dir.join(&thumbnail_path), &thumbnail)?;
+
+    let contact_sheet = if options.contact_sheet {
+        let count = slices.len().min(CONTACT_SHEET_SLICES);
+        let tiles = (0..count)
+            .map(|k| {
+                let (instance, frame) = slices[(2 * k + 1) * slices.len() / (2 * count)];
+                Ok(fit(&slice_renderer.render(instance, frame)?, options.size))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let path = dir.join("contact_sheet.png");
+        render::write_png(&output_dir.join(&path), &mosaic(&tiles, options.size))?;
+        Some(path.to_string_lossy().to_string())
+    } else {
+        None
+    };
+
+    Ok(Some(SeriesThumbnails { thumbnail: thumbnail_path.to_string_lossy().to_string(), contact_sheet }))
+}
+
+pub fn frame_count(instance: &DicomInstance) -> usize {
+    match &instance.pixels {
+        Some(pixels) => pixels.frames,
+        None => get_tag_value(&instance.metadata.tags, tags::NUMBER_OF_FRAMES)
+            .and_then(|frames| frames.trim().parse().ok())
+            .unwrap_or(1)
+            .max(1),
+    }
+}
+
+/// Renders slices in 8 bits, keeping the last file open for the frames that follow
+struct SliceRenderer {
+    open: Option<(String, DefaultDicomObject, PixelData)>,
+    window: Option<Window>,
+}
+
+impl SliceRenderer {
+    fn render(&mut self, instance: &DicomInstance, frame: usize) -> Result<Rendered> {
+        if self.open.as_ref().is_none_or(|(path, ..)| *path != instance.file_path) {
+            let obj = OpenFileOptions::new()
+                .open_file(&instance.file_path)
+                .with_context(|| format!("Failed to open DICOM file: {:?}", instance.file_path))?;
+            let pixels = PixelData::new(&obj, obj.meta().transfer_syntax())
+                .with_context(|| format!("Failed to decode Pixel Data of {:?}", instance.file_path))?;
+            self.open = Some((instance.file_path.clone(), obj, pixels));
+        }
+        let (path, obj, pixels) = self.open.as_ref().unwrap();
+        let samples = pixels.frame(frame).with_context(|| format!("Failed to decode frame {} of {:?}", frame + 1, path))?;
+        render::render(obj, pixels, frame, &samples, self.window, 8)
+    }
+}
+
+/// Downscale by area averaging so the longest side is at most `size`
+fn fit(image: &Rendered, size: usize) -> Rendered {
+    let scale = (size as f64 / image.width.max(image.height) as f64).min(1.0);
+    let width = ((image.width as f64 * scale).round() as usize).max(1);
+    let height = ((image.height as f64 * scale).round() as usize).max(1);
+    let channels = image.channels;
+
+    let mut samples = Vec::with_capacity(width * height * channels);
+    for y in 0..height {
+        let (y0, y1) = (y * image.height / height, ((y + 1) * image.height / height).max(y * image.height / height + 1));
+        for x in 0..width {
+            let (x0, x1) = (x * image.width / width, ((x + 1) * image.width / width).max(x * image.width / width + 1));
+            let count = ((y1 - y0) * (x1 - x0)) as u32;
+            for channel in 0..channels {
+                let sum: u32 = (y0..y1)
+                    .flat_map(|sy| (x0..x1).map(move |sx| (sy * image.width + sx) * channels + channel))
+                    .map(|index| u32::from(image.samples[index]))
+                    .sum();
+                samples.push(((sum + count / 2) / count) as u16);
+            }
+        }
+    }
+    Rendered { width, height, channels, bit_depth: image.bit_depth, samples }
+}
+
+/// Tiles centered in `size` squares on a black background, row by row in a near-square grid
+fn mosaic(tiles: &[Rendered], size: usize) -> Rendered {
+    let columns = (tiles.len() as f64).sqrt().ceil() as usize;
+    let rows = tiles.len().div_ceil(columns);
+    let channels = tiles.iter().map(|tile| tile.channels).max().unwrap_or(1);
+    let (width, height) = (columns * size, rows * size);
+
+    let mut samples = vec![0u16; width * height * channels];
+    for (index, tile) in tiles.iter().enumerate() {
+        let left = (index % columns) * size + (size - tile.width) / 2;
+        let top = (index / columns) * size + (size - tile.height) / 2;
+        for y in 0..tile.height {
+            for x in 0..tile.width {
+                let target = ((top + y) * width + left + x) * channels;
+                for channel in 0..channels {
+                    samples[target + channel] = tile.samples[(y * tile.width + x) * tile.channels + channel.min(tile.channels - 1)];
+                }
+            }
+        }
+    }
+    Rendered { width, height, channels, bit_depth: 8, samples }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(width: usize, height: usize, channels: usize, samples: &[u16]) -> Rendered {
+        Rendered { width, height, channels, bit_depth: 8, samples: samples.to_vec() }
+    }
+
+    #[test]
+    fn fit_averages_areas_and_never_enlarges() {
+        let source = image(4, 2, 1, &[0, 10, 100, 101, 20, 31, 200, 200]);
+        let fitted = fit(&source, 2);
+        assert_eq!((fitted.width, fitted.height), (2, 1));
+        assert_eq!(fitted.samples, [15, 150]);
+
+        let fitted = fit(&source, 8);
+        assert_eq!((fitted.width, fitted.height), (4, 2));
+        assert_eq!(fitted.samples, source.samples);
+
+        let rgb = image(2, 1, 3, &[10, 20, 30, 20, 40, 60]);
+        assert_eq!(fit(&rgb, 1).samples, [15, 30, 45]);
+    }
+
+    #[test]
+    fn mosaic_centers_tiles_in_a_square_grid() {
+        let tiles = [image(2, 1, 1, &[1, 2]), image(1, 2, 1, &[3, 4]), image(1, 1, 3, &[5, 6, 7])];
+        let sheet = mosaic(&tiles, 2);
+        assert_eq!((sheet.width, sheet.height, sheet.channels), (4, 4, 3));
+        let pixel = |x: usize, y: usize| &sheet.samples[(y * 4 + x) * 3..][..3];
+        // Grayscale tiles are spread over the three channels, odd margins round down
+        assert_eq!((pixel(0, 0), pixel(1, 0), pixel(0, 1)), (&[1, 1, 1][..], &[2, 2, 2][..], &[0, 0, 0][..]));
+        assert_eq!((pixel(2, 0), pixel(2, 1), pixel(3, 0)), (&[3, 3, 3][..], &[4, 4, 4][..], &[0, 0, 0][..]));
+        assert_eq!((pixel(0, 2), pixel(1, 3)), (&[5, 6, 7][..], &[0, 0, 0][..]));
+        assert!(sheet.samples[(2 * 4 + 2) * 3..].iter().all(|&sample| sample == 0));
+    }
+}
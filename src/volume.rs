@@ -0,0 +1,295 @@
+//! NumPy `.npy` volumes of image series with a JSON sidecar of their geometry, for ML pipelines.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use anyhow::{Context, Result, bail};
+use dicom_object::OpenFileOptions;
+use serde::{Deserialize, Serialize};
+use crate::geometry::SeriesGeometry;
+use crate::pixels::PixelData;
+use crate::render::ModalityLut;
+use crate::thumbnail::frame_count;
+use crate::{DicomInstance, DicomSeries, sanitize_filename};
+
+/// Files written for a series, relative to the output directory
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SeriesVolume {
+    pub npy: String,
+    pub sidecar: String,
+}
+
+#[derive(Serialize)]
+struct Sidecar<'a> {
+    study_instance_uid: &'a str,
+    series_instance_uid: &'a str,
+    /// Array shape: slices, rows, columns and, for color images, samples
+    shape: Vec<usize>,
+    dtype: &'static str,
+    photometric_interpretation: String,
+    /// Modality LUT or Rescale Slope/Intercept applied to the stored values
+    rescaled: bool,
+    /// Spatial metadata in array axis order (slice, row, column) and patient LPS coordinates,
+    /// when the slices form a stack
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spacing: Option<[f64; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    origin: Option<[f64; 3]>,
+    /// Unit vector of each array axis
+    #[serde(skip_serializing_if = "Option::is_none")]
+    direction: Option<[[f64; 3]; 3]>,
+    /// Source of each slice, in array order
+    slices: Vec<Slice<'a>>,
+}
+
+#[derive(Serialize)]
+struct Slice<'a> {
+    sop_instance_uid: &'a str,
+    /// 1-based frame number
+    frame: usize,
+}
+
+/// Sample layout shared by every slice of a volume
+#[derive(PartialEq)]
+struct Layout {
+    rows: usize,
+    columns: usize,
+    samples_per_pixel: usize,
+    bits_allocated: u16,
+    signed: bool,
+}
+
+/// Write `volumes/<study>/<series>/volume.npy` and `volume.json` below `output_dir`, stacking
+/// the series' slices in its spatial order; `None` for series without images
+pub fn write(output_dir: &Path, study_uid: &str, series: &DicomSeries, rescale: bool) -> Result<Option<SeriesVolume>> {
+    let slices: Vec<(&DicomInstance, usize)> = series.instances.iter()
+        .filter(|instance| instance.has_pixel_data)
+        .flat_map(|instance| (0..frame_count(instance)).map(move |frame| (instance, frame)))
+        .collect();
+    if slices.is_empty() {
+        return Ok(None);
+    }
+    let dir = Path::new("volumes").join(sanitize_filename(study_uid)).join(sanitize_filename(&series.series_instance_uid));
+    let npy_path = dir.join("volume.npy");
+    let sidecar_path = dir.join("volume.json");
+    fs::create_dir_all(output_dir.join(&dir))?;
+
+    let (layout, photometric_interpretation, rescaled) = match write_npy(&output_dir.join(&npy_path), &slices, rescale) {
+        Ok(written) => written,
+        Err(e) => {
+            let _ = fs::remove_file(output_dir.join(&npy_path));
+            let _ = fs::remove_file(output_dir.join(&sidecar_path));
+            return Err(e);
+        }
+    };
+
+    // Series geometry describes one slice per instance
+    let geometry = series.geometry.as_ref().filter(|geometry| geometry.dimensions[2] == slices.len());
+    let (spacing, origin, direction) = match geometry {
+        Some(geometry) => {
+            let (spacing, origin, direction) = spatial(geometry);
+            (Some(spacing), Some(origin), Some(direction))
+        }
+        None => (None, None, None),
+    };
+    let sidecar = Sidecar {
+        study_instance_uid: study_uid,
+        series_instance_uid: &series.series_instance_uid,
+        shape: shape(&layout, slices.len()),
+        dtype: dtype(&layout, rescaled),
+        photometric_interpretation,
+        rescaled,
+        spacing,
+        origin,
+        direction,
+        slices: slices.iter()
+            .map(|(instance, frame)| Slice { sop_instance_uid: &instance.sop_instance_uid, frame: frame + 1 })
+            .collect(),
+    };
+    fs::write(output_dir.join(&sidecar_path), serde_json::to_string_pretty(&sidecar)?)?;
+
+    Ok(Some(SeriesVolume {
+        npy: npy_path.to_string_lossy().to_string(),
+        sidecar: sidecar_path.to_string_lossy().to_string(),
+    }))
+}
+
+/// Stream the decoded slices to `path`; the layout, Photometric Interpretation and whether
+/// values were rescaled
+fn write_npy(path: &Path, slices: &[(&DicomInstance, usize)], rescale: bool) -> Result<(Layout, String, bool)> {
+    let file = File::create(path).with_context(|| format!("Failed to create {:?}", path))?;
+    let mut writer = BufWriter::new(file);
+    let mut layout = None;
+    let mut photometric_interpretation = String::new();
+    let mut rescaled = false;
+
+    let mut index = 0;
+    while index < slices.len() {
+        let instance = slices[index].0;
+        let obj = OpenFileOptions::new()
+            .open_file(&instance.file_path)
+            .with_context(|| format!("Failed to open DICOM file: {:?}", instance.file_path))?;
+        let pixels = PixelData::new(&obj, obj.meta().transfer_syntax())
+            .with_context(|| format!("Failed to decode Pixel Data of {:?}", instance.file_path))?;
+        let this_layout = Layout {
+            rows: pixels.rows,
+            columns: pixels.columns,
+            samples_per_pixel: pixels.samples_per_pixel,
+            bits_allocated: pixels.bits_allocated,
+            signed: pixels.signed,
+        };
+
+        match &layout {
+            Some(layout) if *layout != this_layout => {
+                bail!("{:?} differs from the first slice in size or sample format", instance.file_path);
+            }
+            Some(_) => {}
+            None => {
+                photometric_interpretation = pixels.photometric_interpretation.clone();
+                rescaled = rescale && photometric_interpretation.starts_with("MONOCHROME");
+                write_header(&mut writer, &shape(&this_layout, slices.len()), dtype(&this_layout, rescaled))?;
+                layout = Some(this_layout);
+            }
+        }
+
+        while index < slices.len() && std::ptr::eq(slices[index].0, instance) {
+            let frame = slices[index].1;
+            let samples = pixels.frame(frame).with_context(|| format!("Failed to decode frame {} of {:?}", frame + 1, instance.file_path))?;
+            if rescaled {
+                let modality = ModalityLut::new(&obj, &pixels, frame)?;
+                for &sample in &samples {
+                    writer.write_all(&(modality.apply(sample) as f32).to_le_bytes())?;
+                }
+            } else {
+                write_samples(&mut writer, &samples, pixels.bits_allocated, pixels.signed)?;
+            }
+            index += 1;
+        }
+    }
+    writer.flush()?;
+    Ok((layout.expect("at least one slice"), photometric_interpretation, rescaled))
+}
+
+fn shape(layout: &Layout, slices: usize) -> Vec<usize> {
+    let mut shape = vec![slices, layout.rows, layout.columns];
+    if layout.samples_per_pixel > 1 {
+        shape.push(layout.samples_per_pixel);
+    }
+    shape
+}
+
+/// NumPy type of the samples: stored values keep Bits Allocated and Pixel Representation
+fn dtype(layout: &Layout, rescaled: bool) -> &'static str {
+    match (rescaled, layout.bits_allocated, layout.signed) {
+        (true, ..) => "<f4",
+        (false, 1 | 8, false) => "|u1",
+        (false, 1 | 8, true) => "|i1",
+        (false, 16, false) => "<u2",
+        (false, 16, true) => "<i2",
+        (false, _, false) => "<u4",
+        (false, _, true) => "<i4",
+    }
+}
+
+/// NPY format version 1.0 header, padded so the data starts on a 64-byte boundary
+fn write_header(writer: &mut impl Write, shape: &[usize], dtype: &str) -> Result<()> {
+    let dimensions: Vec<String> = shape.iter().map(|dimension| dimension.to_string()).collect();
+    let mut header = format!("{{'descr': '{}', 'fortran_order': False, 'shape': ({},), }}", dtype, dimensions.join(", "));
+    let padded = (10 + header.len() + 1).div_ceil(64) * 64;
+    header.push_str(&" ".repeat(padded - 10 - header.len() - 1));
+    header.push('\n');
+
+    writer.write_all(b"\x93NUMPY\x01\x00")?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    Ok(())
+}
+
+fn write_samples(writer: &mut impl Write, samples: &[i32], bits_allocated: u16, signed: bool) -> Result<()> {
+    for &sample in samples {
+        match (bits_allocated, signed) {
+            (1 | 8, false) => writer.write_all(&[sample as u8])?,
+            (1 | 8, true) => writer.write_all(&(sample as i8).to_le_bytes())?,
+            (16, false) => writer.write_all(&(sample as u16).to_le_bytes())?,
+            (16, true) => writer.write_all(&(sample as i16).to_le_bytes())?,
+            _ => writer.write_all(&sample.to_le_bytes())?,
+        }
+    }
+    Ok(())
+}
+
+/// Spacing, origin and axis directions from the voxel-to-LPS affine, whose columns are the
+/// column, row and slice steps
+fn spatial(geometry: &SeriesGeometry) -> ([f64; 3], [f64; 3], [[f64; 3]; 3]) {
+    let affine = &geometry.affine_lps;
+    let step = |axis: usize| [affine[0][axis], affine[1][axis], affine[2][axis]];
+    let length = |v: [f64; 3]| (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    let unit = |v: [f64; 3]| {
+        let length = length(v);
+        if length > 0.0 { [v[0] / length, v[1] / length, v[2] / length] } else { v }
+    };
+
+    // Array axes are slice, row, column: the reverse of the affine's
+    let (slice, row, column) = (step(2), step(1), step(0));
+    (
+        [length(slice), length(row), length(column)],
+        step(3),
+        [unit(slice), unit(row), unit(column)],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(samples_per_pixel: usize, bits_allocated: u16, signed: bool) -> Layout {
+        Layout { rows: 512, columns: 256, samples_per_pixel, bits_allocated, signed }
+    }
+
+    /// The header dictionary, after checking the magic, version and 64-byte alignment
+    fn header(shape: &[usize], dtype: &str) -> String {
+        let mut bytes = Vec::new();
+        write_header(&mut bytes, shape, dtype).unwrap();
+        assert_eq!(&bytes[..8], b"\x93NUMPY\x01\x00");
+        assert_eq!(usize::from(u16::from_le_bytes([bytes[8], bytes[9]])), bytes.len() - 10);
+        assert_eq!(bytes.len() % 64, 0);
+        assert_eq!(bytes.last(), Some(&b'\n'));
+        String::from_utf8(bytes[10..].to_vec()).unwrap().trim_end().to_string()
+    }
+
+    #[test]
+    fn headers_describe_the_array_and_align_the_data() {
+        assert_eq!(header(&[3, 512, 256], "<i2"), "{'descr': '<i2', 'fortran_order': False, 'shape': (3, 512, 256,), }");
+        assert_eq!(header(&[1, 2, 3], "|u1"), "{'descr': '|u1', 'fortran_order': False, 'shape': (1, 2, 3,), }");
+        // Long enough to need a second 64-byte block
+        let dict = header(&[123_456_789, 987_654_321, 555_555_555, 3], "<f4");
+        assert!(dict.ends_with("'shape': (123456789, 987654321, 555555555, 3,), }"), "{}", dict);
+    }
+
+    #[test]
+    fn shapes_and_dtypes_follow_the_sample_format() {
+        assert_eq!(shape(&layout(1, 16, true), 40), [40, 512, 256]);
+        assert_eq!(shape(&layout(3, 8, false), 2), [2, 512, 256, 3]);
+
+        assert_eq!(dtype(&layout(1, 1, false), false), "|u1");
+        assert_eq!(dtype(&layout(1, 8, true), false), "|i1");
+        assert_eq!(dtype(&layout(1, 16, false), false), "<u2");
+        assert_eq!(dtype(&layout(1, 16, true), false), "<i2");
+        assert_eq!(dtype(&layout(1, 32, false), false), "<u4");
+        assert_eq!(dtype(&layout(1, 32, true), false), "<i4");
+        assert_eq!(dtype(&layout(1, 16, true), true), "<f4");
+    }
+
+    #[test]
+    fn samples_are_written_little_endian_in_the_dtype_width() {
+        let mut bytes = Vec::new();
+        write_samples(&mut bytes, &[-2, 300], 16, true).unwrap();
+        assert_eq!(bytes, [0xFE, 0xFF, 0x2C, 0x01]);
+        bytes.clear();
+        write_samples(&mut bytes, &[255, 1], 8, false).unwrap();
+        assert_eq!(bytes, [0xFF, 0x01]);
+        bytes.clear();
+        write_samples(&mut bytes, &[-1], 32, true).unwrap();
+        assert_eq!(bytes, [0xFF; 4]);
+    }
+}
@@ -0,0 +1,372 @@
+//! `--watch` hot-folder mode: convert files as they land in the input directory.
+//! Native file events are used where available, with a polling watcher as fallback
+//! (and for network shares with `--poll`). State lives in the `--incremental` manifest,
+//! so a restarted watcher merges into the same outputs. Files that fail to convert without
+//! a quarantine folder are retried with a growing delay.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
+use anyhow::{Context, Result, bail};
+use dicom_object::OpenFileOptions;
+use notify::{Config, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use rayon::prelude::*;
+use walkdir::WalkDir;
+use crate::index::CatalogIndex;
+use crate::manifest::{MANIFEST_DIR, Manifest};
+use crate::{DicomInstance, DicomProcessor, collect_dicom_files, forward, is_likely_dicom_file, remove_stale_outputs, update_index, write_results};
+
+/// How often pending files are checked and, when polling, the directory is rescanned
+const TICK: Duration = Duration::from_millis(500);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Delay before the first retry of a failed file, doubled on every further failure
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(600);
+/// Settled files that do not parse are given this many more settle periods to complete
+const PARSE_CHECKS: u32 = 3;
+
+/// A file seen in the input directory that may still be being written
+struct PendingFile {
+    size: u64,
+    modified: Option<SystemTime>,
+    stable_since: Instant,
+    /// Extra wait of a file that failed before
+    retry_after: Duration,
+    /// Settle periods after which the file did not parse yet
+    parse_checks: u32,
+}
+
+impl PendingFile {
+    fn new(retry_after: Duration) -> Self {
+        Self { size: u64::MAX, modified: None, stable_since: Instant::now(), retry_after, parse_checks: 0 }
+    }
+}
+
+pub fn run(processor: &DicomProcessor, input: &Path, output_dir: &Path, mut index: Option<&mut CatalogIndex>) -> Result<()> {
+    let cli = &processor.cli;
+    if !input.is_dir() {
+        bail!("--watch requires a directory input: {:?}", input);
+    }
+    let input = fs::canonicalize(input)?;
+    let settle = Duration::from_secs_f64(cli.settle);
+
+    let mut excluded = vec![fs::canonicalize(output_dir)?.join(MANIFEST_DIR)];
+    let archive_dir = cli.archive_dir.as_ref()
+        .map(|dir| fs::create_dir_all(dir).and_then(|_| fs::canonicalize(dir)))
+        .transpose()?;
+    for dir in [&cli.archive_dir, &cli.quarantine_dir].into_iter().flatten() {
+        fs::create_dir_all(dir)?;
+        excluded.push(fs::canonicalize(dir)?);
+    }
+    let is_excluded = |path: &Path| excluded.iter().any(|dir| path.starts_with(dir));
+
+    let mut manifest = Manifest::load(output_dir, processor.manifest_options())?;
+    if cli.verbose && manifest.invalidated {
+        println!("♻️  Options changed since the last run, converting all files");
+    }
+
+    let (sender, events) = mpsc::channel();
+    let _watcher = start_watcher(&input, cli.poll, sender, cli.verbose)?;
+
+    // Files already waiting when the watcher starts
+    let mut pending: HashMap<PathBuf, PendingFile> = HashMap::new();
+    let mut failures: HashMap<PathBuf, u32> = HashMap::new();
+    for file in collect_dicom_files(&input, cli.max_depth, false)? {
+        if !is_excluded(&file) && !manifest.is_unchanged(&file)? {
+            track(&mut pending, file);
+        }
+    }
+    if cli.verbose {
+        println!("👀 Watching {:?} ({} files pending)", input, pending.len());
+    }
+
+    loop {
+        match events.recv_timeout(TICK) {
+            Ok(Ok(event)) => {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    for path in event.paths {
+                        if path.is_dir() {
+                            // A copied folder may be populated before its own watch is set up
+                            for entry in WalkDir::new(&path).into_iter().filter_map(|e| e.ok()) {
+                                if entry.file_type().is_file() && !is_excluded(entry.path()) {
+                                    track(&mut pending, entry.into_path());
+                                }
+                            }
+                        } else if path.is_file() && !is_excluded(&path) {
+                            track(&mut pending, path);
+                        }
+                    }
+                }
+            }
+            Ok(Err(e)) => {
+                if cli.verbose {
+                    eprintln!("⚠️  Watch error: {}", e);
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => bail!("File watcher stopped unexpectedly"),
+        }
+
+        let ready = settled_files(&mut pending, settle);
+        if ready.is_empty() {
+            continue;
+        }
+        // A failed batch is retried as a whole: nothing is archived before its outputs are saved
+        let retry = convert_batch(processor, &input, archive_dir.as_deref(), output_dir, &mut manifest, index.as_deref_mut(), &ready)
+            .unwrap_or_else(|e| {
+                eprintln!("❌ Failed to convert {} files: {:#}", ready.len(), e);
+                ready.clone()
+            });
+        let retry: HashSet<PathBuf> = retry.into_iter().collect();
+        for file in ready {
+            if !retry.contains(&file) {
+                failures.remove(&file);
+                continue;
+            }
+            let attempts = failures.entry(file.clone()).or_insert(0);
+            *attempts += 1;
+            let delay = RETRY_DELAY.saturating_mul(1 << (*attempts - 1).min(16)).min(MAX_RETRY_DELAY);
+            if cli.verbose {
+                println!("🔁 Retrying {:?} in {:?}", file, delay);
+            }
+            pending.insert(file, PendingFile::new(delay));
+        }
+    }
+}
+
+fn start_watcher(input: &Path, poll: bool, sender: mpsc::Sender<notify::Result<notify::Event>>, verbose: bool) -> Result<Box<dyn Watcher>> {
+    if !poll {
+        let native = RecommendedWatcher::new(sender.clone(), Config::default())
+            .and_then(|mut watcher| watcher.watch(input, RecursiveMode::Recursive).map(|_| watcher));
+        match native {
+            Ok(watcher) => return Ok(Box::new(watcher)),
+            Err(e) => {
+                if verbose {
+                    println!("⚠️  File system events unavailable ({}), polling every {:?}", e, POLL_INTERVAL);
+                }
+            }
+        }
+    }
+
+    let mut watcher = PollWatcher::new(sender, Config::default().with_poll_interval(POLL_INTERVAL))?;
+    watcher.watch(input, RecursiveMode::Recursive)
+        .with_context(|| format!("Failed to watch {:?}", input))?;
+    Ok(Box::new(watcher))
+}
+
+/// Start or restart the stability clock of a file
+fn track(pending: &mut HashMap<PathBuf, PendingFile>, path: PathBuf) {
+    let path = fs::canonicalize(&path).unwrap_or(path);
+    pending.insert(path, PendingFile::new(Duration::ZERO));
+}
+
+/// Remove and return the DICOM files whose size and modification time have not changed for
+/// `settle` (plus any retry delay) and that parse, or still do not after `PARSE_CHECKS` more
+/// settle periods. Other files are dropped.
+fn settled_files(pending: &mut HashMap<PathBuf, PendingFile>, settle: Duration) -> Vec<PathBuf> {
+    let mut ready = Vec::new();
+    pending.retain(|path, file| {
+        let Ok(metadata) = fs::metadata(path) else {
+            // Deleted or moved away before it settled
+            return false;
+        };
+        let modified = metadata.modified().ok();
+        if metadata.len() != file.size || modified != file.modified {
+            *file = PendingFile { size: metadata.len(), modified, stable_since: Instant::now(), ..*file };
+            return true;
+        }
+        if file.stable_since.elapsed() < settle + file.retry_after {
+            return true;
+        }
+        if !is_likely_dicom_file(path) {
+            return false;
+        }
+        // Writers that preallocate or keep the modification time can look settled too early
+        if file.parse_checks < PARSE_CHECKS && OpenFileOptions::new().open_file(path).is_err() {
+            file.parse_checks += 1;
+            file.stable_since = Instant::now();
+            file.retry_after = Duration::ZERO;
+            return true;
+        }
+        ready.push(path.clone());
+        false
+    });
+    ready.sort();
+    ready
+}
+
+/// Convert the files, rewrite the outputs of the studies they belong to and then move them
+/// to the archive or quarantine folder. Returns the files to retry: those that failed without
+/// a quarantine folder and those that could not be forwarded.
+fn convert_batch(
+    processor: &DicomProcessor,
+    input: &Path,
+    archive_dir: Option<&Path>,
+    output_dir: &Path,
+    manifest: &mut Manifest,
+    index: Option<&mut CatalogIndex>,
+    files: &[PathBuf],
+) -> Result<Vec<PathBuf>> {
+    let cli = &processor.cli;
+    let outcomes: Vec<Result<Option<DicomInstance>>> = if cli.parallel {
+        files.par_iter().map(|file| processor.process_file(file)).collect()
+    } else {
+        files.iter().map(|file| processor.process_file(file)).collect()
+    };
+
+    let mut converted = Vec::new();
+    let mut recorded = Vec::new();
+    let mut filtered_out = Vec::new();
+    let mut retry = Vec::new();
+    let mut failed = 0;
+
+    for (file, outcome) in files.iter().zip(outcomes) {
+        match outcome {
+            Ok(Some(instance)) => {
+                converted.push(instance);
+                recorded.push(file.clone());
+            }
+            Ok(None) => {
+                filtered_out.push(file.clone());
+                recorded.push(file.clone());
+            }
+            Err(e) => {
+                failed += 1;
+                if cli.verbose {
+                    eprintln!("❌ Failed to process {:?}: {:#}", file, e);
+                }
+                match &cli.quarantine_dir {
+                    Some(quarantine) => {
+                        let destination = move_file(file, input, quarantine)?;
+                        let mut note = destination.clone().into_os_string();
+                        note.push(".error.txt");
+                        fs::write(note, format!("{:?}\n", e))?;
+                    }
+                    None => retry.push(file.clone()),
+                }
+            }
+        }
+    }
+
+    // The processor's own list only feeds the one-shot summary
+    processor.filtered_out.lock().unwrap().clear();
+
+    manifest.record(&recorded, &converted, &filtered_out, cli.parallel)?;
+
+    // Forwarding reads the files before they are archived; files that could not be forwarded
+    // stay in place and are converted and sent again
+    let mut forward_failed = 0;
+    if cli.forward.is_some() && !converted.is_empty() {
+        for status in forward::forward(processor, &converted)? {
+            if !status.is_stored() {
+                forward_failed += 1;
+                eprintln!("❌ Failed to forward {}: {}", status.file_path, status.describe());
+                manifest.retry(Path::new(&status.file_path));
+                retry.push(PathBuf::from(&status.file_path));
+            }
+        }
+    }
+
+    // Archive names are claimed up front so the outputs and manifest already refer to them
+    let mut moves: Vec<(PathBuf, PathBuf)> = Vec::new();
+    if let Some(archive_dir) = archive_dir {
+        for file in recorded.iter().filter(|file| !retry.contains(file)) {
+            let claimed: Vec<&Path> = moves.iter().map(|(_, destination)| destination.as_path()).collect();
+            let destination = free_destination(file, input, archive_dir, &claimed);
+            manifest.rename(file, &destination);
+            moves.push((file.clone(), destination));
+        }
+        for instance in &mut converted {
+            if let Some((_, destination)) = moves.iter().find(|(file, _)| file.as_os_str() == instance.file_path.as_str()) {
+                instance.file_path = destination.to_string_lossy().to_string();
+            }
+        }
+    }
+
+    if let Err(e) = write_outputs(processor, output_dir, manifest) {
+        // The batch is retried under its original names
+        for (file, destination) in &moves {
+            manifest.rename(destination, file);
+        }
+        return Err(e);
+    }
+
+    // Originals are only moved once the outputs and manifest are saved
+    let mut unarchived = 0;
+    for (file, destination) in &moves {
+        if let Err(e) = rename_file(file, destination) {
+            eprintln!("❌ Failed to archive {:?}: {:#}", file, e);
+            manifest.rename(destination, file);
+            unarchived += 1;
+        }
+    }
+    if unarchived > 0 {
+        manifest.save()?;
+    }
+
+    if let Some(index) = index {
+        update_index(index, &converted, processor)?;
+    }
+
+    if cli.verbose {
+        println!(
+            "📥 {} converted, {} skipped by --where, {} failed",
+            converted.len(), filtered_out.len(), failed
+        );
+        if cli.forward.is_some() {
+            println!("📤 {} forwarded, {} failed", converted.len() - forward_failed, forward_failed);
+        }
+    }
+    Ok(retry)
+}
+
+/// Rewrite the outputs of the studies changed since the last call and save the manifest
+fn write_outputs(processor: &DicomProcessor, output_dir: &Path, manifest: &mut Manifest) -> Result<()> {
+    let affected = manifest.take_affected_studies();
+    if !affected.is_empty() {
+        let instances: Vec<DicomInstance> = manifest.instances().cloned().collect();
+        let outputs = write_results(&instances, output_dir, processor, Some(&affected))?;
+        let stale = manifest.replace_outputs(output_dir, &outputs);
+        remove_stale_outputs(output_dir, &stale, processor.cli.verbose);
+    }
+    manifest.save()
+}
+
+/// Move a file below `target_dir`, keeping its path relative to the input directory
+/// and numbering it when the name is taken
+fn move_file(file: &Path, input: &Path, target_dir: &Path) -> Result<PathBuf> {
+    let destination = free_destination(file, input, target_dir, &[]);
+    rename_file(file, &destination)?;
+    Ok(fs::canonicalize(&destination).unwrap_or(destination))
+}
+
+/// Path of `file` below `target_dir`, relative to the input directory, numbered when the
+/// name is taken on disk or in `claimed`
+fn free_destination(file: &Path, input: &Path, target_dir: &Path, claimed: &[&Path]) -> PathBuf {
+    let relative = file.strip_prefix(input).unwrap_or(file.file_name().map(Path::new).unwrap_or(file));
+    let mut destination = target_dir.join(relative);
+
+    let stem = destination.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let extension = destination.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+    let mut counter = 1;
+    while destination.exists() || claimed.contains(&destination.as_path()) {
+        destination.set_file_name(format!("{}_{}{}", stem, counter, extension));
+        counter += 1;
+    }
+    destination
+}
+
+fn rename_file(file: &Path, destination: &Path) -> Result<()> {
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Rename fails across file systems
+    if fs::rename(file, destination).is_err() {
+        fs::copy(file, destination)
+            .with_context(|| format!("Failed to move {:?} to {:?}", file, destination))?;
+        fs::remove_file(file)?;
+    }
+    Ok(())
+}
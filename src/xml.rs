@@ -0,0 +1,277 @@
+//! PS3.19 Native DICOM Model XML, rendered straight from the DICOM file so that
+//! nested sequences and binary values are preserved.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use anyhow::{Context, Result};
+use base64::Engine;
+use dicom_core::{Tag, VR, header::Header, dictionary::DataDictionary, value::{PrimitiveValue, Value}};
+use dicom_object::{InMemDicomObject, OpenFileOptions, mem::InMemElement};
+
+/// Binary values larger than this are written to a bulk data file instead of inline base64
+const BULK_DATA_THRESHOLD: usize = 1024;
+
+/// Namespace of the Native DICOM Model schema
+const NAMESPACE: &str = "http://dicom.nema.org/PS3.19/models/NativeDICOM";
+
+/// Where binary values of one document go
+struct BulkData<'a> {
+    /// Directory for bulk data files, next to the XML document
+    directory: &'a Path,
+    /// `directory` as referenced from the XML document
+    uri_prefix: String,
+}
+
+/// Render the file as a NativeDicomModel document. Large binary values are
+/// written under `<document stem>_bulk/` and referenced with `BulkData` URIs.
+pub fn create_document(file_path: &Path, output_file: &Path, include_private: bool) -> Result<String> {
+    let obj = OpenFileOptions::new()
+        .open_file(file_path)
+        .with_context(|| format!("Failed to open DICOM file: {:?}", file_path))?;
+
+    let stem = output_file.file_stem().unwrap_or_default().to_string_lossy();
+    let bulk_dir_name = format!("{}_bulk", stem);
+    let bulk_data = BulkData {
+        directory: &output_file.parent().unwrap_or(Path::new(".")).join(&bulk_dir_name),
+        uri_prefix: bulk_dir_name,
+    };
+
+    let mut xml = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<NativeDicomModel xmlns=\"{}\" xml:space=\"preserve\">\n", NAMESPACE);
+    write_dataset(&mut xml, &obj, include_private, &bulk_data, "", 1)?;
+    xml.push_str("</NativeDicomModel>\n");
+
+    Ok(xml)
+}
+
+fn write_dataset(
+    xml: &mut String,
+    dataset: &InMemDicomObject,
+    include_private: bool,
+    bulk_data: &BulkData,
+    path: &str,
+    depth: usize,
+) -> Result<()> {
+    for element in dataset.iter() {
+        let tag = element.tag();
+        if !include_private && tag.group() % 2 == 1 {
+            continue;
+        }
+        write_attribute(xml, dataset, element, include_private, bulk_data, path, depth)?;
+    }
+    Ok(())
+}
+
+fn write_attribute(
+    xml: &mut String,
+    dataset: &InMemDicomObject,
+    element: &InMemElement,
+    include_private: bool,
+    bulk_data: &BulkData,
+    path: &str,
+    depth: usize,
+) -> Result<()> {
+    let tag = element.tag();
+    let vr = element.vr();
+    let indent = "  ".repeat(depth);
+    let tag_hex = format!("{:04X}{:04X}", tag.group(), tag.element());
+
+    write!(xml, "{}<DicomAttribute tag=\"{}\" vr=\"{}\"", indent, tag_hex, vr)?;
+    if tag.group() % 2 == 1 {
+        if let Some(creator) = private_creator(dataset, tag) {
+            write!(xml, " privateCreator=\"{}\"", escape(&creator))?;
+        }
+    } else if let Some(entry) = dicom_dictionary_std::StandardDataDictionary.by_tag(tag) {
+        write!(xml, " keyword=\"{}\"", entry.alias)?;
+    }
+
+    let element_path = format!("{}{}", path, tag_hex);
+    let mut body = String::new();
+    match element.value() {
+        Value::Sequence(sequence) => {
+            for (index, item) in sequence.items().iter().enumerate() {
+                writeln!(body, "{}  <Item number=\"{}\">", indent, index + 1)?;
+                let item_path = format!("{}.{}.", element_path, index + 1);
+                write_dataset(&mut body, item, include_private, bulk_data, &item_path, depth + 2)?;
+                writeln!(body, "{}  </Item>", indent)?;
+            }
+        }
+        Value::PixelSequence(sequence) => {
+            // Fragments are concatenated; for single-frame images this is the compressed frame
+            let bytes: Vec<u8> = sequence.fragments().iter().flatten().copied().collect();
+            write_binary(&mut body, &bytes, bulk_data, &element_path, &indent)?;
+        }
+        Value::Primitive(value) => {
+            write_primitive(&mut body, vr, value, bulk_data, &element_path, &indent)?;
+        }
+    }
+
+    if body.is_empty() {
+        xml.push_str("/>\n");
+    } else {
+        xml.push_str(">\n");
+        xml.push_str(&body);
+        writeln!(xml, "{}</DicomAttribute>", indent)?;
+    }
+    Ok(())
+}
+
+fn write_primitive(
+    xml: &mut String,
+    vr: VR,
+    value: &PrimitiveValue,
+    bulk_data: &BulkData,
+    element_path: &str,
+    indent: &str,
+) -> Result<()> {
+    match vr {
+        VR::OB | VR::OD | VR::OF | VR::OL | VR::OV | VR::OW | VR::UN => {
+            if value.calculate_byte_len() > 0 {
+                write_binary(xml, &value.to_bytes(), bulk_data, element_path, indent)?;
+            }
+        }
+        VR::PN => {
+            for (index, name) in value.to_multi_str().iter().enumerate() {
+                write_person_name(xml, name, index + 1, indent)?;
+            }
+        }
+        VR::AT => {
+            if let PrimitiveValue::Tags(tags) = value {
+                for (index, tag) in tags.iter().enumerate() {
+                    writeln!(xml, "{}  <Value number=\"{}\">{:04X}{:04X}</Value>", indent, index + 1, tag.group(), tag.element())?;
+                }
+            }
+        }
+        _ => {
+            for (index, text) in value.to_multi_str().iter().enumerate() {
+                let text = text.trim_end_matches([' ', '\0']);
+                if !text.is_empty() {
+                    writeln!(xml, "{}  <Value number=\"{}\">{}</Value>", indent, index + 1, escape(text))?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_binary(xml: &mut String, bytes: &[u8], bulk_data: &BulkData, element_path: &str, indent: &str) -> Result<()> {
+    if bytes.len() <= BULK_DATA_THRESHOLD {
+        writeln!(
+            xml,
+            "{}  <InlineBinary>{}</InlineBinary>",
+            indent,
+            base64::engine::general_purpose::STANDARD.encode(bytes)
+        )?;
+        return Ok(());
+    }
+
+    fs::create_dir_all(bulk_data.directory)?;
+    let file_name = format!("{}.raw", element_path);
+    let bulk_file = bulk_data.directory.join(&file_name);
+    fs::write(&bulk_file, bytes)
+        .with_context(|| format!("Failed to write bulk data file: {:?}", bulk_file))?;
+
+    writeln!(xml, "{}  <BulkData uri=\"{}/{}\"/>", indent, escape(&bulk_data.uri_prefix), escape(&file_name))?;
+    Ok(())
+}
+
+/// PN value groups (alphabetic=ideographic=phonetic) and components (family^given^middle^prefix^suffix)
+fn write_person_name(xml: &mut String, name: &str, number: usize, indent: &str) -> Result<()> {
+    const GROUPS: [&str; 3] = ["Alphabetic", "Ideographic", "Phonetic"];
+    const COMPONENTS: [&str; 5] = ["FamilyName", "GivenName", "MiddleName", "NamePrefix", "NameSuffix"];
+
+    writeln!(xml, "{}  <PersonName number=\"{}\">", indent, number)?;
+    for (group, group_value) in GROUPS.iter().zip(name.trim_end().split('=')) {
+        if group_value.is_empty() {
+            continue;
+        }
+        writeln!(xml, "{}    <{}>", indent, group)?;
+        for (component, component_value) in COMPONENTS.iter().zip(group_value.split('^')) {
+            if !component_value.is_empty() {
+                writeln!(xml, "{}      <{}>{}</{}>", indent, component, escape(component_value), component)?;
+            }
+        }
+        writeln!(xml, "{}    </{}>", indent, group)?;
+    }
+    writeln!(xml, "{}  </PersonName>", indent)?;
+    Ok(())
+}
+
+/// Value of the Private Creator element reserving the block of a private tag
+fn private_creator(dataset: &InMemDicomObject, tag: Tag) -> Option<String> {
+    if tag.element() < 0x1000 {
+        return None;
+    }
+    let creator = dataset.element(Tag(tag.group(), tag.element() >> 8)).ok()?;
+    Some(creator.to_str().ok()?.trim_end().to_string())
+}
+
+/// Escape XML markup characters and drop control characters XML 1.0 cannot carry
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicom_core::{DataElement, value::DataSetSequence};
+    use dicom_dictionary_std::tags;
+
+    #[test]
+    fn writes_sequences_names_and_bulk_data() {
+        let item = InMemDicomObject::from_element_iter([
+            DataElement::new(tags::REFERENCED_SOP_INSTANCE_UID, VR::UI, PrimitiveValue::from("1.2.3")),
+        ]);
+        let dataset = InMemDicomObject::from_element_iter([
+            DataElement::new(tags::PATIENT_NAME, VR::PN, PrimitiveValue::from("DOE^JOHN=ドウ^ジョン")),
+            DataElement::new(tags::STUDY_DESCRIPTION, VR::LO, PrimitiveValue::from("CT <head> & neck")),
+            DataElement::new(tags::REFERENCED_IMAGE_SEQUENCE, VR::SQ, DataSetSequence::from(vec![item])),
+            DataElement::new(tags::ICON_IMAGE_SEQUENCE, VR::OB, PrimitiveValue::from(vec![7u8; 2000])),
+            DataElement::new(tags::PIXEL_PADDING_VALUE, VR::OB, PrimitiveValue::from(vec![1u8, 2, 3])),
+            DataElement::new(Tag(0x0009, 0x0010), VR::LO, PrimitiveValue::from("ACME")),
+            DataElement::new(Tag(0x0009, 0x1001), VR::LO, PrimitiveValue::from("secret")),
+        ]);
+        let dir = tempfile::tempdir().unwrap();
+        let bulk_data = BulkData { directory: &dir.path().join("doc_bulk"), uri_prefix: "doc_bulk".to_string() };
+
+        let mut public = String::new();
+        write_dataset(&mut public, &dataset, false, &bulk_data, "", 1).unwrap();
+        assert!(public.contains("<Alphabetic>\n        <FamilyName>DOE</FamilyName>\n        <GivenName>JOHN</GivenName>"));
+        assert!(public.contains("<Ideographic>"));
+        assert!(public.contains("<Value number=\"1\">CT &lt;head&gt; &amp; neck</Value>"));
+        assert!(public.contains("<Item number=\"1\">\n      <DicomAttribute tag=\"00081155\" vr=\"UI\" keyword=\"ReferencedSOPInstanceUID\">"));
+        assert!(public.contains("<InlineBinary>AQID</InlineBinary>"));
+        assert!(public.contains("<BulkData uri=\"doc_bulk/00880200.raw\"/>"));
+        assert_eq!(fs::read(dir.path().join("doc_bulk/00880200.raw")).unwrap(), vec![7u8; 2000]);
+        assert!(!public.contains("secret"));
+
+        let mut private = String::new();
+        write_dataset(&mut private, &dataset, true, &bulk_data, "", 1).unwrap();
+        assert!(private.contains("<DicomAttribute tag=\"00091001\" vr=\"LO\" privateCreator=\"ACME\">"));
+    }
+
+    #[test]
+    fn renders_sample_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let xml = create_document(Path::new("data/693_J2KR.dcm"), &dir.path().join("x.xml"), false).unwrap();
+        assert!(xml.starts_with(concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<NativeDicomModel xmlns=\"http://dicom.nema.org/PS3.19/models/NativeDICOM\" xml:space=\"preserve\">\n",
+        )));
+        assert!(xml.contains("keyword=\"SOPInstanceUID\""));
+        assert!(xml.contains("<BulkData uri=\"x_bulk/7FE00010.raw\"/>"));
+    }
+}
@@ -0,0 +1,75 @@
+//! Small DICOM files and a runner for the command line tests.
+
+#![allow(dead_code)]
+
+use std::path::Path;
+use std::process::{Command, Output};
+use dicom_core::{DataElement, PrimitiveValue, Tag, VR};
+use dicom_dictionary_std::{tags, uids};
+use dicom_object::{FileMetaTableBuilder, InMemDicomObject};
+
+/// CT image with patient, study and series attributes and no Pixel Data
+pub fn instance(study_uid: &str, series_uid: &str, sop_uid: &str) -> InMemDicomObject {
+    let mut obj = InMemDicomObject::new_empty();
+    put_str(&mut obj, tags::SOP_CLASS_UID, VR::UI, uids::CT_IMAGE_STORAGE);
+    put_str(&mut obj, tags::SOP_INSTANCE_UID, VR::UI, sop_uid);
+    put_str(&mut obj, tags::STUDY_INSTANCE_UID, VR::UI, study_uid);
+    put_str(&mut obj, tags::SERIES_INSTANCE_UID, VR::UI, series_uid);
+    put_str(&mut obj, tags::PATIENT_NAME, VR::PN, "DOE^JANE");
+    put_str(&mut obj, tags::PATIENT_ID, VR::LO, "P1");
+    put_str(&mut obj, tags::STUDY_DATE, VR::DA, "20240102");
+    put_str(&mut obj, tags::MODALITY, VR::CS, "CT");
+    obj
+}
+
+/// 16-bit signed MONOCHROME2 Pixel Data of `rows` x `columns`, one frame
+pub fn with_pixels(obj: &mut InMemDicomObject, rows: u16, columns: u16, samples: &[i16]) {
+    put_str(obj, tags::PHOTOMETRIC_INTERPRETATION, VR::CS, "MONOCHROME2");
+    obj.put(DataElement::new(tags::SAMPLES_PER_PIXEL, VR::US, PrimitiveValue::from(1u16)));
+    obj.put(DataElement::new(tags::ROWS, VR::US, PrimitiveValue::from(rows)));
+    obj.put(DataElement::new(tags::COLUMNS, VR::US, PrimitiveValue::from(columns)));
+    obj.put(DataElement::new(tags::BITS_ALLOCATED, VR::US, PrimitiveValue::from(16u16)));
+    obj.put(DataElement::new(tags::BITS_STORED, VR::US, PrimitiveValue::from(16u16)));
+    obj.put(DataElement::new(tags::HIGH_BIT, VR::US, PrimitiveValue::from(15u16)));
+    obj.put(DataElement::new(tags::PIXEL_REPRESENTATION, VR::US, PrimitiveValue::from(1u16)));
+    obj.put(DataElement::new(tags::PIXEL_DATA, VR::OW, PrimitiveValue::I16(samples.iter().copied().collect())));
+}
+
+pub fn put_str(obj: &mut InMemDicomObject, tag: Tag, vr: VR, value: &str) {
+    obj.put(DataElement::new(tag, vr, PrimitiveValue::from(value)));
+}
+
+/// Save as explicit VR little endian Part 10 file
+pub fn save(obj: InMemDicomObject, path: &Path) {
+    let sop_class = obj.element(tags::SOP_CLASS_UID).unwrap().to_str().unwrap().to_string();
+    let sop_instance = obj.element(tags::SOP_INSTANCE_UID).unwrap().to_str().unwrap().to_string();
+    let file = obj
+        .with_meta(
+            FileMetaTableBuilder::new()
+                .media_storage_sop_class_uid(sop_class)
+                .media_storage_sop_instance_uid(sop_instance)
+                .transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN),
+        )
+        .unwrap();
+    file.write_to_file(path).unwrap();
+}
+
+/// Run the binary, failing the test with its output when it does not succeed
+pub fn run(args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_dicom-json")).args(args).output().unwrap();
+    assert!(
+        output.status.success(),
+        "dicom-json {:?} failed:\n{}{}",
+        args,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+/// Run the binary expecting it to fail; its stderr
+pub fn run_failing(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_dicom-json")).args(args).output().unwrap();
+    assert!(!output.status.success(), "dicom-json {:?} succeeded", args);
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
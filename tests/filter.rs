@@ -0,0 +1,39 @@
+mod common;
+
+use std::fs;
+use dicom_core::VR;
+use dicom_dictionary_std::tags;
+use serde_json::Value;
+
+#[test]
+fn where_skips_files_before_reading_their_pixels() {
+    let input = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+
+    let mut ct = common::instance("1.2.3", "1.2.3.1", "1.2.3.1.1");
+    common::with_pixels(&mut ct, 2, 2, &[1, 2, 3, 4]);
+    let ct_path = input.path().join("ct.dcm");
+    common::save(ct, &ct_path);
+    // Pixel Data cut short: the file cannot be read in full
+    let bytes = fs::read(&ct_path).unwrap();
+    fs::write(&ct_path, &bytes[..bytes.len() - 4]).unwrap();
+
+    let mut mr = common::instance("1.2.4", "1.2.4.1", "1.2.4.1.1");
+    common::put_str(&mut mr, tags::MODALITY, VR::CS, "MR");
+    common::with_pixels(&mut mr, 2, 2, &[1, 2, 3, 4]);
+    common::save(mr, &input.path().join("mr.dcm"));
+
+    let run = common::run(&[
+        input.path().to_str().unwrap(), "-o", output.path().to_str().unwrap(), "--where", "Modality=MR", "-v",
+    ]);
+    let stdout = String::from_utf8_lossy(&run.stdout);
+    let stderr = String::from_utf8_lossy(&run.stderr);
+    assert!(stdout.contains("1 files did not match --where"), "{}", stdout);
+    assert!(!stderr.contains("Failed to process"), "{}", stderr);
+
+    let json: Value = serde_json::from_str(&fs::read_to_string(output.path().join("dicom_data.json")).unwrap()).unwrap();
+    let instances = json["instances"].as_array().unwrap();
+    assert_eq!(instances.len(), 1);
+    assert_eq!(instances[0]["has_pixel_data"], true);
+    assert!(instances[0]["metadata"]["tags"].get("(7FE0,0010)").is_some());
+}
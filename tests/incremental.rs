@@ -0,0 +1,132 @@
+mod common;
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+/// Output files and directories below `output`, without the manifest
+fn outputs(output: &Path) -> Vec<String> {
+    let mut paths = Vec::new();
+    let mut dirs = vec![output.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let relative = path.strip_prefix(output).unwrap().to_string_lossy().to_string();
+            if relative.starts_with(".dicom-json") {
+                continue;
+            }
+            if path.is_dir() {
+                dirs.push(path);
+            }
+            paths.push(relative);
+        }
+    }
+    paths.sort();
+    paths
+}
+
+#[test]
+fn outputs_of_removed_studies_are_deleted() {
+    let input = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+    for (study, sop) in [("1.1", "1.1.1"), ("1.1", "1.1.2"), ("1.2", "1.2.1")] {
+        common::save(common::instance(study, &format!("{}.9", study), sop), &input.path().join(format!("{}.dcm", sop)));
+    }
+    let args = [input.path().to_str().unwrap(), "-o", output.path().to_str().unwrap(), "--split", "study", "--incremental"];
+
+    common::run(&args);
+    assert_eq!(outputs(output.path()), ["study_1_1", "study_1_1/study.json", "study_1_2", "study_1_2/study.json"]);
+
+    fs::remove_file(input.path().join("1.2.1.dcm")).unwrap();
+    common::run(&args);
+    assert_eq!(outputs(output.path()), ["study_1_1", "study_1_1/study.json"]);
+
+    // A study losing only some of its files is rewritten
+    fs::remove_file(input.path().join("1.1.2.dcm")).unwrap();
+    common::run(&args);
+    let study: serde_json::Value = serde_json::from_str(&fs::read_to_string(output.path().join("study_1_1/study.json")).unwrap()).unwrap();
+    assert_eq!(study["series"]["1.1.9"]["instances"].as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn output_options_invalidate_the_manifest() {
+    let input = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+    common::save(common::instance("1.1", "1.1.9", "1.1.1"), &input.path().join("1.dcm"));
+    let run = |extra: &[&str]| {
+        let mut args = vec![input.path().to_str().unwrap(), "-o", output.path().to_str().unwrap(), "--incremental"];
+        args.extend_from_slice(extra);
+        String::from_utf8_lossy(&common::run(&args).stdout).to_string()
+    };
+
+    run(&["--split", "series"]);
+    assert_eq!(outputs(output.path()), ["study_1_1", "study_1_1/series_1_1_9", "study_1_1/series_1_1_9/series.json"]);
+
+    // The series file and its emptied directory make way for the study file
+    run(&["--split", "study"]);
+    assert_eq!(outputs(output.path()), ["study_1_1", "study_1_1/study.json"]);
+
+    let compact = fs::read_to_string(output.path().join("study_1_1/study.json")).unwrap();
+    run(&["--split", "study", "--pretty"]);
+    let pretty = fs::read_to_string(output.path().join("study_1_1/study.json")).unwrap();
+    assert!(pretty.lines().count() > compact.lines().count());
+
+    for options in [&["--split", "study", "--pretty", "--name-template", "{PatientID}.json"][..], &["--format", "csv", "--columns", "PatientID"]] {
+        assert!(!run(&[options, &["-v"]].concat()).contains("No changes since the last run"), "{:?}", options);
+    }
+    assert!(run(&["--format", "csv", "--columns", "PatientID", "-v"]).contains("No changes since the last run"));
+    assert_eq!(outputs(output.path()), ["dicom_data.csv"]);
+}
+
+/// Write a ZIP archive of one study with the given SOP Instance UIDs
+fn write_archive(input: &Path, sops: &[&str]) -> std::path::PathBuf {
+    let archive_path = input.join("archive.zip");
+    let mut archive = zip::ZipWriter::new(File::create(&archive_path).unwrap());
+    for sop in sops {
+        let path = input.join(sop);
+        common::save(common::instance("1.1", "1.1.9", sop), &path);
+        archive.start_file(format!("{}.dcm", sop), zip::write::FileOptions::default()).unwrap();
+        archive.write_all(&fs::read(&path).unwrap()).unwrap();
+    }
+    archive.finish().unwrap();
+    archive_path
+}
+
+#[test]
+fn zip_entries_are_cached_across_runs() {
+    let input = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let archive_path = write_archive(input.path(), &["1.1.1", "1.1.2"]);
+
+    let args = [archive_path.to_str().unwrap(), "-o", output.path().to_str().unwrap(), "--incremental", "-v"];
+    common::run(&args);
+    let stdout = String::from_utf8_lossy(&common::run(&args).stdout).to_string();
+    assert!(stdout.contains("Incremental: 2 unchanged, 0 new or changed, 0 removed files"), "{}", stdout);
+}
+
+#[test]
+fn cached_zip_entries_are_read_from_the_current_extraction() {
+    let input = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let args = |archive_path: &Path| {
+        [archive_path.to_str().unwrap(), "-o", output.path().to_str().unwrap(), "--incremental", "--format", "xml", "-v"]
+            .map(str::to_string)
+    };
+
+    let archive_path = write_archive(input.path(), &["1.1.1", "1.1.2"]);
+    common::run(&args(&archive_path).each_ref().map(String::as_str));
+
+    // The extraction of the first run is gone, as after a temp directory cleanup
+    let cache = fs::read_to_string(output.path().join(".dicom-json/instances.jsonl")).unwrap();
+    for line in cache.lines() {
+        let (_, instance): (String, serde_json::Value) = serde_json::from_str(line).unwrap();
+        let extracted = Path::new(instance["file_path"].as_str().unwrap());
+        let _ = fs::remove_dir_all(extracted.parent().unwrap());
+    }
+
+    // The new entry rewrites the study, whose cached instances are read again for XML
+    let archive_path = write_archive(input.path(), &["1.1.1", "1.1.2", "1.1.3"]);
+    let stdout = String::from_utf8_lossy(&common::run(&args(&archive_path).each_ref().map(String::as_str)).stdout).to_string();
+    assert!(stdout.contains("Incremental: 2 unchanged, 1 new or changed, 0 removed files"), "{}", stdout);
+    assert_eq!(outputs(output.path()).iter().filter(|path| path.ends_with(".xml")).count(), 3);
+}
@@ -0,0 +1,27 @@
+mod common;
+
+use std::fs::File;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+
+#[test]
+fn filtering_out_every_file_writes_an_empty_table() {
+    let input = tempfile::tempdir().unwrap();
+    common::save(common::instance("1.2.3", "1.2.3.1", "1.2.3.1.1"), &input.path().join("a.dcm"));
+
+    // Streamed, and written in one go when the duplicate report needs every instance
+    for extra in [None, Some("--hash-pixels")] {
+        let output = tempfile::tempdir().unwrap();
+        let mut args = vec![
+            input.path().to_str().unwrap(), "-o", output.path().to_str().unwrap(),
+            "--format", "parquet", "--where", "Modality=MR", "--columns", "PatientID,Modality",
+        ];
+        args.extend(extra);
+        common::run(&args);
+
+        let reader = SerializedFileReader::new(File::open(output.path().join("dicom_data.parquet")).unwrap()).unwrap();
+        let metadata = reader.metadata().file_metadata();
+        assert_eq!(metadata.num_rows(), 0);
+        let columns: Vec<&str> = metadata.schema_descr().columns().iter().map(|column| column.name()).collect();
+        assert_eq!(columns, ["FilePath", "SOPInstanceUID", "TransferSyntaxUID", "HasPixelData", "PatientID", "Modality"]);
+    }
+}
@@ -0,0 +1,140 @@
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+struct Scp(Child);
+
+impl Drop for Scp {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+    }
+}
+
+fn start(store_dir: &Path, args: &[&str]) -> (Scp, u16) {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let child = Command::new(env!("CARGO_BIN_EXE_dicom-json"))
+        .args(["scp", store_dir.to_str().unwrap(), "--port", &port.to_string()])
+        .args(args)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    (Scp(child), port)
+}
+
+fn connect(port: u16) -> TcpStream {
+    let start = Instant::now();
+    loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+                return stream;
+            }
+            Err(_) if start.elapsed() < Duration::from_secs(15) => sleep(Duration::from_millis(100)),
+            Err(e) => panic!("scp did not start: {}", e),
+        }
+    }
+}
+
+fn item(item_type: u8, content: &[u8]) -> Vec<u8> {
+    let mut item = vec![item_type, 0];
+    item.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    item.extend_from_slice(content);
+    item
+}
+
+fn pdu(pdu_type: u8, body: &[u8]) -> Vec<u8> {
+    let mut pdu = vec![pdu_type, 0];
+    pdu.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    pdu.extend_from_slice(body);
+    pdu
+}
+
+/// A-ASSOCIATE-RQ to DICOMJSON proposing Verification in Implicit VR Little Endian
+fn associate_rq() -> Vec<u8> {
+    let mut body = vec![0, 1, 0, 0];
+    body.extend_from_slice(b"DICOMJSON       ECHOSCU         ");
+    body.extend_from_slice(&[0; 32]);
+    body.extend(item(0x10, b"1.2.840.10008.3.1.1.1"));
+    let mut context = vec![1, 0, 0, 0];
+    context.extend(item(0x30, b"1.2.840.10008.1.1"));
+    context.extend(item(0x40, b"1.2.840.10008.1.2\0"));
+    body.extend(item(0x20, &context));
+    body.extend(item(0x50, &item(0x51, &16384u32.to_be_bytes())));
+    pdu(0x01, &body)
+}
+
+/// PDU type and body
+fn read_pdu(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut header = [0u8; 6];
+    stream.read_exact(&mut header).unwrap();
+    let mut body = vec![0u8; u32::from_be_bytes(header[2..6].try_into().unwrap()) as usize];
+    stream.read_exact(&mut body).unwrap();
+    (header[0], body)
+}
+
+fn associate(port: u16) -> (TcpStream, u8, Vec<u8>) {
+    let mut stream = connect(port);
+    stream.write_all(&associate_rq()).unwrap();
+    let (pdu_type, body) = read_pdu(&mut stream);
+    (stream, pdu_type, body)
+}
+
+/// Associate, retrying while the SCP still reports the previous association as open
+fn associate_when_free(port: u16) -> TcpStream {
+    let start = Instant::now();
+    loop {
+        let (stream, pdu_type, _) = associate(port);
+        if pdu_type == 0x02 {
+            return stream;
+        }
+        assert!(start.elapsed() < Duration::from_secs(10), "association slot was not released");
+        sleep(Duration::from_millis(100));
+    }
+}
+
+fn release(mut stream: TcpStream) {
+    stream.write_all(&pdu(0x05, &[0; 4])).unwrap();
+    assert_eq!(read_pdu(&mut stream).0, 0x06);
+}
+
+#[test]
+fn associations_beyond_the_limit_are_rejected_as_transient() {
+    let store = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let (_scp, port) = start(store.path(), &["-o", output.path().to_str().unwrap(), "--max-associations", "1"]);
+
+    let (first, pdu_type, _) = associate(port);
+    assert_eq!(pdu_type, 0x02);
+
+    // Rejected-transient (2) by the service provider (3): local limit exceeded (2)
+    let (_, pdu_type, body) = associate(port);
+    assert_eq!((pdu_type, body), (0x03, vec![0, 2, 3, 2]));
+
+    release(first);
+    release(associate_when_free(port));
+}
+
+#[test]
+fn connections_without_an_association_request_are_dropped() {
+    let store = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let (_scp, port) = start(
+        store.path(),
+        &["-o", output.path().to_str().unwrap(), "--max-associations", "1", "--dimse-timeout", "1"],
+    );
+
+    let mut silent = connect(port);
+    let start = Instant::now();
+    let mut buffer = [0u8; 1];
+    match silent.read(&mut buffer) {
+        Ok(read) => assert_eq!(read, 0),
+        Err(e) => assert_eq!(e.kind(), ErrorKind::ConnectionReset),
+    }
+    assert!(start.elapsed() < Duration::from_secs(5), "closed after {:?}", start.elapsed());
+
+    release(associate_when_free(port));
+}
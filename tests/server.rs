@@ -0,0 +1,61 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn connect(port: u16) -> TcpStream {
+    let start = Instant::now();
+    loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                return stream;
+            }
+            Err(_) if start.elapsed() < Duration::from_secs(15) => sleep(Duration::from_millis(100)),
+            Err(e) => panic!("server did not start: {}", e),
+        }
+    }
+}
+
+#[test]
+fn slow_uploads_do_not_block_searches() {
+    let source = tempfile::tempdir().unwrap();
+    common::save(common::instance("1.1", "1.1.9", "1.1.1"), &source.path().join("1.dcm"));
+    let port = free_port();
+    let _server = Server(
+        Command::new(env!("CARGO_BIN_EXE_dicom-json"))
+            .args(["serve", source.path().to_str().unwrap(), "--port", &port.to_string()])
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+
+    // An upload whose body never arrives keeps one worker reading
+    let mut upload = connect(port);
+    upload.write_all(
+        b"POST /studies HTTP/1.1\r\nHost: localhost\r\nContent-Type: multipart/related; type=\"application/dicom\"; boundary=B\r\nContent-Length: 100000\r\n\r\n--B\r\n",
+    ).unwrap();
+    sleep(Duration::from_millis(300));
+
+    let mut search = connect(port);
+    search.write_all(b"GET /studies?_=1 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = String::new();
+    search.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.contains("1.1"), "{}", response);
+}
@@ -0,0 +1,96 @@
+mod common;
+
+use std::fs;
+use dicom_core::VR;
+use dicom_dictionary_std::tags;
+use serde_json::Value;
+
+#[test]
+fn factor_series_keeps_output_paths() {
+    let input = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+    for (series, sop) in [("1.2.3.1", "1.2.3.1.1"), ("1.2.3.1", "1.2.3.1.2"), ("1.2.3.2", "1.2.3.2.1"), ("1.2.3.2", "1.2.3.2.2")] {
+        common::save(common::instance("1.2.3", series, sop), &input.path().join(format!("{}.dcm", sop)));
+    }
+
+    common::run(&[
+        input.path().to_str().unwrap(), "-o", output.path().to_str().unwrap(),
+        "--split", "series", "--factor-series",
+    ]);
+
+    for series in ["1_2_3_1", "1_2_3_2"] {
+        let path = output.path().join("study_1_2_3").join(format!("series_{}", series)).join("series.json");
+        let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert!(json.to_string().contains("\"common\""), "{:?} has no common block", path);
+    }
+    assert!(!output.path().join("study_unknown").exists());
+}
+
+/// Tags of every instance of a series output by SOP Instance UID, with `common` merged in
+fn instance_tags(json: &Value) -> Vec<(String, Value)> {
+    let series = json["series"].as_object().unwrap().values().next().unwrap();
+    let common = series.get("common").and_then(Value::as_object).cloned().unwrap_or_default();
+    let mut instances: Vec<(String, Value)> = series["instances"].as_array().unwrap().iter()
+        .map(|instance| {
+            let mut tags = common.clone();
+            tags.extend(instance["metadata"]["tags"].as_object().unwrap().clone());
+            (instance["sop_instance_uid"].as_str().unwrap().to_string(), Value::Object(tags))
+        })
+        .collect();
+    instances.sort_by(|a, b| a.0.cmp(&b.0));
+    instances
+}
+
+#[test]
+fn factored_series_merge_back_to_the_full_tags() {
+    let input = tempfile::tempdir().unwrap();
+    for (sop, number, comments) in [("1.2.3.1.1", "1", Some("contrast")), ("1.2.3.1.2", "2", None), ("1.2.3.1.3", "3", Some("contrast"))] {
+        let mut obj = common::instance("1.2.3", "1.2.3.1", sop);
+        common::put_str(&mut obj, tags::INSTANCE_NUMBER, VR::IS, number);
+        if let Some(comments) = comments {
+            common::put_str(&mut obj, tags::IMAGE_COMMENTS, VR::LT, comments);
+        }
+        common::save(obj, &input.path().join(format!("{}.dcm", sop)));
+    }
+
+    let series = |factor: bool| {
+        let output = tempfile::tempdir().unwrap();
+        let mut args = vec![input.path().to_str().unwrap(), "-o", output.path().to_str().unwrap(), "--split", "series"];
+        if factor {
+            args.push("--factor-series");
+        }
+        common::run(&args);
+        let path = output.path().join("study_1_2_3/series_1_2_3_1/series.json");
+        serde_json::from_str::<Value>(&fs::read_to_string(path).unwrap()).unwrap()
+    };
+    let (plain, factored) = (series(false), series(true));
+    let instances = instance_tags(&plain);
+    assert_eq!(instances.len(), 3);
+    assert_eq!(instance_tags(&factored), instances);
+
+    // Image Comments is missing from one instance, so it is not factored out
+    let common = factored["series"]["1.2.3.1"]["common"].as_object().unwrap();
+    assert!(common.contains_key("(0010,0010)") && !common.contains_key("(0020,4000)"));
+    let comments: Vec<bool> = instances.iter().map(|(_, tags)| tags.get("(0020,4000)").is_some()).collect();
+    assert_eq!(comments, [true, false, true]);
+}
+
+#[test]
+fn resent_instances_are_written_once() {
+    let input = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+    for (sop, file) in [("1.2.3.1.1", "a.dcm"), ("1.2.3.1.1", "b.dcm"), ("1.2.3.1.2", "c.dcm")] {
+        common::save(common::instance("1.2.3", "1.2.3.1", sop), &input.path().join(file));
+    }
+
+    let result = common::run(&[input.path().to_str().unwrap(), "-o", output.path().to_str().unwrap(), "--split", "instance"]);
+
+    let series = output.path().join("study_1_2_3").join("series_1_2_3_1");
+    let mut written: Vec<String> = fs::read_dir(&series).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    written.sort();
+    assert_eq!(written, ["1_2_3_1_1.json", "1_2_3_1_2.json"]);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.contains("SOP Instance UID 1.2.3.1.1 was already written"), "{}", stderr);
+}
@@ -0,0 +1,97 @@
+mod common;
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+struct Watcher(Option<Child>);
+
+impl Watcher {
+    fn start(input: &Path, output: &Path, extra: &[&str]) -> Self {
+        let child = Command::new(env!("CARGO_BIN_EXE_dicom-json"))
+            .args([input.to_str().unwrap(), "-o", output.to_str().unwrap(), "--watch", "--settle", "0.3", "-v"])
+            .args(extra)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // Let it start watching
+        sleep(Duration::from_millis(500));
+        Self(Some(child))
+    }
+
+    /// Stop the watcher; its stdout and stderr
+    fn stop(mut self) -> String {
+        let mut child = self.0.take().unwrap();
+        child.kill().unwrap();
+        let output = child.wait_with_output().unwrap();
+        format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr))
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.0 {
+            let _ = child.kill();
+        }
+    }
+}
+
+fn wait_for(condition: impl Fn() -> bool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(15) {
+        if condition() {
+            return true;
+        }
+        sleep(Duration::from_millis(100));
+    }
+    false
+}
+
+#[test]
+fn failed_files_stay_for_retry_and_converted_ones_are_archived() {
+    let input = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let archive = tempfile::tempdir().unwrap();
+    fs::write(input.path().join("broken.dcm"), b"not a DICOM file").unwrap();
+    let watcher = Watcher::start(input.path(), output.path(), &["--split", "study", "--archive-dir", archive.path().to_str().unwrap()]);
+
+    // The broken file neither stops the watcher nor keeps the next file from converting
+    sleep(Duration::from_secs(3));
+    common::save(common::instance("1.1", "1.1.9", "1.1.1"), &input.path().join("1.dcm"));
+    let study = output.path().join("study_1_1/study.json");
+    assert!(wait_for(|| study.exists() && archive.path().join("1.dcm").exists()));
+    assert!(!input.path().join("1.dcm").exists());
+    assert!(input.path().join("broken.dcm").exists());
+
+    // Outputs refer to the archived copy
+    let archived = fs::canonicalize(archive.path().join("1.dcm")).unwrap();
+    assert!(fs::read_to_string(&study).unwrap().contains(archived.to_str().unwrap()));
+
+    let log = watcher.stop();
+    assert!(log.contains("Retrying") && log.contains("broken.dcm"), "{}", log);
+}
+
+#[test]
+fn files_are_converted_once_they_parse() {
+    let input = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let source = tempfile::tempdir().unwrap();
+    let complete = source.path().join("1.dcm");
+    common::save(common::instance("1.1", "1.1.9", "1.1.1"), &complete);
+    let bytes = fs::read(&complete).unwrap();
+    let watcher = Watcher::start(input.path(), output.path(), &["--split", "study"]);
+
+    // A copy that stalls halfway looks settled but does not parse yet
+    let path = input.path().join("1.dcm");
+    fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+    sleep(Duration::from_millis(1500));
+    OpenOptions::new().append(true).open(&path).unwrap().write_all(&bytes[bytes.len() / 2..]).unwrap();
+
+    assert!(wait_for(|| output.path().join("study_1_1/study.json").exists()));
+    let log = watcher.stop();
+    assert!(!log.contains("Failed"), "{}", log);
+}